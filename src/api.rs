@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use std::collections::HashMap;
+
 use crate::config;
 use crate::item;
 
@@ -34,6 +36,10 @@ pub struct Recipe {
 pub enum RecipeFlags {
     AutoLearned,
     LearnedFromItem,
+    // Flags added to the API in future are captured here so that they don't break
+    // deserialization. They are not used to decide whether a recipe is known.
+    #[serde(other)]
+    Unknown,
 }
 
 impl Recipe {
@@ -43,6 +49,37 @@ impl Recipe {
     pub fn is_automatic(&self) -> bool {
         self.flags.contains(&RecipeFlags::AutoLearned)
     }
+    /// Whether a character with these crafting levels could craft the recipe, using any of its
+    /// disciplines
+    pub fn is_craftable_at(&self, crafting_levels: &HashMap<config::Discipline, u16>) -> bool {
+        self.disciplines.iter().any(|discipline| {
+            crafting_levels
+                .get(discipline)
+                .filter(|&&rating| rating >= self.min_rating)
+                .is_some()
+        })
+    }
+}
+
+// types for /account/wallet
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalletCurrency {
+    pub id: u32,
+    pub value: u32,
+}
+
+// types for /characters
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Character {
+    pub name: String,
+    pub crafting: Vec<CharacterCrafting>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CharacterCrafting {
+    pub discipline: config::Discipline,
+    pub rating: u16,
+    pub active: bool,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -51,6 +88,14 @@ pub struct RecipeIngredient {
     pub count: u32,
 }
 
+/// An amount of a wallet currency (e.g. karma) spent directly by a recipe, rather than through an
+/// item representing the currency
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct RecipeCurrencyIngredient {
+    pub currency_id: u32,
+    pub count: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiItem {
     pub id: u32,
@@ -68,6 +113,13 @@ pub struct ApiItem {
     pub details: Option<serde_json::Value>,
 }
 
+// types for /commerce/exchange/coins
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoinExchange {
+    pub coins_per_gem: u32,
+    pub quantity: u32,
+}
+
 // types for /commerce/listings
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ItemListings {
@@ -82,3 +134,90 @@ pub struct Listing {
     pub unit_price: u32,
     pub quantity: u32,
 }
+
+impl ItemListings {
+    /// Number of distinct sell orders, as opposed to the number of items for sale
+    pub fn sell_listing_count(&self) -> u32 {
+        self.sells.iter().map(|listing| listing.listings).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recipe_unknown_flags() {
+        let recipe: Recipe = serde_json::from_str(
+            r#"{
+                "id": 1,
+                "output_item_id": 2,
+                "output_item_count": 1,
+                "time_to_craft_ms": 0,
+                "disciplines": [],
+                "min_rating": 0,
+                "flags": ["LearnedFromItem", "SomeNewFlag"],
+                "ingredients": []
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            recipe.flags,
+            vec![RecipeFlags::LearnedFromItem, RecipeFlags::Unknown]
+        );
+        assert!(recipe.is_purchased());
+        assert!(!recipe.is_automatic());
+    }
+
+    #[test]
+    fn sell_listing_count() {
+        let listings: ItemListings = serde_json::from_str(
+            r#"{
+                "id": 1,
+                "buys": [{"listings": 5, "unit_price": 90, "quantity": 500}],
+                "sells": [
+                    {"listings": 1, "unit_price": 100, "quantity": 10000},
+                    {"listings": 3, "unit_price": 101, "quantity": 30}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(listings.sell_listing_count(), 4);
+    }
+
+    #[test]
+    fn recipe_craftable_at_crafting_levels() {
+        let recipe: Recipe = serde_json::from_str(
+            r#"{
+                "id": 1,
+                "output_item_id": 2,
+                "output_item_count": 1,
+                "time_to_craft_ms": 0,
+                "disciplines": ["Armorsmith", "Weaponsmith"],
+                "min_rating": 400,
+                "flags": ["AutoLearned"],
+                "ingredients": []
+            }"#,
+        )
+        .unwrap();
+        let characters: Vec<Character> = serde_json::from_str(
+            r#"[
+                {"name": "A", "level": 80, "crafting": [
+                    {"discipline": "Armorsmith", "rating": 350, "active": true},
+                    {"discipline": "Chef", "rating": 500, "active": true}
+                ]},
+                {"name": "B", "level": 80, "crafting": []}
+            ]"#,
+        )
+        .unwrap();
+        let mut crafting_levels: HashMap<config::Discipline, u16> = characters
+            .iter()
+            .flat_map(|character| &character.crafting)
+            .map(|crafting| (crafting.discipline, crafting.rating))
+            .collect();
+        assert!(!recipe.is_craftable_at(&crafting_levels));
+        crafting_levels.insert(config::Discipline::Weaponsmith, 400);
+        assert!(recipe.is_craftable_at(&crafting_levels));
+        assert!(!recipe.is_craftable_at(&HashMap::new()));
+    }
+}
@@ -1,56 +1,223 @@
 use num_rational::Rational32;
-use std::collections::HashSet;
+use num_traits::ToPrimitive;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
-use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 
-use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use structopt::StructOpt;
 use strum::{Display, EnumString, EnumVariantNames, VariantNames};
 use toml;
 
 use lazy_static::lazy_static;
 
+use crate::item::Rarity;
+use crate::money;
+use crate::profit::{SaleVenue, LISTING_HOLD_COST};
+
 pub const CACHE_PREFIX: &str = "cache_";
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct CraftingOptions {
     pub include_timegated: bool,
     pub count: Option<u32>,
+    pub allow_overcraft: bool,
     pub threshold: Option<u32>,
     pub value: Option<u32>,
+    pub sale_percentile: Option<u32>,
+    /// Venues the crafted item can be sold to, with each batch sold to whichever pays the most.
+    /// Only buy orders if empty
+    pub sale_venues: Vec<SaleVenue>,
+    /// Percent of the price lost while a listing waits to sell, if not the default
+    pub listing_hold_cost_percent: Option<u8>,
+    pub include_breakeven: bool,
+    /// Number of the highest buy order prices of the crafted item not to sell into
+    pub ignore_top_buys: u32,
+    pub audit: bool,
+    /// Record the trading post listings each ingredient is bought from
+    pub audit_listings: bool,
+    pub ingredient_pricing: IngredientPricing,
+    /// Trading post listing fee in percent, if not the game's current fee
+    pub listing_fee_percent: Option<u8>,
+    /// Trading post exchange fee in percent, if not the game's current fee
+    pub exchange_fee_percent: Option<u8>,
+    /// Minimum quantity listed on the trading post for an item to be bought or crafted for sale
+    pub min_sell_volume: Option<u32>,
+    /// Minimum quantity of buy orders for a crafted item to be sold
+    pub min_buy_volume: Option<u32>,
+    /// Quantities of items already owned, which are used before acquiring more
+    #[serde(serialize_with = "serialize_sorted_map")]
+    pub inventory: HashMap<u32, u32>,
+}
+
+impl CraftingOptions {
+    pub fn listing_fee(&self) -> u8 {
+        self.listing_fee_percent
+            .unwrap_or(money::TRADING_POST_LISTING_FEE)
+    }
+
+    pub fn exchange_fee(&self) -> u8 {
+        self.exchange_fee_percent
+            .unwrap_or(money::TRADING_POST_EXCHANGE_FEE)
+    }
+
+    /// Revenue from selling at `price` after the trading post fees
+    pub fn sale_revenue(&self, price: money::Money) -> money::Money {
+        price.trading_post_sale_revenue_with_fees(self.listing_fee(), self.exchange_fee())
+    }
+
+    pub fn listing_hold_cost(&self) -> u8 {
+        self.listing_hold_cost_percent.unwrap_or(LISTING_HOLD_COST)
+    }
+
+    /// Revenue from listing at `price` after the trading post fees and the cost of holding the
+    /// listing until it sells
+    pub fn listing_revenue(&self, price: money::Money) -> money::Money {
+        self.sale_revenue(price) - price * u32::from(self.listing_hold_cost()) / 100
+    }
+
+    /// Whether the crafted item can be sold to the given venue
+    pub fn sells_to(&self, venue: SaleVenue) -> bool {
+        if self.sale_venues.is_empty() {
+            venue == SaleVenue::BuyOrder
+        } else {
+            self.sale_venues.contains(&venue)
+        }
+    }
+
+    /// Whether enough of an item is listed on the trading post to rely on buying it there
+    pub fn meets_sell_volume(&self, quantity: u32) -> bool {
+        quantity >= self.min_sell_volume.unwrap_or(0)
+    }
+
+    /// Whether there are enough buy orders for an item to rely on selling it to them
+    pub fn meets_buy_volume(&self, quantity: u32) -> bool {
+        quantity >= self.min_buy_volume.unwrap_or(0)
+    }
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize)]
 pub struct Config {
     pub crafting: CraftingOptions,
 
+    pub mode: Mode,
+
     pub output_csv: Option<PathBuf>,
+    pub csv_metadata: bool,
+    pub output_json: Option<PathBuf>,
+    pub undercut_scan: bool,
+    pub vendor_sell_scan: bool,
+    pub best_venue_scan: bool,
+    pub compare_known_recipes: bool,
+    /// Only scan this many recipes, in order of item id
+    pub limit_recipes: Option<usize>,
+    pub chain_value: bool,
+    pub export_calculator: Option<Option<usize>>,
+    /// Only list this many of the most profitable items
+    pub top: Option<u32>,
+    pub sort_by: SortBy,
+    /// Minimum profit as a percentage of the crafting cost
+    pub min_roi: Option<u32>,
+    pub slot_cost: u32,
+    /// Crafting steps done per hour, to estimate the gold earned per hour
+    pub crafts_per_hour: Option<u32>,
+    pub group_by_vendor: bool,
+    pub checklist: bool,
+    pub steps_csv: Option<PathBuf>,
+    /// Number of crafted items kept for personal use instead of sold
+    pub keep: Option<u32>,
+    #[serde(serialize_with = "serialize_sorted_map")]
+    pub aliases: HashMap<u32, String>,
+    pub include_recipe_cost: bool,
+    pub copper_rounding: CopperRounding,
     pub filter_disciplines: Option<Vec<Discipline>>,
+    pub filter_rarities: Option<Vec<Rarity>>,
+    pub filter_stats: Option<Vec<String>>,
+    pub min_output_count: Option<u32>,
+    pub max_output_count: Option<u32>,
+    pub min_listings: Option<u32>,
+    pub no_mystic_forge: bool,
+    pub exclude_stations: Option<Vec<Station>>,
     pub lang: Option<Language>,
+    /// Languages to also store item data for, in addition to --lang
+    pub langs: Vec<Language>,
+    /// Language of the item names printed, if not --lang
+    pub name_lang: Option<Language>,
+    /// API used for Chinese item and recipe data, if not the global one
+    pub zh_api_url: Option<String>,
+    #[serde(serialize_with = "serialize_redacted")]
     pub api_key: Option<String>,
 
     // Currency conversion values
     pub ascended: Option<u32>,
+    pub empyreal: Option<u32>,
+    pub dragonite: Option<u32>,
+    pub bloodstone: Option<u32>,
+    #[serde(serialize_with = "serialize_rational")]
     pub karma: Option<Rational32>,
+    #[serde(serialize_with = "serialize_rational")]
     pub um: Option<Rational32>,
+    #[serde(serialize_with = "serialize_rational")]
     pub vm: Option<Rational32>,
+    #[serde(serialize_with = "serialize_rational")]
     pub rn: Option<Rational32>,
+    #[serde(serialize_with = "serialize_rational")]
+    pub ss: Option<Rational32>,
+    /// Coins per gem, from --gem-rate or the gem exchange when fetching the live rate
+    #[serde(serialize_with = "serialize_gem_rate")]
+    pub gem: OnceCell<Rational32>,
+    pub live_gem_rate: bool,
 
     pub cache_dir: PathBuf,
+    /// Fetch trading post prices and listings even if they were cached in the last few minutes
+    #[serde(skip)]
+    pub refresh_prices: bool,
+    pub data_dir: PathBuf,
     pub api_recipes_file: PathBuf,
+    pub recursive_recipes_file: PathBuf,
     pub custom_recipes_file: PathBuf,
     pub items_file: PathBuf,
 
+    pub custom_recipes_stdin: bool,
+    /// Use recipes with a random number of outputs at their average output
+    pub expected_value: bool,
+    pub prices_file: Option<PathBuf>,
+    /// Chance in percent with which the shown promotion profit must be made
+    pub promotion_confidence: Option<u32>,
+
+    pub parallel_requests: Option<usize>,
+    /// Sent with every API request so that ArenaNet can tell where heavy use comes from
+    pub user_agent: String,
+    /// Seconds after which the run is aborted
+    pub max_runtime: Option<u64>,
+
+    #[serde(serialize_with = "serialize_sorted_ids")]
+    pub known_recipes: Option<HashSet<u32>>,
+    /// Treat automatic and discoverable recipes as unknown when no character has the crafting
+    /// level for them
+    pub check_crafting_levels: bool,
+    /// Output item ids of the achievement recipes which have been unlocked. If set, other
+    /// achievement recipes are ignored
+    #[serde(serialize_with = "serialize_sorted_ids")]
+    pub known_achievement_recipes: Option<HashSet<u32>>,
+
+    #[serde(serialize_with = "serialize_sorted_ids")]
     pub item_blacklist: Option<HashSet<u32>>,
+    #[serde(serialize_with = "serialize_sorted_ids")]
     pub recipe_blacklist: Option<HashSet<u32>>,
 
-    pub item_id: Option<u32>,
+    pub error_format: ErrorFormat,
+
+    #[serde(skip)]
+    pub print_config: bool,
+    #[serde(skip)]
+    pub reset_data: bool,
 }
 
 lazy_static! {
@@ -64,15 +231,110 @@ impl Config {
         let opt = Opt::from_args();
 
         config.crafting.include_timegated = opt.include_timegated;
-        config.crafting.count = opt.count;
-        config.crafting.threshold = opt.threshold;
-        config.crafting.value = opt.value;
+        config.crafting.audit = opt.audit;
+        config.crafting.audit_listings = opt.audit_listings;
+        config.crafting.ingredient_pricing = opt.ingredient_pricing;
+        config.copper_rounding = opt.copper_rounding;
+
+        match opt
+            .command
+            .unwrap_or_else(|| Command::Scan(ScanOpt::default()))
+        {
+            Command::Scan(scan) => {
+                config.mode = Mode::Scan;
+                config.apply_profit_options(scan.profit);
+                config.output_csv = scan.output_csv;
+                config.csv_metadata = scan.csv_metadata;
+                config.output_json = scan.output_json;
+                config.undercut_scan = scan.undercut_scan;
+                config.vendor_sell_scan = scan.vendor_sell_scan;
+                config.best_venue_scan = scan.best_venue_scan;
+                config.compare_known_recipes = scan.compare_known_recipes;
+                config.limit_recipes = scan.limit_recipes;
+                config.chain_value = scan.chain_value;
+                config.export_calculator = scan.export_calculator;
+                config.top = scan.top;
+                config.sort_by = scan.sort_by;
+                config.min_roi = scan.min_roi;
+                config.filter_disciplines = scan.filter_disciplines;
+                config.filter_rarities = scan.filter_rarities;
+                config.filter_stats = scan.filter_stats;
+                config.min_output_count = scan.min_output_count;
+                config.max_output_count = scan.max_output_count;
+                config.min_listings = scan.min_listings;
+                config.prices_file = scan.prices_file;
+            }
+            Command::Item(item) => {
+                let mut items = item.items;
+                if let Some(path) = &item.items_file {
+                    match fs::read_to_string(path) {
+                        Ok(s) => items.extend(parse_item_list(&s)),
+                        Err(e) => {
+                            eprintln!("Failed to read items from '{}': {}", path.display(), e)
+                        }
+                    }
+                }
+                let rarity = item.rarity;
+                config.mode = Mode::Item(
+                    items
+                        .into_iter()
+                        .map(|item| match item.parse::<u32>() {
+                            Ok(item_id) => ItemQuery::Id(item_id),
+                            Err(_) => ItemQuery::Name(item, rarity),
+                        })
+                        .collect(),
+                );
+                config.apply_profit_options(item.profit);
+                config.slot_cost = item.slot_cost;
+                config.group_by_vendor = item.group_by_vendor;
+                config.checklist = item.checklist;
+                config.steps_csv = item.steps_csv;
+                config.keep = item.keep;
+                config.include_recipe_cost = item.include_recipe_cost;
+            }
+            Command::Acquire(acquire) => {
+                config.mode = Mode::Acquire(acquire.item_ids);
+                config.crafting.count = acquire.count;
+                config.group_by_vendor = acquire.group_by_vendor;
+                config.checklist = acquire.checklist;
+            }
+            Command::Feasibility { item_id, count } => {
+                config.mode = Mode::Feasibility(item_id);
+                config.crafting.count = count;
+            }
+            Command::Search { name } => config.mode = Mode::Search(name),
+            Command::PromotionScan {
+                prices_file,
+                confidence,
+            } => {
+                config.mode = Mode::PromotionScan;
+                config.prices_file = prices_file;
+                config.promotion_confidence = confidence;
+            }
+            Command::CurrencyArbitrage => config.mode = Mode::CurrencyArbitrage,
+            Command::ValidateData => config.mode = Mode::ValidateData,
+        }
 
-        config.output_csv = opt.output_csv;
+        config.no_mystic_forge = opt.no_mystic_forge;
+        config.exclude_stations = opt.exclude_stations;
 
-        config.item_id = opt.item_id;
+        config.parallel_requests = opt.parallel_requests.filter(|&n| n > 0);
+        config.max_runtime = opt.max_runtime;
 
-        config.filter_disciplines = opt.filter_disciplines;
+        config.error_format = opt.error_format;
+        config.print_config = opt.print_config;
+        config.reset_data = opt.reset_data;
+        config.refresh_prices = opt.refresh_prices;
+
+        if let Some(path) = &opt.aliases {
+            match fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| parse_aliases(&s))
+            {
+                Ok(aliases) => config.aliases = aliases,
+                Err(e) => eprintln!("Failed to read aliases from '{}': {}", path.display(), e),
+            }
+        }
 
         let file: ConfigFile = match get_file_config(&opt.config_file) {
             Ok(config) => config,
@@ -84,6 +346,13 @@ impl Config {
 
         config.api_key = file.api_key;
 
+        let file_user_agent = file_header("user_agent", file.user_agent);
+        let file_contact = file_header("contact", file.contact);
+        config.user_agent = user_agent(
+            opt.user_agent.or(file_user_agent),
+            opt.contact.or(file_contact),
+        );
+
         config.lang = if let Some(_) = opt.lang {
             opt.lang
         } else if let Some(code) = file.lang {
@@ -106,6 +375,30 @@ impl Config {
             None
         };
 
+        config.empyreal = if let Some(value) = opt.empyreal_value {
+            Some(value)
+        } else if let Some(currencies) = &file.currencies {
+            currencies.empyreal
+        } else {
+            None
+        };
+
+        config.dragonite = if let Some(value) = opt.dragonite_value {
+            Some(value)
+        } else if let Some(currencies) = &file.currencies {
+            currencies.dragonite
+        } else {
+            None
+        };
+
+        config.bloodstone = if let Some(value) = opt.bloodstone_value {
+            Some(value)
+        } else if let Some(currencies) = &file.currencies {
+            currencies.bloodstone
+        } else {
+            None
+        };
+
         config.karma = if let Some(value) = opt.karma {
             Rational32::approximate_float(value)
         } else if let Some(currencies) = &file.currencies {
@@ -138,72 +431,479 @@ impl Config {
             None
         };
 
+        let file_fees = file.fees.as_ref();
+        config.crafting.listing_fee_percent = opt
+            .listing_fee
+            .or_else(|| file_fees.and_then(|fees| fees.listing));
+        config.crafting.exchange_fee_percent = opt
+            .exchange_fee
+            .or_else(|| file_fees.and_then(|fees| fees.exchange));
+
+        let file_liquidity = file.liquidity.as_ref();
+        config.crafting.min_sell_volume = opt
+            .min_sell_volume
+            .or_else(|| file_liquidity.and_then(|liquidity| liquidity.min_sell_volume));
+        config.crafting.min_buy_volume = opt
+            .min_buy_volume
+            .or_else(|| file_liquidity.and_then(|liquidity| liquidity.min_buy_volume));
+
+        if let Some(path) = &opt.inventory_file {
+            match fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+            {
+                Ok(inventory) => config.crafting.inventory = inventory,
+                Err(e) => eprintln!("Failed to read inventory from '{}': {}", path.display(), e),
+            }
+        }
+
+        config.ss = if let Some(value) = opt.spirit_shard {
+            Rational32::approximate_float(value)
+        } else if let Some(currencies) = &file.currencies {
+            currencies.ss.and_then(Rational32::approximate_float)
+        } else {
+            None
+        };
+
+        let gem_coins = match opt.gem_rate {
+            Some(GemRate::Live) => {
+                config.live_gem_rate = true;
+                None
+            }
+            Some(GemRate::Coins(coins)) => Some(coins),
+            None => file
+                .currencies
+                .as_ref()
+                .and_then(|currencies| currencies.gem),
+        };
+        if let Some(coins) = gem_coins {
+            config.set_gem_rate(coins);
+        }
+
+        config.custom_recipes_stdin = opt.custom_recipes_stdin;
+        config.expected_value = opt.expected_value;
+
+        let file_known_achievement_recipes = file
+            .recipes
+            .as_ref()
+            .and_then(|recipes| recipes.achievement_known.clone());
+        config.known_achievement_recipes = match (
+            opt.achievement_recipes_known,
+            file_known_achievement_recipes,
+        ) {
+            (None, None) => None,
+            (opt_known, file_known) => Some(
+                opt_known
+                    .into_iter()
+                    .flatten()
+                    .chain(file_known.into_iter().flatten())
+                    .collect(),
+            ),
+        };
+
+        let file_known_recipes = file.recipes.and_then(|recipes| recipes.known);
+        config.check_crafting_levels = opt.check_crafting_levels;
+        config.known_recipes = match (opt.known_recipes, file_known_recipes) {
+            (None, None) => None,
+            (opt_known, file_known) => Some(
+                opt_known
+                    .into_iter()
+                    .flatten()
+                    .chain(file_known.into_iter().flatten())
+                    .collect(),
+            ),
+        };
+
         if let Some(blacklists) = file.blacklist {
-            config.item_blacklist = blacklists.items.map(HashSet::from_iter);
-            config.recipe_blacklist = blacklists.recipes.map(HashSet::from_iter);
+            config.item_blacklist = blacklists.items.map(blacklist_ids);
+            config.recipe_blacklist = blacklists.recipes.map(blacklist_ids);
         };
+        if let Some(ranges) = opt.blacklist_range {
+            config
+                .item_blacklist
+                .get_or_insert_with(HashSet::new)
+                .extend(ranges.into_iter().flatten());
+        }
 
-        let cache_dir = cache_dir(&opt.cache_dir).expect("Failed to identify cache dir");
-        ensure_dir(&cache_dir).expect("Failed to create cache dir");
-        match flush_cache(&cache_dir) {
-            Err(e) => println!("Failed to flush cache dir {}: {}", &cache_dir.display(), e),
-            _ => (),
+        if let Err(message) = config.validate() {
+            structopt::clap::Error::with_description(
+                &message,
+                structopt::clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
         }
-        config.cache_dir = cache_dir;
+
+        config.cache_dir = cache_dir(&opt.cache_dir).expect("Failed to identify cache dir");
 
         let data_dir = data_dir(&opt.data_dir).expect("Failed to identify data dir");
-        ensure_dir(&data_dir).expect("Failed to create data dir");
+        config.data_dir = data_dir.clone();
 
         let mut api_recipes_path = data_dir.clone();
         api_recipes_path.push("recipes.bin");
         config.api_recipes_file = api_recipes_path;
 
-        let mut custom_recipes_path = data_dir.clone();
-        custom_recipes_path.push("custom.bin");
-        config.custom_recipes_file = custom_recipes_path;
+        let mut recursive_recipes_path = data_dir.clone();
+        recursive_recipes_path.push("recursive_recipes.bin");
+        config.recursive_recipes_file = recursive_recipes_path;
 
         let lang_suffix =
             Language::code(&config.lang).map_or_else(|| "".to_string(), |c| format!("_{}", c));
-        let mut items_path = data_dir.clone();
-        items_path.push(format!("items{}.bin", lang_suffix));
-        config.items_file = items_path;
 
-        if opt.reset_data {
-            match remove_data_file(&config.items_file) {
+        // custom recipes are only published in English, but are cached per language so that
+        // switching languages never mixes data fetched for another language
+        let mut custom_recipes_path = data_dir.clone();
+        custom_recipes_path.push(format!("custom{}.bin", lang_suffix));
+        config.custom_recipes_file = custom_recipes_path;
+        config.items_file = config.lang_items_file(&config.lang);
+        config.langs = opt.langs;
+        config.name_lang = opt.name_lang;
+        config.zh_api_url = opt
+            .zh_api_url
+            .map(|url| url.trim_end_matches('/').to_string());
+
+        config
+    }
+
+    /// Create the cache and data dirs, remove expired cache files and, with --reset-data, remove
+    /// the data files. Separate from `new` so that --print-config has no side effects.
+    pub fn prepare_dirs(&self) -> Result<(), Box<dyn std::error::Error>> {
+        ensure_dir(&self.cache_dir)?;
+        if let Err(e) = flush_cache(&self.cache_dir) {
+            println!(
+                "Failed to flush cache dir {}: {}",
+                &self.cache_dir.display(),
+                e
+            );
+        }
+        ensure_dir(&self.data_dir)?;
+
+        if self.reset_data {
+            match remove_data_file(&self.items_file) {
                 Err(e) => println!(
                     "Failed to remove file {}: {}",
-                    &config.items_file.display(),
+                    &self.items_file.display(),
                     e
                 ),
                 _ => (),
             };
-            match remove_data_file(&config.api_recipes_file) {
+            for lang in self.langs.iter().chain(self.name_lang.iter()) {
+                let items_file = self.lang_items_file(&Some(*lang));
+                if items_file != self.items_file {
+                    if let Err(e) = remove_data_file(&items_file) {
+                        println!("Failed to remove file {}: {}", &items_file.display(), e);
+                    }
+                }
+            }
+            match remove_data_file(&self.api_recipes_file) {
                 Err(e) => println!(
                     "Failed to remove file {}: {}",
-                    &config.api_recipes_file.display(),
+                    &self.api_recipes_file.display(),
                     e
                 ),
                 _ => (),
             };
-            match remove_data_file(&config.custom_recipes_file) {
+            if let Err(e) = remove_data_file(&self.recursive_recipes_file) {
+                println!(
+                    "Failed to remove file {}: {}",
+                    &self.recursive_recipes_file.display(),
+                    e
+                );
+            }
+            match remove_data_file(&self.custom_recipes_file) {
                 Err(e) => println!(
                     "Failed to remove file {}: {}",
-                    &config.custom_recipes_file.display(),
+                    &self.custom_recipes_file.display(),
                     e
                 ),
                 _ => (),
             };
         }
 
-        config
+        Ok(())
+    }
+
+    /// Data file storing the items with names in the given language
+    pub fn lang_items_file(&self, lang: &Option<Language>) -> PathBuf {
+        let lang_suffix =
+            Language::code(lang).map_or_else(|| "".to_string(), |c| format!("_{}", c));
+        let mut items_path = self.data_dir.clone();
+        items_path.push(format!("items{}.bin", lang_suffix));
+        items_path
     }
+
+    /// Value gems at this many coins each. Once set, the rate can't change, so a rate fetched from
+    /// the gem exchange doesn't replace one given with --gem-rate.
+    pub fn set_gem_rate(&self, coins_per_gem: u32) {
+        let _ = self.gem.set(Rational32::from(coins_per_gem as i32));
+    }
+
+    /// Reject combinations of options which contradict each other, rather than silently ignoring
+    /// one of them
+    pub fn validate(&self) -> Result<(), String> {
+        if self.crafting.value.is_some() {
+            if self.crafting.sale_percentile.is_some() {
+                return Err(
+                    "--value sets a fixed sale price, so buy orders aren't used and \
+                            --sale-percentile would have no effect"
+                        .to_string(),
+                );
+            }
+            if self.crafting.ignore_top_buys > 0 {
+                return Err(
+                    "--value sets a fixed sale price, so buy orders aren't used and \
+                            --ignore-top-buy would have no effect"
+                        .to_string(),
+                );
+            }
+            if !self.crafting.sale_venues.is_empty() {
+                return Err(
+                    "--value sets a fixed sale price, so --sale-venues would have no effect"
+                        .to_string(),
+                );
+            }
+            if self.chain_value {
+                return Err(
+                    "--chain-value values items by their use in other recipes, which \
+                            contradicts the fixed sale price set by --value"
+                        .to_string(),
+                );
+            }
+        }
+        if u32::from(self.crafting.listing_fee()) + u32::from(self.crafting.exchange_fee()) >= 100 {
+            return Err(format!(
+                "The listing fee ({}%) and exchange fee ({}%) would take the whole sale price",
+                self.crafting.listing_fee(),
+                self.crafting.exchange_fee()
+            ));
+        }
+        if self.crafting.listing_hold_cost() > 100 {
+            return Err(format!(
+                "--listing-hold-cost ({}%) can't be more than the whole sale price",
+                self.crafting.listing_hold_cost()
+            ));
+        }
+        if let (Some(min), Some(max)) = (self.min_output_count, self.max_output_count) {
+            if min > max {
+                return Err(format!(
+                    "--min-output-count ({}) is greater than --max-output-count ({}), so no \
+                     recipes would match",
+                    min, max
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_profit_options(&mut self, opt: ProfitOpt) {
+        self.crafting.count = opt.count;
+        self.crafting.allow_overcraft = opt.allow_overcraft;
+        self.crafting.threshold = opt.threshold;
+        self.crafting.value = opt.value;
+        self.crafting.sale_percentile = opt.sale_percentile.filter(|&p| p < 100);
+        self.crafting.sale_venues = opt.sale_venues;
+        self.crafting.listing_hold_cost_percent = opt.listing_hold_cost;
+        self.crafting.include_breakeven = opt.include_breakeven;
+        self.crafting.ignore_top_buys = match opt.ignore_top_buy {
+            Some(count) => count.unwrap_or(1),
+            None => 0,
+        };
+        self.crafts_per_hour = opt.crafts_per_hour;
+    }
+}
+
+/// What to do after loading recipes and items, chosen by the subcommand
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    #[default]
+    Scan,
+    Item(Vec<ItemQuery>),
+    Acquire(Vec<u32>),
+    Feasibility(u32),
+    Search(String),
+    CurrencyArbitrage,
+    PromotionScan,
+    ValidateData,
+}
+
+/// An item given on the command line, by id or by name
+#[derive(Debug, PartialEq, Serialize)]
+pub enum ItemQuery {
+    Id(u32),
+    /// Items of different rarities can share a name, so a rarity may be needed to pick one
+    Name(String, Option<Rarity>),
+}
+
+/// Accepts a number, or a string using either "." or "," as the decimal separator
+fn deserialize_decimal<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Decimal {
+        Number(f64),
+        Text(String),
+    }
+
+    match Decimal::deserialize(deserializer)? {
+        Decimal::Number(value) => Ok(Some(value)),
+        Decimal::Text(text) => parse_decimal(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn serialize_redacted<S>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    value.as_ref().map(|_| "<redacted>").serialize(serializer)
+}
+
+fn serialize_gem_rate<S>(value: &OnceCell<Rational32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serialize_rational(&value.get().copied(), serializer)
+}
+
+fn serialize_rational<S>(value: &Option<Rational32>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    value.and_then(|value| value.to_f64()).serialize(serializer)
+}
+
+// the serialized config is part of the result cache key, so it must not depend on hash order
+fn serialize_sorted_map<S, V>(value: &HashMap<u32, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    V: Serialize,
+{
+    value
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
 }
 
+fn serialize_sorted_ids<S>(value: &Option<HashSet<u32>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    value
+        .as_ref()
+        .map(|ids| ids.iter().collect::<BTreeSet<_>>())
+        .serialize(serializer)
+}
+
+/// Reads the config file given on the command line or, if none was given, the global config file
+/// with any `gw2-arbitrage.toml` in the current directory layered on top of it
 fn get_file_config(file: &Option<PathBuf>) -> Result<ConfigFile, Box<dyn std::error::Error>> {
-    let mut file = File::open(config_file(file)?)?;
+    if file.is_some() {
+        return Ok(read_toml(&config_file(file)?)?.try_into()?);
+    }
+
+    let global_file = config_file(&None)?;
+    let local_file = local_config_file()?;
+    let global = if global_file.exists() || !local_file.exists() {
+        read_toml(&global_file)?
+    } else {
+        toml::Value::Table(Default::default())
+    };
+    let merged = if local_file.exists() && local_file != global_file {
+        merge_toml(global, read_toml(&local_file)?)
+    } else {
+        global
+    };
+    Ok(merged.try_into()?)
+}
+
+fn read_toml(path: &Path) -> Result<toml::Value, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
     let mut s = String::new();
     file.read_to_string(&mut s)?;
-    Ok(toml::from_str(&s)?)
+    toml::from_str(&s).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Parse a single id, or an inclusive range of ids such as "49424-49439"
+fn parse_id_range(s: &str) -> Result<RangeInclusive<u32>, String> {
+    let parse_id = |id: &str| {
+        id.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("Invalid id range: {}", s))
+    };
+    let range = match s.split_once('-') {
+        Some((first, last)) => parse_id(first)?..=parse_id(last)?,
+        None => {
+            let id = parse_id(s)?;
+            id..=id
+        }
+    };
+    if range.is_empty() {
+        return Err(format!(
+            "Invalid id range: {} (the first id must not be larger than the last)",
+            s
+        ));
+    }
+    Ok(range)
+}
+
+/// Expand the ids and id ranges of a blacklist in the config file, ignoring invalid ranges
+fn blacklist_ids(entries: Vec<ConfigFileBlacklistEntry>) -> HashSet<u32> {
+    let mut ids = HashSet::new();
+    for entry in entries {
+        match entry {
+            ConfigFileBlacklistEntry::Id(id) => {
+                ids.insert(id);
+            }
+            ConfigFileBlacklistEntry::Range(range) => match parse_id_range(&range) {
+                Ok(range) => ids.extend(range),
+                Err(e) => eprintln!("Ignoring blacklist entry: {}", e),
+            },
+        }
+    }
+    ids
+}
+
+/// Parse item nicknames from TOML lines like `19976 = "ecto"`
+fn parse_aliases(s: &str) -> Result<HashMap<u32, String>, String> {
+    let aliases: HashMap<String, String> = toml::from_str(s).map_err(|e| e.to_string())?;
+    aliases
+        .into_iter()
+        .map(|(item_id, alias)| {
+            item_id
+                .parse()
+                .map(|item_id| (item_id, alias))
+                .map_err(|_| format!("'{}' is not an item id", item_id))
+        })
+        .collect()
+}
+
+/// Item ids or names from an --items-file, one per line, skipping blank lines and # comments
+fn parse_item_list(s: &str) -> Vec<String> {
+    s.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Merges tables recursively, with keys in `overlay` replacing those in `base`
+fn merge_toml(base: toml::Value, overlay: toml::Value) -> toml::Value {
+    match (base, overlay) {
+        (toml::Value::Table(mut base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                let merged = match base.remove(&key) {
+                    Some(base_value) => merge_toml(base_value, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            toml::Value::Table(base)
+        }
+        (_, overlay) => overlay,
+    }
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -211,97 +911,577 @@ struct ConfigFile {
     // API key requires scope unlocks
     api_key: Option<String>,
     lang: Option<String>,
+    user_agent: Option<String>,
+    contact: Option<String>,
     currencies: Option<ConfigFileCurrencySection>,
     blacklist: Option<ConfigFileBlacklistSection>,
+    recipes: Option<ConfigFileRecipesSection>,
+    fees: Option<ConfigFileFeesSection>,
+    liquidity: Option<ConfigFileLiquiditySection>,
 }
 #[derive(Debug, Default, Deserialize)]
 struct ConfigFileCurrencySection {
     ascended: Option<u32>,
+    empyreal: Option<u32>,
+    dragonite: Option<u32>,
+    bloodstone: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_decimal")]
     karma: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_decimal")]
     um: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_decimal")]
     vm: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_decimal")]
     rn: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_decimal")]
+    ss: Option<f64>,
+    gem: Option<u32>,
 }
 #[derive(Debug, Default, Deserialize)]
 struct ConfigFileBlacklistSection {
-    items: Option<Vec<u32>>,
-    recipes: Option<Vec<u32>>,
+    items: Option<Vec<ConfigFileBlacklistEntry>>,
+    recipes: Option<Vec<ConfigFileBlacklistEntry>>,
+}
+/// A single id, or a range of ids such as "49424-49439". TOML arrays can't mix types, so ids
+/// listed alongside ranges are written as strings too, e.g. ["24", "49424-49439"]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ConfigFileBlacklistEntry {
+    Id(u32),
+    Range(String),
+}
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFileFeesSection {
+    listing: Option<u8>,
+    exchange: Option<u8>,
+}
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFileLiquiditySection {
+    min_sell_volume: Option<u32>,
+    min_buy_volume: Option<u32>,
+}
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFileRecipesSection {
+    known: Option<Vec<u32>>,
+    achievement_known: Option<Vec<u32>>,
 }
 
 #[derive(StructOpt, Debug)]
 struct Opt {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     /// Include timegated recipes such as Deldrimor Steel Ingot
     #[structopt(short = "t", long)]
     include_timegated: bool,
 
-    /// Output the full list of profitable recipes to this CSV file
-    #[structopt(short, long, parse(from_os_str))]
-    output_csv: Option<PathBuf>,
-
-    /// Print a shopping list of ingredients for the given item id
-    item_id: Option<u32>,
-
-    /// Limit the maximum number of items produced for a recipe
-    #[structopt(short, long)]
-    count: Option<u32>,
+    /// How to price ingredients bought from the trading post. "marginal" prices each purchase at
+    /// the next cheapest listings, "average" prices all purchases of an ingredient at the average
+    /// price of the cheapest listings
+    #[structopt(long, default_value = "marginal", possible_values = IngredientPricing::VARIANTS)]
+    ingredient_pricing: IngredientPricing,
+
+    /// Trading post listing fee in percent, to model a change to the fees. Defaults to 5
+    #[structopt(long, value_name = "percent")]
+    listing_fee: Option<u8>,
+
+    /// Trading post exchange fee in percent, to model a change to the fees. Defaults to 10
+    #[structopt(long, value_name = "percent")]
+    exchange_fee: Option<u8>,
+
+    /// Only buy items from the trading post, and only sell crafted items, when at least this many
+    /// are listed for sale in total, since a few listings are easily bought out
+    #[structopt(long, value_name = "quantity")]
+    min_sell_volume: Option<u32>,
+
+    /// Only sell crafted items with buy orders for at least this many items in total
+    #[structopt(long, value_name = "quantity")]
+    min_buy_volume: Option<u32>,
+
+    /// Use items you already own before buying or crafting more, from a JSON file mapping item ids
+    /// to owned quantities, e.g. {"49438": 2}. Owned items are free, so profits include their value.
+    /// When pricing several items, each uses what the items before it left of the inventory
+    #[structopt(long, parse(from_os_str))]
+    inventory_file: Option<PathBuf>,
+
+    /// How to round values with fractions of a copper, e.g. from currency conversions or batches
+    /// shared between several items. "ceil" never understates a cost, but the trading post fees
+    /// are always rounded to the nearest copper, so a profit recomputed by hand may differ by a
+    /// copper; "round" matches the fees
+    #[structopt(long, default_value = "ceil", possible_values = CopperRounding::VARIANTS)]
+    copper_rounding: CopperRounding,
+
+    /// Check each profit calculation independently and warn about any discrepancies
+    #[structopt(long)]
+    audit: bool,
 
-    /// Calculate profit based on a fixed value instead of from buy orders
+    /// List the exact trading post sell listings, as price and quantity, that each ingredient in a
+    /// shopping list is bought from, to cross-check against the trading post in game
     #[structopt(long)]
-    value: Option<u32>,
+    audit_listings: bool,
 
-    /// Threshold - min profit per item in copper
+    /// Ignore Mystic Forge recipes, both for crafted items and their ingredients
     #[structopt(long)]
-    threshold: Option<u32>,
+    no_mystic_forge: bool,
+
+    /// Maximum number of API requests to make at once when downloading data and trading post
+    /// listings. Defaults to 10.
+    #[structopt(long, value_name = "count")]
+    parallel_requests: Option<usize>,
+
+    /// Abort with an error if the whole run takes longer than this many seconds, e.g. so that an
+    /// API outage can't block a scheduled job. Checked while waiting for the API, so a long
+    /// calculation may overrun it
+    #[structopt(long, value_name = "seconds")]
+    max_runtime: Option<u64>,
+
+    /// Send this User-Agent with API requests instead of the default, which names this tool and
+    /// its version
+    #[structopt(long, parse(try_from_str = get_header))]
+    user_agent: Option<String>,
+
+    /// Contact details, such as an email address or account name, added to the User-Agent of API
+    /// requests so that ArenaNet can get in touch about heavy use instead of rate limiting it
+    #[structopt(long, parse(try_from_str = get_header))]
+    contact: Option<String>,
+
+    /// Comma-separated list of recipe ids which have already been learned, in addition to any
+    /// fetched using the API key
+    #[structopt(long, use_delimiter = true, value_name = "ids")]
+    known_recipes: Option<Vec<u32>>,
+
+    /// Fetch the crafting levels of the account's characters using the API key, which needs the
+    /// characters scope, and treat automatic and discoverable recipes as unknown if no character
+    /// has the level to craft them. Otherwise they are all assumed to be known
+    #[structopt(long)]
+    check_crafting_levels: bool,
 
-    #[structopt(short = "d", long = "disciplines", use_delimiter = true, help = &DISCIPLINES_HELP, parse(try_from_str = get_discipline))]
-    filter_disciplines: Option<Vec<Discipline>>,
+    /// Comma-separated list of output item ids of achievement recipes which have been unlocked by
+    /// completing their achievement. Achievement recipes aren't included in the API's recipe
+    /// unlocks, so are otherwise all assumed to be known. When given, other achievement recipes
+    /// are treated as unknown
+    #[structopt(long, use_delimiter = true, value_name = "item ids")]
+    achievement_recipes_known: Option<Vec<u32>>,
+
+    /// Comma-separated list of item ids or inclusive ranges of item ids (e.g. 49424-49439) to
+    /// ignore, in addition to the blacklist in the config file
+    #[structopt(long, use_delimiter = true, value_name = "ranges", parse(try_from_str = parse_id_range))]
+    blacklist_range: Option<Vec<RangeInclusive<u32>>>,
+
+    /// Ignore recipes which can only be crafted at these stations, as a comma-separated list (e.g.
+    /// --exclude-stations=guildhall,mysticforge)
+    #[structopt(long, use_delimiter = true, possible_values = Station::VARIANTS)]
+    exclude_stations: Option<Vec<Station>>,
 
     /// Download recipes and items from the GW2 API, replacing any previously cached recipes and items
     #[structopt(long)]
     reset_data: bool,
 
-    #[structopt(long, parse(from_os_str), help = &CACHE_DIR_HELP)]
+    /// Fetch trading post prices and listings from the API instead of reusing those fetched in the
+    /// last few minutes. The fresh results are still cached for later runs
+    #[structopt(long)]
+    refresh_prices: bool,
+
+    /// Read additional recipes in the gw2efficiency custom recipe JSON format from stdin. These
+    /// replace any other recipes for the same output item
+    #[structopt(long)]
+    custom_recipes_stdin: bool,
+
+    /// Include Mystic Forge recipes which produce a random number of items, such as Mystic Clovers,
+    /// as producing their average number of items. Profits from these are only an expectation, and
+    /// will be higher or lower in practice. Their ingredients are bought for enough crafts to
+    /// produce a whole number of items on average, e.g. 2 crafts for an average of 2.5 items
+    #[structopt(long)]
+    expected_value: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        env = "GW2_ARBITRAGE_CACHE_DIR",
+        help = &CACHE_DIR_HELP
+    )]
     cache_dir: Option<PathBuf>,
 
-    #[structopt(long, parse(from_os_str), help = &DATA_DIR_HELP)]
+    #[structopt(
+        long,
+        parse(from_os_str),
+        env = "GW2_ARBITRAGE_DATA_DIR",
+        help = &DATA_DIR_HELP
+    )]
     data_dir: Option<PathBuf>,
 
     #[structopt(long, parse(from_os_str), help = &CONFIG_FILE_HELP)]
     config_file: Option<PathBuf>,
 
-    /// One of "en", "es", "de", or "fr". Defaults to "en"
-    // /// One of "en", "es", "de", "fr", or "zh". Defaults to "en"
+    /// Show the given nicknames instead of item names, read from a file of lines like
+    /// `19976 = "ecto"`
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    aliases: Option<PathBuf>,
+
+    /// Print the effective configuration, after merging options and the config file, then exit
+    #[structopt(long)]
+    print_config: bool,
+
+    /// Print errors as "text" or as a "json" object with error_kind, message and url fields
+    #[structopt(long, default_value = "text", possible_values = ErrorFormat::VARIANTS)]
+    error_format: ErrorFormat,
+
+    /// One of "en", "es", "de", "fr", or "zh". Defaults to "en"
     #[structopt(long, parse(try_from_str = get_lang))]
     lang: Option<Language>,
 
+    /// Base URL of the API to fetch Chinese ("zh") item and recipe data from, e.g. one serving the
+    /// Chinese client, instead of https://api.guildwars2.com/v2
+    #[structopt(long, value_name = "url")]
+    zh_api_url: Option<String>,
+
+    /// Comma-separated list of languages to also fetch and store item data for in the same run,
+    /// e.g. "en,de,fr", so that item names can be shown in any of them with --name-lang without
+    /// fetching again
+    #[structopt(long, use_delimiter = true, parse(try_from_str = get_lang))]
+    langs: Vec<Language>,
+
+    /// Show item names in this language instead of the one given by --lang, fetching its item
+    /// data if it isn't stored yet. Prices, recipes and everything else are unaffected
+    #[structopt(long, parse(try_from_str = get_lang))]
+    name_lang: Option<Language>,
+
     /// Include recipes that require Piles of Bloodstone Dust, Dragonite Ore or Empyreal Fragments,
-    /// with an optional opportunity cost per item
+    /// with an optional opportunity cost per item in copper
     #[structopt(short = "a", long)]
     ascended_value: Option<Option<u32>>,
 
+    /// Include recipes that require Empyreal Fragments, with this opportunity cost per item.
+    /// Overrides --ascended-value
+    #[structopt(long, value_name = "coins", parse(try_from_str = get_coins))]
+    empyreal_value: Option<u32>,
+
+    /// Include recipes that require Dragonite Ore, with this opportunity cost per item. Overrides
+    /// --ascended-value
+    #[structopt(long, value_name = "coins", parse(try_from_str = get_coins))]
+    dragonite_value: Option<u32>,
+
+    /// Include recipes that require Piles of Bloodstone Dust, with this opportunity cost per item.
+    /// Overrides --ascended-value
+    #[structopt(long, value_name = "coins", parse(try_from_str = get_coins))]
+    bloodstone_value: Option<u32>,
+
     /// Include recipes that require ingredients that can only be purchased with karma, using this
     /// conversion factor as the opportunity cost
-    #[structopt(long)]
+    #[structopt(long, parse(try_from_str = get_decimal))]
     karma: Option<f64>,
 
     /// Include recipes that use LW3 map tokens, using this conversion factor as the opportunity cost
-    #[structopt(long)]
+    #[structopt(long, parse(try_from_str = get_decimal))]
     um: Option<f64>,
 
     /// Include recipes that use LW4 map tokens, using this conversion factor as the opportunity cost
-    #[structopt(long)]
+    #[structopt(long, parse(try_from_str = get_decimal))]
     vm: Option<f64>,
 
     /// Include recipes that use research notes, using this conversion factor as the opportunity cost
-    #[structopt(long)]
+    #[structopt(long, parse(try_from_str = get_decimal))]
     rn: Option<f64>,
+
+    /// Include recipes that use spirit shards, such as Mystic Forge conversions, using this
+    /// conversion factor as the opportunity cost
+    #[structopt(long, parse(try_from_str = get_decimal))]
+    spirit_shard: Option<f64>,
+
+    /// Value gems at this many coins each (e.g. 25s), or at the current rate of the gem exchange
+    /// with "live". Profits are also shown in gems
+    #[structopt(long, value_name = "coins|live", parse(try_from_str = get_gem_rate))]
+    gem_rate: Option<GemRate>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Scan the whole trading post for profitable recipes. This is the default when no command is
+    /// given
+    Scan(ScanOpt),
+
+    /// Print a shopping list of ingredients for crafting an item and selling it for profit
+    Item(ItemOpt),
+
+    /// Print the cheapest way to obtain items, regardless of resale profit
+    Acquire(AcquireOpt),
+
+    /// Print a JSON report on whether an item can be obtained right now: the most that the trading
+    /// post supply allows, recipes which still need to be learned, and ingredients limited by
+    /// currencies or account bound tokens
+    Feasibility {
+        item_id: u32,
+
+        /// Number of the item to obtain. Defaults to 1
+        #[structopt(short, long, parse(try_from_str = get_nonzero))]
+        count: Option<u32>,
+    },
+
+    /// Find the ids of items whose name contains the given text
+    Search {
+        /// Case-insensitive part of the item name
+        name: String,
+    },
+
+    /// List recipes which convert currencies such as karma or volatile magic into account bound
+    /// tokens worth more than the currencies spent, using the configured currency values
+    CurrencyArbitrage,
+
+    /// List Mystic Forge material promotions which are profitable when buying the ingredients from
+    /// the lowest sell listings and selling the output to the highest buy orders
+    PromotionScan {
+        /// Read trading post prices from this file, in the same JSON format as the
+        /// /v2/commerce/prices API endpoint, instead of fetching them
+        #[structopt(long, parse(from_os_str))]
+        prices_file: Option<PathBuf>,
+
+        /// Also show the profit made with at least this percent (1-100) chance, since the number
+        /// of items produced is random
+        #[structopt(long, parse(try_from_str = get_percentile))]
+        confidence: Option<u32>,
+    },
+
+    /// Check the downloaded recipes and items for inconsistencies, such as recipes referring to
+    /// unknown items
+    ValidateData,
+}
+
+/// Options for calculating the profit of crafting and selling items
+#[derive(StructOpt, Debug, Default)]
+struct ProfitOpt {
+    /// Limit the maximum number of items produced for a recipe
+    #[structopt(short, long, parse(try_from_str = get_nonzero))]
+    count: Option<u32>,
+
+    /// Allow crafting past --count when a recipe produces several items at once, keeping the
+    /// extra items as leftovers
+    #[structopt(long, requires = "count")]
+    allow_overcraft: bool,
+
+    /// Calculate profit based on a fixed value instead of from buy orders, in copper or gold (e.g.
+    /// 1.50.00g)
+    #[structopt(long, value_name = "coins", parse(try_from_str = get_coins))]
+    value: Option<u32>,
+
+    /// Only sell into buy orders priced at least as high as the buy order at this percentile (1-100)
+    /// of buy order depth, counted from the highest price, to value large batches more
+    /// conservatively. Defaults to 100, selling into every buy order
+    #[structopt(long, parse(try_from_str = get_percentile))]
+    sale_percentile: Option<u32>,
+
+    /// Comma-separated list of venues to sell crafted items to: buy-order, listing (1 copper below
+    /// the lowest sell listing) or merchant. Each batch is sold to whichever pays the most after
+    /// fees. Defaults to buy-order
+    #[structopt(long, use_delimiter = true, possible_values = SaleVenue::VARIANTS)]
+    sale_venues: Vec<SaleVenue>,
+
+    /// Percent of the price lost while a listing waits to sell, e.g. to the coins tied up in it or
+    /// to being undercut, when selling to the listing venue of --sale-venues. Defaults to 5
+    #[structopt(long, value_name = "percent")]
+    listing_hold_cost: Option<u8>,
+
+    /// Threshold - min profit per item, in copper or gold (e.g. 50g or 5.00.00g)
+    #[structopt(long, value_name = "coins", parse(try_from_str = get_coins))]
+    threshold: Option<u32>,
+
+    /// Also show items which can be crafted without losing money but make no profit, e.g. for
+    /// leveling crafting disciplines
+    #[structopt(long)]
+    include_breakeven: bool,
+
+    /// Don't sell into the highest buy order price, or the given number of highest prices, in
+    /// case they are inflated or won't last until the items are crafted
+    #[structopt(long, value_name = "count")]
+    ignore_top_buy: Option<Option<u32>>,
+
+    /// Show the profit per hour when doing this many crafting steps per hour, including buying
+    /// the ingredients and selling the output, to compare against other ways of making gold
+    #[structopt(long, value_name = "count", parse(try_from_str = get_nonzero))]
+    crafts_per_hour: Option<u32>,
+}
+
+#[derive(StructOpt, Debug, Default)]
+struct ScanOpt {
+    #[structopt(flatten)]
+    profit: ProfitOpt,
+
+    /// Output the full list of profitable recipes to this CSV file
+    #[structopt(short, long, parse(from_os_str))]
+    output_csv: Option<PathBuf>,
+
+    /// Also write the time, version and settings used for the CSV file to a .meta.json file
+    /// alongside it
+    #[structopt(long, requires = "output-csv")]
+    csv_metadata: bool,
+
+    /// Output the full list of profitable recipes to this JSON file, which can be used together
+    /// with --output-csv
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    output_json: Option<PathBuf>,
+
+    /// Print a gw2efficiency crafting calculator link for all profitable items, or only the given
+    /// number of most profitable items
+    #[structopt(long, value_name = "count")]
+    export_calculator: Option<Option<usize>>,
+
+    /// Only list, and write to the CSV or JSON file, this many of the most profitable items
+    #[structopt(long, value_name = "count", parse(try_from_str = get_nonzero))]
+    top: Option<u32>,
+
+    /// Which profit to sort the list of profitable items by. Items with the same value are
+    /// sorted by total profit, then by item id
+    #[structopt(long, default_value = "profit", possible_values = SortBy::VARIANTS)]
+    sort_by: SortBy,
+
+    /// Only show items whose profit is at least this percentage of their crafting cost. Crafting
+    /// costs paid in other currencies count at the rates given by --karma etc.
+    #[structopt(long, value_name = "percent")]
+    min_roi: Option<u32>,
+
+    /// Also list items which can be crafted for less than their lowest sell listing, for listing
+    /// on the trading post instead of selling to buy orders
+    #[structopt(long)]
+    undercut_scan: bool,
+
+    /// Also list items which can be crafted for less than a merchant pays for them, for selling
+    /// to a merchant instead of the trading post
+    #[structopt(long)]
+    vendor_sell_scan: bool,
+
+    /// Also list items which are profitable to craft, with whichever of selling to buy orders,
+    /// listing on the trading post or selling to a merchant makes the most profit per item
+    #[structopt(long)]
+    best_venue_scan: bool,
+
+    /// Also scan using only the recipes already learned, and list the items that are less
+    /// profitable because of recipes still to learn, with the cost of their recipe sheets
+    #[structopt(long)]
+    compare_known_recipes: bool,
+
+    /// Only scan the recipes for this many sellable items, in order of item id, for a quick
+    /// preview on slow machines. Results are partial and will miss most profitable items
+    #[structopt(long, value_name = "count")]
+    limit_recipes: Option<usize>,
+
+    /// Value crafted items by their use as an ingredient in another profitable recipe, when that
+    /// is worth more than selling them on the trading post
+    #[structopt(long)]
+    chain_value: bool,
+
+    #[structopt(short = "d", long = "disciplines", use_delimiter = true, help = &DISCIPLINES_HELP, parse(try_from_str = get_discipline))]
+    filter_disciplines: Option<Vec<Discipline>>,
+
+    /// Only show items of this rarity or comma-separated list of rarities (e.g.
+    /// --rarity=Exotic,Ascended)
+    #[structopt(long = "rarity", use_delimiter = true, parse(try_from_str = get_rarity))]
+    filter_rarities: Option<Vec<Rarity>>,
+
+    /// Only show gear with all of these stat attributes, as a comma-separated list (e.g.
+    /// --stats=Power,Precision,CritDamage). Requires item data downloaded by this version, see
+    /// --reset-data
+    #[structopt(long = "stats", use_delimiter = true)]
+    filter_stats: Option<Vec<String>>,
+
+    /// Only show items from recipes which produce at least this many items per craft
+    #[structopt(long, value_name = "count")]
+    min_output_count: Option<u32>,
+
+    /// Only show items from recipes which produce at most this many items per craft
+    #[structopt(long, value_name = "count")]
+    max_output_count: Option<u32>,
+
+    /// Only show items with at least this many separate sell listings on the trading post, to
+    /// avoid markets controlled by a few sellers
+    #[structopt(long, value_name = "count")]
+    min_listings: Option<u32>,
+
+    /// Read trading post prices for the market scan from this file, in the same JSON format as
+    /// the /v2/commerce/prices API endpoint, instead of fetching them
+    #[structopt(long, parse(from_os_str))]
+    prices_file: Option<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+struct ItemOpt {
+    /// The item ids or exact item names to print shopping lists for. Several items are analyzed
+    /// in one run, each under its own header
+    #[structopt(value_name = "item-id-or-name", required_unless = "items-file")]
+    items: Vec<String>,
+
+    /// Read more items to print shopping lists for from this file, one item id or name per line.
+    /// Blank lines and lines starting with # are ignored
+    #[structopt(long, parse(from_os_str))]
+    items_file: Option<PathBuf>,
+
+    /// The rarity of the items, when selecting an item by a name shared by items of several
+    /// rarities
+    #[structopt(long, parse(try_from_str = get_rarity))]
+    rarity: Option<Rarity>,
+
+    #[structopt(flatten)]
+    profit: ProfitOpt,
+
+    /// Opportunity cost of each inventory slot needed to craft an item, e.g. from buying extra
+    /// storage with gems, in copper or gold (e.g. 20s). Subtracted from the profit of each crafted
+    /// item, so it also counts towards --min-roi and --sort-by
+    #[structopt(long, value_name = "coins", default_value = "0", parse(try_from_str = get_coins))]
+    slot_cost: u32,
+
+    /// Group vendor items in shopping lists by the vendor selling them, so each vendor only has to
+    /// be visited once
+    #[structopt(long)]
+    group_by_vendor: bool,
+
+    /// Print the crafting steps as a numbered checklist, buying the ingredients for each step just
+    /// before crafting it
+    #[structopt(long)]
+    checklist: bool,
+
+    /// Write the crafting steps to this CSV file, one row per step with the ingredients it uses.
+    /// Each row includes the id of the item it is crafted for, so several items share the file
+    #[structopt(long, parse(from_os_str))]
+    steps_csv: Option<PathBuf>,
+
+    /// Keep this many of the crafted items for personal use, valuing them at the buy order price
+    /// they would have sold for but without trading post fees, and show the resulting profit
+    #[structopt(long, value_name = "count", parse(try_from_str = get_nonzero))]
+    keep: Option<u32>,
+
+    /// Subtract the cost of buying any recipe sheets needed to craft the item from the total
+    /// profit, as a one-time cost shared by every item crafted
+    #[structopt(long)]
+    include_recipe_cost: bool,
+}
+
+#[derive(StructOpt, Debug)]
+struct AcquireOpt {
+    /// The item id to obtain. Several comma-separated item ids are obtained together, crafting any
+    /// shared ingredients in the same batches
+    #[structopt(value_name = "item-ids", use_delimiter = true, required = true)]
+    item_ids: Vec<u32>,
+
+    /// Number of each item to obtain. Defaults to 1
+    #[structopt(short, long, parse(try_from_str = get_nonzero))]
+    count: Option<u32>,
+
+    /// Group vendor items in shopping lists by the vendor selling them, so each vendor only has to
+    /// be visited once
+    #[structopt(long)]
+    group_by_vendor: bool,
+
+    /// Print the crafting steps as a numbered checklist, buying the ingredients for each step just
+    /// before crafting it
+    #[structopt(long)]
+    checklist: bool,
 }
 
 static CACHE_DIR_HELP: Lazy<String> = Lazy::new(|| {
     format!(
         r#"Save cached API calls to this directory
 
-If provided, the parent directory of the cache directory must already exist. Defaults to '{}'."#,
+If provided, the parent directory of the cache directory must already exist. The option takes
+precedence over the environment variable. Defaults to '{}', or the current directory if the system
+cache directory can't be found."#,
         cache_dir(&None).unwrap().display()
     )
 });
@@ -310,7 +1490,9 @@ static DATA_DIR_HELP: Lazy<String> = Lazy::new(|| {
     format!(
         r#"Save cached recipes and items to this directory
 
-If provided, the parent directory of the cache directory must already exist. Defaults to '{}'."#,
+If provided, the parent directory of the data directory must already exist. The option takes
+precedence over the environment variable. Defaults to '{}', or the current directory if the system
+data directory can't be found."#,
         data_dir(&None).unwrap().display()
     )
 });
@@ -321,15 +1503,34 @@ static CONFIG_FILE_HELP: Lazy<String> = Lazy::new(|| {
 
     api_key = "<key-with-unlocks-scope>"
     lang = "<lang>"
+    user_agent = "<user-agent>"
+    contact = "<contact details>"
 
     [currencies]
     ascended = <opportunity cost per item>
-    karma = <opportunity cost per karma>
+    empyreal = <opportunity cost per Empyreal Fragment, overrides ascended>
+    dragonite = <opportunity cost per Dragonite Ore, overrides ascended>
+    bloodstone = <opportunity cost per Pile of Bloodstone Dust, overrides ascended>
+    karma = <opportunity cost per karma, e.g. 0.0005 or "0,0005">
     um = <opportunity cost per Unbound Magic>
     vm = <opportunity cost per Volatile Magic>
     rn = <opportunity cost per Research Note>
+    ss = <opportunity cost per Spirit Shard>
+    gem = <coins per gem>
+
+    [recipes]
+    known = [<recipe id>, ...]
+
+    [fees]
+    listing = <trading post listing fee in percent>
+    exchange = <trading post exchange fee in percent>
+
+    [liquidity]
+    min_sell_volume = <minimum quantity listed for sale>
+    min_buy_volume = <minimum quantity of buy orders>
 
-The default file location is '{}'."#,
+The default file location is '{}'. If no file is given, a 'gw2-arbitrage.toml' in the current
+directory is also read, with its options overriding those in the default file."#,
         config_file(&None).unwrap().display()
     )
 });
@@ -343,7 +1544,61 @@ valid values: {}"#,
     )
 });
 
-#[derive(Debug, EnumString, EnumVariantNames)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum IngredientPricing {
+    #[default]
+    Marginal,
+    Average,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum CopperRounding {
+    #[default]
+    Ceil,
+    Floor,
+    Round,
+}
+
+/// Which profit the list of profitable items is sorted by
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum SortBy {
+    #[default]
+    Profit,
+    ProfitPerItem,
+    ProfitPerStep,
+    ProfitOnCost,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Where a recipe has to be crafted
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Display, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Station {
+    /// Crafting stations found in cities and most hubs
+    Crafting,
+    /// Scribe stations are only available in guild halls
+    GuildHall,
+    MysticForge,
+    /// Recipes which don't need a station, e.g. double clicking or buying from a merchant
+    Anywhere,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, EnumString, EnumVariantNames)]
 pub enum Language {
     #[strum(serialize = "en")]
     English,
@@ -353,20 +1608,20 @@ pub enum Language {
     German,
     #[strum(serialize = "fr")]
     French,
-    // If you read this and can help test the TP code and extract strings from the Chinese version,
-    // and would like to see this work in Chinese, please open an issue.
-    // #[strum(serialize="zh")]
-    // Chinese, // No lang client, and TP might have different data source anyway
+    /// The Chinese client is run separately and its trading post may differ, so item data can be
+    /// fetched from another API with --zh-api-url
+    #[strum(serialize = "zh")]
+    Chinese,
 }
 impl Language {
-    pub fn code(lang: &Option<Language>) -> Option<&str> {
+    pub fn code(lang: &Option<Language>) -> Option<&'static str> {
         if let Some(lang) = lang {
             match lang {
                 Language::English => None, // English is the default, so leave it off
                 Language::Spanish => Some("es"),
                 Language::German => Some("de"),
                 Language::French => Some("fr"),
-                //Language::Chinese => Some("zh"),
+                Language::Chinese => Some("zh"),
             }
         } else {
             None
@@ -388,7 +1643,17 @@ fn get_lang<Language: FromStr + VariantNames>(
 }
 
 #[derive(
-    Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Display, EnumString, EnumVariantNames,
+    Copy,
+    Clone,
+    Debug,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    Hash,
+    Display,
+    EnumString,
+    EnumVariantNames,
 )]
 pub enum Discipline {
     // TODO: swap these next two, next time rebuilding data files, so alphabetical
@@ -414,6 +1679,27 @@ pub enum Discipline {
 }
 
 impl Discipline {
+    pub fn station(&self) -> Station {
+        match self {
+            Discipline::Artificer
+            | Discipline::Armorsmith
+            | Discipline::Chef
+            | Discipline::Huntsman
+            | Discipline::Jeweler
+            | Discipline::Leatherworker
+            | Discipline::Tailor
+            | Discipline::Weaponsmith => Station::Crafting,
+            Discipline::Scribe => Station::GuildHall,
+            Discipline::MysticForge => Station::MysticForge,
+            Discipline::DoubleClick
+            | Discipline::Salvage
+            | Discipline::Merchant
+            | Discipline::Charge
+            | Discipline::Achievement
+            | Discipline::Growing => Station::Anywhere,
+        }
+    }
+
     pub fn get_abbrev(&self) -> String {
         let s = self.to_string();
         match &s[..1] {
@@ -442,6 +1728,117 @@ fn get_discipline<Discipline: FromStr + VariantNames>(
     })
 }
 
+fn get_rarity<Rarity: FromStr + VariantNames>(
+    rarity: &str,
+) -> Result<Rarity, Box<dyn std::error::Error>> {
+    Rarity::from_str(rarity).map_err(|_| {
+        format!(
+            "Invalid rarity: {} (valid values are {})",
+            rarity,
+            Rarity::VARIANTS.join(", ")
+        )
+        .into()
+    })
+}
+
+/// The User-Agent sent to the API: the given one, or the name and version of this tool, followed
+/// by any contact details
+fn user_agent(user_agent: Option<String>, contact: Option<String>) -> String {
+    let user_agent = user_agent.unwrap_or_else(|| {
+        format!(
+            "{}/{} (+{})",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            env!("CARGO_PKG_REPOSITORY")
+        )
+    });
+    match contact {
+        Some(contact) => format!("{} ({})", user_agent, contact),
+        None => user_agent,
+    }
+}
+
+/// Whether the value can be sent in an HTTP header
+fn is_valid_header(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii() && !c.is_ascii_control())
+}
+
+/// The value of a config file option sent in an HTTP header, if it is valid
+fn file_header(name: &str, value: Option<String>) -> Option<String> {
+    value.filter(|value| {
+        let valid = is_valid_header(value);
+        if !valid {
+            println!("Config file: Invalid {}: {}", name, value);
+        }
+        valid
+    })
+}
+
+fn get_header(value: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if is_valid_header(value) {
+        Ok(value.to_string())
+    } else {
+        Err(format!("Invalid header value: {} (must be printable ASCII)", value).into())
+    }
+}
+
+/// Parse a decimal number written with either "." or "," as the decimal separator, e.g. "0,0005".
+/// Numbers using both are rejected, since it isn't clear which one separates thousands.
+fn parse_decimal(value: &str) -> Result<f64, String> {
+    let trimmed = value.trim();
+    let normalized = if trimmed.contains('.') && trimmed.contains(',') {
+        None
+    } else if trimmed.matches(',').count() == 1 {
+        Some(trimmed.replace(',', "."))
+    } else {
+        Some(trimmed.to_string())
+    };
+    normalized
+        .and_then(|normalized| normalized.parse::<f64>().ok())
+        .filter(|value| value.is_finite())
+        .ok_or_else(|| format!("Invalid number: {} (e.g. 0.0005 or 0,0005)", value))
+}
+
+fn get_decimal(value: &str) -> Result<f64, Box<dyn std::error::Error>> {
+    parse_decimal(value).map_err(|e| e.into())
+}
+
+fn get_coins(coins: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    money::parse_coins(coins).map_err(|e| e.into())
+}
+
+/// How to value gems, given by --gem-rate
+#[derive(Debug)]
+enum GemRate {
+    Coins(u32),
+    Live,
+}
+
+fn get_gem_rate(rate: &str) -> Result<GemRate, Box<dyn std::error::Error>> {
+    if rate.eq_ignore_ascii_case("live") {
+        Ok(GemRate::Live)
+    } else {
+        match get_coins(rate)? {
+            0 => Err("Invalid gem rate: 0 (gems must cost at least 1 copper)".into()),
+            coins => Ok(GemRate::Coins(coins)),
+        }
+    }
+}
+
+fn get_percentile(percentile: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    match percentile.parse::<u32>() {
+        Ok(p) if (1..=100).contains(&p) => Ok(p),
+        _ => Err(format!("Invalid percentile: {} (must be from 1 to 100)", percentile).into()),
+    }
+}
+
+fn get_nonzero(count: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    match count.parse::<u32>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("Invalid count: {} (must be at least 1)", count).into()),
+    }
+}
+
 fn ensure_dir(dir: &PathBuf) -> Result<&PathBuf, Box<dyn std::error::Error>> {
     if !dir.exists() {
         std::fs::create_dir(&dir)
@@ -530,3 +1927,199 @@ fn config_file(file: &Option<PathBuf>) -> Result<PathBuf, Box<dyn std::error::Er
         })
         .ok_or_else(|| "Failed to access current working directory".into())
 }
+
+fn local_config_file() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut path = std::env::current_dir()?;
+    path.push("gw2-arbitrage.toml");
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chinese_language() {
+        let lang: Language = get_lang("zh").unwrap();
+        assert_eq!(lang, Language::Chinese);
+        assert_eq!(Language::code(&Some(lang)), Some("zh"));
+        assert!(get_lang::<Language>("cn").is_err());
+    }
+
+    #[test]
+    fn user_agent_with_contact() {
+        let default = user_agent(None, None);
+        assert!(default.starts_with("gw2-arbitrage/"));
+        assert_eq!(
+            user_agent(None, Some("name.1234".to_string())),
+            format!("{} (name.1234)", default)
+        );
+        assert_eq!(
+            user_agent(Some("my-tool/1.0".to_string()), Some("a@b.c".to_string())),
+            "my-tool/1.0 (a@b.c)"
+        );
+        assert!(!is_valid_header("name\n"));
+        assert!(!is_valid_header("名前"));
+    }
+
+    #[test]
+    fn zero_count() {
+        for args in &[
+            vec!["gw2-arbitrage", "item", "19976", "--count", "0"],
+            vec!["gw2-arbitrage", "acquire", "19976", "--count", "0"],
+        ] {
+            assert!(Opt::from_iter_safe(args).is_err(), "{:?}", args);
+        }
+        assert!(
+            Opt::from_iter_safe(&["gw2-arbitrage", "acquire", "19976", "--count", "2"]).is_ok()
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.crafting.value = Some(100);
+        config.crafting.threshold = Some(10);
+        assert!(config.validate().is_ok());
+        config.crafting.sale_percentile = Some(50);
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.crafting.value = Some(100);
+        config.crafting.ignore_top_buys = 1;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.crafting.value = Some(100);
+        config.chain_value = true;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.min_output_count = Some(5);
+        config.max_output_count = Some(5);
+        assert!(config.validate().is_ok());
+        config.max_output_count = Some(4);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn blacklist_ranges() {
+        assert_eq!(parse_id_range("49424-49439"), Ok(49424..=49439));
+        assert_eq!(parse_id_range(" 24 "), Ok(24..=24));
+        assert!(parse_id_range("49439-49424").is_err());
+        assert!(parse_id_range("49424-").is_err());
+
+        let blacklist: ConfigFileBlacklistSection =
+            toml::from_str("items = [24, 25]\nrecipes = [\"24\", \"49424-49426\", \"invalid\"]")
+                .unwrap();
+        let ids = blacklist_ids(blacklist.items.unwrap());
+        assert_eq!(ids, vec![24, 25].into_iter().collect());
+        let ids = blacklist_ids(blacklist.recipes.unwrap());
+        assert_eq!(ids, vec![24, 49424, 49425, 49426].into_iter().collect());
+    }
+
+    #[test]
+    fn rarities() {
+        let opt = Opt::from_iter_safe(&["gw2-arbitrage", "scan", "--rarity", "exotic,Ascended"]);
+        match opt.unwrap().command {
+            Some(Command::Scan(scan)) => assert_eq!(
+                scan.filter_rarities,
+                Some(vec![Rarity::Exotic, Rarity::Ascended])
+            ),
+            command => panic!("Unexpected command: {:?}", command),
+        }
+        assert!(Opt::from_iter_safe(&["gw2-arbitrage", "scan", "--rarity", "shiny"]).is_err());
+    }
+
+    #[test]
+    fn serialized_order() {
+        let serialize = |ids: &[u32]| {
+            let mut config = Config::default();
+            config.crafting.inventory = ids.iter().map(|&id| (id, 1)).collect();
+            config.aliases = ids.iter().map(|&id| (id, id.to_string())).collect();
+            config.known_recipes = Some(ids.iter().copied().collect());
+            config.known_achievement_recipes = Some(ids.iter().copied().collect());
+            config.item_blacklist = Some(ids.iter().copied().collect());
+            config.recipe_blacklist = Some(ids.iter().copied().collect());
+            serde_json::to_string(&config).unwrap()
+        };
+        let ids: Vec<u32> = (0..100).collect();
+        let reversed: Vec<u32> = ids.iter().rev().copied().collect();
+        assert_eq!(serialize(&ids), serialize(&reversed));
+    }
+
+    #[test]
+    fn aliases() {
+        let aliases = parse_aliases("19976 = \"ecto\"\n\"19721\" = \"glob\"\n").unwrap();
+        assert_eq!(aliases.get(&19976).map(String::as_str), Some("ecto"));
+        assert_eq!(aliases.get(&19721).map(String::as_str), Some("glob"));
+
+        assert!(parse_aliases("ecto = \"19976\"").is_err());
+        assert!(parse_aliases("19976 = 1").is_err());
+    }
+
+    #[test]
+    fn item_list() {
+        let items = parse_item_list("# gifts\n49439\n\n  Gift of Metal  \n46747\n");
+        assert_eq!(items, vec!["49439", "Gift of Metal", "46747"]);
+    }
+
+    #[test]
+    fn merge_config_files() {
+        let global: toml::Value = toml::from_str(
+            r#"
+            api_key = "global"
+            lang = "en"
+
+            [currencies]
+            ascended = 100
+            karma = 0.5
+            "#,
+        )
+        .unwrap();
+        let local: toml::Value = toml::from_str(
+            r#"
+            lang = "de"
+
+            [currencies]
+            karma = 1.0
+            "#,
+        )
+        .unwrap();
+
+        let file: ConfigFile = merge_toml(global, local).try_into().unwrap();
+        assert_eq!(file.api_key.as_deref(), Some("global"));
+        assert_eq!(file.lang.as_deref(), Some("de"));
+        let currencies = file.currencies.unwrap();
+        assert_eq!(currencies.ascended, Some(100));
+        assert_eq!(currencies.karma, Some(1.0));
+    }
+
+    #[test]
+    fn decimal_separators() {
+        assert_eq!(parse_decimal("0.0005"), Ok(0.0005));
+        assert_eq!(parse_decimal("0,0005"), Ok(0.0005));
+        assert_eq!(parse_decimal(" 2 "), Ok(2.0));
+        assert!(parse_decimal("1.000,5").is_err());
+        assert!(parse_decimal("1,000,5").is_err());
+        assert!(parse_decimal("inf").is_err());
+
+        let file: ConfigFile = toml::from_str(
+            r#"
+            [currencies]
+            karma = "0,5"
+            um = 2
+            vm = 0.25
+            "#,
+        )
+        .unwrap();
+        let currencies = file.currencies.unwrap();
+        assert_eq!(currencies.karma, Some(0.5));
+        assert_eq!(currencies.um, Some(2.0));
+        assert_eq!(currencies.vm, Some(0.25));
+        assert_eq!(currencies.rn, None);
+        assert!(toml::from_str::<ConfigFile>("[currencies]\nkarma = \"0,5,\"").is_err());
+    }
+}
@@ -7,10 +7,13 @@ use crate::recipe::Recipe;
 
 use num_rational::Ratio;
 use num_traits::Zero;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
 
-#[derive(Debug, Copy, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Debug, Copy, Clone, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum Source {
     Crafting,
     TradingPost,
@@ -37,10 +40,11 @@ pub fn calculate_estimated_min_crafting_cost(
     let output_item_count = recipe.map(|recipe| recipe.output_item_count).unwrap_or(1);
 
     let crafting_cost = recipe.and_then(|recipe| {
-        if !opt.include_timegated && recipe.is_timegated() {
+        // recipes without ingredients would be free to craft
+        if (!opt.include_timegated && recipe.is_timegated()) || !recipe.has_ingredients() {
             None
         } else {
-            let mut cost = Money::zero();
+            let mut cost = recipe.wallet_currency_cost()?;
             for ingredient in &recipe.ingredients {
                 let ingredient_cost = calculate_estimated_min_crafting_cost(
                     ingredient.item_id,
@@ -67,13 +71,12 @@ pub fn calculate_estimated_min_crafting_cost(
 
     let tp_cost = tp_prices_map
         .get(&item_id)
-        .filter(|price| price.sells.quantity > 0)
+        .filter(|price| price.sells.quantity > 0 && opt.meets_sell_volume(price.sells.quantity))
         .map(|price| Money::from_copper(price.sells.unit_price as i32));
 
-    let vendor_cost = item.and_then(|item| {
-        item.vendor_cost()
-            .map_or_else(|| item.token_value(), |cost| Some(cost.0))
-    });
+    let vendor_cost = item
+        .and_then(|item| item.currency_value())
+        .and_then(|cost| non_negative_cost(item_id, cost));
     let cost = tp_cost.inner_min(crafting_cost).inner_min(vendor_cost)?;
 
     // give trading post precedence over crafting if costs are equal
@@ -97,17 +100,156 @@ pub struct PreciseCraftingCost {
 }
 
 pub struct PreciseCraftingCostContext {
-    pub purchases: Vec<(u32, u32, Source)>, // id, count, Source
+    /// Id, count, source and cost of the items to buy. The cost is the trading post price used to
+    /// choose the source, which is what the purchase is recorded at
+    pub purchases: Vec<(u32, u32, Source, Money)>,
     pub items: CraftedItems,
+    /// Previous values of everything changed in `items` since the last batch started, so that
+    /// changes can be undone without cloning the maps for every recipe explored
+    changes: Vec<ItemsChange>,
+}
+
+enum ItemsChange {
+    Crafted(u32, Option<u32>),
+    Leftovers(u32, Option<(u32, Money, Source)>),
+    UsedLeftovers(u32, Option<u32>),
 }
 
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+impl PreciseCraftingCostContext {
+    pub fn new(items: CraftedItems) -> Self {
+        PreciseCraftingCostContext {
+            purchases: vec![],
+            items,
+            changes: vec![],
+        }
+    }
+
+    /// Forget the purchases and changes of the previous batch, keeping its crafted items
+    pub fn start_batch(&mut self) {
+        self.purchases.clear();
+        self.changes.clear();
+    }
+
+    /// Undo the changes to the crafted items since the batch started
+    pub fn undo_batch(&mut self) {
+        self.undo_changes(0);
+    }
+
+    fn undo_changes(&mut self, changes_ptr: usize) {
+        while self.changes.len() > changes_ptr {
+            match self.changes.pop().unwrap() {
+                ItemsChange::Crafted(item_id, Some(count)) => {
+                    self.items.crafted.insert(item_id, count);
+                }
+                ItemsChange::Crafted(item_id, None) => {
+                    self.items.crafted.remove(&item_id);
+                }
+                ItemsChange::Leftovers(item_id, Some(leftovers)) => {
+                    self.items.leftovers.insert(item_id, leftovers);
+                }
+                ItemsChange::Leftovers(item_id, None) => {
+                    self.items.leftovers.remove(&item_id);
+                }
+                ItemsChange::UsedLeftovers(item_id, Some(count)) => {
+                    self.items.used_leftovers.insert(item_id, count);
+                }
+                ItemsChange::UsedLeftovers(item_id, None) => {
+                    self.items.used_leftovers.remove(&item_id);
+                }
+            }
+        }
+    }
+
+    fn add_crafted(&mut self, item_id: u32, count: u32) {
+        let previous = self.items.crafted.insert(item_id, 0);
+        self.changes.push(ItemsChange::Crafted(item_id, previous));
+        self.items
+            .crafted
+            .insert(item_id, previous.unwrap_or(0) + count);
+    }
+
+    fn add_used_leftovers(&mut self, item_id: u32, count: u32) {
+        let previous = self.items.used_leftovers.insert(item_id, 0);
+        self.changes
+            .push(ItemsChange::UsedLeftovers(item_id, previous));
+        self.items
+            .used_leftovers
+            .insert(item_id, previous.unwrap_or(0) + count);
+    }
+
+    fn take_leftovers(&mut self, item_id: u32) -> Option<(u32, Money, Source)> {
+        let previous = self.items.leftovers.remove(&item_id);
+        if previous.is_some() {
+            self.changes.push(ItemsChange::Leftovers(item_id, previous));
+        }
+        previous
+    }
+
+    fn insert_leftovers(&mut self, item_id: u32, leftovers: (u32, Money, Source)) {
+        let previous = self.items.leftovers.insert(item_id, leftovers);
+        self.changes.push(ItemsChange::Leftovers(item_id, previous));
+    }
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct CraftedItems {
     pub crafted: HashMap<u32, u32>, // id, count
     pub leftovers: HashMap<u32, (u32, Money, Source)>,
+    /// Items taken from the leftovers of earlier crafts or purchases instead of being acquired
+    #[serde(default)]
+    pub used_leftovers: HashMap<u32, u32>, // id, count
+    /// Items which were put in the leftovers from the inventory
+    #[serde(default)]
+    pub owned: HashMap<u32, u32>, // id, count
 }
 
 impl CraftedItems {
+    /// Owned items as leftovers at no cost, so that crafting takes from them before acquiring
+    /// more. The item crafted for sale is left out, as selling owned items isn't crafting.
+    pub fn owned(inventory: &HashMap<u32, u32>, output_item_id: u32) -> Self {
+        let owned: HashMap<u32, u32> = inventory
+            .iter()
+            .filter(|&(&item_id, &count)| item_id != output_item_id && count > 0)
+            .map(|(&item_id, &count)| (item_id, count))
+            .collect();
+        CraftedItems {
+            leftovers: owned
+                .iter()
+                .map(|(&item_id, &count)| (item_id, (count, Money::zero(), Source::Crafting)))
+                .collect(),
+            owned,
+            ..Default::default()
+        }
+    }
+
+    /// Remove owned items which weren't used from the leftovers. Owned items are used before any
+    /// leftovers of the same item, so the unused ones are the owned count less the used count.
+    pub fn remove_unused_owned(&mut self) {
+        for (&item_id, &owned) in &self.owned {
+            let used = self.used_leftovers.get(&item_id).copied().unwrap_or(0);
+            let unused = owned.saturating_sub(used);
+            if let Some((count, _, _)) = self.leftovers.get_mut(&item_id) {
+                *count -= unused.min(*count);
+                if *count == 0 {
+                    self.leftovers.remove(&item_id);
+                }
+            }
+        }
+    }
+
+    /// What is left of the inventory after crafting. Owned items are used before any leftovers of
+    /// the same item, so no more than the owned quantity of each is taken from the inventory.
+    pub fn remaining_inventory(&self, inventory: &HashMap<u32, u32>) -> HashMap<u32, u32> {
+        inventory
+            .iter()
+            .map(|(&item_id, &count)| {
+                let used = self.used_leftovers.get(&item_id).copied().unwrap_or(0);
+                (item_id, count.saturating_sub(used))
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect()
+    }
+
     pub fn crafting_steps(&self, recipes_map: &HashMap<u32, Recipe>) -> Ratio<u32> {
         let total_crafting_steps = self
             .crafted
@@ -115,7 +257,8 @@ impl CraftedItems {
             .map(|(item_id, &count)| {
                 let recipe = recipes_map.get(item_id);
                 let output_item_count = recipe.map(|recipe| recipe.output_item_count).unwrap_or(1);
-                Ratio::new(count, output_item_count)
+                let crafts = recipe.map(|recipe| recipe.crafts()).unwrap_or(1);
+                Ratio::new(count, output_item_count) * crafts
             })
             .reduce(|total, count| total + count)
             .unwrap();
@@ -123,6 +266,15 @@ impl CraftedItems {
         total_crafting_steps
     }
 
+    /// Cost of the leftover items, which was spent without being included in the cost of the
+    /// items crafted
+    pub fn leftovers_value(&self) -> Money {
+        self.leftovers
+            .values()
+            .map(|(count, cost, _)| *cost * *count)
+            .sum()
+    }
+
     // TODO: merge w/recipes? The difference is there we need all regardless of what will be
     // crafted; here we know what will be crafted.
     pub fn unknown_recipes(
@@ -153,6 +305,28 @@ impl CraftedItems {
         unknown_recipes
     }
 
+    /// Output item ids of the crafted items whose achievement recipes aren't known. These recipes
+    /// have no id, so aren't included in `unknown_recipes`.
+    pub fn unknown_achievement_recipes(
+        &self,
+        recipes_map: &HashMap<u32, Recipe>,
+        known_achievement_recipes: &Option<HashSet<u32>>,
+    ) -> Vec<u32> {
+        let mut item_ids: Vec<u32> = self
+            .crafted
+            .keys()
+            .filter(|item_id| {
+                recipes_map
+                    .get(item_id)
+                    .filter(|recipe| recipe.is_unknown_achievement(known_achievement_recipes))
+                    .is_some()
+            })
+            .copied()
+            .collect();
+        item_ids.sort_unstable();
+        item_ids
+    }
+
     /// Sort in the order which will remove ingredients from the inventory fastest
     fn sort_ingredients<'a>(
         &self,
@@ -230,11 +404,11 @@ pub fn calculate_precise_min_crafting_cost(
     let output_item_count = recipe.map(|recipe| recipe.output_item_count).unwrap_or(1);
 
     let purchases_ptr = context.purchases.len();
-    let crafted_backup = context.items.crafted.clone();
 
     // Take from leftovers first if any
     let (item_count, cost_of_leftovers_used) =
-        if let Some((count, cost, source)) = context.items.leftovers.remove(&item_id) {
+        if let Some((count, cost, source)) = context.take_leftovers(item_id) {
+            context.add_used_leftovers(item_id, count.min(item_count));
             match count.cmp(&item_count) {
                 std::cmp::Ordering::Less => {
                     // Source is only checked against crafting to break out of profit loop; so prefer
@@ -248,10 +422,7 @@ pub fn calculate_precise_min_crafting_cost(
                     })
                 }
                 std::cmp::Ordering::Greater => {
-                    context
-                        .items
-                        .leftovers
-                        .insert(item_id, (count - item_count, cost, source));
+                    context.insert_leftovers(item_id, (count - item_count, cost, source));
                     return Some(PreciseCraftingCost {
                         cost: cost * item_count,
                         source,
@@ -268,13 +439,18 @@ pub fn calculate_precise_min_crafting_cost(
         .to_integer();
     let output_count = crafting_count * output_item_count;
 
-    let leftovers_backup = context.items.leftovers.clone();
+    // the leftovers used above stay used, whichever source is chosen
+    let changes_ptr = context.changes.len();
     let crafting_cost_per_item = recipe.and_then(|recipe| {
         if !opt.include_timegated && recipe.is_timegated() {
             return None;
         }
+        // recipes without ingredients would be free to craft
+        if !recipe.has_ingredients() {
+            return None;
+        }
 
-        let mut cost = Money::zero();
+        let mut cost = recipe.wallet_currency_cost()? * crafting_count;
         for ingredient in &recipe.ingredients {
             // adjust ingredient count based on fraction of parent recipe that was requested
             let ingredient_count = ingredient.count * crafting_count;
@@ -309,12 +485,17 @@ pub fn calculate_precise_min_crafting_cost(
 
     let tp_cost = tp_listings_map
         .get(&item_id)
-        .and_then(|listings| listings.lowest_sell_offer(item_count))
-        .and_then(|offer| Some(Money::from_copper(offer as i32)));
+        .and_then(|listings| match opt.ingredient_pricing {
+            config::IngredientPricing::Marginal => listings
+                .lowest_sell_offer(item_count)
+                .map(|offer| Money::from_copper(offer as i32)),
+            config::IngredientPricing::Average => listings.average_sell_offer(item_count),
+        });
 
     let vendor_data = item.and_then(|item| {
         item.vendor_cost()
             .or_else(|| item.token_value().map(|v| (v, 1)))
+            .filter(|&(cost, _)| non_negative_cost(item_id, cost).is_some())
             .map(|cost| (cost.0 * item_count, cost.1))
     });
     let vendor_cost = if let Some((cost, _)) = vendor_data {
@@ -322,7 +503,32 @@ pub fn calculate_precise_min_crafting_cost(
     } else {
         None
     };
-    let cost = tp_cost.inner_min(crafting_cost).inner_min(vendor_cost)?;
+
+    // buy any listings cheaper than the vendor from the trading post, and the rest from the vendor
+    let split = match (opt.ingredient_pricing, &vendor_data) {
+        (config::IngredientPricing::Marginal, Some((vendor_cost, _))) => {
+            let vendor_cost_per_item = *vendor_cost / item_count;
+            tp_listings_map
+                .get(&item_id)
+                .map(|listings| listings.quantity_cheaper_than(vendor_cost_per_item, item_count))
+                .filter(|&tp_count| tp_count > 0 && tp_count < item_count)
+                .and_then(|tp_count| {
+                    let tp_cost = tp_listings_map.get(&item_id)?.lowest_sell_offer(tp_count)?;
+                    let tp_cost = Money::from_copper(tp_cost as i32);
+                    Some((
+                        tp_count,
+                        tp_cost,
+                        tp_cost + vendor_cost_per_item * (item_count - tp_count),
+                    ))
+                })
+        }
+        _ => None,
+    };
+
+    let cost = tp_cost
+        .inner_min(crafting_cost)
+        .inner_min(vendor_cost)
+        .inner_min(split.map(|(_, _, cost)| cost))?;
 
     // give trading post precedence over crafting if costs are equal
     let source = if tp_cost == Some(cost) {
@@ -332,13 +538,23 @@ pub fn calculate_precise_min_crafting_cost(
     } else {
         Source::Vendor
     };
+    // number and cost of items bought from the trading post, with any others bought from a vendor
+    let (tp_count, tp_purchase_cost) = match (source, split) {
+        (Source::TradingPost, _) => (item_count, cost),
+        (Source::Vendor, Some((tp_count, tp_cost, split_cost)))
+            if vendor_cost != Some(cost) && split_cost == cost =>
+        {
+            (tp_count, tp_cost)
+        }
+        _ => (0, Money::zero()),
+    };
 
     if source == Source::Crafting {
-        *context.items.crafted.entry(item_id).or_insert(0) += output_count;
+        context.add_crafted(item_id, output_count);
         if output_count > item_count {
             // Should never have leftovers if we're crafting more
             debug_assert!(context.items.leftovers.get(&item_id) == None);
-            context.items.leftovers.insert(
+            context.insert_leftovers(
                 item_id,
                 (
                     output_count - item_count,
@@ -349,7 +565,7 @@ pub fn calculate_precise_min_crafting_cost(
         }
     } else {
         // Un-mark ingredients for purchase
-        for (purchase_id, purchase_quantity, purchase_source) in
+        for (purchase_id, purchase_quantity, purchase_source, _) in
             context.purchases.drain(purchases_ptr..)
         {
             if purchase_source == Source::TradingPost {
@@ -359,26 +575,31 @@ pub fn calculate_precise_min_crafting_cost(
                     .pending_buy_quantity -= purchase_quantity;
             }
         }
-        context.items.crafted = crafted_backup;
-        context.items.leftovers = leftovers_backup;
+        context.undo_changes(changes_ptr);
     }
 
     // Mark for purchase
-    if source == Source::TradingPost {
-        context.purchases.push((item_id, item_count, source));
+    if tp_count > 0 {
+        context
+            .purchases
+            .push((item_id, tp_count, Source::TradingPost, tp_purchase_cost));
         tp_listings_map
             .get_mut(&item_id)
             .unwrap()
-            .pending_buy_quantity += item_count;
+            .pending_buy_quantity += tp_count;
     }
     if source == Source::Vendor {
-        let (cost_per_item, purchase_count) = vendor_data.unwrap();
+        let (cost, purchase_count) = vendor_data.unwrap();
+        let cost_per_item = cost / item_count;
+        let item_count = item_count - tp_count;
         let purchase = item_count.div_ceil(purchase_count) * purchase_count;
-        context.purchases.push((item_id, purchase, source));
+        context
+            .purchases
+            .push((item_id, purchase, source, Money::zero()));
         if purchase > item_count {
             // Should never still have leftovers if we're buying more
             debug_assert!(context.items.leftovers.get(&item_id) == None);
-            context.items.leftovers.insert(
+            context.insert_leftovers(
                 item_id,
                 (purchase - item_count, cost_per_item, Source::Vendor),
             );
@@ -391,12 +612,34 @@ pub fn calculate_precise_min_crafting_cost(
     })
 }
 
+/// Items already warned about by `non_negative_cost`, so each is only reported once
+static NEGATIVE_COST_ITEMS: Lazy<Mutex<HashSet<u32>>> = Lazy::new(Default::default);
+
+/// Currency conversion factors can give vendor items and tokens a negative value, which would let
+/// crafting costs go below zero. Returns `None` for such costs, warning once per item.
+pub fn non_negative_cost(item_id: u32, cost: Money) -> Option<Money> {
+    if cost >= Money::zero() {
+        return Some(cost);
+    }
+    if NEGATIVE_COST_ITEMS.lock().unwrap().insert(item_id) {
+        eprintln!(
+            "Warning: item {} has a negative cost of {} after currency conversion and will not be \
+             bought from a vendor",
+            item_id, cost
+        );
+    }
+    None
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct PurchasedIngredient {
     pub count: u32,
     pub max_price: Money,
     pub min_price: Money,
     pub total_cost: Money,
+    /// Trading post sell listings bought from, as unit price and quantity in the order bought.
+    /// Only recorded with --audit-listings.
+    pub listings: Vec<(u32, u32)>,
 }
 
 trait OptionInnerMin<T> {
@@ -1,23 +1,22 @@
-use serde::{de::Deserializer, Deserialize};
+use reqwest::{header, StatusCode};
+use serde::{de::Deserializer, Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::api;
 use crate::config;
 use crate::recipe;
+use crate::request;
 
+use std::fs;
+use std::path::Path;
 use std::str::FromStr;
 
 use phf::phf_set;
 
 // Bad recipes; blacklist based on item ID
 static BLACKLIST_ITEM_IDS: phf::Set<u32> = phf_set! {
-    // Non-integer output, probabalistic:
-    19675_u32, // Mystic Clover
-    38131_u32, // Delicate Snowflake
-    38132_u32, // Glittering Snowflake
-    38133_u32, // Unique Snowflake
-    38134_u32, // Pristine Snowflake
-    38135_u32, // Flawless Snowflake
+    // Non-integer output, probabalistic, e.g. Mystic Clovers and Snowflakes, are kept with their
+    // average output, and only used with --expected-value
 
     // Integer output is wrong, probabalistic:
     38121_u32, // Endless Gift Dolyak Tonic; 1/3 chance
@@ -55,26 +54,128 @@ static BLACKLIST_ITEM_IDS: phf::Set<u32> = phf_set! {
 
 #[derive(Debug, Deserialize)]
 pub struct Recipe {
-    pub name: String, // used only in error output
+    pub name: String, // used only in error output, always English
     pub output_item_id: u32,
+    // usually an integer, but an average for recipes with a random number of outputs
     #[serde(deserialize_with = "treat_error_as_none")]
-    pub output_item_count: Option<u32>,
+    pub output_item_count: Option<f64>,
     #[serde(deserialize_with = "strum_discipline")]
     pub disciplines: Vec<config::Discipline>,
     pub ingredients: Vec<api::RecipeIngredient>,
+    // wallet currencies spent by the recipe, e.g. [{"currency_id": 2, "count": 7000}] for karma
+    #[serde(default)]
+    pub currencies: Vec<api::RecipeCurrencyIngredient>,
 }
 
-pub async fn fetch_custom_recipes(
+const CUSTOM_RECIPES_URL: &str =
+    "https://raw.githubusercontent.com/gw2efficiency/custom-recipes/master/recipes.json";
+
+/// HTTP cache validators for the last download of the custom recipes
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Load custom recipes stored at data_path, downloading them again only if they have changed
+/// since they were stored
+pub async fn get_custom_recipes(
+    data_path: &Path,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<Vec<recipe::Recipe>, Box<dyn std::error::Error>> {
+    get_custom_recipes_from(CUSTOM_RECIPES_URL, data_path, notify).await
+}
+
+/// Like get_custom_recipes, but downloading the recipes from url
+pub async fn get_custom_recipes_from(
+    url: &str,
+    data_path: &Path,
     notify: Option<&dyn Fn(&str)>,
 ) -> Result<Vec<recipe::Recipe>, Box<dyn std::error::Error>> {
-    let url = "https://raw.githubusercontent.com/gw2efficiency/custom-recipes/master/recipes.json";
+    let validators_path = data_path.with_extension("json");
+    // recipes stored by an older version may no longer deserialize, so download them again
+    let cached_recipes: Option<Vec<recipe::Recipe>> = if data_path.exists() {
+        request::read_data_file(data_path).ok()
+    } else {
+        None
+    };
+    let validators: Option<CacheValidators> = fs::read_to_string(&validators_path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok());
+    // without validators from a previous download, download again to store them
+    let validators = match (cached_recipes, validators) {
+        (Some(recipes), Some(validators)) => {
+            if validators.etag.is_none() && validators.last_modified.is_none() {
+                // no way to check for changes
+                return Ok(recipes);
+            }
+            Some((recipes, validators))
+        }
+        _ => None,
+    };
+
     if let Some(notify) = notify {
-        notify(&url);
+        notify(url);
+    }
+    let mut request = request::CLIENT.get(url);
+    if let Some((_, validators)) = &validators {
+        if let Some(etag) = &validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
     }
 
-    let custom_recipes: Vec<Recipe> = reqwest::get(url).await?.json().await?;
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            if let Some((recipes, _)) = validators {
+                eprintln!("Failed to check for updated custom recipes: {}", e);
+                return Ok(recipes);
+            }
+            return Err(e.into());
+        }
+    };
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some((recipes, _)) = validators {
+            return Ok(recipes);
+        }
+    }
+
+    let header_value = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &header::HeaderValue| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    let new_validators = CacheValidators {
+        etag: header_value(header::ETAG),
+        last_modified: header_value(header::LAST_MODIFIED),
+    };
+
+    let custom_recipes: Vec<Recipe> = response.error_for_status()?.json().await?;
+    let recipes = convert_custom_recipes(custom_recipes);
+
+    request::write_data_file(data_path, &recipes)?;
+    fs::write(&validators_path, serde_json::to_string(&new_validators)?)?;
 
-    let recipes: Vec<recipe::Recipe> = custom_recipes
+    Ok(recipes)
+}
+
+/// Read custom recipes in the gw2efficiency JSON format, e.g. from stdin
+pub fn read_custom_recipes(
+    reader: impl std::io::Read,
+) -> Result<Vec<recipe::Recipe>, Box<dyn std::error::Error>> {
+    let de = &mut serde_json::Deserializer::from_reader(reader);
+    let custom_recipes: Vec<Recipe> = serde_path_to_error::deserialize(de)?;
+
+    Ok(convert_custom_recipes(custom_recipes))
+}
+
+fn convert_custom_recipes(custom_recipes: Vec<Recipe>) -> Vec<recipe::Recipe> {
+    custom_recipes
         .into_iter()
         // Remove blacklisted recipes here to avoid printing errors for non-integers
         .filter(|r| !BLACKLIST_ITEM_IDS.contains(&r.output_item_id))
@@ -86,9 +187,19 @@ pub async fn fetch_custom_recipes(
                 None
             }
         })
-        .collect();
+        .collect()
+}
 
-    Ok(recipes)
+/// Build a gw2efficiency crafting calculator url for the given (count, item id) pairs
+pub fn calculator_url(items: &[(u32, u32)]) -> String {
+    let items: Vec<String> = items
+        .iter()
+        .map(|(count, item_id)| format!("{}-{}", count, item_id))
+        .collect();
+    format!(
+        "https://gw2efficiency.com/crafting/calculator/a~1!b~1!c~1!d~{}",
+        items.join(";")
+    )
 }
 
 fn treat_error_as_none<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
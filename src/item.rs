@@ -2,7 +2,7 @@ use std::fmt;
 
 use serde::{Deserialize, Serialize};
 
-use strum::Display;
+use strum::{Display, EnumString, EnumVariantNames};
 
 use crate::api::ApiItem;
 use crate::config;
@@ -33,6 +33,10 @@ impl From<ApiItem> for Item {
                 serde_json::from_value(details)
                     .unwrap_or_else(|err| panic!("Error parsing API consumable item: {}", err)),
             )),
+            // gear details vary a lot between types, so ignore any which can't be parsed
+            (Type::Armor | Type::Back | Type::Trinket | Type::Weapon, Some(details)) => {
+                serde_json::from_value(details).ok().map(Details::Gear)
+            }
             _ => None,
         };
         Item {
@@ -74,7 +78,10 @@ pub enum Type {
     Mwcc, // TODO: From SoTO, will probably be renamed eventually
 }
 
-#[derive(Debug, Serialize, Deserialize, Display)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Display, EnumString, EnumVariantNames,
+)]
+#[strum(ascii_case_insensitive)]
 pub enum Rarity {
     Junk,
     Basic,
@@ -88,10 +95,15 @@ pub enum Rarity {
 
 impl Rarity {
     fn crafted_localized(&self) -> String {
-        let lang = CONFIG.lang.as_ref().unwrap_or(&config::Language::English);
+        // the rarity is shown next to the item name, so is in the same language
+        let lang = CONFIG
+            .name_lang
+            .or(CONFIG.lang)
+            .unwrap_or(config::Language::English);
         // NOTE: these strings were extracted by hand from client crafting interface
         match lang {
-            config::Language::English => match &self {
+            // TODO: Chinese names from the client crafting interface, English until then
+            config::Language::English | config::Language::Chinese => match &self {
                 Self::Masterwork => "Master".to_string(),
                 _ => self.to_string(),
             },
@@ -141,12 +153,47 @@ pub enum Flag {
     Unique,
 }
 
+/// Vendors selling the items priced by `Item::vendor_cost`, in the order a shopping trip visits them
+#[derive(Debug, Display, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum VendorLocation {
+    #[strum(serialize = "Master Craftsman")]
+    MasterCraftsman,
+    #[strum(serialize = "Master Chef")]
+    MasterChef,
+    #[strum(serialize = "Guild Hall Scribe Supplier")]
+    ScribeSupplier,
+    #[strum(serialize = "Karma Merchant (heart vendors)")]
+    KarmaMerchant,
+    #[strum(serialize = "Miyani (Mystic Forge)")]
+    Miyani,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Details {
     Consumable(ItemConsumableDetails),
+    Gear(ItemGearDetails),
     // don't care about the rest for now
 }
 
+/// Details shared by armor, back items, trinkets and weapons
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ItemGearDetails {
+    infix_upgrade: Option<ItemInfixUpgrade>,
+}
+
+/// The fixed stat combination of a piece of gear
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ItemInfixUpgrade {
+    id: u32,
+    attributes: Vec<ItemAttribute>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ItemAttribute {
+    attribute: String,
+    modifier: i32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ItemConsumableDetails {
     #[serde(rename = "type")]
@@ -177,31 +224,33 @@ pub enum ItemConsumableType {
 impl Item {
     // Output is cost per item, min purchase count
     pub fn vendor_cost(&self) -> Option<(Money, u32)> {
+        self.vendor_offer().map(|(cost, count, _)| (cost, count))
+    }
+
+    /// Where vendor items from `vendor_cost` can be bought in-game
+    pub fn vendor_location(&self) -> Option<VendorLocation> {
+        self.vendor_offer().map(|(_, _, location)| location)
+    }
+
+    // Output is cost per item, min purchase count and where the item is sold
+    fn vendor_offer(&self) -> Option<(Money, u32, VendorLocation)> {
+        use VendorLocation::*;
+
         // standard vendor sell price is generally buy price * 8, see:
         // https://forum-en.gw2archive.eu/forum/community/api/How-to-get-the-vendor-sell-price
+        let standard_price = Money::from_copper((self.vendor_value * 8) as i32);
         match &self.id {
-            // Standard vendor sell price
-
-            // Singular
-            8576  | // Bottle of Rice Wine
+            // Scribing supplies, standard vendor sell price
             76839 | // Milling Basin
             70647 | // Crystalline Bottle
             75762 | // Bag of Mortar
-            75087 | // Essence of Elegance
-            // Rune of Holding: Minor, Regular, Major, Greater, Superior
-            13010 | 13006 | 13007 | 13008 | 13009
-                => Some((Money::from_copper((self.vendor_value * 8) as i32), 1)),
-            // 10s
+            75087   // Essence of Elegance
+                => Some((standard_price, 1, ScribeSupplier)),
+
+            // Cooking supplies, standard vendor sell price
+            8576 // Bottle of Rice Wine
+                => Some((standard_price, 1, MasterChef)),
             12136 | // Bag of Flour - 1, from some vendors, 10 from master chefs
-            19792 | // Spool of Jute Thread
-            19789 | // Spool of Wool Thread
-            19794 | // Spool of Cotton Thread
-            19793 | // Spool of Linen Thread
-            19791 | // Spool of Silk Thread
-            19790 | // Spool of Gossamer Thread
-            19704 | // Lump of Tin
-            19750 | // Lump of Coal
-            19924 | // Lump of Primordium
             12157 | // Jar of Vinegar
             12151 | // Packet of Baking Powder
             12158 | // Jar of Vegetable Oil
@@ -211,14 +260,31 @@ impl Item {
             12324 | // Bag of Starch
             12271   // Bottle of Soy Sauce
                 // Price is already scaled per item
-                => Some((Money::from_copper((self.vendor_value * 8) as i32), 10)),
+                => Some((standard_price, 10, MasterChef)),
+            // Custom price
+            91702 => Some((Money::from_copper(1000) / 5, 5, MasterChef)), // Pile of Powdered Gelatin Mix; prereq achievement
+            90201 => Some((Money::from_copper(40000), 1, MasterChef)), // Smell-Enhancing Culture; prereq achievement
 
-            // Custom Price
+            // Crafting supplies, standard vendor sell price
+            // Rune of Holding: Minor, Regular, Major, Greater, Superior
+            13006..=13010 => Some((standard_price, 1, MasterCraftsman)),
+            19792 | // Spool of Jute Thread
+            19789 | // Spool of Wool Thread
+            19794 | // Spool of Cotton Thread
+            19793 | // Spool of Linen Thread
+            19791 | // Spool of Silk Thread
+            19790 | // Spool of Gossamer Thread
+            19704 | // Lump of Tin
+            19750 | // Lump of Coal
+            19924   // Lump of Primordium
+                // Price is already scaled per item
+                => Some((standard_price, 10, MasterCraftsman)),
+            // Custom price
+            46747 => Some((Money::from_copper(1496) / 10, 10, MasterCraftsman)), // Thermocatalytic Reagent
+            91739 => Some((Money::from_copper(1496) / 10, 10, MasterCraftsman)), // Pile of Compost Starter
 
-            46747 => Some((Money::from_copper(1496) / 10, 10)), // Thermocatalytic Reagent
-            91739 => Some((Money::from_copper(1496) / 10, 10)), // Pile of Compost Starter
-            91702 => Some((Money::from_copper(1000) / 5, 5)), // Pile of Powdered Gelatin Mix; prereq achievement
-            90201 => Some((Money::from_copper(40000), 1)), // Smell-Enhancing Culture; prereq achievement
+            // Spirit Shards
+            20796 if CONFIG.ss.is_some() => Some((Money::from_ss(1) / 10, 10, Miyani)), // Philosopher's Stone
 
             // Karma Ingredients - Bulk package item ids
 
@@ -226,17 +292,17 @@ impl Item {
             // Seeds, Tomatoes, Yeast
             12788 | 12801 | 12790 | 12802 | 12793 | 12794 | 12795 | 12796 | 12798 | 12804
                 if CONFIG.karma != None
-                => Some((Money::from_karma(35), 1)),
+                => Some((Money::from_karma(35), 1, KarmaMerchant)),
             // Bananas, Basil Leaves, Bell Peppers, Black Beans, Kidney Beans, Rice
-            12773 | 12774 | 12776 | 12777 | 12778 | 12780 if CONFIG.karma != None => Some((Money::from_karma(49), 1)),
+            12773 | 12774 | 12776 | 12777 | 12778 | 12780 if CONFIG.karma != None => Some((Money::from_karma(49), 1, KarmaMerchant)),
             // Almonds, Avocados, Cherries, Ginger Root, Limes, Sour Cream
-            12765 | 12766 | 12767 | 12768 | 12769 | 12764 if CONFIG.karma != None => Some((Money::from_karma(77), 1)),
+            12765 | 12766 | 12767 | 12768 | 12769 | 12764 if CONFIG.karma != None => Some((Money::from_karma(77), 1, KarmaMerchant)),
             // Chickpeas, Coconuts, Horseradish Root, Pears, Pinenuts, Shallots
-            12781 | 12782 | 12783 | 12785 | 12786 | 12787 if CONFIG.karma != None => Some((Money::from_karma(112), 1)),
+            12781 | 12782 | 12783 | 12785 | 12786 | 12787 if CONFIG.karma != None => Some((Money::from_karma(112), 1, KarmaMerchant)),
             // Eggplants, Peaches
-            12770 | 12771 if CONFIG.karma != None => Some((Money::from_karma(154), 1)),
+            12770 | 12771 if CONFIG.karma != None => Some((Money::from_karma(154), 1, KarmaMerchant)),
             // Mangos
-            12772 if CONFIG.karma != None => Some((Money::from_karma(203), 1)),
+            12772 if CONFIG.karma != None => Some((Money::from_karma(203), 1, KarmaMerchant)),
 
             _ => None,
         }
@@ -246,10 +312,21 @@ impl Item {
     pub fn token_value(&self) -> Option<Money> {
         match &self.id {
             // Base game
-            // Empyreal Fragment, Dragonite Ore, Pile of Bloodstone Dust
-            46735 | 46733 | 46731 if CONFIG.ascended != None => {
-                Some(Money::from_copper(CONFIG.ascended.unwrap() as i32))
-            }
+            // Empyreal Fragment
+            46735 => CONFIG
+                .empyreal
+                .or(CONFIG.ascended)
+                .map(|value| Money::from_copper(value as i32)),
+            // Dragonite Ore
+            46733 => CONFIG
+                .dragonite
+                .or(CONFIG.ascended)
+                .map(|value| Money::from_copper(value as i32)),
+            // Pile of Bloodstone Dust
+            46731 => CONFIG
+                .bloodstone
+                .or(CONFIG.ascended)
+                .map(|value| Money::from_copper(value as i32)),
             // LW1
             // 50025 Blade Shard
             50025 => Some(Money::from_copper(0)),
@@ -277,7 +354,7 @@ impl Item {
             // 92272 Eternal Ice Shard
             92272 if CONFIG.vm != None && CONFIG.karma != None => {
                 // Can convert 75 into 10 tokens worth 20 VM each for 2688 karma
-                let value = Money::new(0, -2688, 0, 200, 0) / 75;
+                let value = Money::new(0, -2688, 0, 200, 0, 0, 0) / 75;
                 if value.to_copper_value() >= 0 {
                     Some(value)
                 } else {
@@ -301,6 +378,48 @@ impl Item {
         }
     }
 
+    /// Value per item in currencies, for items bought from a vendor or account bound tokens
+    pub fn currency_value(&self) -> Option<Money> {
+        self.vendor_cost()
+            .map(|(cost, _)| cost)
+            .or_else(|| self.token_value())
+    }
+
+    /// Coins paid by a merchant for the item, if it can be sold to one
+    pub fn vendor_sell_value(&self) -> Option<Money> {
+        if self.vendor_value == 0 || self.flags.contains(&Flag::NoSell) {
+            return None;
+        }
+        Some(Money::from_copper(self.vendor_value as i32))
+    }
+
+    /// Account bound tokens can't be bought from a vendor or the trading post, only farmed
+    pub fn is_farmed(&self) -> bool {
+        self.vendor_cost().is_none() && self.token_value().is_some()
+    }
+
+    /// Attributes of gear with a fixed stat combination, e.g. ["Power", "Precision", "CritDamage"]
+    pub fn stat_attributes(&self) -> Option<Vec<&str>> {
+        match &self.details {
+            Some(Details::Gear(ItemGearDetails {
+                infix_upgrade: Some(infix_upgrade),
+            })) => Some(
+                infix_upgrade
+                    .attributes
+                    .iter()
+                    .map(|attribute| attribute.attribute.as_str())
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn rarity(&self) -> Rarity {
+        self.rarity
+    }
+
+    /// Whether the item can't be sold on the trading post. Race and profession restrictions only
+    /// limit who can use an item, not who can trade it, so they're not considered here
     pub fn is_restricted(&self) -> bool {
         // 76363 == legacy catapult schematic
         self.id == 76363
@@ -310,14 +429,25 @@ impl Item {
                 .any(|flag| *flag == Flag::AccountBound || *flag == Flag::SoulbindOnAcquire)
     }
 
+    /// Races or professions the item can only be used by, e.g. "Asura" or "Guardian"
+    pub fn restrictions(&self) -> &[String] {
+        &self.restrictions
+    }
+
     pub fn recipe_unlocks(&self) -> Option<Vec<u32>> {
         match (&self.item_type, &self.details) {
             (Type::Consumable, Some(Details::Consumable(details))) => {
                 let mut unlocks = vec![];
+                // some items unlock a primary recipe as well as several extra recipes
                 if let Some(recipe_id) = details.recipe_id {
                     unlocks.push(recipe_id);
-                } else if let Some(extra_recipe_ids) = &details.extra_recipe_ids {
-                    unlocks.extend(extra_recipe_ids);
+                }
+                if let Some(extra_recipe_ids) = &details.extra_recipe_ids {
+                    for recipe_id in extra_recipe_ids {
+                        if !unlocks.contains(recipe_id) {
+                            unlocks.push(*recipe_id);
+                        }
+                    }
                 }
                 Some(unlocks)
             }
@@ -342,8 +472,156 @@ impl fmt::Display for Item {
     }
 }
 
+/// Items with exactly this name, ignoring case, and of the given rarity if any
+pub fn items_named<'a>(
+    name: &str,
+    rarity: Option<Rarity>,
+    items: impl IntoIterator<Item = &'a Item>,
+) -> Vec<&'a Item> {
+    let name = name.trim().to_lowercase();
+    let mut matching_items: Vec<&Item> = items
+        .into_iter()
+        .filter(|item| item.name.to_lowercase() == name)
+        .filter(|item| rarity.is_none() || rarity == Some(item.rarity))
+        .collect();
+    matching_items.sort_unstable_by_key(|item| item.id);
+    matching_items
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Upgrade {
     upgrade: String,
     item_id: i32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consumable(recipe_id: Option<u32>, extra_recipe_ids: Option<Vec<u32>>) -> Item {
+        Item {
+            id: 1,
+            name: "Recipe Sheet".to_string(),
+            item_type: Type::Consumable,
+            rarity: Rarity::Basic,
+            level: 0,
+            vendor_value: 0,
+            flags: vec![],
+            restrictions: vec![],
+            upgrades_into: None,
+            upgrades_from: None,
+            details: Some(Details::Consumable(ItemConsumableDetails {
+                consumable_type: ItemConsumableType::Unlock,
+                recipe_id,
+                extra_recipe_ids,
+            })),
+        }
+    }
+
+    #[test]
+    fn items_named_by_rarity() {
+        let item = |id, name: &str, rarity| Item {
+            id,
+            name: name.to_string(),
+            rarity,
+            ..consumable(None, None)
+        };
+        let items = vec![
+            item(3, "Mist Pendant", Rarity::Ascended),
+            item(2, "Mist Pendant", Rarity::Exotic),
+            item(1, "Mist Pendant of Power", Rarity::Exotic),
+        ];
+
+        let ids = |rarity| {
+            items_named("mist pendant", rarity, &items)
+                .iter()
+                .map(|item| item.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(None), vec![2, 3]);
+        assert_eq!(ids(Some(Rarity::Exotic)), vec![2]);
+        assert!(ids(Some(Rarity::Rare)).is_empty());
+    }
+
+    #[test]
+    fn recipe_unlocks() {
+        assert_eq!(consumable(None, None).recipe_unlocks(), Some(vec![]));
+        assert_eq!(consumable(Some(10), None).recipe_unlocks(), Some(vec![10]));
+        assert_eq!(
+            consumable(None, Some(vec![11, 12])).recipe_unlocks(),
+            Some(vec![11, 12])
+        );
+        assert_eq!(
+            consumable(Some(10), Some(vec![11, 10, 12])).recipe_unlocks(),
+            Some(vec![10, 11, 12])
+        );
+    }
+
+    #[test]
+    fn stat_attributes() {
+        let api_item: ApiItem = serde_json::from_str(
+            r#"{
+                "id": 2,
+                "name": "Berserker's Sword",
+                "type": "Weapon",
+                "rarity": "Exotic",
+                "level": 80,
+                "vendor_value": 0,
+                "flags": [],
+                "restrictions": [],
+                "details": {
+                    "type": "Sword",
+                    "infix_upgrade": {
+                        "id": 161,
+                        "attributes": [
+                            {"attribute": "Power", "modifier": 125},
+                            {"attribute": "Precision", "modifier": 90},
+                            {"attribute": "CritDamage", "modifier": 90}
+                        ]
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Item::from(api_item).stat_attributes(),
+            Some(vec!["Power", "Precision", "CritDamage"])
+        );
+
+        let api_item: ApiItem = serde_json::from_str(
+            r#"{
+                "id": 3,
+                "name": "Sword",
+                "type": "Weapon",
+                "rarity": "Exotic",
+                "level": 80,
+                "vendor_value": 0,
+                "flags": [],
+                "restrictions": [],
+                "details": {"infix_upgrade": "unexpected"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(Item::from(api_item).stat_attributes(), None);
+    }
+
+    #[test]
+    fn race_restricted_item_is_tradable() {
+        let api_item: ApiItem = serde_json::from_str(
+            r#"{
+                "id": 4,
+                "name": "Asuran Mask",
+                "type": "Armor",
+                "rarity": "Exotic",
+                "level": 80,
+                "vendor_value": 0,
+                "flags": [],
+                "restrictions": ["Asura"]
+            }"#,
+        )
+        .unwrap();
+        let item = Item::from(api_item);
+        assert!(!item.is_restricted());
+        assert_eq!(item.restrictions(), ["Asura".to_string()]);
+    }
+}
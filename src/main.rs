@@ -1,10 +1,14 @@
 use colored::Colorize;
+use num_traits::Zero;
 use serde::Serialize;
 
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use config::CONFIG;
 use gw2_arbitrage::*;
@@ -12,14 +16,60 @@ use item::Item;
 use money::Money;
 use recipe::Recipe;
 
-const ITEM_STACK_SIZE: u32 = 250; // GW2 uses a "stack size" of 250
-
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
+    let result = match CONFIG.max_runtime {
+        Some(seconds) => tokio::time::timeout(Duration::from_secs(seconds), run())
+            .await
+            .unwrap_or_else(|_| Err(Box::new(RuntimeExceeded { seconds }))),
+        None => run().await,
+    };
+    if let Err(e) = result {
+        match CONFIG.error_format {
+            config::ErrorFormat::Text => eprintln!("Error: {:?}", e),
+            config::ErrorFormat::Json => eprintln!(
+                "{}",
+                serde_json::to_string(&ErrorOutput::from(e.as_ref()))
+                    .expect("Failed to serialize error")
+            ),
+        }
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    if CONFIG.print_config {
+        println!("{}", serde_json::to_string_pretty(&*CONFIG)?);
+        return Ok(());
+    }
+    CONFIG.prepare_dirs()?;
+
+    println!(
+        "Storing recipes and items in '{}'",
+        CONFIG.data_dir.display()
+    );
+    println!("Caching API responses in '{}'", CONFIG.cache_dir.display());
+
     let notify_print = |url: &str| println!("Fetching {}", url);
     let notify = Some(&notify_print as &dyn Fn(&str));
 
-    let known_recipes = if let Some(key) = &CONFIG.api_key {
+    if CONFIG.live_gem_rate {
+        match request::fetch_gem_rate(&CONFIG.cache_dir, notify).await {
+            Ok(coins_per_gem) => {
+                CONFIG.set_gem_rate(coins_per_gem);
+                println!(
+                    "Gem exchange rate: {} per gem",
+                    Money::from_copper(coins_per_gem as i32)
+                );
+            }
+            Err(error) => eprintln!(
+                "API error fetching the gem exchange rate, gems will not be valued: {}",
+                error
+            ),
+        }
+    }
+
+    let api_known_recipes = if let Some(key) = &CONFIG.api_key {
         match request::fetch_account_recipes(&key, &CONFIG.cache_dir, notify).await {
             Ok(recipes) => Some(recipes),
             Err(error) => {
@@ -30,6 +80,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     } else {
         None
     };
+    let crafting_levels = match &CONFIG.api_key {
+        Some(key) if CONFIG.check_crafting_levels => {
+            match request::fetch_crafting_levels(&key, &CONFIG.cache_dir, notify).await {
+                Ok(crafting_levels) => Some(crafting_levels),
+                Err(error) => {
+                    eprintln!(
+                        "API error fetching crafting levels, assuming all automatic and \
+                         discoverable recipes are known. The API key needs the characters scope: {}",
+                        error
+                    );
+                    None
+                }
+            }
+        }
+        None if CONFIG.check_crafting_levels => {
+            eprintln!("--check-crafting-levels requires an API key, see --help");
+            None
+        }
+        _ => None,
+    };
+    let known_recipes = match (api_known_recipes, &CONFIG.known_recipes) {
+        (Some(mut recipes), Some(config_recipes)) => {
+            recipes.extend(config_recipes);
+            Some(recipes)
+        }
+        (Some(recipes), None) => Some(recipes),
+        (None, config_recipes) => config_recipes.clone(),
+    };
 
     println!("Loading recipes");
     let api_recipes = {
@@ -49,41 +127,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     println!("Loading custom recipes");
-    let custom_recipes: Vec<Recipe> = request::get_data(&CONFIG.custom_recipes_file, || {
-        gw2efficiency::fetch_custom_recipes(notify)
-    })
-    .await
-    .unwrap_or_else(|e| {
-        eprintln!("Failed to fetch custom recipes: {}", e);
-        vec![]
-    });
+    let custom_recipes: Vec<Recipe> =
+        gw2efficiency::get_custom_recipes(&CONFIG.custom_recipes_file, notify)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to fetch custom recipes: {}", e);
+                vec![]
+            });
     println!(
         "Loaded {} custom recipes stored at '{}'",
         custom_recipes.len(),
         CONFIG.custom_recipes_file.display()
     );
 
-    println!("Loading items");
-    let items: Vec<Item> = request::get_data(&CONFIG.items_file, || async {
-        let api_items: Vec<api::ApiItem> =
-            request::request_paginated("items", &CONFIG.lang, notify).await?;
-        Ok(api_items
-            .into_iter()
-            .map(|api_item| Item::from(api_item))
-            .collect())
-    })
-    .await?;
-    println!(
-        "Loaded {} items stored at '{}'",
-        items.len(),
-        CONFIG.items_file.display()
-    );
+    let stdin_recipes: Vec<Recipe> = if CONFIG.custom_recipes_stdin {
+        println!("Loading custom recipes from stdin");
+        let recipes = gw2efficiency::read_custom_recipes(io::stdin().lock())?;
+        println!("Loaded {} custom recipes from stdin", recipes.len());
+        recipes
+    } else {
+        vec![]
+    };
+
+    let mut items = load_items(&CONFIG.lang, notify).await?;
+    load_other_languages(&mut items, notify).await?;
 
     let mut recipes: Vec<Recipe> = custom_recipes
         .into_iter()
         // prefer api recipes over custom recipes if they share the same output item id, by inserting them later
-        .chain(api_recipes.into_iter().map(std::convert::From::from))
+        .chain(api_recipes.into_iter().map(|api_recipe| {
+            let is_craftable = match &crafting_levels {
+                Some(crafting_levels) => api_recipe.is_craftable_at(crafting_levels),
+                None => true,
+            };
+            let mut recipe = Recipe::from(api_recipe);
+            if !is_craftable {
+                recipe.lock_by_crafting_level();
+            }
+            recipe
+        }))
+        // recipes from stdin are for testing new recipes, so should replace any existing ones
+        .chain(stdin_recipes.into_iter())
+        .filter(|recipe| CONFIG.expected_value || !recipe.has_random_output())
+        .map(Recipe::with_expected_output)
         .filter(|recipe| {
+            if CONFIG.no_mystic_forge
+                && recipe
+                    .disciplines
+                    .contains(&config::Discipline::MysticForge)
+            {
+                return false;
+            }
+            if let Some(exclude_stations) = &CONFIG.exclude_stations {
+                if recipe.is_only_craftable_at(exclude_stations) {
+                    return false;
+                }
+            }
             if let Some(recipe_blacklist) = &CONFIG.recipe_blacklist {
                 if let Some(id) = recipe.id {
                     if recipe_blacklist.contains(&id) {
@@ -106,87 +205,485 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut recipes_map = profit::vec_to_map(recipes, |x| x.output_item_id);
     let items_map = profit::vec_to_map(items, |x| x.id);
 
-    let recursive_recipes = recipe::mark_recursive_recipes(&recipes_map);
-    for recipe_id in recursive_recipes.into_iter() {
-        recipes_map.remove(&recipe_id);
+    let recursive_recipes = load_recursive_recipes(&recipes_map);
+    // material promotions are recursive, but can still be evaluated on their own
+    let mut promotion_recipes_map = HashMap::new();
+    for recipe_id in &recursive_recipes {
+        if let Some(recipe) = recipes_map.remove(recipe_id) {
+            if recipe.is_promotion() {
+                promotion_recipes_map.insert(*recipe_id, recipe);
+            }
+        }
     }
 
-    if let Some(item_id) = CONFIG.item_id {
-        let (profitable_item, purchased_ingredients, required_unknown_recipes, recipe_prices) =
-            profit::calc_item_profit(item_id, &recipes_map, &items_map, &known_recipes, notify)
+    // a recipe without ingredients would appear to be free to craft
+    let empty_recipes = recipe::mark_empty_recipes(&recipes_map);
+    for &item_id in &empty_recipes {
+        eprintln!(
+            "Ignoring recipe for item {} with no ingredients",
+            items_map
+                .get(&item_id)
+                .map_or_else(|| item_id.to_string(), |item| item.to_string())
+        );
+        recipes_map.remove(&item_id);
+    }
+
+    match &CONFIG.mode {
+        config::Mode::Acquire(item_ids) => {
+            let count = CONFIG.crafting.count.unwrap_or(1);
+            if let [item_id] = item_ids[..] {
+                let (acquired_item, purchased_ingredients) =
+                    profit::calc_item_acquisition(item_id, count, &recipes_map, &items_map, notify)
+                        .await?;
+                print_acquired_item(
+                    item_id,
+                    &acquired_item,
+                    &purchased_ingredients,
+                    &recipes_map,
+                    &items_map,
+                );
+            } else {
+                let (joint_acquisition, purchased_ingredients) = profit::calc_joint_acquisition(
+                    item_ids,
+                    count,
+                    &recipes_map,
+                    &items_map,
+                    notify,
+                )
                 .await?;
-        print_profitable_item(
-            item_id,
-            &profitable_item,
-            &purchased_ingredients,
-            &required_unknown_recipes,
-            &recipe_prices,
-            &recipes_map,
-            &items_map,
-            &known_recipes,
-        )?;
+                print_joint_acquisition(
+                    &joint_acquisition,
+                    &purchased_ingredients,
+                    &recipes_map,
+                    &items_map,
+                );
+            }
+        }
+        config::Mode::Feasibility(item_id) => {
+            let feasibility = profit::calc_feasibility(
+                *item_id,
+                CONFIG.crafting.count.unwrap_or(1),
+                &recipes_map,
+                &items_map,
+                &known_recipes,
+                notify,
+            )
+            .await?;
+            println!("{}", serde_json::to_string_pretty(&feasibility)?);
+        }
+        config::Mode::CurrencyArbitrage => {
+            let conversions = profit::find_currency_conversions(&recipes_map, &items_map);
+            print_currency_conversion_list(&conversions, &items_map);
+        }
+        config::Mode::PromotionScan => {
+            let tp_prices_map = profit::vec_to_map(load_tp_prices().await?, |x| x.id);
+            let promotions =
+                profit::find_promotion_profits(&tp_prices_map, &promotion_recipes_map, &items_map);
+            print_promotion_list(&promotions, &items_map);
+        }
+        config::Mode::Search(name) => print_search_results(name, &recipes_map, &items_map),
+        config::Mode::ValidateData => {
+            println!(
+                "Ignored {} recursive recipes and {} recipes with no ingredients",
+                recursive_recipes.len(),
+                empty_recipes.len()
+            );
+            let mut unknown_item_ids: Vec<u32> = recipe::unknown_item_ids(&recipes_map, &items_map)
+                .into_iter()
+                .collect();
+            unknown_item_ids.sort_unstable();
+            if unknown_item_ids.is_empty() {
+                println!("All items used by recipes were found in the item data");
+            } else {
+                println!(
+                    "{} items used by recipes are missing from the item data: {}",
+                    unknown_item_ids.len(),
+                    unknown_item_ids
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                println!("Run with --reset-data to download the latest recipes and items");
+            }
+        }
+        config::Mode::Item(queries) => {
+            let item_ids = queries
+                .iter()
+                .map(|query| match query {
+                    config::ItemQuery::Id(item_id) => Ok(*item_id),
+                    config::ItemQuery::Name(name, rarity) => {
+                        find_item_by_name(name, *rarity, &recipes_map, &items_map)
+                    }
+                })
+                .collect::<Result<Vec<u32>, _>>()?;
+            if item_ids.is_empty() {
+                return Err("No items to print a shopping list for".into());
+            }
+
+            let wallet = if let Some(key) = &CONFIG.api_key {
+                match request::fetch_wallet(&key, &CONFIG.cache_dir, notify).await {
+                    Ok(wallet) => Some(wallet),
+                    Err(error) => {
+                        eprintln!(
+                            "API error fetching the wallet, currency balances will not be shown. \
+                             The API key needs the wallet scope: {}",
+                            error
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            // fetch listings for the whole batch at once, as items often share ingredients
+            let mut request_listing_item_ids: Vec<u32> = item_ids
+                .iter()
+                .flat_map(|&item_id| profit::item_listing_ids(item_id, &recipes_map))
+                .collect();
+            request_listing_item_ids.sort_unstable();
+            request_listing_item_ids.dedup();
+            let tp_listings = request::fetch_item_listings(
+                &request_listing_item_ids,
+                Some(&CONFIG.cache_dir),
+                notify,
+            )
+            .await?;
+            let tp_listings_map = profit::vec_to_map(tp_listings, |x| x.id);
+
+            // the steps of every item go in one file, so are written once all items are priced
+            let mut step_rows = vec![];
+            // items are crafted one after another, so each only uses what is left of the inventory
+            let mut crafting = CONFIG.crafting.clone();
+            for (i, &item_id) in item_ids.iter().enumerate() {
+                if item_ids.len() > 1 {
+                    if i > 0 {
+                        println!();
+                    }
+                    println!("## {} ({})", item_name(item_id, &items_map), item_id);
+                }
+                let (
+                    profitable_item,
+                    purchased_ingredients,
+                    required_unknown_recipes,
+                    recipe_prices,
+                    kept_profit,
+                ) = profit::calc_item_profit(
+                    item_id,
+                    &recipes_map,
+                    &items_map,
+                    &tp_listings_map,
+                    &known_recipes,
+                    &crafting,
+                    notify,
+                )
+                .await?;
+                if let Some(profitable_item) = &profitable_item {
+                    crafting.inventory = profitable_item
+                        .crafted_items
+                        .remaining_inventory(&crafting.inventory);
+                }
+                print_profitable_item(
+                    item_id,
+                    &profitable_item,
+                    &kept_profit,
+                    &purchased_ingredients,
+                    &required_unknown_recipes,
+                    &recipe_prices,
+                    &recipes_map,
+                    &items_map,
+                    &known_recipes,
+                    &wallet,
+                )?;
+                if let (Some(_), Some(profitable_item)) = (&CONFIG.steps_csv, &profitable_item) {
+                    step_rows.extend(steps_csv_rows(
+                        item_id,
+                        profitable_item.count,
+                        &profitable_item.crafted_items,
+                        &recipes_map,
+                        &items_map,
+                    ));
+                }
+            }
+            if let Some(path) = &CONFIG.steps_csv {
+                write_steps_csv(path, &step_rows)?;
+                println!("Crafting steps written to '{}'", path.display());
+            }
+        }
+        config::Mode::Scan => scan(&recipes_map, &items_map, &known_recipes, notify).await?,
+    }
+
+    Ok(())
+}
+
+/// Find recipes which need their own output as an ingredient, reusing the result of a previous
+/// run if the recipes haven't changed since
+fn load_recursive_recipes(recipes_map: &HashMap<u32, Recipe>) -> HashSet<u32> {
+    let path = &CONFIG.recursive_recipes_file;
+    let recipe_graph_hash = recipe::recipe_graph_hash(recipes_map);
+    if path.exists() {
+        match request::read_data_file::<recipe::RecursiveRecipesCache>(path) {
+            Ok(cache) => {
+                if let Some(cache) = cache
+                    .into_iter()
+                    .next()
+                    .filter(|cache| cache.recipe_graph_hash == recipe_graph_hash)
+                {
+                    return cache.item_ids.into_iter().collect();
+                }
+            }
+            Err(e) => eprintln!("Failed to read recursive recipes: {}", e),
+        }
+    }
+
+    let recursive_recipes = recipe::mark_recursive_recipes(recipes_map);
+    let cache = recipe::RecursiveRecipesCache {
+        recipe_graph_hash,
+        item_ids: recursive_recipes.iter().copied().collect(),
+    };
+    if let Err(e) = request::write_data_file(path, &[cache]) {
+        eprintln!("Failed to save recursive recipes: {}", e);
+    }
+    recursive_recipes
+}
+
+/// Load the trading post prices of all items, from --prices-file if set
+async fn load_tp_prices() -> Result<Vec<api::Price>, Box<dyn std::error::Error>> {
+    let tp_prices: Vec<api::Price> = if let Some(path) = &CONFIG.prices_file {
+        println!("Loading trading post prices from '{}'", path.display());
+        request::read_json_file(path)?
     } else {
         println!("Loading trading post prices");
-        print!("Pages:");
         let commerce_notify = |url: &str| {
             print!(" {}", &url[51..url.len() - 14]);
             io::stdout()
                 .flush()
                 .unwrap_or_else(|e| println!("Flush failed: {}", &e));
         };
-        let tp_prices: Vec<api::Price> = request::request_paginated(
-            "commerce/prices",
-            &None,
-            Some(&commerce_notify as &dyn Fn(&str)),
+        // prices fetched by another run in the last few minutes are reused
+        request::window_cached(
+            &"commerce/prices",
+            &CONFIG.cache_dir,
+            CONFIG.refresh_prices,
+            || async {
+                print!("Pages:");
+                let tp_prices = request::request_paginated(
+                    "commerce/prices",
+                    &None,
+                    Some(&commerce_notify as &dyn Fn(&str)),
+                )
+                .await;
+                println!("");
+                tp_prices
+            },
         )
-        .await?;
-        println!("");
-        println!("Loaded {} trading post prices", tp_prices.len());
-
-        let tp_prices_map = profit::vec_to_map(tp_prices, |x| x.id);
-
-        let (profitable_item_ids, ingredient_ids) =
-            profit::find_profitable_items(&tp_prices_map, &recipes_map, &items_map);
-
-        println!("Loading detailed trading post listings");
-        let mut request_listing_item_ids = vec![];
-        request_listing_item_ids.extend(&profitable_item_ids);
-        request_listing_item_ids.extend(ingredient_ids);
-        request_listing_item_ids.sort_unstable();
-        request_listing_item_ids.dedup();
-        // Caching these is pointless, as the vector changes on each run, leading to new URLs
-        let tp_listings =
-            request::fetch_item_listings(&request_listing_item_ids, None, notify).await?;
+        .await?
+    };
+    println!("Loaded {} trading post prices", tp_prices.len());
+    Ok(tp_prices)
+}
+
+/// Scan the whole trading post for profitable recipes
+async fn scan(
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+    known_recipes: &Option<HashSet<u32>>,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tp_prices = load_tp_prices().await?;
+
+    let tp_prices_map = profit::vec_to_map(tp_prices, |x| x.id);
+
+    let chain_values = if CONFIG.chain_value {
+        let chain_values = profit::chain_values(&tp_prices_map, recipes_map, items_map);
         println!(
-            "Loaded {} detailed trading post listings",
-            tp_listings.len()
+            "Valuing {} items by their use as an ingredient",
+            chain_values.len()
         );
-        let tp_listings_map = profit::vec_to_map(tp_listings, |x| x.id);
+        chain_values
+    } else {
+        HashMap::new()
+    };
 
-        let profitable_items = profit::profitable_item_list(
+    if let Some(limit) = CONFIG.limit_recipes {
+        println!(
+            "Only scanning the recipes for the first {} sellable items by id; results are partial",
+            limit
+        );
+    }
+    let (estimated_costs, ingredient_ids) =
+        profit::find_profitable_items(&tp_prices_map, recipes_map, items_map, &chain_values);
+
+    // the same scan with only the recipes learned so far, to compare against
+    let known_scan = if CONFIG.compare_known_recipes {
+        let known_recipes_map = profit::known_recipes_map(recipes_map, known_recipes);
+        let (known_estimated_costs, known_ingredient_ids) = profit::find_profitable_items(
+            &tp_prices_map,
+            &known_recipes_map,
+            items_map,
+            &chain_values,
+        );
+        Some((
+            known_recipes_map,
+            known_estimated_costs,
+            known_ingredient_ids,
+        ))
+    } else {
+        None
+    };
+
+    println!("Loading detailed trading post listings");
+    let mut request_listing_item_ids = vec![];
+    request_listing_item_ids.extend(estimated_costs.keys());
+    request_listing_item_ids.extend(ingredient_ids);
+    if let Some((_, known_estimated_costs, known_ingredient_ids)) = &known_scan {
+        request_listing_item_ids.extend(known_estimated_costs.keys());
+        request_listing_item_ids.extend(known_ingredient_ids);
+    }
+    request_listing_item_ids.sort_unstable();
+    request_listing_item_ids.dedup();
+    // The item ids change between runs whenever prices do, leading to new URLs, so cache the
+    // listings for the whole list instead
+    let tp_listings = request::window_cached(
+        &("commerce/listings", &request_listing_item_ids),
+        &CONFIG.cache_dir,
+        CONFIG.refresh_prices,
+        || request::fetch_item_listings(&request_listing_item_ids, None, notify),
+    )
+    .await?;
+    println!(
+        "Loaded {} detailed trading post listings",
+        tp_listings.len()
+    );
+    let tp_listings_map = profit::vec_to_map(tp_listings, |x| x.id);
+
+    // everything the profits depend on, in a consistent order so that unchanged prices give the
+    // same hash. The full recipes and items are included since custom recipes and item data can
+    // change between runs without changing any item ids.
+    let result_inputs = (
+        env!("CARGO_PKG_VERSION"),
+        serde_json::to_string(&*CONFIG)?,
+        recipes_map.iter().collect::<BTreeMap<_, _>>(),
+        items_map.iter().collect::<BTreeMap<_, _>>(),
+        tp_listings_map.iter().collect::<BTreeMap<_, _>>(),
+        estimated_costs.iter().collect::<BTreeMap<_, _>>(),
+        chain_values.iter().collect::<BTreeMap<_, _>>(),
+    );
+    let (profitable_items, is_cached) =
+        request::cached_result(&result_inputs, &CONFIG.cache_dir, || {
+            profit::profitable_item_list(
+                &tp_listings_map,
+                &estimated_costs,
+                &request_listing_item_ids,
+                recipes_map,
+                items_map,
+                &chain_values,
+            )
+        })?;
+    if is_cached {
+        println!("Prices are unchanged since a recent run, reusing its results");
+    }
+
+    print_item_list(&profitable_items, recipes_map, items_map, known_recipes)?;
+
+    if let Some((known_recipes_map, known_estimated_costs, _)) = &known_scan {
+        let known_profitable_items = profit::profitable_item_list(
             &tp_listings_map,
-            &profitable_item_ids,
+            known_estimated_costs,
             &request_listing_item_ids,
-            &recipes_map,
-            &items_map,
+            known_recipes_map,
+            items_map,
+            &chain_values,
+        );
+        let missed_profits = profit::missed_profits(
+            &profitable_items,
+            &known_profitable_items,
+            recipes_map,
+            known_recipes,
+            &tp_prices_map,
+            items_map,
         );
+        print_missed_profits(&missed_profits, items_map);
+    }
+
+    if CONFIG.undercut_scan {
+        let undercut_items = profit::find_undercut_items(&tp_prices_map, recipes_map, items_map);
+        print_undercut_list(&undercut_items, items_map);
+    }
+
+    if CONFIG.vendor_sell_scan {
+        let vendor_sell_items =
+            profit::find_vendor_sell_items(&tp_prices_map, recipes_map, items_map);
+        print_vendor_sell_list(&vendor_sell_items, items_map);
+    }
 
-        print_item_list(&profitable_items, &recipes_map, &items_map, &known_recipes)?;
+    if CONFIG.best_venue_scan {
+        let best_venue_items =
+            profit::find_best_venue_items(&tp_prices_map, recipes_map, items_map);
+        print_best_venue_list(&best_venue_items, items_map);
     }
 
     Ok(())
 }
 
+/// Print the items which would be more profitable after learning more recipes
+fn print_missed_profits(missed_profits: &[profit::MissedProfit], items_map: &HashMap<u32, Item>) {
+    println!("============");
+    if missed_profits.is_empty() {
+        println!("No items are more profitable with unknown recipes");
+        return;
+    }
+    println!(
+        "{} items are more profitable with unknown recipes:",
+        missed_profits.len()
+    );
+    for missed in missed_profits {
+        let known_msg = match missed.known_profit {
+            Some(profit) => format!(
+                "{} with known recipes",
+                Money::from_copper(profit.to_copper_value())
+            ),
+            None => "not profitable with known recipes".to_string(),
+        };
+        let unlock_msg = if missed.unlock_cost.is_zero() {
+            "".to_string()
+        } else {
+            format!(", recipe sheets cost {}", missed.unlock_cost)
+        };
+        println!(
+            "{}: {} profit, {}. Needs recipe{} {}{}",
+            item_name(missed.id, items_map),
+            Money::from_copper(missed.profit.to_copper_value()),
+            known_msg,
+            if missed.unknown_recipes.len() > 1 {
+                "s"
+            } else {
+                ""
+            },
+            missed
+                .unknown_recipes
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            unlock_msg,
+        );
+    }
+}
+
 /// Print detailed information about a profitable item
 fn print_profitable_item(
     item_id: u32,
     profitable_item: &Option<profit::ProfitableItem>,
+    kept_profit: &Option<profit::KeptProfit>,
     purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
     required_unknown_recipes: &Vec<u32>,
     recipe_prices: &HashMap<u32, api::Price>,
     recipes_map: &HashMap<u32, Recipe>,
     items_map: &HashMap<u32, Item>,
     known_recipes: &Option<HashSet<u32>>,
+    wallet: &Option<HashMap<u32, u32>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let profitable_item = if let Some(item) = profitable_item {
         item
@@ -199,13 +696,68 @@ fn print_profitable_item(
     println!(
         "Shopping list for {} x {} = {} profit ({} / step, {}%)",
         profitable_item.count,
-        items_map
-            .get(&item_id)
-            .map_or_else(|| "???".to_string(), |item| item.to_string()),
+        item_name(item_id, items_map),
         Money::from_copper(profitable_item.profit.to_copper_value()),
         profitable_item.profit_per_crafting_step().to_copper_value(),
         (profitable_item.profit_on_cost() * 100_f64).round(),
     );
+    print_restrictions(item_id, items_map);
+    if let Some(gems) = profitable_item.profit.to_gems_f64() {
+        println!("Profit in gems: {:.1}", gems);
+    }
+    if let Some(average) = recipes_map
+        .get(&item_id)
+        .filter(|recipe| recipe.has_random_output())
+        .and_then(|recipe| recipe.output_item_average)
+    {
+        println!(
+            "The recipe produces a random number of items, {} on average, so the profit is only \
+             an expectation",
+            average
+        );
+    }
+    if let Some(crafts_per_hour) = CONFIG.crafts_per_hour {
+        println!(
+            "At {} crafting steps per hour: {} / hour, taking {:.1} hours",
+            crafts_per_hour,
+            Money::from_copper(
+                profitable_item
+                    .profit_per_hour(crafts_per_hour)
+                    .to_copper_value()
+            ),
+            f64::from(profitable_item.crafting_steps) / f64::from(crafts_per_hour),
+        );
+    }
+    if profitable_item.count_max_roi < profitable_item.count {
+        println!(
+            "Best return on cost: {} x {} = {} profit before recipe cost",
+            profitable_item.count_max_roi,
+            item_name(item_id, items_map),
+            Money::from_copper(profitable_item.profit_max_roi.to_copper_value()),
+        );
+    }
+    if !profitable_item.recipe_cost.is_zero() {
+        println!(
+            "Profit before recipe cost: {}, after {} of recipes: {} ({} / item)",
+            Money::from_copper(
+                profitable_item
+                    .profit_before_recipe_cost()
+                    .to_copper_value()
+            ),
+            profitable_item.recipe_cost,
+            Money::from_copper(profitable_item.profit.to_copper_value()),
+            Money::from_copper(profitable_item.profit_per_item().to_copper_value()),
+        );
+    }
+    if let Some(kept_profit) = kept_profit {
+        println!(
+            "Keeping {} worth {} without fees and selling {}: {} profit",
+            kept_profit.kept,
+            kept_profit.value,
+            profitable_item.count - kept_profit.kept,
+            Money::from_copper(kept_profit.profit.to_copper_value()),
+        );
+    }
     let price_msg = if profitable_item.max_sell == profitable_item.min_sell {
         format!("{}", profitable_item.min_sell)
     } else {
@@ -217,15 +769,442 @@ fn print_profitable_item(
     println!(
         "Sell at: {}, Money Required: {}, Breakeven price: {}",
         price_msg,
-        profitable_item.crafting_cost.increase_by_listing_fee(),
+        profitable_item
+            .crafting_cost
+            .increase_by_listing_fee_with(CONFIG.crafting.listing_fee()),
         profitable_item.breakeven,
     );
+    if let Some(wallet) = wallet {
+        print_wallet_balances(
+            profitable_item
+                .crafting_cost
+                .increase_by_listing_fee_with(CONFIG.crafting.listing_fee()),
+            wallet,
+        );
+    }
+    if profitable_item
+        .sales
+        .iter()
+        .any(|&(venue, _)| venue != profit::SaleVenue::BuyOrder)
+    {
+        let sales: Vec<String> = profitable_item
+            .sales
+            .iter()
+            .map(|(venue, count)| format!("{} to {}", count, venue))
+            .collect();
+        println!("Selling: {}", sales.join(", "));
+    }
+    if CONFIG.crafting.value.is_none() {
+        println!(
+            "Buy order depth: selling {} of {} ordered, down to {}",
+            profitable_item.count, profitable_item.buy_volume, profitable_item.min_sell,
+        );
+    }
+    if !profitable_item.tax.is_zero() {
+        println!("TP tax paid: {}", profitable_item.tax);
+    }
+    if let (Some(value), Some(next_crafting_cost)) =
+        (CONFIG.crafting.value, profitable_item.next_crafting_cost)
+    {
+        println!(
+            "Profitable up to {} items at a value of {}; the next would cost {} each to craft",
+            profitable_item.count,
+            Money::from_copper(value as i32),
+            next_crafting_cost,
+        );
+    }
+    let limit_msg = match profitable_item.limited_by {
+        profit::CraftingLimit::Count => "the requested count was reached",
+        profit::CraftingLimit::SalePercentile => "the sale percentile was reached",
+        profit::CraftingLimit::Supply => "ingredients ran out or became too expensive",
+        profit::CraftingLimit::Demand => "buy orders ran out or their prices fell too low",
+    };
+    println!(
+        "Stopped at {} items because {}",
+        profitable_item.count, limit_msg
+    );
+    if let Some(count) = CONFIG.crafting.count.filter(|&c| c < profitable_item.count) {
+        println!(
+            "Count rounded up from {} to {} to craft whole batches, keeping {} as leftovers",
+            count,
+            profitable_item.count,
+            profitable_item.count - count,
+        );
+    }
+
+    println!("============");
+    print_shopping_list(purchased_ingredients, items_map);
+    print_farm_list(purchased_ingredients, items_map);
+    print_used_leftovers(&profitable_item.crafted_items, &[item_id], items_map);
+
+    println!("============");
+    // + 1 for the crafting output
+    let inventory = profit::inventory_slots(purchased_ingredients, items_map) + 1;
+    println!("Max inventory slots: {}", inventory);
+    if !profitable_item.storage_cost.is_zero() {
+        println!(
+            "Storage cost included in the profit: {} ({} per slot)",
+            profitable_item.storage_cost,
+            Money::from_copper(CONFIG.slot_cost as i32),
+        );
+    }
+    print_crafting_steps(
+        &[(profitable_item.count, item_id)],
+        &profitable_item.crafted_items,
+        purchased_ingredients,
+        recipes_map,
+        items_map,
+    );
+
+    if required_unknown_recipes.len() > 0 {
+        let req_recipes = required_unknown_recipes
+            .iter()
+            .map(|id| {
+                let recipe_names = items_map
+                    .iter()
+                    .filter(|(_, item)| {
+                        if let Some(unlocks) = &item.recipe_unlocks() {
+                            unlocks.iter().filter(|&recipe_id| id == recipe_id).count() > 0
+                        } else {
+                            false
+                        }
+                    })
+                    .map(|(_, item)| {
+                        // Need to get price, which means up at collect_ingredient_ids we'd need to
+                        // also search for unknown recipes at all levels, and add those to the
+                        // market list
+                        if let Some(listing) = recipe_prices.get(&item.id) {
+                            debug_assert!(listing.sells.unit_price < i32::MAX as u32);
+                            return format!(
+                                "{}, buy for {}",
+                                &item.name,
+                                Money::from_copper(listing.sells.unit_price as i32)
+                            );
+                        }
+                        format!("{}", &item.name)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" or ");
+                if recipe_names.len() > 0 {
+                    recipe_names
+                } else {
+                    // recipe 5424 for item 29407 has no unlock item, possibly others
+                    format!("Recipe {} is not available!", &id)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        println!(
+            "You {} craft this yet. Required recipes{}:\n{}",
+            match known_recipes {
+                Some(_) => "can not",
+                None => "may not be able to",
+            },
+            if required_unknown_recipes.len() > 1 {
+                "s"
+            } else {
+                ""
+            },
+            req_recipes,
+        );
+    }
+    let unknown_achievement_recipes = profitable_item
+        .crafted_items
+        .unknown_achievement_recipes(recipes_map, &CONFIG.known_achievement_recipes);
+    if !unknown_achievement_recipes.is_empty() {
+        println!(
+            "You can not craft this yet. Required achievement recipes for: {}",
+            unknown_achievement_recipes
+                .iter()
+                .map(|&item_id| item_name(item_id, items_map))
+                .collect::<Vec<String>>()
+                .join(", "),
+        );
+    }
+
+    print_leftovers(&profitable_item.crafted_items, items_map);
+
+    return Ok(());
+}
+
+/// Load the items with names in the given language, fetching them if they aren't stored yet
+async fn load_items(
+    lang: &Option<config::Language>,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<Vec<Item>, Box<dyn std::error::Error>> {
+    let items_file = CONFIG.lang_items_file(lang);
+    println!("Loading items");
+    let items: Vec<Item> = request::get_data(&items_file, || async {
+        let api_items: Vec<api::ApiItem> =
+            request::request_paginated("items", lang, notify).await?;
+        Ok(api_items
+            .into_iter()
+            .map(|api_item| Item::from(api_item))
+            .collect())
+    })
+    .await?;
+    println!(
+        "Loaded {} items stored at '{}'",
+        items.len(),
+        items_file.display()
+    );
+    Ok(items)
+}
+
+/// Load the item data of the languages in --langs and --name-lang, fetching any not stored yet,
+/// and rename the items to their names in --name-lang
+async fn load_other_languages(
+    items: &mut Vec<Item>,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let primary_code = config::Language::code(&CONFIG.lang);
+    let name_code = CONFIG
+        .name_lang
+        .and_then(|lang| config::Language::code(&Some(lang)));
+    let mut loaded_codes = vec![primary_code];
+    let mut langs = vec![];
+    for lang in CONFIG.langs.iter().chain(CONFIG.name_lang.iter()) {
+        let lang = Some(*lang);
+        let code = config::Language::code(&lang);
+        if !loaded_codes.contains(&code) {
+            loaded_codes.push(code);
+            langs.push(lang);
+        }
+    }
+
+    // fetch the languages which aren't stored yet together
+    let missing_langs: Vec<Option<config::Language>> = langs
+        .iter()
+        .filter(|lang| !CONFIG.lang_items_file(lang).exists())
+        .copied()
+        .collect();
+    if !missing_langs.is_empty() {
+        let lang_api_items: Vec<Vec<api::ApiItem>> =
+            request::request_paginated_langs("items", &missing_langs, notify).await?;
+        for (lang, api_items) in missing_langs.iter().zip(lang_api_items) {
+            let lang_items: Vec<Item> = api_items.into_iter().map(Item::from).collect();
+            request::write_data_file(CONFIG.lang_items_file(lang), &lang_items)?;
+        }
+    }
+
+    for lang in langs {
+        let code = config::Language::code(&lang);
+        let lang_items = load_items(&lang, notify).await?;
+        if CONFIG.name_lang.is_some() && code == name_code {
+            let names: HashMap<u32, String> = lang_items
+                .into_iter()
+                .map(|item| (item.id, item.name))
+                .collect();
+            for item in items.iter_mut() {
+                if let Some(name) = names.get(&item.id) {
+                    item.name = name.clone();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name to show for an item, using the nickname from --aliases if there is one
+fn item_name(item_id: u32, items_map: &HashMap<u32, Item>) -> String {
+    if let Some(alias) = CONFIG.aliases.get(&item_id) {
+        return alias.clone();
+    }
+    items_map
+        .get(&item_id)
+        .map_or_else(|| "???".to_string(), |item| item.to_string())
+}
+
+/// Print the account's balance of each currency spent, warning about any it doesn't have enough of
+fn print_wallet_balances(cost: Money, wallet: &HashMap<u32, u32>) {
+    for (currency_id, needed) in cost.wallet_amounts() {
+        let held = wallet.get(&currency_id).copied().unwrap_or(0) as i32;
+        if let (Some(held_money), Some(needed_money)) = (
+            Money::from_wallet_amount(currency_id, held),
+            Money::from_wallet_amount(currency_id, needed),
+        ) {
+            // zero amounts display as an empty string
+            let held_msg = if held == 0 {
+                "none".to_string()
+            } else {
+                held_money.to_string()
+            };
+            let msg = format!("In wallet: {} of {} needed", held_msg, needed_money);
+            if held < needed {
+                println!("{}", msg.red());
+            } else {
+                println!("{}", msg);
+            }
+        }
+    }
+}
+
+/// Print the races or professions an item can only be used by, if any
+fn print_restrictions(item_id: u32, items_map: &HashMap<u32, Item>) {
+    if let Some(item) = items_map.get(&item_id) {
+        if !item.restrictions().is_empty() {
+            println!("Restricted to: {}", item.restrictions().join(", "));
+        }
+    }
+}
+
+/// Print the cheapest way to obtain an item
+fn print_acquired_item(
+    item_id: u32,
+    acquired_item: &Option<profit::AcquiredItem>,
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) {
+    let acquired_item = if let Some(item) = acquired_item {
+        item
+    } else {
+        println!("Item cannot be obtained from the trading post, vendors or crafting");
+        return;
+    };
+
+    println!("============");
+    println!(
+        "Cheapest way to obtain {} x {}: {} for {} ({} each)",
+        acquired_item.count,
+        item_name(item_id, items_map),
+        match acquired_item.source {
+            crafting::Source::Crafting => "crafting",
+            crafting::Source::TradingPost => "trading post",
+            crafting::Source::Vendor => "vendor",
+        },
+        acquired_item.cost,
+        acquired_item.cost_per_item(),
+    );
+    print_restrictions(item_id, items_map);
+
+    println!("============");
+    print_shopping_list(purchased_ingredients, items_map);
+    print_farm_list(purchased_ingredients, items_map);
+    print_used_leftovers(&acquired_item.crafted_items, &[item_id], items_map);
+
+    println!("============");
+    let inventory = profit::inventory_slots(purchased_ingredients, items_map);
+    if acquired_item.source == crafting::Source::Crafting {
+        println!("Max inventory slots: {}", inventory + 1); // + 1 for the crafting output
+        print_crafting_steps(
+            &[(acquired_item.count, item_id)],
+            &acquired_item.crafted_items,
+            purchased_ingredients,
+            recipes_map,
+            items_map,
+        );
+    } else {
+        println!("Max inventory slots: {}", inventory);
+    }
+
+    print_leftovers(&acquired_item.crafted_items, items_map);
+}
+
+/// Print the cheapest way to obtain several items together
+fn print_joint_acquisition(
+    joint_acquisition: &Option<profit::JointAcquisition>,
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) {
+    let joint_acquisition = if let Some(joint_acquisition) = joint_acquisition {
+        joint_acquisition
+    } else {
+        println!("Items cannot all be obtained from the trading post, vendors or crafting");
+        return;
+    };
+
+    println!("============");
+    println!(
+        "Cheapest way to obtain {} of each item together: {} ({} including leftovers)",
+        joint_acquisition.count, joint_acquisition.cost, joint_acquisition.outlay,
+    );
+    for (item_id, cost, source) in &joint_acquisition.items {
+        println!(
+            "{} x {}: {} for {}",
+            joint_acquisition.count,
+            item_name(*item_id, items_map),
+            match source {
+                crafting::Source::Crafting => "crafting",
+                crafting::Source::TradingPost => "trading post",
+                crafting::Source::Vendor => "vendor",
+            },
+            cost,
+        );
+        print_restrictions(*item_id, items_map);
+    }
+    if joint_acquisition.savings() > Money::zero() {
+        println!(
+            "Saved {} compared to obtaining each item separately, by sharing crafted ingredients",
+            joint_acquisition.savings(),
+        );
+    }
+
+    println!("============");
+    print_shopping_list(purchased_ingredients, items_map);
+    print_farm_list(purchased_ingredients, items_map);
+    let inventory = profit::inventory_slots(purchased_ingredients, items_map);
+    let output_ids: Vec<u32> = joint_acquisition
+        .items
+        .iter()
+        .map(|(item_id, _, _)| *item_id)
+        .collect();
+    print_used_leftovers(&joint_acquisition.crafted_items, &output_ids, items_map);
 
     println!("============");
+    let crafted_count = joint_acquisition
+        .items
+        .iter()
+        .filter(|(_, _, source)| *source == crafting::Source::Crafting)
+        .count() as u32;
+    println!("Max inventory slots: {}", inventory + crafted_count); // + crafting outputs
+    if crafted_count > 0 {
+        let items: Vec<(u32, u32)> = joint_acquisition
+            .items
+            .iter()
+            .map(|(item_id, _, _)| (joint_acquisition.count, *item_id))
+            .collect();
+        print_crafting_steps(
+            &items,
+            &joint_acquisition.crafted_items,
+            purchased_ingredients,
+            recipes_map,
+            items_map,
+        );
+    }
+
+    print_leftovers(&joint_acquisition.crafted_items, items_map);
+}
+
+/// Print purchased ingredients, grouped by source
+fn print_shopping_list(
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    items_map: &HashMap<u32, Item>,
+) {
     let mut sorted_ingredients: Vec<(&(u32, crafting::Source), &crafting::PurchasedIngredient)> =
         purchased_ingredients.iter().collect();
+    let vendor_location = |(item_id, source): &(u32, crafting::Source)| {
+        if CONFIG.group_by_vendor && *source == crafting::Source::Vendor {
+            items_map
+                .get(item_id)
+                .and_then(|item| item.vendor_location())
+        } else {
+            None
+        }
+    };
     sorted_ingredients.sort_unstable_by(|a, b| {
         if b.0 .1 == a.0 .1 {
+            // vendor items without a known location go last
+            let location_order = match (vendor_location(a.0), vendor_location(b.0)) {
+                (Some(a_location), Some(b_location)) => a_location.cmp(&b_location),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            };
+            if location_order != Ordering::Equal {
+                return location_order;
+            }
             match b.1.count.cmp(&a.1.count) {
                 Ordering::Equal => match b.1.total_cost.cmp(&a.1.total_cost) {
                     Ordering::Equal => b.0 .0.cmp(&a.0 .0),
@@ -239,8 +1218,24 @@ fn print_profitable_item(
             Ordering::Greater
         }
     });
-    let mut inventory = 0;
-    for ((ingredient_id, ingredient_source), ingredient) in sorted_ingredients {
+    let mut current_location = None;
+    for (key @ (ingredient_id, ingredient_source), ingredient) in sorted_ingredients {
+        if *ingredient_source == crafting::Source::Vendor
+            && items_map
+                .get(ingredient_id)
+                .map_or(false, |item| item.is_farmed())
+        {
+            // listed separately by print_farm_list
+            continue;
+        }
+        let location = vendor_location(key);
+        if location != current_location {
+            match location {
+                Some(location) => println!("At {}:", location),
+                None => println!("Elsewhere:"),
+            }
+            current_location = location;
+        }
         let purchase_count = if *ingredient_source == crafting::Source::Vendor {
             items_map
                 .get(ingredient_id)
@@ -249,11 +1244,10 @@ fn print_profitable_item(
                 .unwrap_or((Money::from_copper(0), 1))
                 .1
         } else {
-            ITEM_STACK_SIZE
+            profit::ITEM_STACK_SIZE
         };
         let ingredient_count_msg = if purchase_count > 1 && ingredient.count > purchase_count {
             let stack_count = ingredient.count / purchase_count;
-            inventory += ingredient.count.div_ceil(ITEM_STACK_SIZE);
             let remainder = ingredient.count % purchase_count;
             let remainder_msg = if remainder != 0 {
                 format!(" + {}", remainder)
@@ -265,7 +1259,6 @@ fn print_profitable_item(
                 ingredient.count, stack_count, purchase_count, remainder_msg
             )
         } else {
-            inventory += 1;
             ingredient.count.to_string()
         };
         let source_msg = match *ingredient_source {
@@ -282,138 +1275,473 @@ fn print_profitable_item(
                     )
                 }
             }
-            crafting::Source::Vendor => {
-                let vendor_cost = items_map
-                    .get(ingredient_id)
-                    .unwrap_or_else(|| panic!("Missing item for ingredient {}", ingredient_id))
-                    .vendor_cost();
-                if let Some((cost, purchase_count)) = vendor_cost {
-                    if purchase_count > 1 {
-                        format!(
-                            " (vendor: {} per {}) Subtotal: {}",
-                            cost * purchase_count,
-                            purchase_count,
-                            cost * ingredient.count,
-                        )
-                    } else {
-                        format!(" (vendor: {}) Subtotal: {}", cost, cost * ingredient.count,)
-                    }
-                } else {
-                    "".to_string()
-                }
+            crafting::Source::Vendor => {
+                let vendor_cost = items_map
+                    .get(ingredient_id)
+                    .unwrap_or_else(|| panic!("Missing item for ingredient {}", ingredient_id))
+                    .vendor_cost();
+                if let Some((cost, purchase_count)) = vendor_cost {
+                    if purchase_count > 1 {
+                        format!(
+                            " (vendor: {} per {}) Subtotal: {}",
+                            cost * purchase_count,
+                            purchase_count,
+                            cost * ingredient.count,
+                        )
+                    } else {
+                        format!(" (vendor: {}) Subtotal: {}", cost, cost * ingredient.count,)
+                    }
+                } else {
+                    "".to_string()
+                }
+            }
+            crafting::Source::Crafting => "".to_string(),
+        };
+        println!(
+            "{} {}{}",
+            ingredient_count_msg,
+            item_name(*ingredient_id, items_map),
+            source_msg,
+        );
+        if !ingredient.listings.is_empty() {
+            println!(
+                "    Listings bought from: {}",
+                ingredient
+                    .listings
+                    .iter()
+                    .map(|(unit_price, quantity)| format!(
+                        "{} at {}",
+                        quantity,
+                        Money::from_copper(*unit_price as i32)
+                    ))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+    }
+    print_ingredient_totals(purchased_ingredients, items_map);
+}
+
+/// Print the total count of each ingredient bought from more than one source, which is otherwise
+/// split across several lines of the shopping list
+fn print_ingredient_totals(
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    items_map: &HashMap<u32, Item>,
+) {
+    let mut totals: BTreeMap<u32, Vec<(crafting::Source, u32)>> = BTreeMap::new();
+    for ((ingredient_id, source), ingredient) in purchased_ingredients {
+        totals
+            .entry(*ingredient_id)
+            .or_default()
+            .push((*source, ingredient.count));
+    }
+    let mut totals: Vec<(u32, Vec<(crafting::Source, u32)>)> = totals
+        .into_iter()
+        .filter(|(_, sources)| sources.len() > 1)
+        .collect();
+    if totals.is_empty() {
+        return;
+    }
+
+    println!("Total needed per ingredient bought from several sources:");
+    for (ingredient_id, sources) in totals.iter_mut() {
+        sources.sort_unstable();
+        let total: u32 = sources.iter().map(|(_, count)| count).sum();
+        let sources_msg = sources
+            .iter()
+            .map(|(source, count)| {
+                format!(
+                    "{} {}",
+                    count,
+                    match source {
+                        crafting::Source::Crafting => "crafted",
+                        crafting::Source::TradingPost => "trading post",
+                        crafting::Source::Vendor => "vendor",
+                    }
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(" + ");
+        println!(
+            "{} {} ({})",
+            total,
+            item_name(*ingredient_id, items_map),
+            sources_msg
+        );
+    }
+}
+
+/// Print ingredients which can't be bought and have to be farmed or acquired with account-bound
+/// currencies
+fn print_farm_list(
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    items_map: &HashMap<u32, Item>,
+) {
+    let mut farmed_ingredients: Vec<(&Item, &crafting::PurchasedIngredient)> =
+        purchased_ingredients
+            .iter()
+            .filter(|((_, source), _)| *source == crafting::Source::Vendor)
+            .filter_map(|((ingredient_id, _), ingredient)| {
+                items_map
+                    .get(ingredient_id)
+                    .filter(|item| item.is_farmed())
+                    .map(|item| (item, ingredient))
+            })
+            .collect();
+    if farmed_ingredients.is_empty() {
+        return;
+    }
+    farmed_ingredients.sort_unstable_by(|a, b| match b.1.count.cmp(&a.1.count) {
+        Ordering::Equal => b.0.id.cmp(&a.0.id),
+        v => v,
+    });
+
+    println!("============");
+    println!("Farm/acquire (not available on the trading post):");
+    for (item, ingredient) in farmed_ingredients {
+        let value_msg = if ingredient.total_cost.is_zero() {
+            "".to_string()
+        } else {
+            format!(" (worth {})", ingredient.total_cost)
+        };
+        println!("{} {}{}", ingredient.count, item, value_msg);
+    }
+}
+
+/// Print the crafting steps, in an order which will remove ingredients from the inventory fastest
+fn print_crafting_steps(
+    items: &[(u32, u32)],
+    crafted_items: &crafting::CraftedItems,
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) {
+    println!("Crafting steps: {}", gw2efficiency::calculator_url(items));
+    let steps = crafting_steps(items, crafted_items, recipes_map);
+    if CONFIG.checklist {
+        print_checklist(&steps, purchased_ingredients, items_map);
+        return;
+    }
+    for (item_id, count, recipe) in steps {
+        println!("{}", crafting_step_msg(item_id, count, recipe, items_map));
+    }
+}
+
+/// Item id, count and recipe of each crafting step, in the order to craft them
+fn crafting_steps<'a>(
+    items: &[(u32, u32)],
+    crafted_items: &crafting::CraftedItems,
+    recipes_map: &'a HashMap<u32, Recipe>,
+) -> Vec<(u32, u32, &'a Recipe)> {
+    // ingredients shared between items are crafted together, so only list each step once
+    let mut listed_steps = HashSet::new();
+    items
+        .iter()
+        .filter(|(_, item_id)| crafted_items.crafted.contains_key(item_id))
+        .flat_map(|(_, item_id)| crafted_items.sorted(*item_id, recipes_map))
+        .filter(|(item_id, _, _)| listed_steps.insert(*item_id))
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+struct StepRow {
+    /// Item being crafted for sale, as several items' steps are written to the same file
+    output_item_id: u32,
+    step: usize,
+    item_id: u32,
+    name: String,
+    /// Number of items made, which may be more than the number of crafts
+    count: u32,
+    crafts: u32,
+    disciplines: String,
+    stations: String,
+    /// Ingredients used by all the crafts, as "count name (item id)" separated by semicolons
+    ingredients: String,
+}
+
+/// Write the crafting steps to a CSV file, one row per step
+fn write_steps_csv(path: &Path, rows: &[StepRow]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_path(path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Rows for the crafting steps of an item, numbered from 1
+fn steps_csv_rows(
+    output_item_id: u32,
+    count: u32,
+    crafted_items: &crafting::CraftedItems,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> Vec<StepRow> {
+    let steps = crafting_steps(&[(count, output_item_id)], crafted_items, recipes_map);
+    let mut rows = vec![];
+    for (index, (item_id, count, recipe)) in steps.into_iter().enumerate() {
+        let batches = count / recipe.output_item_count;
+        rows.push(StepRow {
+            output_item_id,
+            step: index + 1,
+            item_id,
+            name: item_name(item_id, items_map),
+            count,
+            crafts: batches * recipe.crafts(),
+            disciplines: recipe
+                .disciplines
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join("/"),
+            stations: recipe
+                .stations()
+                .iter()
+                .map(|station| station.to_string())
+                .collect::<Vec<_>>()
+                .join("/"),
+            ingredients: recipe
+                .sorted_ingredients()
+                .iter()
+                .map(|ingredient| {
+                    format!(
+                        "{} {} ({})",
+                        ingredient.count * batches,
+                        item_name(ingredient.item_id, items_map),
+                        ingredient.item_id
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+        });
+    }
+    rows
+}
+
+fn crafting_step_msg(
+    item_id: u32,
+    count: u32,
+    recipe: &Recipe,
+    items_map: &HashMap<u32, Item>,
+) -> String {
+    let batches = count / recipe.output_item_count;
+    let num_crafted = batches * recipe.crafts();
+    let name = item_name(item_id, items_map);
+    let ingredients = recipe
+        .sorted_ingredients()
+        .iter()
+        .map(|ingredient| {
+            let ingredient_name = item_name(ingredient.item_id, items_map);
+            format!("{} {}", ingredient.count * batches, ingredient_name)
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+    if num_crafted != count {
+        format!(
+            "{} (makes {}) {} from {}",
+            num_crafted, count, name, ingredients
+        )
+    } else {
+        format!("{} {} from {}", count, name, ingredients)
+    }
+}
+
+/// Print the crafting steps as a numbered checklist, with each purchased ingredient bought just
+/// before the first step that uses it
+fn print_checklist(
+    steps: &[(u32, u32, &Recipe)],
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    items_map: &HashMap<u32, Item>,
+) {
+    let mut step_number = 0;
+    let mut bought = HashSet::new();
+    let mut print_purchase = |key: (u32, crafting::Source), step_number: &mut u32| {
+        let ingredient = match purchased_ingredients.get(&key) {
+            Some(ingredient) if bought.insert(key) => ingredient,
+            _ => return,
+        };
+        let (item_id, source) = key;
+        let item = items_map.get(&item_id);
+        let item_name = item_name(item_id, items_map);
+        let action = match source {
+            crafting::Source::TradingPost if ingredient.min_price == ingredient.max_price => {
+                format!(
+                    "Buy {} {} at {}",
+                    ingredient.count, item_name, ingredient.min_price
+                )
+            }
+            crafting::Source::TradingPost => format!(
+                "Buy {} {} at {} to {}",
+                ingredient.count, item_name, ingredient.min_price, ingredient.max_price
+            ),
+            _ if item.map_or(false, |item| item.is_farmed()) => {
+                format!("Farm/acquire {} {}", ingredient.count, item_name)
             }
-            crafting::Source::Crafting => "".to_string(),
+            _ => match item.and_then(|item| item.vendor_location()) {
+                Some(location) => format!("Buy {} {} at {}", ingredient.count, item_name, location),
+                None => format!("Buy {} {} from a vendor", ingredient.count, item_name),
+            },
         };
+        *step_number += 1;
+        println!("{}. {}", step_number, action);
+    };
+
+    println!("Checklist:");
+    for (item_id, count, recipe) in steps {
+        for ingredient in recipe.sorted_ingredients() {
+            for source in [crafting::Source::TradingPost, crafting::Source::Vendor] {
+                print_purchase((ingredient.item_id, source), &mut step_number);
+            }
+        }
+        step_number += 1;
         println!(
-            "{} {}{}",
-            ingredient_count_msg,
-            items_map
-                .get(ingredient_id)
-                .map_or_else(|| "???".to_string(), |item| item.to_string()),
-            source_msg,
+            "{}. Craft {}",
+            step_number,
+            crafting_step_msg(*item_id, *count, recipe, items_map)
         );
     }
+}
 
-    println!("============");
-    println!("Max inventory slots: {}", inventory + 1); // + 1 for the crafting output
-    println!(
-        "Crafting steps: https://gw2efficiency.com/crafting/calculator/a~1!b~1!c~1!d~{}-{}",
-        profitable_item.count, item_id
-    );
-    for (item_id, count, recipe) in profitable_item.crafted_items.sorted(item_id, &recipes_map) {
-        let num_crafted = count / recipe.output_item_count;
-        let item_name = items_map
-            .get(&item_id)
-            .map_or_else(|| "???".to_string(), |item| item.to_string());
-        let ingredients = recipe
-            .sorted_ingredients()
-            .iter()
-            .map(|ingredient| {
-                let ingredient_name = items_map
-                    .get(&ingredient.item_id)
-                    .map_or_else(|| "???".to_string(), |item| item.to_string());
-                format!("{} {}", ingredient.count * num_crafted, ingredient_name)
-            })
-            .collect::<Vec<String>>()
-            .join(" ");
-        if recipe.output_item_count > 1 {
-            println!(
-                "{} (makes {}) {} from {}",
-                num_crafted, count, item_name, ingredients
-            );
-        } else {
-            println!("{} {} from {}", count, item_name, ingredients);
-        }
+/// Print the ingredients which were covered by the leftovers of earlier crafts or purchases,
+/// rather than bought or crafted for the use they are put to. Leftovers of the output items
+/// themselves are only a result of crafting in batches, so they are not listed.
+fn print_used_leftovers(
+    crafted_items: &crafting::CraftedItems,
+    output_ids: &[u32],
+    items_map: &HashMap<u32, Item>,
+) {
+    let mut used_leftovers: Vec<(u32, u32)> = crafted_items
+        .used_leftovers
+        .iter()
+        .filter(|(item_id, _)| !output_ids.contains(item_id))
+        .map(|(&item_id, &count)| (item_id, count))
+        .collect();
+    if used_leftovers.is_empty() {
+        return;
     }
+    used_leftovers.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
 
-    if required_unknown_recipes.len() > 0 {
-        let req_recipes = required_unknown_recipes
-            .iter()
-            .map(|id| {
-                let recipe_names = items_map
-                    .iter()
-                    .filter(|(_, item)| {
-                        if let Some(unlocks) = &item.recipe_unlocks() {
-                            unlocks.iter().filter(|&recipe_id| id == recipe_id).count() > 0
-                        } else {
-                            false
-                        }
-                    })
-                    .map(|(_, item)| {
-                        // Need to get price, which means up at collect_ingredient_ids we'd need to
-                        // also search for unknown recipes at all levels, and add those to the
-                        // market list
-                        if let Some(listing) = recipe_prices.get(&item.id) {
-                            debug_assert!(listing.sells.unit_price < i32::MAX as u32);
-                            return format!(
-                                "{}, buy for {}",
-                                &item.name,
-                                Money::from_copper(listing.sells.unit_price as i32)
-                            );
-                        }
-                        format!("{}", &item.name)
-                    })
-                    .collect::<Vec<String>>()
-                    .join(" or ");
-                if recipe_names.len() > 0 {
-                    recipe_names
-                } else {
-                    // recipe 5424 for item 29407 has no unlock item, possibly others
-                    format!("Recipe {} is not available!", &id)
-                }
-            })
-            .collect::<Vec<String>>()
-            .join("\n");
-        println!(
-            "You {} craft this yet. Required recipes{}:\n{}",
-            match known_recipes {
-                Some(_) => "can not",
-                None => "may not be able to",
-            },
-            if required_unknown_recipes.len() > 1 {
-                "s"
-            } else {
-                ""
-            },
-            req_recipes,
-        );
+    println!("============");
+    println!("Taken from leftovers instead of being acquired:");
+    for (item_id, count) in used_leftovers {
+        println!("{} {}", count, item_name(item_id, items_map));
     }
+}
 
-    if !profitable_item.crafted_items.leftovers.is_empty() {
+fn print_leftovers(crafted_items: &crafting::CraftedItems, items_map: &HashMap<u32, Item>) {
+    if !crafted_items.leftovers.is_empty() {
         println!("Leftovers:");
-        for (leftover_id, (count, cost, _)) in profitable_item.crafted_items.leftovers.iter() {
+        for (leftover_id, (count, cost, _)) in crafted_items.leftovers.iter() {
             println!(
                 "{} {}, breakeven: {} each",
                 count,
-                items_map
-                    .get(&leftover_id)
-                    .map_or_else(|| "???".to_string(), |item| item.to_string()),
-                cost.trading_post_listing_price(),
+                item_name(*leftover_id, items_map),
+                cost.trading_post_listing_price_with_fees(
+                    CONFIG.crafting.listing_fee(),
+                    CONFIG.crafting.exchange_fee()
+                ),
             );
         }
     }
+}
 
-    return Ok(());
+#[derive(Serialize)]
+struct CsvMetadata {
+    timestamp: u64,
+    version: &'static str,
+    listing_fee_percent: u8,
+    exchange_fee_percent: u8,
+    config: &'static config::Config,
+}
+
+/// Write the settings used to produce a CSV file to a sidecar file alongside it, e.g.
+/// profit.meta.json for profit.csv
+fn write_csv_metadata(csv_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = CsvMetadata {
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        version: env!("CARGO_PKG_VERSION"),
+        listing_fee_percent: CONFIG.crafting.listing_fee(),
+        exchange_fee_percent: CONFIG.crafting.exchange_fee(),
+        config: &CONFIG,
+    };
+    let file = File::create(csv_path.with_extension("meta.json"))?;
+    serde_json::to_writer_pretty(file, &metadata)?;
+    Ok(())
+}
+
+/// The run took longer than --max-runtime
+struct RuntimeExceeded {
+    seconds: u64,
+}
+
+// errors are printed with their Debug output, so show the message instead of the fields
+impl std::fmt::Debug for RuntimeExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::fmt::Display for RuntimeExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Aborted after exceeding the maximum runtime of {} seconds",
+            self.seconds
+        )
+    }
+}
+
+impl std::error::Error for RuntimeExceeded {}
+
+#[derive(Debug, Serialize)]
+struct ErrorOutput {
+    error_kind: &'static str,
+    message: String,
+    url: Option<String>,
+}
+
+impl From<&(dyn std::error::Error + 'static)> for ErrorOutput {
+    fn from(e: &(dyn std::error::Error + 'static)) -> Self {
+        let (error_kind, url) = if let Some(e) = e.downcast_ref::<request::ApiError>() {
+            let error_kind = if e.status == 429 {
+                "rate_limited"
+            } else {
+                "api"
+            };
+            (error_kind, Some(e.url.clone()))
+        } else if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            let error_kind = if e.status().map_or(false, |s| s.as_u16() == 429) {
+                "rate_limited"
+            } else {
+                "network"
+            };
+            (error_kind, e.url().map(|url| url.to_string()))
+        } else if e
+            .downcast_ref::<serde_path_to_error::Error<serde_json::Error>>()
+            .is_some()
+        {
+            ("parse", None)
+        } else if e.downcast_ref::<bincode::ErrorKind>().is_some() {
+            ("data", None)
+        } else if e.downcast_ref::<std::io::Error>().is_some() {
+            ("io", None)
+        } else if e.downcast_ref::<csv::Error>().is_some() {
+            ("csv", None)
+        } else if e.downcast_ref::<RuntimeExceeded>().is_some() {
+            ("timeout", None)
+        } else {
+            ("other", None)
+        };
+        ErrorOutput {
+            error_kind,
+            message: e.to_string(),
+            // don't leak the api key of authenticated requests
+            url: url.map(|url| match url.find("access_token=") {
+                Some(i) => format!("{}access_token=<api-key>", &url[..i]),
+                None => url,
+            }),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -428,20 +1756,50 @@ struct OutputRow {
     crafting_steps: u32,
     profit_per_step: i32,
     profit_on_cost: f64,
+    total_profit_gold: f64,
+    profit_per_item_gold: f64,
+    /// Profit in copper per hour at the configured number of crafting steps per hour
+    profit_per_hour: Option<i32>,
+    restrictions: String,
+    /// Difference in copper between the precise and estimated crafting cost of the first item
+    estimate_delta: Option<i32>,
+    /// Total quantity of buy orders for the item
+    buy_volume: u32,
+}
+
+/// A row of the JSON output, which has the exact total profit that the CSV only has as text and
+/// in gold
+#[derive(Debug, Serialize)]
+struct JsonOutputRow {
+    #[serde(flatten)]
+    row: OutputRow,
+    total_profit_copper: i32,
 }
 
-/// List profitable items to screen or CSV
+/// List profitable items to screen, CSV or JSON
 fn print_item_list(
     profitable_items: &Vec<profit::ProfitableItem>,
     recipes_map: &HashMap<u32, Recipe>,
     items_map: &HashMap<u32, Item>,
     known_recipes: &Option<HashSet<u32>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    // profitable items are sorted by ascending --sort-by profit, so the most profitable are at the
+    // end
+    let all_items = profitable_items;
+    let profitable_items = match CONFIG.top {
+        Some(top) => &all_items[all_items.len().saturating_sub(top as usize)..],
+        None => &all_items[..],
+    };
+
     let mut csv_writer = if let Some(path) = &CONFIG.output_csv {
+        if CONFIG.csv_metadata {
+            write_csv_metadata(path)?;
+        }
         Some(csv::Writer::from_path(path)?)
     } else {
         None
     };
+    let mut json_rows = vec![];
 
     let mut line_colors = [
         colored::Color::Red,
@@ -453,8 +1811,13 @@ fn print_item_list(
     .iter()
     .cycle();
 
+    // only shown when --crafts-per-hour is given
+    let hourly_header = CONFIG
+        .crafts_per_hour
+        .map(|_| format!(" {:>15}", "Profit / hour"))
+        .unwrap_or_default();
     let header = format!(
-        "{:<50} {:<15} {:<15} {:<20} {:>15} {:>15} {:>15} {:>15} {:>15} {:>15}",
+        "{:<50} {:<15} {:<15} {:<20} {:>15} {:>15} {:>15} {:>15} {:>15} {:>15} {:>16}{}",
         "Name",
         "Disciplines",
         "Item id",
@@ -465,6 +1828,8 @@ fn print_item_list(
         "Crafting steps",
         "Profit / step",
         "Profit on cost",
+        "Cost vs estimate",
+        hourly_header,
     );
 
     println!("{}", header);
@@ -479,9 +1844,7 @@ fn print_item_list(
         }
 
         let item_id = profitable_item.id;
-        let name = items_map
-            .get(&item_id)
-            .map_or_else(|| "???".to_string(), |item| item.to_string());
+        let name = item_name(item_id, items_map);
 
         let recipe = recipes_map.get(&item_id).expect("Missing recipe");
 
@@ -506,6 +1869,20 @@ fn print_item_list(
             crafting_steps: profitable_item.crafting_steps,
             profit_per_step: profitable_item.profit_per_crafting_step().to_copper_value(),
             profit_on_cost: profitable_item.profit_on_cost(),
+            total_profit_gold: profitable_item.profit.to_gold_f64(),
+            profit_per_item_gold: profitable_item.profit_per_item().to_gold_f64(),
+            profit_per_hour: CONFIG.crafts_per_hour.map(|crafts_per_hour| {
+                profitable_item
+                    .profit_per_hour(crafts_per_hour)
+                    .to_copper_value()
+            }),
+            restrictions: items_map
+                .get(&item_id)
+                .map_or_else(String::new, |item| item.restrictions().join("/")),
+            estimate_delta: profitable_item
+                .estimate_delta
+                .map(|delta| delta.to_copper_value()),
+            buy_volume: profitable_item.buy_volume,
         };
 
         if let Some(writer) = &mut csv_writer {
@@ -513,7 +1890,7 @@ fn print_item_list(
         }
 
         let line = format!(
-            "{:<50} {:<15} {:<15} {:<20} {:>15} {:>15} {:>15} {:>15} {:>15} {:>15}",
+            "{:<50} {:<15} {:<15} {:<20} {:>15} {:>15} {:>15} {:>15} {:>15} {:>15} {:>16}{}",
             output_row.name,
             output_row.disciplines,
             format!("{}", output_row.item_id),
@@ -539,26 +1916,343 @@ fn print_item_list(
             format!("{} / item", output_row.profit_per_item),
             format!("{} steps", output_row.crafting_steps),
             format!("{} / step", output_row.profit_per_step),
-            format!("{}%", (output_row.profit_on_cost * 100_f64).round())
+            format!("{}%", (output_row.profit_on_cost * 100_f64).round()),
+            match output_row.estimate_delta {
+                Some(delta) if delta > 0 => format!("+{}", Money::from_copper(delta)),
+                Some(delta) if delta < 0 => Money::from_copper(delta).to_string(),
+                _ => "-".to_string(),
+            },
+            output_row
+                .profit_per_hour
+                .map(|profit| format!(" {:>15}", format!("{} / hour", profit)))
+                .unwrap_or_default(),
         );
 
         println!("{}", line.color(*line_colors.next().unwrap()));
+
+        if CONFIG.output_json.is_some() {
+            json_rows.push(JsonOutputRow {
+                row: output_row,
+                total_profit_copper: profitable_item.profit.to_copper_value(),
+            });
+        }
     }
 
     println!("{}", "=".repeat(header.len()));
     println!("{}", header);
     println!("{}", "=".repeat(header.len()));
 
+    if let Some(export_count) = CONFIG.export_calculator {
+        // profitable items are sorted by ascending --sort-by profit
+        let items: Vec<(u32, u32)> = profitable_items
+            .iter()
+            .rev()
+            .filter(|item| item.count > 0)
+            .take(export_count.unwrap_or(usize::MAX))
+            .map(|item| (item.count, item.id))
+            .collect();
+        println!(
+            "Crafting steps for {} item{}: {}",
+            items.len(),
+            if items.len() != 1 { "s" } else { "" },
+            gw2efficiency::calculator_url(&items)
+        );
+    }
+
     let total_profit: Money = profitable_items.iter().map(|item| item.profit).sum();
-    println!("Total: {}", total_profit);
+    if profitable_items.len() < all_items.len() {
+        let all_profit: Money = all_items.iter().map(|item| item.profit).sum();
+        println!(
+            "Total: {} for the {} items shown, {} for all {} items",
+            total_profit,
+            profitable_items.len(),
+            all_profit,
+            all_items.len()
+        );
+    } else {
+        println!("Total: {}", total_profit);
+    }
+    if let Some(crafts_per_hour) = CONFIG.crafts_per_hour {
+        let total_steps: u32 = profitable_items
+            .iter()
+            .map(|item| item.crafting_steps)
+            .sum();
+        if total_steps > 0 {
+            println!(
+                "At {} crafting steps per hour: {} / hour, taking {:.1} hours",
+                crafts_per_hour,
+                Money::from_copper(
+                    (total_profit / total_steps * crafts_per_hour).to_copper_value()
+                ),
+                f64::from(total_steps) / f64::from(crafts_per_hour),
+            );
+        }
+    }
+    let total_tax: Money = profitable_items.iter().map(|item| item.tax).sum();
+    if !total_tax.is_zero() {
+        println!("TP tax paid: {}", total_tax);
+    }
 
     if let Some(writer) = &mut csv_writer {
         writer.flush()?;
     }
+    if let Some(path) = &CONFIG.output_json {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &json_rows)?;
+    }
 
     Ok(())
 }
 
+/// List items which can be crafted and listed below the current lowest sell listing
+fn print_undercut_list(undercut_items: &[profit::UndercutItem], items_map: &HashMap<u32, Item>) {
+    println!();
+    println!(
+        "Undercut candidates (estimated from current prices, sold by listing on the trading post):"
+    );
+
+    let header = format!(
+        "{:<50} {:<15} {:>15} {:>15} {:>15}",
+        "Name", "Item id", "List at", "Crafting cost", "Profit / item",
+    );
+
+    println!("{}", header);
+    println!("{}", "=".repeat(header.len()));
+    for undercut_item in undercut_items {
+        let name = item_name(undercut_item.id, items_map);
+        println!(
+            "{:<50} {:<15} {:>15} {:>15} {:>15}",
+            name,
+            undercut_item.id,
+            undercut_item.sell_price.to_string(),
+            undercut_item.crafting_cost.to_string(),
+            undercut_item.profit.to_string(),
+        );
+    }
+    println!("{}", "=".repeat(header.len()));
+}
+
+/// List items which are profitable to craft and sell to a merchant
+fn print_vendor_sell_list(
+    vendor_sell_items: &[profit::VendorSellItem],
+    items_map: &HashMap<u32, Item>,
+) {
+    println!();
+    println!("Vendor sale candidates (estimated from current prices, sold to a merchant):");
+
+    let header = format!(
+        "{:<50} {:<15} {:>15} {:>15} {:>15}",
+        "Name", "Item id", "Merchant pays", "Crafting cost", "Profit / item",
+    );
+
+    println!("{}", header);
+    println!("{}", "=".repeat(header.len()));
+    for vendor_sell_item in vendor_sell_items {
+        let name = item_name(vendor_sell_item.id, items_map);
+        println!(
+            "{:<50} {:<15} {:>15} {:>15} {:>15}",
+            name,
+            vendor_sell_item.id,
+            vendor_sell_item.vendor_value.to_string(),
+            vendor_sell_item.crafting_cost.to_string(),
+            vendor_sell_item.profit.to_string(),
+        );
+    }
+    println!("{}", "=".repeat(header.len()));
+}
+
+/// List items which are profitable to craft, with the venue they are most profitable to sell to
+fn print_best_venue_list(
+    best_venue_items: &[profit::BestVenueItem],
+    items_map: &HashMap<u32, Item>,
+) {
+    println!();
+    println!("Best sale venues (estimated from current prices):");
+
+    let header = format!(
+        "{:<50} {:<15} {:<15} {:>15} {:>15} {:>15}",
+        "Name", "Item id", "Sell to", "Revenue / item", "Crafting cost", "Profit / item",
+    );
+
+    println!("{}", header);
+    println!("{}", "=".repeat(header.len()));
+    for best_venue_item in best_venue_items {
+        let name = item_name(best_venue_item.id, items_map);
+        println!(
+            "{:<50} {:<15} {:<15} {:>15} {:>15} {:>15}",
+            name,
+            best_venue_item.id,
+            best_venue_item.best_venue.venue.to_string(),
+            best_venue_item.best_venue.revenue.to_string(),
+            best_venue_item.crafting_cost.to_string(),
+            best_venue_item.best_venue.profit.to_string(),
+        );
+    }
+    println!("{}", "=".repeat(header.len()));
+}
+
+/// Find the id of the only item with the given name, or list the items to choose from if several
+/// items share it
+fn find_item_by_name(
+    name: &str,
+    rarity: Option<item::Rarity>,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let matching_items = item::items_named(name, rarity, items_map.values());
+    // items without a recipe can't be crafted, so aren't worth asking about
+    let craftable_items: Vec<&Item> = matching_items
+        .iter()
+        .copied()
+        .filter(|item| recipes_map.contains_key(&item.id))
+        .collect();
+    match (craftable_items.as_slice(), matching_items.as_slice()) {
+        ([item], _) | ([], [item]) => Ok(item.id),
+        (_, []) => Err(format!(
+            "No item is named '{}'{}. Use the search command to find the item id",
+            name,
+            rarity.map_or_else(String::new, |rarity| format!(" with rarity {}", rarity)),
+        )
+        .into()),
+        (candidates, _) => {
+            let candidates = if candidates.is_empty() {
+                &matching_items
+            } else {
+                candidates
+            };
+            println!("Several items are named '{}':", name);
+            println!("Item id    Rarity          Name");
+            for item in candidates {
+                println!("{:<10} {:<15} {}", item.id, item.rarity(), item);
+            }
+            Err("Select one of the items by id, or by name with --rarity".into())
+        }
+    }
+}
+
+/// Print the ids of items whose name contains the given text
+fn print_search_results(
+    name: &str,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) {
+    let name = name.to_lowercase();
+    let mut matching_items: Vec<&Item> = items_map
+        .values()
+        .filter(|item| item.name.to_lowercase().contains(&name))
+        .collect();
+    if matching_items.is_empty() {
+        println!("No items found");
+        return;
+    }
+    matching_items.sort_unstable_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+
+    println!("Item id    Name");
+    for item in matching_items {
+        let craftable_msg = if recipes_map.contains_key(&item.id) {
+            " (craftable)"
+        } else {
+            ""
+        };
+        println!("{:<10} {}{}", item.id, item, craftable_msg);
+    }
+}
+
+/// List profitable material promotions
+fn print_promotion_list(promotions: &[profit::PromotionProfit], items_map: &HashMap<u32, Item>) {
+    println!(
+        "Material promotions (estimated from current prices, with the average output rounded down):"
+    );
+    if CONFIG.promotion_confidence.is_some() {
+        println!(
+            "Output is assumed to vary only between the whole numbers around its average, so the \
+             real risk may be higher"
+        );
+    }
+
+    let confidence_header = CONFIG
+        .promotion_confidence
+        .map(|confidence| format!("{}% chance of", confidence))
+        .unwrap_or_default();
+    let header = format!(
+        "{:<50} {:<15} {:>10} {:>15} {:>15} {:>15} {:>15} {:>15} {:>15}  {}",
+        "Name",
+        "Item id",
+        "Output",
+        "Cost",
+        "Value",
+        "Profit",
+        "Expected",
+        "Std dev",
+        confidence_header,
+        "Also requires",
+    );
+
+    println!("{}", header);
+    println!("{}", "=".repeat(header.len()));
+    for promotion in promotions {
+        let name = item_name(promotion.id, items_map);
+        let unpriced_msg = promotion
+            .unpriced_ingredients
+            .iter()
+            .map(|ingredient| {
+                format!(
+                    "{} x {}",
+                    ingredient.count,
+                    item_name(ingredient.item_id, items_map)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let confident_msg = promotion
+            .confident_profit
+            .map(|profit| profit.to_string())
+            .unwrap_or_default();
+        println!(
+            "{:<50} {:<15} {:>10} {:>15} {:>15} {:>15} {:>15} {:>15} {:>15}  {}",
+            name,
+            promotion.id,
+            promotion.output_count,
+            promotion.cost.to_string(),
+            promotion.value.to_string(),
+            Money::from_copper(promotion.profit.to_copper_value()).to_string(),
+            promotion.expected_profit.to_string(),
+            promotion.profit_std_dev.to_string(),
+            confident_msg,
+            unpriced_msg,
+        );
+    }
+    println!("{}", "=".repeat(header.len()));
+}
+
+/// List recipes which convert currencies into a more valuable account bound token
+fn print_currency_conversion_list(
+    conversions: &[profit::CurrencyConversion],
+    items_map: &HashMap<u32, Item>,
+) {
+    println!("Currency conversions (per craft, at the configured currency values):");
+
+    let header = format!(
+        "{:<50} {:<15} {:>25} {:>25} {:>15}",
+        "Name", "Item id", "Cost", "Value", "Profit",
+    );
+
+    println!("{}", header);
+    println!("{}", "=".repeat(header.len()));
+    for conversion in conversions {
+        let name = item_name(conversion.id, items_map);
+        println!(
+            "{:<50} {:<15} {:>25} {:>25} {:>15}",
+            name,
+            conversion.id,
+            conversion.cost.to_string(),
+            conversion.value.to_string(),
+            Money::from_copper(conversion.profit.to_copper_value()).to_string(),
+        );
+    }
+    println!("{}", "=".repeat(header.len()));
+}
+
 trait DivCeil {
     fn div_ceil(&self, other: Self) -> Self;
 }
@@ -1,11 +1,13 @@
 use num_rational::{Rational32, Rational64};
 use num_traits::{ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
 
 use std::cmp;
 use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
 
-use crate::config::CONFIG;
+use crate::config::{CopperRounding, CONFIG};
 
 // https://wiki.guildwars2.com/wiki/Trading_Post
 // Listing Fee (5%) — This nonrefundable cost covers listing and holding your items for sale. This
@@ -13,20 +15,22 @@ use crate::config::CONFIG;
 // sell an item.
 // Exchange Fee (10%) — This fee is the Trading Post's cut of the profit. This fee has a minimum of
 // 1c and is deducted from coins delivered to the seller after a successful sale.
-const TRADING_POST_LISTING_FEE: u8 = 5; // %
-const TRADING_POST_EXCHANGE_FEE: u8 = 10; // %
+pub const TRADING_POST_LISTING_FEE: u8 = 5; // %
+pub const TRADING_POST_EXCHANGE_FEE: u8 = 10; // %
 
-// TODO: spirit shards, laurels,
+// TODO: laurels,
 // badges of honor? Testimony/proof of heroics
 // Geodes, Bandit Crests, Airship Parts, Aurillium, Ley Crystals, Trade Contracts, Racing Medallions
 // Fractal Relics
-#[derive(Copy, Clone, Eq)]
+#[derive(Copy, Clone, Eq, Serialize, Deserialize)]
 pub struct Money {
     copper: Rational32,
     karma: Rational32,
     um: Rational32,
     vm: Rational32,
     rn: Rational32,
+    ss: Rational32,
+    gem: Rational32,
 }
 impl Money {
     pub fn from_copper(copper: i32) -> Self {
@@ -59,13 +63,73 @@ impl Money {
             ..Default::default()
         }
     }
-    pub fn new(copper: i32, karma: i32, um: i32, vm: i32, rn: i32) -> Self {
+    pub fn from_ss(ss: i32) -> Self {
+        Self {
+            ss: Rational32::from(ss),
+            ..Default::default()
+        }
+    }
+    pub fn from_gem(gem: i32) -> Self {
+        Self {
+            gem: Rational32::from(gem),
+            ..Default::default()
+        }
+    }
+    /// Value of an amount of a wallet currency by its API id, if the currency has a configured
+    /// conversion factor
+    pub fn from_wallet_currency(currency_id: u32, amount: i32) -> Option<Self> {
+        let valued = match currency_id {
+            1 => true,
+            2 => CONFIG.karma.is_some(),
+            32 => CONFIG.um.is_some(),
+            45 => CONFIG.vm.is_some(),
+            23 => CONFIG.ss.is_some(),
+            61 => CONFIG.rn.is_some(),
+            4 => CONFIG.gem.get().is_some(),
+            _ => false,
+        };
+        Self::from_wallet_amount(currency_id, amount).filter(|_| valued)
+    }
+    /// An amount of a wallet currency by its API id, whether or not it has a conversion factor, if
+    /// it is one of the currencies `Money` holds
+    pub fn from_wallet_amount(currency_id: u32, amount: i32) -> Option<Self> {
+        match currency_id {
+            1 => Some(Self::from_copper(amount)),
+            2 => Some(Self::from_karma(amount)),
+            32 => Some(Self::from_um(amount)),
+            45 => Some(Self::from_vm(amount)),
+            23 => Some(Self::from_ss(amount)),
+            61 => Some(Self::from_rn(amount)),
+            4 => Some(Self::from_gem(amount)),
+            _ => None,
+        }
+    }
+    /// The amount of each currency by its wallet currency id, rounded up, leaving out currencies
+    /// with no amount
+    pub fn wallet_amounts(&self) -> Vec<(u32, i32)> {
+        [
+            (1, self.copper),
+            (2, self.karma),
+            (32, self.um),
+            (45, self.vm),
+            (23, self.ss),
+            (61, self.rn),
+            (4, self.gem),
+        ]
+        .iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(currency_id, amount)| (*currency_id, amount.ceil().to_integer()))
+        .collect()
+    }
+    pub fn new(copper: i32, karma: i32, um: i32, vm: i32, rn: i32, ss: i32, gem: i32) -> Self {
         Self {
             copper: Rational32::from(copper),
             karma: Rational32::from(karma),
             um: Rational32::from(um),
             vm: Rational32::from(vm),
             rn: Rational32::from(rn),
+            ss: Rational32::from(ss),
+            gem: Rational32::from(gem),
         }
     }
 
@@ -75,12 +139,31 @@ impl Money {
             + self.um * CONFIG.um.unwrap_or(Rational32::zero())
             + self.vm * CONFIG.vm.unwrap_or(Rational32::zero())
             + self.rn * CONFIG.rn.unwrap_or(Rational32::zero())
+            + self.ss * CONFIG.ss.unwrap_or(Rational32::zero())
+            + self.gem * CONFIG.gem.get().copied().unwrap_or(Rational32::zero())
     }
+
+    /// Whole copper value, rounded according to --copper-rounding. Rounding up by default keeps
+    /// costs conservative, whereas trading post fees are rounded to the nearest copper by `fee`.
     pub fn to_copper_value(&self) -> i32 {
-        self.copper_value().ceil().to_integer()
+        round_copper(self.copper_value(), CONFIG.copper_rounding)
+    }
+
+    /// Total value in gold, for numeric output
+    pub fn to_gold_f64(&self) -> f64 {
+        self.copper_value().to_f64().unwrap_or(0_f64) / 10000_f64
+    }
+
+    /// Total value in gems at the configured gem rate, if there is one
+    pub fn to_gems_f64(&self) -> Option<f64> {
+        let coins_per_gem = CONFIG.gem.get()?;
+        (self.copper_value() / coins_per_gem).to_f64()
     }
 
     fn fee(&self, percent: u8) -> Rational32 {
+        if percent == 0 {
+            return Rational32::zero();
+        }
         cmp::max(
             Rational32::from(1),
             self.copper * Rational32::new(percent as i32, 100),
@@ -88,8 +171,15 @@ impl Money {
         .round()
     }
 
+    /// Revenue after the configured trading post fees
     pub fn trading_post_sale_revenue(self) -> Money {
-        let fees = self.fee(TRADING_POST_EXCHANGE_FEE) + self.fee(TRADING_POST_LISTING_FEE);
+        self.trading_post_sale_revenue_with_fees(
+            CONFIG.crafting.listing_fee(),
+            CONFIG.crafting.exchange_fee(),
+        )
+    }
+    pub fn trading_post_sale_revenue_with_fees(self, listing_fee: u8, exchange_fee: u8) -> Money {
+        let fees = self.fee(exchange_fee) + self.fee(listing_fee);
         Money {
             copper: if self.copper > fees {
                 self.copper - fees
@@ -101,16 +191,24 @@ impl Money {
     }
     /// Has an error of at most 1 copper too high (could have broken even at one copper less)
     pub fn trading_post_listing_price(self) -> Money {
+        self.trading_post_listing_price_with_fees(
+            CONFIG.crafting.listing_fee(),
+            CONFIG.crafting.exchange_fee(),
+        )
+    }
+    pub fn trading_post_listing_price_with_fees(self, listing_fee: u8, exchange_fee: u8) -> Money {
         let copper = self.copper_value();
+        // the minimum fee of 1 copper each has to be covered too
+        let minimum_fees = [listing_fee, exchange_fee]
+            .iter()
+            .filter(|&&fee| fee > 0)
+            .count() as i32;
         Money {
             copper: cmp::max(
                 (copper
-                    * Rational32::new(
-                        100,
-                        (100 - TRADING_POST_LISTING_FEE - TRADING_POST_EXCHANGE_FEE) as i32,
-                    ))
+                    * Rational32::new(100, 100 - i32::from(listing_fee) - i32::from(exchange_fee)))
                 .ceil(),
-                copper + 2,
+                copper + minimum_fees,
             ),
             ..Default::default()
         }
@@ -120,22 +218,122 @@ impl Money {
     /// the TP sometimes glitches w/small sale volumes, requiring filling them multiple times
     /// anyway, reintroducing rounding errors.
     pub fn increase_by_listing_fee(self) -> Money {
+        self.increase_by_listing_fee_with(CONFIG.crafting.listing_fee())
+    }
+    pub fn increase_by_listing_fee_with(self, listing_fee: u8) -> Money {
         Money {
-            copper: self.copper + self.fee(TRADING_POST_LISTING_FEE),
+            copper: self.copper + self.fee(listing_fee),
             karma: self.karma,
             um: self.um,
             vm: self.vm,
             rn: self.rn,
+            ss: self.ss,
+            gem: self.gem,
         }
     }
 
+    /// Whether any currency other than coins is spent
+    pub fn has_currencies(&self) -> bool {
+        !(self.karma.is_zero()
+            && self.um.is_zero()
+            && self.vm.is_zero()
+            && self.rn.is_zero()
+            && self.ss.is_zero()
+            && self.gem.is_zero())
+    }
+
     // Gives an approximate ratio between two money values; for profit on cost
     pub fn percent(self, other: Self) -> f64 {
         let value = self.copper_value().to_f64().unwrap_or(0_f64);
         let other_value = other.copper_value().to_f64().unwrap_or(f64::INFINITY);
         value / other_value
     }
+
+    /// Whether this is at least the given percentage of `other`. Compared exactly in wider
+    /// integers, since multiplying a large amount by the percentage would overflow.
+    pub fn is_at_least_percent_of(self, other: Self, percent: u32) -> bool {
+        let value = self.copper_value();
+        let other_value = other.copper_value();
+        i128::from(*value.numer()) * i128::from(*other_value.denom()) * 100
+            >= i128::from(*other_value.numer()) * i128::from(*value.denom()) * i128::from(percent)
+    }
+}
+
+fn round_copper(copper: Rational32, rounding: CopperRounding) -> i32 {
+    match rounding {
+        CopperRounding::Ceil => copper.ceil(),
+        CopperRounding::Floor => copper.floor(),
+        CopperRounding::Round => copper.round(),
+    }
+    .to_integer()
+}
+
+/// Parse an amount of coins into copper. Accepts plain copper (e.g. "500000"), the gold format
+/// used for display (e.g. "50.00.00g") or amounts with unit suffixes (e.g. "50g", "2s 50c")
+pub fn parse_coins(coins: &str) -> Result<u32, String> {
+    let invalid = || format!("Invalid amount of coins: {}", coins);
+    let trimmed = coins.trim().to_lowercase();
+    if trimmed.is_empty() {
+        return Err(invalid());
+    }
+    if trimmed.chars().all(|c| c.is_ascii_digit()) {
+        return trimmed.parse().map_err(|_| invalid());
+    }
+
+    if let Some(display) = trimmed.strip_suffix('g').filter(|s| s.contains('.')) {
+        let parts: Vec<&str> = display.split('.').collect();
+        if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+            return Err(invalid());
+        }
+        let parse_part = |part: &str| part.parse::<u32>().map_err(|_| invalid());
+        let (gold, silver, copper) = (
+            parse_part(parts[0])?,
+            parse_part(parts[1])?,
+            parse_part(parts[2])?,
+        );
+        if silver >= 100 || copper >= 100 {
+            return Err(invalid());
+        }
+        return gold
+            .checked_mul(10000)
+            .and_then(|value| value.checked_add(silver * 100 + copper))
+            .ok_or_else(invalid);
+    }
+
+    let mut total: u32 = 0;
+    let mut seen_units = vec![];
+    let mut digits = String::new();
+    for c in trimmed.chars() {
+        match c {
+            '0'..='9' => digits.push(c),
+            'g' | 's' | 'c' => {
+                if digits.is_empty() || seen_units.contains(&c) {
+                    return Err(invalid());
+                }
+                seen_units.push(c);
+                let multiplier = match c {
+                    'g' => 10000,
+                    's' => 100,
+                    _ => 1,
+                };
+                total = digits
+                    .parse::<u32>()
+                    .ok()
+                    .and_then(|value| value.checked_mul(multiplier))
+                    .and_then(|value| total.checked_add(value))
+                    .ok_or_else(invalid)?;
+                digits.clear();
+            }
+            c if c.is_whitespace() && digits.is_empty() => {}
+            _ => return Err(invalid()),
+        }
+    }
+    if !digits.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
 }
+
 impl fmt::Display for Money {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut currencies = Vec::new();
@@ -168,9 +366,75 @@ impl fmt::Display for Money {
             currencies.push(format!("{} RN", self.rn.to_integer()));
         }
 
+        if self.ss != Rational32::zero() {
+            currencies.push(format!("{} Spirit Shards", self.ss.to_integer()));
+        }
+
+        if self.gem != Rational32::zero() {
+            currencies.push(format!("{} Gems", self.gem.to_integer()));
+        }
+
         write!(f, "{}", currencies.join(", "))
     }
 }
+
+/// Parses the format written by `Display`: an amount of coins such as "-1.23.45g", "5c" or
+/// "2s 50c", and any other currencies after it, e.g. "1.23.45g, 35 Karma, 2 UM". An empty string is
+/// zero, as that is how zero is displayed.
+impl FromStr for Money {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid amount of money: {}", s);
+        let mut money = Money::zero();
+        if s.trim().is_empty() {
+            return Ok(money);
+        }
+
+        let mut seen = vec![];
+        for part in s.split(',').map(|part| part.trim()) {
+            let currency = part.split_once(' ').and_then(|(amount, currency)| {
+                let amount = amount.parse::<i32>().ok()?;
+                let currency = match currency.trim().to_lowercase().as_str() {
+                    "karma" => "karma",
+                    "um" => "um",
+                    "vm" => "vm",
+                    "rn" => "rn",
+                    "spirit shards" | "spirit shard" => "ss",
+                    "gems" | "gem" => "gem",
+                    _ => return None,
+                };
+                Some((currency, Rational32::from(amount)))
+            });
+            let (name, amount) = if let Some(currency) = currency {
+                currency
+            } else {
+                let (sign, coins) = match part.strip_prefix('-') {
+                    Some(coins) => (-1, coins),
+                    None => (1, part),
+                };
+                let copper = parse_coins(coins).map_err(|_| invalid())?;
+                let copper = i32::try_from(copper).map_err(|_| invalid())?;
+                ("copper", Rational32::from(sign * copper))
+            };
+            if seen.contains(&name) {
+                return Err(invalid());
+            }
+            seen.push(name);
+            match name {
+                "copper" => money.copper = amount,
+                "karma" => money.karma = amount,
+                "um" => money.um = amount,
+                "vm" => money.vm = amount,
+                "rn" => money.rn = amount,
+                "ss" => money.ss = amount,
+                _ => money.gem = amount,
+            }
+        }
+        Ok(money)
+    }
+}
+
 impl Default for Money {
     fn default() -> Self {
         Self::zero()
@@ -184,8 +448,11 @@ impl Zero for Money {
             um: Rational32::zero(),
             vm: Rational32::zero(),
             rn: Rational32::zero(),
+            ss: Rational32::zero(),
+            gem: Rational32::zero(),
         }
     }
+    /// Only the coins are compared, so currencies spent alongside them are ignored
     fn is_zero(&self) -> bool {
         self.copper.is_zero()
     }
@@ -200,6 +467,8 @@ impl std::ops::Add for Money {
             um: self.um + other.um,
             vm: self.vm + other.vm,
             rn: self.rn + other.rn,
+            ss: self.ss + other.ss,
+            gem: self.gem + other.gem,
         }
     }
 }
@@ -213,6 +482,8 @@ impl std::ops::Sub for Money {
             um: self.um - other.um,
             vm: self.vm - other.vm,
             rn: self.rn - other.rn,
+            ss: self.ss - other.ss,
+            gem: self.gem - other.gem,
         }
     }
 }
@@ -224,6 +495,8 @@ impl std::ops::AddAssign for Money {
             um: self.um + other.um,
             vm: self.vm + other.vm,
             rn: self.rn + other.rn,
+            ss: self.ss + other.ss,
+            gem: self.gem + other.gem,
         }
     }
 }
@@ -237,6 +510,8 @@ impl std::ops::Mul<u32> for Money {
             um: self.um * other as i32,
             vm: self.vm * other as i32,
             rn: self.rn * other as i32,
+            ss: self.ss * other as i32,
+            gem: self.gem * other as i32,
         }
     }
 }
@@ -250,6 +525,8 @@ impl std::ops::Div<u32> for Money {
             um: self.um / other as i32,
             vm: self.vm / other as i32,
             rn: self.rn / other as i32,
+            ss: self.ss / other as i32,
+            gem: self.gem / other as i32,
         }
     }
 }
@@ -260,6 +537,8 @@ impl PartialEq for Money {
             && self.um == other.um
             && self.vm == other.vm
             && self.rn == other.rn
+            && self.ss == other.ss
+            && self.gem == other.gem
     }
 }
 impl PartialOrd for Money {
@@ -287,6 +566,8 @@ impl std::iter::Sum for Money {
             sink.um += src.um;
             sink.vm += src.vm;
             sink.rn += src.rn;
+            sink.ss += src.ss;
+            sink.gem += src.gem;
         }
         let mut error = false;
         sink.copper = Rational32::new(
@@ -341,6 +622,14 @@ impl fmt::Debug for Money {
             result.field("rn", &self.rn.to_integer());
         }
 
+        if self.ss != Rational32::zero() {
+            result.field("ss", &self.ss.to_integer());
+        }
+
+        if self.gem != Rational32::zero() {
+            result.field("gem", &self.gem.to_integer());
+        }
+
         result.finish()
     }
 }
@@ -364,6 +653,156 @@ mod tests {
         }
     }
 
+    #[test]
+    fn configured_fees() {
+        for &sell in &[1, 2, 51, 68, 12345] {
+            let price = Money::from_copper(sell);
+            assert_eq!(price.trading_post_sale_revenue_with_fees(0, 0), price);
+            assert_eq!(price.increase_by_listing_fee_with(0), price);
+            assert_eq!(price.trading_post_listing_price_with_fees(0, 0), price);
+        }
+        // a 20% exchange fee without a listing fee
+        assert_eq!(
+            Money::from_copper(100).trading_post_sale_revenue_with_fees(0, 20),
+            Money::from_copper(80)
+        );
+        assert_eq!(
+            Money::from_copper(80).trading_post_listing_price_with_fees(0, 20),
+            Money::from_copper(100)
+        );
+    }
+
+    #[test]
+    fn copper_rounding() {
+        let third = Rational32::new(1, 3);
+        let half = Rational32::new(1, 2);
+        assert_eq!(round_copper(third, CopperRounding::Ceil), 1);
+        assert_eq!(round_copper(third, CopperRounding::Floor), 0);
+        assert_eq!(round_copper(third, CopperRounding::Round), 0);
+        assert_eq!(round_copper(half, CopperRounding::Round), 1);
+        assert_eq!(round_copper(-third, CopperRounding::Ceil), 0);
+        assert_eq!(round_copper(-third, CopperRounding::Floor), -1);
+
+        // a cost of 10/3 copper is rounded up, while the 5% listing fee on 50 copper (2.5) is
+        // rounded to the nearest copper like in game
+        assert_eq!((Money::from_copper(10) / 3).to_copper_value(), 4);
+        assert_eq!(
+            Money::from_copper(50)
+                .increase_by_listing_fee()
+                .to_copper_value(),
+            53
+        );
+    }
+
+    #[test]
+    fn spirit_shards() {
+        let money = (Money::from_copper(5) + Money::from_ss(3)) * 2;
+        assert_eq!(money.to_string(), "0.00.10g, 6 Spirit Shards");
+        assert_eq!(money - Money::from_ss(6), Money::from_copper(10));
+        assert_eq!(
+            vec![Money::from_ss(1), Money::from_ss(2)]
+                .into_iter()
+                .sum::<Money>(),
+            Money::from_ss(3)
+        );
+        assert_eq!(format!("{:?}", Money::from_ss(4) / 2), "Money { ss: 2 }");
+    }
+
+    #[test]
+    fn wallet_amounts() {
+        let money = Money::new(12345, 35, 0, 20, 0, 0, 0) + Money::from_ss(1) / 2;
+        assert_eq!(
+            money.wallet_amounts(),
+            vec![(1, 12345), (2, 35), (45, 20), (23, 1)]
+        );
+        for (currency_id, amount) in Money::new(5, 4, 3, 2, 1, 6, 7).wallet_amounts() {
+            assert_eq!(
+                Money::from_wallet_amount(currency_id, amount).map(|money| money.wallet_amounts()),
+                Some(vec![(currency_id, amount)])
+            );
+        }
+        assert_eq!(Money::from_wallet_amount(3, 10), None);
+    }
+
+    #[test]
+    fn gold_f64() {
+        assert_eq!(Money::from_copper(12345).to_gold_f64(), 1.2345);
+        assert_eq!(Money::from_copper(-50).to_gold_f64(), -0.005);
+        assert_eq!((Money::from_copper(1) / 2).to_gold_f64(), 0.00005);
+    }
+
+    #[test]
+    fn coins() {
+        assert_eq!(parse_coins("500000"), Ok(500000));
+        assert_eq!(parse_coins("50g"), Ok(500000));
+        assert_eq!(parse_coins("5.00.00g"), Ok(50000));
+        assert_eq!(parse_coins("1.23.45g"), Ok(12345));
+        assert_eq!(parse_coins("2s 50c"), Ok(250));
+        assert_eq!(parse_coins("1G50S"), Ok(15000));
+        assert_eq!(parse_coins("5c"), Ok(5));
+        assert_eq!(
+            parse_coins(&Money::from_copper(9876543).to_string()),
+            Ok(9876543)
+        );
+
+        for invalid in &[
+            "",
+            "g",
+            "1.2g",
+            "1.100.00g",
+            "5x",
+            "1g2g",
+            "10 s",
+            "-5",
+            "500000g",
+        ] {
+            assert!(parse_coins(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_money() {
+        let values = vec![
+            Money::zero(),
+            Money::from_copper(1),
+            Money::from_copper(99),
+            Money::from_copper(100),
+            Money::from_copper(12345),
+            Money::from_copper(-12345),
+            Money::from_copper(-5),
+            Money::from_copper(9876543),
+            Money::from_karma(35),
+            Money::new(12345, 35, 2, 20, 1, 4, 0),
+            Money::new(-150, -2688, 0, 200, 0, 0, 0),
+            Money::new(500, 0, 0, 0, 0, 2, 5),
+        ];
+        for value in values {
+            assert_eq!(value.to_string().parse::<Money>(), Ok(value), "{}", value);
+        }
+
+        assert_eq!("5c".parse::<Money>(), Ok(Money::from_copper(5)));
+        assert_eq!("2s".parse::<Money>(), Ok(Money::from_copper(200)));
+        assert_eq!("-2s 50c".parse::<Money>(), Ok(Money::from_copper(-250)));
+        assert_eq!(
+            "1.50.00g, 1 Spirit Shard".parse::<Money>(),
+            Ok(Money::from_copper(15000) + Money::from_ss(1))
+        );
+
+        for invalid in &[
+            "g",
+            "1.2g",
+            "--5c",
+            "5 Laurels",
+            "5 Gems, 1 Gem",
+            "1.00.00g, 2.00.00g",
+            "35 Karma, 35 Karma",
+            "35 Karma,",
+            "1.00.00g Karma",
+        ] {
+            assert!(invalid.parse::<Money>().is_err(), "{}", invalid);
+        }
+    }
+
     #[test]
     fn listing_price() {
         let epsilon = Money::from_copper(1);
@@ -1,7 +1,13 @@
 use rayon::prelude::*;
 
+use num_rational::Ratio;
 use num_traits::Zero;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use strum::{EnumString, EnumVariantNames};
 
 use crate::api;
 use crate::config;
@@ -12,35 +18,34 @@ use crate::recipe::Recipe;
 use crate::request;
 use config::CONFIG;
 
-/// Return a items which are profitable to make at least one of, and their ingredients, for further
-/// scrutiny
+pub const ITEM_STACK_SIZE: u32 = 250; // GW2 uses a "stack size" of 250
+pub const LISTING_HOLD_COST: u8 = 5; // % of the price lost while a listing waits to sell
+
+/// Return a items which are profitable to make at least one of, with their estimated crafting
+/// cost per item, and their ingredients, for further scrutiny
 pub fn find_profitable_items(
     tp_prices_map: &HashMap<u32, api::Price>,
     recipes_map: &HashMap<u32, Recipe>,
     items_map: &HashMap<u32, Item>,
-) -> (Vec<u32>, Vec<u32>) {
-    let mut profitable_item_ids = vec![];
+    chain_values: &HashMap<u32, ChainValue>,
+) -> (HashMap<u32, Money>, Vec<u32>) {
+    let mut profitable_items = HashMap::new();
     let mut ingredient_ids = vec![];
-    for (item_id, recipe) in recipes_map {
-        if let Some(item) = items_map.get(item_id) {
-            // we cannot sell restricted items
-            if item.is_restricted() {
-                continue;
+    let mut item_ids: Vec<&u32> = recipes_map.keys().collect();
+    if CONFIG.limit_recipes.is_some() {
+        // scan the same recipes on every run
+        item_ids.sort_unstable();
+    }
+    let mut scanned_count = 0;
+    for item_id in item_ids {
+        if let Some(limit) = CONFIG.limit_recipes {
+            if scanned_count >= limit {
+                break;
             }
         }
-
-        if let Some(filter_disciplines) = &CONFIG.filter_disciplines {
-            let mut has_discipline = false;
-            for discipline in filter_disciplines {
-                if recipe.disciplines.iter().any(|s| s == discipline) {
-                    has_discipline = true;
-                    break;
-                }
-            }
-
-            if !has_discipline {
-                continue;
-            }
+        let recipe = &recipes_map[item_id];
+        if !is_scan_candidate(*item_id, recipe, items_map, Intent::Sell) {
+            continue;
         }
 
         // some items are craftable and have no listed restrictions but are still not listable on tp
@@ -51,6 +56,12 @@ pub fn find_profitable_items(
             Some(tp_prices) if tp_prices.sells.quantity > 0 => tp_prices,
             _ => continue,
         };
+        scanned_count += 1;
+        if !CONFIG.crafting.meets_sell_volume(tp_prices.sells.quantity)
+            || !CONFIG.crafting.meets_buy_volume(tp_prices.buys.quantity)
+        {
+            continue;
+        }
 
         if let Some(crafting::EstimatedCraftingCost {
             source: crafting::Source::Crafting,
@@ -62,10 +73,18 @@ pub fn find_profitable_items(
             &tp_prices_map,
             &CONFIG.crafting,
         ) {
-            let effective_buy_price =
-                Money::from_copper(tp_prices.buys.unit_price as i32).trading_post_sale_revenue();
-            if effective_buy_price > crafting_cost {
-                profitable_item_ids.push(*item_id);
+            let effective_buy_price = chain_values
+                .get(item_id)
+                .map(|chain_value| chain_value.value)
+                .unwrap_or_else(|| {
+                    CONFIG
+                        .crafting
+                        .sale_revenue(Money::from_copper(tp_prices.buys.unit_price as i32))
+                });
+            if effective_buy_price > crafting_cost
+                || (CONFIG.crafting.include_breakeven && effective_buy_price == crafting_cost)
+            {
+                profitable_items.insert(*item_id, crafting_cost);
                 if let Some(recipe) = recipes_map.get(&item_id) {
                     recipe.collect_ingredient_ids(&recipes_map, &mut ingredient_ids);
                 }
@@ -73,20 +92,482 @@ pub fn find_profitable_items(
         }
     }
 
-    (profitable_item_ids, ingredient_ids)
+    (profitable_items, ingredient_ids)
+}
+
+/// Value items by their use as an ingredient in the most profitable recipe which consumes them,
+/// when that is more than selling them to buy orders. The value of an ingredient is the most that
+/// could be paid for it while breaking even on the recipe, with the other ingredients at their
+/// estimated cost.
+pub fn chain_values(
+    tp_prices_map: &HashMap<u32, api::Price>,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> HashMap<u32, ChainValue> {
+    let effective_buy_price = |item_id: &u32| {
+        tp_prices_map
+            .get(item_id)
+            .filter(|tp_prices| tp_prices.buys.quantity > 0)
+            .map(|tp_prices| {
+                CONFIG
+                    .crafting
+                    .sale_revenue(Money::from_copper(tp_prices.buys.unit_price as i32))
+            })
+    };
+
+    let mut chain_values: HashMap<u32, ChainValue> = HashMap::new();
+    for (item_id, recipe) in recipes_map {
+        if !is_scan_candidate(*item_id, recipe, items_map, Intent::Sell) {
+            continue;
+        }
+        let revenue = match effective_buy_price(item_id) {
+            Some(price) => price * recipe.output_item_count,
+            None => continue,
+        };
+        // crafts needed to fill the buy orders for the output, which exist as it has a buy price
+        let crafts = tp_prices_map[item_id]
+            .buys
+            .quantity
+            .div_ceil(recipe.output_item_count);
+
+        let ingredient_costs: Option<Vec<Money>> = recipe
+            .ingredients
+            .iter()
+            .map(|ingredient| {
+                crafting::calculate_estimated_min_crafting_cost(
+                    ingredient.item_id,
+                    recipes_map,
+                    items_map,
+                    tp_prices_map,
+                    &CONFIG.crafting,
+                )
+                .map(|estimate| estimate.cost * ingredient.count)
+            })
+            .collect();
+        let ingredient_costs = match ingredient_costs {
+            Some(costs) => costs,
+            None => continue,
+        };
+        let total_cost: Money = ingredient_costs.iter().copied().sum();
+
+        for (ingredient, cost) in recipe.ingredients.iter().zip(ingredient_costs) {
+            let value = (revenue - (total_cost - cost)) / ingredient.count;
+            if value <= Money::zero() {
+                continue;
+            }
+            // demand too large to count is unknown, so the item isn't valued by the recipe rather
+            // than crafted without limit
+            let demand = match crafts.checked_mul(ingredient.count) {
+                Some(demand) => demand,
+                None => continue,
+            };
+            let is_worse = chain_values
+                .get(&ingredient.item_id)
+                .filter(|chain_value| value <= chain_value.value)
+                .is_some();
+            if !is_worse {
+                chain_values.insert(ingredient.item_id, ChainValue { value, demand });
+            }
+        }
+    }
+
+    // only keep values which are better than selling the item directly
+    chain_values.retain(|item_id, chain_value| match effective_buy_price(item_id) {
+        Some(price) => chain_value.value > price,
+        None => true,
+    });
+
+    chain_values
+}
+
+/// Value of an item as an ingredient of a more profitable recipe
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChainValue {
+    pub value: Money,
+    /// Number of the item the recipe can use before the buy orders for its output run out
+    pub demand: u32,
+}
+
+/// What a recipe output is wanted for, which decides whether restricted items are of interest
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Intent {
+    /// Crafting to sell on the trading post
+    Sell,
+    /// Crafting to keep, e.g. when looking for the cheapest way to obtain an item
+    Acquire,
+    /// Crafting to sell to a merchant, which also buys items that can't be traded
+    VendorSale,
+}
+
+/// Whether a recipe output should be considered when scanning the whole market
+pub fn is_scan_candidate(
+    item_id: u32,
+    recipe: &Recipe,
+    items_map: &HashMap<u32, Item>,
+    intent: Intent,
+) -> bool {
+    if let Some(item) = items_map.get(&item_id) {
+        // we cannot sell restricted items, but they can still be crafted for ourselves
+        if intent == Intent::Sell && item.is_restricted() {
+            return false;
+        }
+
+        if let Some(filter_rarities) = &CONFIG.filter_rarities {
+            if !filter_rarities.contains(&item.rarity()) {
+                return false;
+            }
+        }
+
+        if let Some(filter_stats) = &CONFIG.filter_stats {
+            let attributes = item.stat_attributes().unwrap_or_default();
+            if !filter_stats.iter().all(|stat| {
+                attributes
+                    .iter()
+                    .any(|attribute| attribute.eq_ignore_ascii_case(stat))
+            }) {
+                return false;
+            }
+        }
+    }
+
+    if !recipe.is_output_count_within(CONFIG.min_output_count, CONFIG.max_output_count) {
+        return false;
+    }
+
+    if let Some(filter_disciplines) = &CONFIG.filter_disciplines {
+        let mut has_discipline = false;
+        for discipline in filter_disciplines {
+            if recipe.disciplines.iter().any(|s| s == discipline) {
+                has_discipline = true;
+                break;
+            }
+        }
+
+        if !has_discipline {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Return items which can be crafted for less than their lowest sell listing, so that they can be
+/// listed on the trading post for profit by undercutting the current listings
+pub fn find_undercut_items(
+    tp_prices_map: &HashMap<u32, api::Price>,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> Vec<UndercutItem> {
+    let mut undercut_items = vec![];
+    for (item_id, recipe) in recipes_map {
+        if !is_scan_candidate(*item_id, recipe, items_map, Intent::Sell) {
+            continue;
+        }
+
+        let tp_prices = match tp_prices_map.get(item_id) {
+            Some(tp_prices) if tp_prices.sells.quantity > 0 => tp_prices,
+            _ => continue,
+        };
+
+        if let Some(crafting::EstimatedCraftingCost {
+            source: crafting::Source::Crafting,
+            cost: crafting_cost,
+        }) = crafting::calculate_estimated_min_crafting_cost(
+            *item_id,
+            recipes_map,
+            items_map,
+            tp_prices_map,
+            &CONFIG.crafting,
+        ) {
+            // undercut the lowest listing by 1 copper
+            let sell_price = Money::from_copper(tp_prices.sells.unit_price as i32 - 1);
+            let profit = CONFIG.crafting.sale_revenue(sell_price) - crafting_cost;
+            if profit > Money::zero() {
+                undercut_items.push(UndercutItem {
+                    id: *item_id,
+                    crafting_cost,
+                    sell_price,
+                    profit,
+                });
+            }
+        }
+    }
+
+    undercut_items.sort_unstable_by_key(|item| item.profit);
+
+    undercut_items
+}
+
+/// Return items which can be crafted for less than a merchant pays for them
+pub fn find_vendor_sell_items(
+    tp_prices_map: &HashMap<u32, api::Price>,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> Vec<VendorSellItem> {
+    let mut vendor_sell_items = vec![];
+    for (item_id, recipe) in recipes_map {
+        // account bound items can't be sold on the trading post, but can still be sold to merchants
+        if !is_scan_candidate(*item_id, recipe, items_map, Intent::VendorSale) {
+            continue;
+        }
+
+        let vendor_value = match items_map
+            .get(item_id)
+            .and_then(|item| item.vendor_sell_value())
+        {
+            Some(vendor_value) => vendor_value,
+            None => continue,
+        };
+
+        if let Some(crafting::EstimatedCraftingCost {
+            source: crafting::Source::Crafting,
+            cost: crafting_cost,
+        }) = crafting::calculate_estimated_min_crafting_cost(
+            *item_id,
+            recipes_map,
+            items_map,
+            tp_prices_map,
+            &CONFIG.crafting,
+        ) {
+            let profit = vendor_value - crafting_cost;
+            if profit > Money::zero() {
+                vendor_sell_items.push(VendorSellItem {
+                    id: *item_id,
+                    crafting_cost,
+                    vendor_value,
+                    profit,
+                });
+            }
+        }
+    }
+
+    vendor_sell_items.sort_unstable_by_key(|item| item.profit);
+
+    vendor_sell_items
+}
+
+/// Return the revenue and profit per item of selling a crafted item to each venue it can be sold
+/// to, in the order buy orders, listing and merchant
+pub fn sale_venue_profits(
+    item: Option<&Item>,
+    tp_prices: Option<&api::Price>,
+    crafting_cost: Money,
+    opt: &config::CraftingOptions,
+) -> Vec<VenueProfit> {
+    let mut revenues = vec![];
+    if let Some(tp_prices) = tp_prices {
+        if tp_prices.buys.quantity > 0 {
+            revenues.push((SaleVenue::BuyOrder, tp_prices.buys.unit_price));
+        }
+        // undercut the lowest listing by 1 copper
+        if tp_prices.sells.quantity > 0 && tp_prices.sells.unit_price > 1 {
+            revenues.push((SaleVenue::Listing, tp_prices.sells.unit_price - 1));
+        }
+    }
+    let mut venue_profits: Vec<VenueProfit> = revenues
+        .into_iter()
+        .map(|(venue, unit_price)| {
+            let price = Money::from_copper(unit_price as i32);
+            let revenue = if venue == SaleVenue::Listing {
+                opt.listing_revenue(price)
+            } else {
+                opt.sale_revenue(price)
+            };
+            VenueProfit {
+                venue,
+                revenue,
+                profit: revenue - crafting_cost,
+            }
+        })
+        .collect();
+    if let Some(vendor_value) = item.and_then(|item| item.vendor_sell_value()) {
+        venue_profits.push(VenueProfit {
+            venue: SaleVenue::Vendor,
+            revenue: vendor_value,
+            profit: vendor_value - crafting_cost,
+        });
+    }
+    venue_profits
+}
+
+/// Return the venue with the highest profit per item. Ties go to the venue that sells soonest, so
+/// buy orders are preferred over listing, and both over a merchant.
+pub fn best_sale_venue(
+    item: Option<&Item>,
+    tp_prices: Option<&api::Price>,
+    crafting_cost: Money,
+    opt: &config::CraftingOptions,
+) -> Option<VenueProfit> {
+    sale_venue_profits(item, tp_prices, crafting_cost, opt)
+        .into_iter()
+        .rev()
+        .max_by_key(|venue_profit| venue_profit.profit)
+}
+
+/// Return items which are profitable to craft, with the venue each is most profitable to sell to
+pub fn find_best_venue_items(
+    tp_prices_map: &HashMap<u32, api::Price>,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> Vec<BestVenueItem> {
+    let mut best_venue_items = vec![];
+    for (item_id, recipe) in recipes_map {
+        // account bound items can't be sold on the trading post, but can still be sold to merchants
+        if !is_scan_candidate(*item_id, recipe, items_map, Intent::VendorSale) {
+            continue;
+        }
+
+        if let Some(crafting::EstimatedCraftingCost {
+            source: crafting::Source::Crafting,
+            cost: crafting_cost,
+        }) = crafting::calculate_estimated_min_crafting_cost(
+            *item_id,
+            recipes_map,
+            items_map,
+            tp_prices_map,
+            &CONFIG.crafting,
+        ) {
+            match best_sale_venue(
+                items_map.get(item_id),
+                tp_prices_map.get(item_id),
+                crafting_cost,
+                &CONFIG.crafting,
+            ) {
+                Some(best_venue) if best_venue.profit > Money::zero() => {
+                    best_venue_items.push(BestVenueItem {
+                        id: *item_id,
+                        crafting_cost,
+                        best_venue,
+                    })
+                }
+                _ => {}
+            }
+        }
+    }
+
+    best_venue_items.sort_unstable_by_key(|item| item.best_venue.profit);
+
+    best_venue_items
+}
+
+/// Return recipes which convert currencies into an account bound token worth more than the
+/// currencies spent, at the configured conversion rates
+pub fn find_currency_conversions(
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> Vec<CurrencyConversion> {
+    let mut conversions = vec![];
+    for (item_id, recipe) in recipes_map {
+        let value = match items_map.get(item_id) {
+            Some(item) if item.is_farmed() => match item.token_value() {
+                Some(value) => value * recipe.output_item_count,
+                None => continue,
+            },
+            _ => continue,
+        };
+        let cost = match recipe.currency_cost(items_map) {
+            Some(cost) => cost,
+            None => continue,
+        };
+
+        let profit = value - cost;
+        if profit > Money::zero() {
+            conversions.push(CurrencyConversion {
+                id: *item_id,
+                cost,
+                value,
+                profit,
+            });
+        }
+    }
+
+    conversions.sort_unstable_by_key(|conversion| conversion.profit);
+
+    conversions
+}
+
+/// Return material promotions which are profitable on average when buying the ingredients from
+/// the lowest sell listings and selling the output to the highest buy orders. Ingredients without
+/// a trading post or currency value (e.g. Philosopher's Stones) are listed instead of being priced.
+pub fn find_promotion_profits(
+    tp_prices_map: &HashMap<u32, api::Price>,
+    promotion_recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> Vec<PromotionProfit> {
+    let mut promotions = vec![];
+    for (item_id, recipe) in promotion_recipes_map {
+        let unit_value = match tp_prices_map.get(item_id) {
+            Some(tp_prices) if tp_prices.buys.quantity > 0 => CONFIG
+                .crafting
+                .sale_revenue(Money::from_copper(tp_prices.buys.unit_price as i32)),
+            _ => continue,
+        };
+        let value = unit_value * recipe.output_item_count;
+
+        let mut cost = Money::zero();
+        let mut unpriced_ingredients = vec![];
+        for ingredient in &recipe.ingredients {
+            match tp_prices_map.get(&ingredient.item_id) {
+                Some(tp_prices) if tp_prices.sells.quantity > 0 => {
+                    cost +=
+                        Money::from_copper(tp_prices.sells.unit_price as i32) * ingredient.count;
+                }
+                _ => match items_map
+                    .get(&ingredient.item_id)
+                    .and_then(|item| item.currency_value())
+                {
+                    Some(currency_value) => cost += currency_value * ingredient.count,
+                    None => unpriced_ingredients.push(*ingredient),
+                },
+            }
+        }
+
+        let profit = value - cost;
+        let distribution = match recipe.output_item_average {
+            Some(average) => OutputDistribution::from_average(average),
+            None => OutputDistribution::fixed(recipe.output_item_count),
+        };
+        let unit_value = unit_value.to_copper_value() as f64;
+        let cost_value = cost.to_copper_value() as f64;
+        let expected_profit = (unit_value * distribution.mean() - cost_value).round();
+        // random outputs can be profitable on average even when the listed count isn't
+        if expected_profit > 0.0 {
+            let profit_std_dev = (unit_value * distribution.std_dev()).round();
+            let confident_profit = CONFIG.promotion_confidence.map(|confidence| {
+                let count = distribution.count_with_probability(f64::from(confidence) / 100.0);
+                (unit_value * f64::from(count) - cost_value).round()
+            });
+            promotions.push(PromotionProfit {
+                id: *item_id,
+                output_count: recipe.output_item_count,
+                cost,
+                value,
+                profit,
+                expected_profit: Money::from_copper(expected_profit as i32),
+                profit_std_dev: Money::from_copper(profit_std_dev as i32),
+                confident_profit: confident_profit.map(|profit| Money::from_copper(profit as i32)),
+                unpriced_ingredients,
+            });
+        }
+    }
+
+    promotions.sort_unstable_by_key(|promotion| promotion.expected_profit);
+
+    promotions
 }
 
 /// Compute exact profit of profitable items independently in parallel
 pub fn profitable_item_list(
     tp_listings_map: &HashMap<u32, api::ItemListings>,
-    profitable_item_ids: &Vec<u32>,
+    estimated_costs: &HashMap<u32, Money>,
     request_listing_item_ids: &Vec<u32>,
     recipes_map: &HashMap<u32, Recipe>,
     items_map: &HashMap<u32, Item>,
+    chain_values: &HashMap<u32, ChainValue>,
 ) -> Vec<ProfitableItem> {
-    let mut profitable_items: Vec<_> = profitable_item_ids
+    let mut profitable_items: Vec<_> = estimated_costs
         .par_iter()
-        .filter_map(|item_id| {
+        .filter_map(|(item_id, estimated_cost)| {
             let mut ingredient_ids = vec![*item_id];
             if let Some(recipe) = recipes_map.get(&item_id) {
                 recipe.collect_ingredient_ids(&recipes_map, &mut ingredient_ids);
@@ -100,27 +581,131 @@ pub fn profitable_item_list(
                 }
             }
 
+            // sell to the recipe consuming the item instead of the trading post, crafting no more
+            // than the recipe can use
+            let chain_opt = chain_values.get(item_id).and_then(|chain_value| {
+                Some(config::CraftingOptions {
+                    value: Some(u32::try_from(chain_value.value.to_copper_value()).ok()?),
+                    count: Some(
+                        CONFIG
+                            .crafting
+                            .count
+                            .map_or(chain_value.demand, |count| count.min(chain_value.demand)),
+                    ),
+                    ..CONFIG.crafting.clone()
+                })
+            });
+
+            if let Some(min_listings) = CONFIG.min_listings {
+                let sell_listing_count = tp_listings_map
+                    .get(item_id)
+                    .map_or(0, |listings| listings.sell_listing_count());
+                if sell_listing_count < min_listings {
+                    return None;
+                }
+            }
+
+            // the item was selected using the prices snapshot, but the listings may have changed
+            // before the detailed listings were fetched
+            if chain_opt.is_none() && CONFIG.crafting.value.is_none() {
+                let has_buys = match tp_listings_map_for_item.get(item_id) {
+                    Some(listings) => !listings.buys.is_empty(),
+                    None => false,
+                };
+                if !has_buys {
+                    eprintln!(
+                        "Skipping {}: no buy orders left in the detailed trading post listings, \
+                         they were removed after prices were loaded",
+                        items_map
+                            .get(item_id)
+                            .map_or_else(|| item_id.to_string(), |item| item.to_string())
+                    );
+                    return None;
+                }
+            }
+
+            // only needed to count the inventory slots for the storage cost
+            let mut purchased_ingredients = HashMap::new();
             calculate_crafting_profit(
                 *item_id,
                 &recipes_map,
                 &items_map,
                 &tp_listings_map_for_item,
-                None,
-                &CONFIG.crafting,
+                Some(&mut purchased_ingredients).filter(|_| CONFIG.slot_cost > 0),
+                chain_opt.as_ref().unwrap_or(&CONFIG.crafting),
             )
+            .map(|mut profitable_item| {
+                profitable_item
+                    .include_storage_cost(storage_cost(&purchased_ingredients, items_map));
+                ProfitableItem {
+                    estimate_delta: Some(profitable_item.first_crafting_cost - *estimated_cost),
+                    ..profitable_item
+                }
+            })
+            .filter(|profitable_item| match CONFIG.min_roi {
+                Some(min_roi) => profitable_item.meets_min_roi(min_roi),
+                None => true,
+            })
         })
         .collect();
 
-    profitable_items.sort_unstable_by_key(|item| item.profit);
+    sort_profitable_items(&mut profitable_items, CONFIG.sort_by);
 
     profitable_items
 }
 
+/// Sort profitable items in ascending order of the given profit, then of total profit and item id
+/// for items with the same value, so that the most profitable are last
+pub fn sort_profitable_items(profitable_items: &mut [ProfitableItem], sort_by: config::SortBy) {
+    // items which aren't crafted any more after fetching detailed listings have no per-item profit
+    let per_item = |profit: Money, count: u32| {
+        if count == 0 {
+            Money::zero()
+        } else {
+            profit / count
+        }
+    };
+    profitable_items.sort_by(|a, b| {
+        let ordering = match sort_by {
+            config::SortBy::Profit => Ordering::Equal,
+            config::SortBy::ProfitPerItem => {
+                per_item(a.profit, a.count).cmp(&per_item(b.profit, b.count))
+            }
+            config::SortBy::ProfitPerStep => {
+                per_item(a.profit, a.crafting_steps).cmp(&per_item(b.profit, b.crafting_steps))
+            }
+            config::SortBy::ProfitOnCost => a
+                .profit_on_cost()
+                .partial_cmp(&b.profit_on_cost())
+                .unwrap_or(Ordering::Equal),
+        };
+        ordering
+            .then_with(|| a.profit.cmp(&b.profit))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+}
+
+/// Ids of the item and all of its ingredients, which need trading post listings to calculate the
+/// profit of crafting the item
+pub fn item_listing_ids(item_id: u32, recipes_map: &HashMap<u32, Recipe>) -> Vec<u32> {
+    let mut item_ids = vec![item_id];
+    if let Some(recipe) = recipes_map.get(&item_id) {
+        recipe.collect_ingredient_ids(recipes_map, &mut item_ids);
+    }
+    item_ids.sort_unstable();
+    item_ids.dedup();
+    item_ids
+}
+
+/// Calculate the profit of crafting an item, using listings fetched for at least the ids returned
+/// by `item_listing_ids`
 pub async fn calc_item_profit(
     item_id: u32,
     recipes_map: &HashMap<u32, Recipe>,
     items_map: &HashMap<u32, Item>,
+    tp_listings_map: &HashMap<u32, api::ItemListings>,
     known_recipes: &Option<HashSet<u32>>,
+    opt: &config::CraftingOptions,
     notify: Option<&dyn Fn(&str)>,
 ) -> Result<
     (
@@ -128,16 +713,13 @@ pub async fn calc_item_profit(
         HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
         Vec<u32>,
         HashMap<u32, api::Price>,
+        Option<KeptProfit>,
     ),
     Box<dyn std::error::Error>,
 > {
-    let mut items_to_price = vec![];
-
     let mut unknown_recipes = HashSet::new();
     let mut recipe_prices = Default::default();
     if let Some(recipe) = recipes_map.get(&item_id) {
-        recipe.collect_ingredient_ids(&recipes_map, &mut items_to_price);
-
         recipe.collect_unknown_recipe_ids(&recipes_map, &known_recipes, &mut unknown_recipes);
         let recipe_items: Vec<u32> = items_map
             .iter()
@@ -162,24 +744,14 @@ pub async fn calc_item_profit(
         recipe_prices = vec_to_map(prices, |x| x.id);
     }
 
-    let mut request_listing_item_ids = vec![item_id];
-    request_listing_item_ids.extend(items_to_price);
-    request_listing_item_ids.sort_unstable();
-    request_listing_item_ids.dedup();
-
-    let tp_listings =
-        request::fetch_item_listings(&request_listing_item_ids, Some(&CONFIG.cache_dir), notify)
-            .await?;
-    let tp_listings_map = vec_to_map(tp_listings, |x| x.id);
-
     let mut purchased_ingredients = Default::default();
     let profitable_item = calculate_crafting_profit(
         item_id,
         &recipes_map,
         &items_map,
-        &tp_listings_map,
+        tp_listings_map,
         Some(&mut purchased_ingredients),
-        &CONFIG.crafting,
+        opt,
     );
 
     let required_unknown_recipes: Vec<u32> = if let Some(profitable_item) = &profitable_item {
@@ -202,38 +774,483 @@ pub async fn calc_item_profit(
         Default::default()
     };
 
+    let mut profitable_item = profitable_item;
+    if CONFIG.include_recipe_cost {
+        if let Some(profitable_item) = &mut profitable_item {
+            profitable_item.include_recipe_cost(recipe_unlock_cost(
+                &required_unknown_recipes,
+                &recipe_prices,
+                items_map,
+            ));
+        }
+    }
+    if let Some(profitable_item) = &mut profitable_item {
+        profitable_item.include_storage_cost(storage_cost(&purchased_ingredients, items_map));
+    }
+
+    let kept_profit = match (CONFIG.keep, &profitable_item) {
+        (Some(keep), Some(profitable_item)) => {
+            profitable_item.profit_keeping(keep, tp_listings_map.get(&item_id), opt)
+        }
+        _ => None,
+    };
+
     Ok((
         profitable_item,
         purchased_ingredients,
         required_unknown_recipes,
         recipe_prices,
+        kept_profit,
     ))
 }
 
-pub fn calculate_crafting_profit(
-    item_id: u32,
-    recipes_map: &HashMap<u32, Recipe>,
+/// Number of inventory slots needed to hold the purchased ingredients, with a stack of up to
+/// `ITEM_STACK_SIZE` per slot. Ingredients bought from a merchant in smaller bundles take a slot
+/// per purchase that doesn't fit a whole bundle.
+pub fn inventory_slots(
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
     items_map: &HashMap<u32, Item>,
-    tp_listings_map: &HashMap<u32, api::ItemListings>,
-    mut purchased_ingredients: Option<
-        &mut HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
-    >,
-    opt: &config::CraftingOptions,
-) -> Option<ProfitableItem> {
-    let mut tp_listings_map: BTreeMap<u32, ItemListings> = tp_listings_map
-        .clone()
-        .into_iter()
-        .map(|(id, listings)| (id, ItemListings::from(listings)))
-        .collect();
+) -> u32 {
+    purchased_ingredients
+        .iter()
+        .map(|((ingredient_id, source), ingredient)| {
+            let item = items_map.get(ingredient_id);
+            if *source != crafting::Source::Vendor || item.filter(|item| item.is_farmed()).is_some()
+            {
+                return ingredient.count.div_ceil(ITEM_STACK_SIZE);
+            }
+            let purchase_count = item
+                .and_then(|item| item.vendor_cost())
+                .map_or(1, |(_, purchase_count)| purchase_count);
+            if purchase_count > 1 && ingredient.count > purchase_count {
+                ingredient.count.div_ceil(ITEM_STACK_SIZE)
+            } else {
+                1
+            }
+        })
+        .sum()
+}
 
-    let recipe = recipes_map.get(&item_id);
-    let output_item_count = recipe.map(|recipe| recipe.output_item_count).unwrap_or(1);
-    let threshold = Money::from_copper(opt.threshold.unwrap_or(0) as i32);
+/// Cost of the inventory slots needed to craft an item at --slot-cost per slot, including a slot
+/// for the crafted item
+fn storage_cost(
+    purchased_ingredients: &HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    items_map: &HashMap<u32, Item>,
+) -> Money {
+    if CONFIG.slot_cost == 0 {
+        return Money::zero();
+    }
+    Money::from_copper(CONFIG.slot_cost as i32)
+        * (inventory_slots(purchased_ingredients, items_map) + 1)
+}
+
+/// Price of the cheapest recipe sheet for sale which unlocks the recipe
+pub fn cheapest_recipe_sheet(
+    recipe_id: u32,
+    recipe_prices: &HashMap<u32, api::Price>,
+    items_map: &HashMap<u32, Item>,
+) -> Option<Money> {
+    items_map
+        .values()
+        .filter(|item| {
+            item.recipe_unlocks()
+                .map(|unlocks| unlocks.contains(&recipe_id))
+                .unwrap_or(false)
+        })
+        .filter_map(|item| recipe_prices.get(&item.id))
+        .filter(|price| price.sells.unit_price > 0)
+        .map(|price| Money::from_copper(price.sells.unit_price as i32))
+        .min()
+}
+
+/// The cost of buying the cheapest recipe sheet for each of the given recipes. Recipes without a
+/// priced recipe sheet are skipped with a warning.
+pub fn recipe_unlock_cost(
+    recipe_ids: &[u32],
+    recipe_prices: &HashMap<u32, api::Price>,
+    items_map: &HashMap<u32, Item>,
+) -> Money {
+    let mut total = Money::zero();
+    for recipe_id in recipe_ids {
+        if let Some(price) = cheapest_recipe_sheet(*recipe_id, recipe_prices, items_map) {
+            total += price;
+        } else {
+            eprintln!(
+                "Warning: no recipe sheet price found for recipe {}, its cost is not included",
+                recipe_id
+            );
+        }
+    }
+    total
+}
+
+/// Only the recipes which are known, automatically learned or custom, as used for crafting now
+/// rather than once every recipe has been learned
+pub fn known_recipes_map(
+    recipes_map: &HashMap<u32, Recipe>,
+    known_recipes: &Option<HashSet<u32>>,
+) -> HashMap<u32, Recipe> {
+    recipes_map
+        .iter()
+        .filter(|(_, recipe)| match recipe.id {
+            Some(id) if !recipe.is_automatic() => known_recipes
+                .as_ref()
+                .map(|known_recipes| known_recipes.contains(&id))
+                .unwrap_or(false),
+            _ => !recipe.is_unknown_achievement(&CONFIG.known_achievement_recipes),
+        })
+        .map(|(item_id, recipe)| (*item_id, recipe.clone()))
+        .collect()
+}
+
+/// Items which are less profitable, or not profitable at all, with only the known recipes
+pub fn missed_profits(
+    profitable_items: &[ProfitableItem],
+    known_profitable_items: &[ProfitableItem],
+    recipes_map: &HashMap<u32, Recipe>,
+    known_recipes: &Option<HashSet<u32>>,
+    tp_prices_map: &HashMap<u32, api::Price>,
+    items_map: &HashMap<u32, Item>,
+) -> Vec<MissedProfit> {
+    let mut missed: Vec<MissedProfit> = profitable_items
+        .iter()
+        .filter_map(|item| {
+            let known_profit = known_profitable_items
+                .iter()
+                .find(|known_item| known_item.id == item.id)
+                .map(|known_item| known_item.profit);
+            if matches!(known_profit, Some(profit) if profit >= item.profit) {
+                return None;
+            }
+            let mut unknown_recipes: Vec<u32> = item
+                .crafted_items
+                .unknown_recipes(recipes_map, known_recipes)
+                .into_iter()
+                .collect();
+            if unknown_recipes.is_empty() {
+                return None;
+            }
+            unknown_recipes.sort_unstable();
+            let unlock_cost = unknown_recipes
+                .iter()
+                .filter_map(|recipe_id| cheapest_recipe_sheet(*recipe_id, tp_prices_map, items_map))
+                .sum();
+            Some(MissedProfit {
+                id: item.id,
+                profit: item.profit,
+                known_profit,
+                unknown_recipes,
+                unlock_cost,
+            })
+        })
+        .collect();
+    missed.sort_unstable_by_key(|missed| std::cmp::Reverse(missed.missed_profit()));
+    missed
+}
+
+/// Fetch listings for the given item and its ingredients and calculate the cheapest way to obtain
+/// `count` of the item, regardless of resale profit
+pub async fn calc_item_acquisition(
+    item_id: u32,
+    count: u32,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<
+    (
+        Option<AcquiredItem>,
+        HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    ),
+    Box<dyn std::error::Error>,
+> {
+    let tp_listings_map = fetch_acquisition_listings(&[item_id], recipes_map, notify).await?;
+
+    let mut purchased_ingredients = Default::default();
+    let acquired_item = calculate_acquisition_cost(
+        item_id,
+        count,
+        recipes_map,
+        items_map,
+        &tp_listings_map,
+        Some(&mut purchased_ingredients),
+        &CONFIG.crafting,
+    );
+
+    Ok((acquired_item, purchased_ingredients))
+}
+
+pub async fn calc_feasibility(
+    item_id: u32,
+    count: u32,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+    known_recipes: &Option<HashSet<u32>>,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<Feasibility, Box<dyn std::error::Error>> {
+    let tp_listings_map = fetch_acquisition_listings(&[item_id], recipes_map, notify).await?;
+    Ok(feasibility(
+        item_id,
+        count,
+        recipes_map,
+        items_map,
+        &tp_listings_map,
+        known_recipes,
+        &CONFIG.crafting,
+    ))
+}
+
+pub async fn calc_joint_acquisition(
+    item_ids: &[u32],
+    count: u32,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<
+    (
+        Option<JointAcquisition>,
+        HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    ),
+    Box<dyn std::error::Error>,
+> {
+    let tp_listings_map = fetch_acquisition_listings(item_ids, recipes_map, notify).await?;
+
+    let mut purchased_ingredients = Default::default();
+    let joint_acquisition = calculate_joint_acquisition_cost(
+        item_ids,
+        count,
+        recipes_map,
+        items_map,
+        &tp_listings_map,
+        Some(&mut purchased_ingredients),
+        &CONFIG.crafting,
+    );
+
+    Ok((joint_acquisition, purchased_ingredients))
+}
+
+async fn fetch_acquisition_listings(
+    item_ids: &[u32],
+    recipes_map: &HashMap<u32, Recipe>,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<HashMap<u32, api::ItemListings>, Box<dyn std::error::Error>> {
+    let mut request_listing_item_ids = item_ids.to_vec();
+    for item_id in item_ids {
+        if let Some(recipe) = recipes_map.get(item_id) {
+            recipe.collect_ingredient_ids(recipes_map, &mut request_listing_item_ids);
+        }
+    }
+    request_listing_item_ids.sort_unstable();
+    request_listing_item_ids.dedup();
+
+    let tp_listings =
+        request::fetch_item_listings(&request_listing_item_ids, Some(&CONFIG.cache_dir), notify)
+            .await?;
+    Ok(vec_to_map(tp_listings, |x| x.id))
+}
+
+/// Convert listings for use in the precise calculations. Items with less than --min-sell-volume
+/// listed can't be bought from the trading post.
+fn item_listings_map(
+    tp_listings_map: &HashMap<u32, api::ItemListings>,
+    opt: &config::CraftingOptions,
+) -> BTreeMap<u32, ItemListings> {
+    tp_listings_map
+        .clone()
+        .into_iter()
+        .map(|(id, listings)| {
+            let mut listings = ItemListings::from(listings);
+            if !opt.meets_sell_volume(listings.sell_quantity()) {
+                listings.sells.clear();
+            }
+            (id, listings)
+        })
+        .collect()
+}
+
+/// Calculate the lowest cost method to obtain `count` of the given item. This is the crafting
+/// profit calculation without the revenue side.
+pub fn calculate_acquisition_cost(
+    item_id: u32,
+    count: u32,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+    tp_listings_map: &HashMap<u32, api::ItemListings>,
+    purchased_ingredients: Option<
+        &mut HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    >,
+    opt: &config::CraftingOptions,
+) -> Option<AcquiredItem> {
+    let mut tp_listings_map = item_listings_map(tp_listings_map, opt);
+
+    let mut context = crafting::PreciseCraftingCostContext::new(Default::default());
+
+    let crafting::PreciseCraftingCost { cost, source } =
+        crafting::calculate_precise_min_crafting_cost(
+            item_id,
+            count,
+            recipes_map,
+            items_map,
+            &mut tp_listings_map,
+            &mut context,
+            opt,
+        )?;
+
+    finalize_purchases(
+        item_id,
+        &context.purchases,
+        &mut tp_listings_map,
+        purchased_ingredients,
+        opt.audit_listings,
+    );
+
+    let crafting_steps = if source == crafting::Source::Crafting {
+        context.items.crafting_steps(recipes_map).to_integer()
+    } else {
+        0
+    };
+
+    Some(AcquiredItem {
+        id: item_id,
+        count,
+        cost,
+        source,
+        crafting_steps,
+        crafted_items: context.items,
+    })
+}
+
+/// Calculate the lowest cost method to obtain `count` of each of the given items together, so
+/// that ingredients shared between them are crafted in the same batches and leftovers from one
+/// item are used for the next.
+pub fn calculate_joint_acquisition_cost(
+    item_ids: &[u32],
+    count: u32,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+    tp_listings_map: &HashMap<u32, api::ItemListings>,
+    mut purchased_ingredients: Option<
+        &mut HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    >,
+    opt: &config::CraftingOptions,
+) -> Option<JointAcquisition> {
+    let mut separate_outlay = Money::zero();
+    for item_id in item_ids {
+        let acquired_item = calculate_acquisition_cost(
+            *item_id,
+            count,
+            recipes_map,
+            items_map,
+            tp_listings_map,
+            None,
+            opt,
+        )?;
+        separate_outlay += acquired_item.cost + acquired_item.crafted_items.leftovers_value();
+    }
+
+    let mut tp_listings_map = item_listings_map(tp_listings_map, opt);
+
+    let mut context = crafting::PreciseCraftingCostContext::new(Default::default());
+
+    let mut items = vec![];
+    let mut cost = Money::zero();
+    for item_id in item_ids {
+        let item_cost = crafting::calculate_precise_min_crafting_cost(
+            *item_id,
+            count,
+            recipes_map,
+            items_map,
+            &mut tp_listings_map,
+            &mut context,
+            opt,
+        )?;
+
+        // take purchases out of the listings before pricing the next item
+        finalize_purchases(
+            *item_id,
+            &context.purchases,
+            &mut tp_listings_map,
+            purchased_ingredients.as_deref_mut(),
+            opt.audit_listings,
+        );
+        context.purchases.clear();
+
+        cost += item_cost.cost;
+        items.push((*item_id, item_cost.cost, item_cost.source));
+    }
+
+    let crafting_steps = if context.items.crafted.is_empty() {
+        0
+    } else {
+        context.items.crafting_steps(recipes_map).to_integer()
+    };
+
+    Some(JointAcquisition {
+        count,
+        items,
+        cost,
+        outlay: cost + context.items.leftovers_value(),
+        separate_outlay,
+        crafting_steps,
+        crafted_items: context.items,
+    })
+}
+
+pub fn calculate_crafting_profit(
+    item_id: u32,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+    tp_listings_map: &HashMap<u32, api::ItemListings>,
+    mut purchased_ingredients: Option<
+        &mut HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    >,
+    opt: &config::CraftingOptions,
+) -> Option<ProfitableItem> {
+    // the raw listings for the output item, to check the profit independently of `ItemListings`
+    let audit_listings = tp_listings_map
+        .get(&item_id)
+        .filter(|_| opt.audit && opt.value.is_none());
+    let mut tp_listings_map = item_listings_map(tp_listings_map, opt);
+
+    if opt.value.is_none() {
+        let buy_quantity = tp_listings_map
+            .get(&item_id)
+            .map_or(0, |listings| listings.buy_quantity());
+        if !opt.meets_buy_volume(buy_quantity) {
+            return None;
+        }
+    }
+
+    if opt.ignore_top_buys > 0 {
+        if let Some(listings) = tp_listings_map.get_mut(&item_id) {
+            listings.ignore_top_buys(opt.ignore_top_buys);
+        }
+    }
+
+    // only sell into buy orders priced at least as high as the given percentile of their depth
+    let mut below_sale_percentile = false;
+    if let Some(percentile) = opt.sale_percentile.filter(|_| opt.value.is_none()) {
+        if let Some(listings) = tp_listings_map.get_mut(&item_id) {
+            below_sale_percentile = listings.remove_buys_below_percentile(percentile);
+        }
+    }
+
+    let recipe = recipes_map.get(&item_id);
+    let output_item_count = recipe.map(|recipe| recipe.output_item_count).unwrap_or(1);
+    let threshold = Money::from_copper(opt.threshold.unwrap_or(0) as i32);
+    let buy_volume = tp_listings_map
+        .get(&item_id)
+        .map_or(0, |listings| listings.buy_quantity());
 
     let mut listing_profit = Money::zero();
     let mut total_crafting_cost = Money::zero();
+    let mut total_tax = Money::zero();
     let mut crafting_count = 0;
-    let mut crafted_items = crafting::CraftedItems::default();
+    let mut sold_count = 0;
+    // items crafted by every profitable batch so far, starting with the owned items
+    let mut context = crafting::PreciseCraftingCostContext::new(crafting::CraftedItems::owned(
+        &opt.inventory,
+        item_id,
+    ));
 
     let mut min_sell = 0;
     let max_sell = tp_listings_map.get(&item_id).map_or_else(
@@ -241,20 +1258,34 @@ pub fn calculate_crafting_profit(
         |listings| listings.buys.last().map_or(0, |l| l.unit_price),
     );
     let mut breakeven = Money::zero();
+    let mut first_crafting_cost = Money::zero();
+    let mut first_sale_price = Money::zero();
+    let mut next_crafting_cost = None;
+    // the number crafted so far with the best return on cost, which can be reached before the
+    // profit peaks when the last batches are barely profitable
+    let mut count_max_roi = 0;
+    let mut profit_max_roi = Money::zero();
+    let mut crafting_cost_max_roi = Money::zero();
+    let mut sales: Vec<(SaleVenue, u32)> = vec![];
+    let limited_by;
 
     // simulate crafting 1 item per loop iteration until it becomes unprofitable
     loop {
+        context.start_batch();
+
+        // number of crafted items to sell, with any remainder kept as leftovers
+        let mut sell_count = output_item_count;
         if let Some(count) = opt.count {
             if crafting_count + output_item_count > count {
-                break;
+                if opt.allow_overcraft && crafting_count < count {
+                    sell_count = count - crafting_count;
+                } else {
+                    limited_by = CraftingLimit::Count;
+                    break;
+                }
             }
         }
 
-        let mut context = crafting::PreciseCraftingCostContext {
-            purchases: vec![],
-            items: crafted_items.clone(),
-        };
-
         let crafting_cost = if let Some(crafting::PreciseCraftingCost {
             source: crafting::Source::Crafting,
             cost,
@@ -269,88 +1300,150 @@ pub fn calculate_crafting_profit(
         ) {
             cost
         } else {
+            limited_by = CraftingLimit::Supply;
             break;
         };
 
-        let (buy_price, min_buy) = if let Some(price) = opt.value {
-            (Money::from_copper(price as i32) * output_item_count, price)
-        } else if let Some((buy_price, min_buy)) = tp_listings_map
-            .get_mut(&item_id)
-            .unwrap_or_else(|| panic!("Missing listings for item id: {}", item_id))
-            .sell(output_item_count)
-        {
-            (buy_price, min_buy)
+        let mut sale_venue = None;
+        let (buy_price, tax, min_buy) = if let Some(price) = opt.value {
+            (
+                Money::from_copper(price as i32) * sell_count,
+                Money::zero(),
+                price,
+            )
+        } else if let Some(sale) = sell_batch(
+            sell_count,
+            sales
+                .iter()
+                .filter(|&&(venue, _)| venue == SaleVenue::Listing)
+                .map(|&(_, count)| count)
+                .sum(),
+            items_map.get(&item_id),
+            tp_listings_map.get_mut(&item_id),
+            opt,
+        ) {
+            sale_venue = Some(sale.venue);
+            (
+                sale.revenue,
+                sale.gross_revenue - sale.revenue,
+                sale.min_price,
+            )
         } else {
+            limited_by = if below_sale_percentile {
+                CraftingLimit::SalePercentile
+            } else {
+                CraftingLimit::Demand
+            };
             break;
         };
 
         // Ensure buy_price is larger before subtracting cost for profit
         if buy_price < crafting_cost + threshold {
+            next_crafting_cost = Some(crafting_cost / output_item_count);
+            // whichever moved further since the first batch stopped crafting from being profitable
+            let cost_increase = crafting_cost / output_item_count - first_crafting_cost;
+            let price_decrease = first_sale_price - buy_price / sell_count;
+            limited_by = if crafting_count == 0 || cost_increase >= price_decrease {
+                CraftingLimit::Supply
+            } else {
+                CraftingLimit::Demand
+            };
             break;
         }
 
+        if let Some(sale_venue) = sale_venue {
+            match sales.last_mut() {
+                Some((venue, count)) if *venue == sale_venue => *count += sell_count,
+                _ => sales.push((sale_venue, sell_count)),
+            }
+        }
         listing_profit += buy_price - crafting_cost;
         total_crafting_cost += crafting_cost;
+        total_tax += tax;
         crafting_count += output_item_count;
-        crafted_items = context.items;
+        sold_count += sell_count;
+        if count_max_roi == 0
+            || listing_profit.percent(total_crafting_cost)
+                > profit_max_roi.percent(crafting_cost_max_roi)
+        {
+            count_max_roi = crafting_count;
+            profit_max_roi = listing_profit;
+            crafting_cost_max_roi = total_crafting_cost;
+        }
+        if sell_count < output_item_count {
+            context.items.leftovers.insert(
+                item_id,
+                (
+                    output_item_count - sell_count,
+                    crafting_cost / output_item_count,
+                    crafting::Source::Crafting,
+                ),
+            );
+        }
 
         min_sell = min_buy;
+        if crafting_count == output_item_count {
+            first_crafting_cost = crafting_cost / output_item_count;
+            first_sale_price = buy_price / sell_count;
+        }
         // Breakeven is based on the last/most expensive to craft
         breakeven = crafting_cost / output_item_count;
 
-        // Finalize purchases
-        for (purchase_id, count, purchase_source) in &context.purchases {
-            let (cost, min_sell, max_sell) = if let crafting::Source::TradingPost = *purchase_source
-            {
-                let listing = tp_listings_map.get_mut(purchase_id).unwrap_or_else(|| {
-                    panic!(
-                        "Missing listings for ingredient {} of item id {}",
-                        purchase_id, item_id
-                    )
-                });
-                listing.pending_buy_quantity -= *count;
-                let (cost, min_sell, max_sell) = listing.buy(*count).unwrap_or_else(|| {
-                    panic!(
-                        "Expected to be able to buy {} of ingredient {} for item id {}",
-                        count, purchase_id, item_id
-                    )
-                });
-                (cost, min_sell, max_sell)
-            } else {
-                (0, 0, 0)
-            };
-
-            if let Some(purchased_ingredients) = &mut purchased_ingredients {
-                let ingredient = purchased_ingredients
-                    .entry((*purchase_id, *purchase_source))
-                    .or_insert_with(|| crafting::PurchasedIngredient {
-                        count: 0,
-                        max_price: Money::default(),
-                        min_price: Money::default(),
-                        total_cost: Money::default(),
-                    });
-                ingredient.count += count;
-                if ingredient.min_price.is_zero() {
-                    ingredient.min_price = Money::from_copper(min_sell as i32);
-                }
-                ingredient.max_price = Money::from_copper(max_sell as i32);
-                ingredient.total_cost += Money::from_copper(cost as i32);
-            }
-        }
+        finalize_purchases(
+            item_id,
+            &context.purchases,
+            &mut tp_listings_map,
+            purchased_ingredients.as_deref_mut(),
+            opt.audit_listings,
+        );
         debug_assert!(tp_listings_map
             .iter()
             .all(|(_, listing)| listing.pending_buy_quantity == 0));
     }
 
-    if crafting_count > 0 && !listing_profit.is_zero() {
+    // the batch that stopped crafting isn't made
+    context.undo_batch();
+    let mut crafted_items = context.items;
+    crafted_items.remove_unused_owned();
+
+    // each crafted batch must have made at least the threshold in profit
+    debug_assert!(listing_profit >= Money::zero());
+    // the audit re-sells into the buy orders, so it only applies when all were sold there
+    let sold_to_buy_orders = sales.iter().all(|&(venue, _)| venue == SaleVenue::BuyOrder);
+    if let Some(listings) = audit_listings.filter(|_| crafting_count > 0 && sold_to_buy_orders) {
+        audit_profit(
+            item_id,
+            sold_count,
+            listing_profit,
+            total_crafting_cost,
+            listings,
+            opt,
+        );
+    }
+
+    // is_zero only looks at the coins, so the profit is zero when the coins made exactly pay for
+    // the ingredients, even if currencies were spent on them
+    if crafting_count > 0 && (opt.include_breakeven || !listing_profit.is_zero()) {
         Some(ProfitableItem {
             id: item_id,
             crafting_cost: total_crafting_cost,
             profit: listing_profit,
+            tax: total_tax,
             count: crafting_count,
+            count_max_roi,
+            profit_max_roi,
             max_sell: Money::from_copper(max_sell as i32),
             min_sell: Money::from_copper(min_sell as i32),
-            breakeven: breakeven.trading_post_listing_price(),
+            buy_volume,
+            breakeven: breakeven
+                .trading_post_listing_price_with_fees(opt.listing_fee(), opt.exchange_fee()),
+            first_crafting_cost,
+            next_crafting_cost,
+            estimate_delta: None,
+            limited_by,
+            sales,
+            recipe_cost: Money::zero(),
+            storage_cost: Money::zero(),
             crafting_steps: crafted_items.crafting_steps(recipes_map).to_integer(),
             crafted_items,
         })
@@ -359,19 +1452,254 @@ pub fn calculate_crafting_profit(
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// Sale of a batch of crafted items
+struct BatchSale {
+    venue: SaleVenue,
+    /// Revenue after any trading post fees and listing hold cost
+    revenue: Money,
+    gross_revenue: Money,
+    /// Lowest price an item was sold for
+    min_price: u32,
+}
+
+/// Sell `count` crafted items to whichever of the venues allowed by --sale-venues pays the most
+/// for them, preferring buy orders and then listing on ties as they sell sooner. `listed` items
+/// were already listed by earlier batches. Returns None if no venue can take them.
+fn sell_batch(
+    count: u32,
+    listed: u32,
+    item: Option<&Item>,
+    listings: Option<&mut ItemListings>,
+    opt: &config::CraftingOptions,
+) -> Option<BatchSale> {
+    let mut sales = vec![];
+    if opt.sells_to(SaleVenue::BuyOrder) {
+        // priced on a copy, as the buy orders are only sold into if they pay the most
+        let buy_order_sale = listings
+            .as_deref()
+            .and_then(|listings| listings.clone().sell(count, opt));
+        if let Some((revenue, gross_revenue, min_price)) = buy_order_sale {
+            sales.push(BatchSale {
+                venue: SaleVenue::BuyOrder,
+                revenue,
+                gross_revenue,
+                min_price,
+            });
+        }
+    }
+    if opt.sells_to(SaleVenue::Listing) {
+        // undercut the lowest listing by 1 copper, listing no more than the quantity already
+        // listed at that price, which shows how many can sell at it; sells are sorted in
+        // descending price
+        let price = listings
+            .as_deref()
+            .and_then(|listings| listings.sells.last())
+            .filter(|listing| listing.unit_price > 1 && listed + count <= listing.quantity)
+            .map(|listing| listing.unit_price - 1);
+        if let Some(price) = price {
+            let gross_revenue = Money::from_copper(price as i32);
+            sales.push(BatchSale {
+                venue: SaleVenue::Listing,
+                revenue: opt.listing_revenue(gross_revenue) * count,
+                gross_revenue: gross_revenue * count,
+                min_price: price,
+            });
+        }
+    }
+    if opt.sells_to(SaleVenue::Vendor) {
+        if let Some(vendor_value) = item.and_then(|item| item.vendor_sell_value()) {
+            sales.push(BatchSale {
+                venue: SaleVenue::Vendor,
+                revenue: vendor_value * count,
+                gross_revenue: vendor_value * count,
+                min_price: vendor_value.to_copper_value() as u32,
+            });
+        }
+    }
+
+    let sale = sales
+        .into_iter()
+        .rev()
+        .max_by(|a, b| a.revenue.cmp(&b.revenue))?;
+    if sale.venue == SaleVenue::BuyOrder {
+        listings?.sell(count, opt)?;
+    }
+    Some(sale)
+}
+
+/// Warn if the profit accumulated while crafting differs from the profit of selling all crafted
+/// items in one go, which would indicate a problem with fee or rounding calculations. The revenue
+/// is totalled straight from the API listings rather than through `ItemListings::sell`.
+fn audit_profit(
+    item_id: u32,
+    count: u32,
+    profit: Money,
+    crafting_cost: Money,
+    listings: &api::ItemListings,
+    opt: &config::CraftingOptions,
+) {
+    if profit < Money::zero() {
+        eprintln!("Audit: item {} has negative profit {}", item_id, profit);
+    }
+
+    let mut buys: Vec<&api::Listing> = listings.buys.iter().collect();
+    buys.sort_unstable_by_key(|listing| std::cmp::Reverse(listing.unit_price));
+    let mut revenue = Money::zero();
+    let mut unsold = count;
+    for listing in buys.into_iter().skip(opt.ignore_top_buys as usize) {
+        let sold = unsold.min(listing.quantity);
+        revenue += opt.sale_revenue(Money::from_copper(listing.unit_price as i32)) * sold;
+        unsold -= sold;
+        if unsold == 0 {
+            break;
+        }
+    }
+    if unsold > 0 {
+        eprintln!(
+            "Audit: item {} cannot sell {} items to buy orders",
+            item_id, count
+        );
+        return;
+    }
+
+    let discrepancy = (revenue - crafting_cost - profit).to_copper_value().abs();
+    if discrepancy > 1 {
+        eprintln!(
+            "Audit: item {} profit {} differs from revenue {} - cost {} by {} copper",
+            item_id, profit, revenue, crafting_cost, discrepancy
+        );
+    }
+}
+
+/// Buy the marked purchases from the listings and record them as purchased ingredients, at the
+/// cost they were priced at when choosing how to obtain them
+fn finalize_purchases(
+    item_id: u32,
+    purchases: &[(u32, u32, crafting::Source, Money)],
+    tp_listings_map: &mut BTreeMap<u32, ItemListings>,
+    mut purchased_ingredients: Option<
+        &mut HashMap<(u32, crafting::Source), crafting::PurchasedIngredient>,
+    >,
+    record_listings: bool,
+) {
+    for (purchase_id, count, purchase_source, purchase_cost) in purchases {
+        let mut listings = vec![];
+        let (min_sell, max_sell) = match (*purchase_source, tp_listings_map.get_mut(purchase_id)) {
+            (crafting::Source::TradingPost, Some(listing)) => {
+                listing.pending_buy_quantity -= *count;
+                let consumed = if record_listings && purchased_ingredients.is_some() {
+                    Some(&mut listings)
+                } else {
+                    None
+                };
+                let (_, min_sell, max_sell) = listing.buy(*count, consumed).unwrap_or_else(|| {
+                    panic!(
+                        "Expected to be able to buy {} of ingredient {} for item id {}",
+                        count, purchase_id, item_id
+                    )
+                });
+                (min_sell, max_sell)
+            }
+            (crafting::Source::TradingPost, None) => {
+                // the purchase is already part of the crafting cost, so it stays on the shopping
+                // list at the price it was costed at
+                let unit_price = (*purchase_cost / *count).to_copper_value() as u32;
+                eprintln!(
+                    "Missing listings for ingredient {} of item id {}, listing its purchase at {}",
+                    purchase_id,
+                    item_id,
+                    Money::from_copper(unit_price as i32)
+                );
+                (unit_price, unit_price)
+            }
+            _ => (0, 0),
+        };
+
+        if let Some(purchased_ingredients) = &mut purchased_ingredients {
+            let ingredient = purchased_ingredients
+                .entry((*purchase_id, *purchase_source))
+                .or_insert_with(|| crafting::PurchasedIngredient {
+                    count: 0,
+                    max_price: Money::default(),
+                    min_price: Money::default(),
+                    total_cost: Money::default(),
+                    listings: vec![],
+                });
+            ingredient.count += count;
+            for (unit_price, quantity) in listings {
+                add_listing(&mut ingredient.listings, unit_price, quantity);
+            }
+            if ingredient.min_price.is_zero() {
+                ingredient.min_price = Money::from_copper(min_sell as i32);
+            }
+            ingredient.max_price = Money::from_copper(max_sell as i32);
+            ingredient.total_cost += *purchase_cost;
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ProfitableItem {
     pub id: u32,
     pub crafting_cost: Money,
     pub count: u32,
     pub profit: Money,
+    /// Number to craft for the highest return on cost, which is at most `count`
+    pub count_max_roi: u32,
+    /// Profit of crafting `count_max_roi` items, before any recipe cost
+    pub profit_max_roi: Money,
+    /// Trading post fees paid when selling
+    pub tax: Money,
     pub max_sell: Money,
+    /// Price of the cheapest buy order sold into
     pub min_sell: Money,
+    /// Total quantity of buy orders for the item before any were sold into, to judge how quickly
+    /// `count` items could be sold
+    pub buy_volume: u32,
     pub breakeven: Money,
+    /// Crafting cost per item of the first and cheapest batch
+    pub first_crafting_cost: Money,
+    /// Crafting cost per item of the next batch, if crafting more stopped being profitable
+    pub next_crafting_cost: Option<Money>,
+    /// How much more the first batch cost than estimated from the prices used to find the item,
+    /// which is non-zero if prices moved before the detailed listings were fetched
+    pub estimate_delta: Option<Money>,
+    /// Why more items weren't crafted
+    pub limited_by: CraftingLimit,
+    /// Number of items sold to each venue, in the order they were first sold to. Empty with --value
+    pub sales: Vec<(SaleVenue, u32)>,
+    /// One-time cost of recipe sheets needed for the craft, already subtracted from `profit`
+    pub recipe_cost: Money,
+    /// Cost of the inventory slots needed for the craft at --slot-cost, already subtracted from
+    /// `profit`
+    pub storage_cost: Money,
     pub crafting_steps: u32,
     pub crafted_items: crafting::CraftedItems,
 }
 
+/// Crafted items kept for personal use instead of sold
+#[derive(Debug, Eq, PartialEq)]
+pub struct KeptProfit {
+    pub kept: u32,
+    /// Value of the kept items at the buy order prices they would have sold for, before fees
+    pub value: Money,
+    /// Profit including the value of the kept items
+    pub profit: Money,
+}
+
+/// What stopped more of an item from being crafted profitably
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CraftingLimit {
+    /// The requested --count was reached
+    Count,
+    /// All the buy orders priced within --sale-percentile were sold into
+    SalePercentile,
+    /// Ingredients ran out or became too expensive
+    Supply,
+    /// Buy orders ran out or their prices became too low
+    Demand,
+}
+
 impl ProfitableItem {
     pub fn profit_per_item(&self) -> Money {
         self.profit / self.count
@@ -381,9 +1709,424 @@ impl ProfitableItem {
         self.profit / self.crafting_steps
     }
 
+    pub fn profit_per_hour(&self, crafts_per_hour: u32) -> Money {
+        self.profit_per_crafting_step() * crafts_per_hour
+    }
+
+    /// Profit if the first `keep` items are kept instead of sold. They are valued at the price of
+    /// the buy orders they would have been sold into, but without the trading post fees, which
+    /// are saved by keeping them. Items valued by --value are valued the same whether kept or not.
+    /// Returns `None` if there aren't enough buy orders to value the kept items.
+    pub fn profit_keeping(
+        &self,
+        keep: u32,
+        tp_listings: Option<&api::ItemListings>,
+        opt: &config::CraftingOptions,
+    ) -> Option<KeptProfit> {
+        let kept = keep.min(self.count);
+        if let Some(value) = opt.value {
+            return Some(KeptProfit {
+                kept,
+                value: Money::from_copper(value as i32) * kept,
+                profit: self.profit,
+            });
+        }
+
+        let mut listings = ItemListings::from(tp_listings?.clone());
+        if opt.ignore_top_buys > 0 {
+            listings.ignore_top_buys(opt.ignore_top_buys);
+        }
+        let (revenue, gross_revenue, _) = listings.sell(kept, opt)?;
+        Some(KeptProfit {
+            kept,
+            value: gross_revenue,
+            profit: self.profit + gross_revenue - revenue,
+        })
+    }
+
     pub fn profit_on_cost(&self) -> f64 {
         self.profit.percent(self.crafting_cost)
     }
+
+    /// Whether the profit is at least the given percentage of the crafting cost. Compared exactly
+    /// instead of using `profit_on_cost`, with other currencies valued at their configured rates.
+    pub fn meets_min_roi(&self, percent: u32) -> bool {
+        self.profit
+            .is_at_least_percent_of(self.crafting_cost, percent)
+    }
+
+    pub fn profit_before_recipe_cost(&self) -> Money {
+        self.profit + self.recipe_cost
+    }
+
+    /// Subtract the one-time cost of recipe sheets from the total profit, spreading it across
+    /// every item crafted
+    pub fn include_recipe_cost(&mut self, recipe_cost: Money) {
+        self.profit = self.profit_before_recipe_cost() - recipe_cost;
+        self.recipe_cost = recipe_cost;
+    }
+
+    /// Subtract the cost of the inventory slots needed for the craft from the total profit
+    pub fn include_storage_cost(&mut self, storage_cost: Money) {
+        self.profit = self.profit + self.storage_cost - storage_cost;
+        self.storage_cost = storage_cost;
+    }
+
+    /// Whether prices moved between estimating the crafting cost and calculating it precisely
+    pub fn price_moved(&self) -> bool {
+        self.estimate_delta
+            .map(|delta| delta.to_copper_value() != 0)
+            .unwrap_or(false)
+    }
+}
+
+/// An item which is more profitable with recipes that haven't been learned yet
+#[derive(Debug)]
+pub struct MissedProfit {
+    pub id: u32,
+    /// Profit with every recipe known
+    pub profit: Money,
+    /// Profit with only the known recipes, if it is still profitable
+    pub known_profit: Option<Money>,
+    pub unknown_recipes: Vec<u32>,
+    /// Cost of the recipe sheets for the unknown recipes that can be bought
+    pub unlock_cost: Money,
+}
+
+impl MissedProfit {
+    pub fn missed_profit(&self) -> Money {
+        self.profit - self.known_profit.unwrap_or_else(Money::zero)
+    }
+}
+
+/// A recipe which converts currencies into an account bound token, per craft
+#[derive(Debug, Eq, PartialEq)]
+pub struct CurrencyConversion {
+    pub id: u32,
+    pub cost: Money,
+    pub value: Money,
+    pub profit: Money,
+}
+
+/// A profitable material promotion, estimated from current prices
+#[derive(Debug)]
+pub struct PromotionProfit {
+    pub id: u32,
+    /// Conservative estimate of the number of items produced, which is random
+    pub output_count: u32,
+    pub cost: Money,
+    /// Value of the output after trading post fees
+    pub value: Money,
+    pub profit: Money,
+    /// Profit from the average number of items produced
+    pub expected_profit: Money,
+    /// Standard deviation of the profit, from the randomness of the number of items produced
+    pub profit_std_dev: Money,
+    /// Profit made with at least the probability given by --confidence
+    pub confident_profit: Option<Money>,
+    /// Ingredients which have to be obtained elsewhere, not included in the cost
+    pub unpriced_ingredients: Vec<api::RecipeIngredient>,
+}
+
+/// Probabilities of the number of items produced by a recipe with a random output
+#[derive(Debug)]
+pub struct OutputDistribution {
+    /// Pairs of item count and probability, in order of increasing count
+    outcomes: Vec<(u32, f64)>,
+}
+
+impl OutputDistribution {
+    pub fn fixed(count: u32) -> Self {
+        OutputDistribution {
+            outcomes: vec![(count, 1.0)],
+        }
+    }
+
+    /// Only the average output of random recipes is known, so assume the narrowest distribution
+    /// with that average: the whole numbers either side of it. The real spread is likely wider,
+    /// so risk derived from this is a lower bound.
+    pub fn from_average(average: f64) -> Self {
+        let low = average.floor();
+        let high_probability = average - low;
+        if high_probability == 0.0 {
+            return OutputDistribution::fixed(low as u32);
+        }
+        OutputDistribution {
+            outcomes: vec![
+                (low as u32, 1.0 - high_probability),
+                (low as u32 + 1, high_probability),
+            ],
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.outcomes
+            .iter()
+            .map(|&(count, probability)| f64::from(count) * probability)
+            .sum()
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        self.outcomes
+            .iter()
+            .map(|&(count, probability)| (f64::from(count) - mean).powi(2) * probability)
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    /// The highest count which is produced or exceeded with at least the given probability
+    pub fn count_with_probability(&self, probability: f64) -> u32 {
+        let mut exceeding_probability = 0.0;
+        for &(count, outcome_probability) in self.outcomes.iter().rev() {
+            exceeding_probability += outcome_probability;
+            // allow for rounding errors in the sum
+            if exceeding_probability + 1e-9 >= probability {
+                return count;
+            }
+        }
+        self.outcomes.first().map_or(0, |&(count, _)| count)
+    }
+}
+
+/// An item which can be crafted and listed for less than the lowest sell listing
+#[derive(Debug, Eq, PartialEq)]
+pub struct UndercutItem {
+    pub id: u32,
+    pub crafting_cost: Money,
+    pub sell_price: Money,
+    /// Estimated profit per item after trading post fees
+    pub profit: Money,
+}
+
+/// An item which can be crafted for less than a merchant pays for it
+#[derive(Debug, Eq, PartialEq)]
+pub struct VendorSellItem {
+    pub id: u32,
+    pub crafting_cost: Money,
+    pub vendor_value: Money,
+    /// Estimated profit per item, without trading post fees
+    pub profit: Money,
+}
+
+/// Where a crafted item is sold
+#[derive(
+    Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, EnumString, EnumVariantNames,
+)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum SaleVenue {
+    /// Sold instantly to the highest buy order
+    BuyOrder,
+    /// Listed on the trading post 1 copper below the lowest sell listing
+    Listing,
+    /// Sold to a merchant
+    #[strum(serialize = "merchant")]
+    #[serde(rename = "merchant")]
+    Vendor,
+}
+
+impl fmt::Display for SaleVenue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaleVenue::BuyOrder => write!(f, "buy order"),
+            SaleVenue::Listing => write!(f, "listing"),
+            SaleVenue::Vendor => write!(f, "merchant"),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct VenueProfit {
+    pub venue: SaleVenue,
+    /// Revenue per item after any trading post fees
+    pub revenue: Money,
+    pub profit: Money,
+}
+
+/// An item which is profitable to craft, with the venue it is most profitable to sell to
+#[derive(Debug, Eq, PartialEq)]
+pub struct BestVenueItem {
+    pub id: u32,
+    pub crafting_cost: Money,
+    pub best_venue: VenueProfit,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct AcquiredItem {
+    pub id: u32,
+    pub count: u32,
+    pub cost: Money,
+    pub source: crafting::Source,
+    pub crafting_steps: u32,
+    pub crafted_items: crafting::CraftedItems,
+}
+
+impl AcquiredItem {
+    pub fn cost_per_item(&self) -> Money {
+        self.cost / self.count
+    }
+}
+
+/// Whether `count` of an item can be obtained with the current trading post supply and known
+/// recipes, and what stands in the way if not
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct Feasibility {
+    pub item_id: u32,
+    pub count: u32,
+    pub feasible: bool,
+    /// Most of the item which can be obtained, up to `count`
+    pub max_count: u32,
+    /// Cost of obtaining `max_count`
+    #[serde(serialize_with = "serialize_money_option")]
+    pub cost: Option<Money>,
+    pub source: Option<crafting::Source>,
+    /// Whether known recipes were available to check; if not, `unknown_recipes` is empty
+    pub recipes_checked: bool,
+    pub unknown_recipes: Vec<u32>,
+    pub currency_gated: Vec<CurrencyGatedIngredient>,
+}
+
+/// An ingredient which can't be bought with coins, so is limited by the currencies or tokens
+/// available
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct CurrencyGatedIngredient {
+    pub item_id: u32,
+    pub count: u32,
+    #[serde(serialize_with = "serialize_money")]
+    pub cost: Money,
+    /// Account bound tokens have to be farmed rather than bought from a vendor
+    pub farmed: bool,
+}
+
+fn serialize_money<S>(money: &Money, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    use serde::ser::SerializeStruct;
+    let mut state = serializer.serialize_struct("Money", 2)?;
+    state.serialize_field("copper_value", &money.to_copper_value())?;
+    state.serialize_field("text", &money.to_string())?;
+    state.end()
+}
+
+fn serialize_money_option<S>(money: &Option<Money>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match money {
+        Some(money) => serialize_money(money, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Check whether `count` of an item can be obtained before committing to a plan: the most that
+/// the trading post listings can supply, the recipes still to be learned, and the ingredients
+/// which are limited by currencies or account bound tokens
+pub fn feasibility(
+    item_id: u32,
+    count: u32,
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+    tp_listings_map: &HashMap<u32, api::ItemListings>,
+    known_recipes: &Option<HashSet<u32>>,
+    opt: &config::CraftingOptions,
+) -> Feasibility {
+    let acquire = |count, purchased_ingredients: Option<&mut _>| {
+        calculate_acquisition_cost(
+            item_id,
+            count,
+            recipes_map,
+            items_map,
+            tp_listings_map,
+            purchased_ingredients,
+            opt,
+        )
+    };
+
+    let mut purchased_ingredients = HashMap::new();
+    let mut acquired_item = acquire(count, Some(&mut purchased_ingredients));
+    let max_count = if acquired_item.is_some() {
+        count
+    } else {
+        // supply only runs out as more is obtained, so search for the most which can be
+        let (mut obtainable, mut unobtainable) = (0, count);
+        while unobtainable - obtainable > 1 {
+            let mid = obtainable + (unobtainable - obtainable) / 2;
+            if acquire(mid, None).is_some() {
+                obtainable = mid;
+            } else {
+                unobtainable = mid;
+            }
+        }
+        if obtainable > 0 {
+            acquired_item = acquire(obtainable, Some(&mut purchased_ingredients));
+        }
+        obtainable
+    };
+
+    let mut unknown_recipes: Vec<u32> = match (&acquired_item, known_recipes) {
+        (Some(acquired_item), Some(_)) => acquired_item
+            .crafted_items
+            .unknown_recipes(recipes_map, known_recipes)
+            .into_iter()
+            .collect(),
+        _ => vec![],
+    };
+    unknown_recipes.sort_unstable();
+
+    let mut currency_gated: Vec<CurrencyGatedIngredient> = purchased_ingredients
+        .iter()
+        .filter(|((_, source), _)| *source == crafting::Source::Vendor)
+        .filter_map(|((ingredient_id, _), ingredient)| {
+            let farmed = matches!(items_map.get(ingredient_id), Some(item) if item.is_farmed());
+            if farmed || ingredient.total_cost.has_currencies() {
+                Some(CurrencyGatedIngredient {
+                    item_id: *ingredient_id,
+                    count: ingredient.count,
+                    cost: ingredient.total_cost,
+                    farmed,
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+    currency_gated.sort_unstable_by_key(|ingredient| ingredient.item_id);
+
+    Feasibility {
+        item_id,
+        count,
+        feasible: max_count == count && unknown_recipes.is_empty(),
+        max_count,
+        cost: acquired_item.as_ref().map(|item| item.cost),
+        source: acquired_item.as_ref().map(|item| item.source),
+        recipes_checked: known_recipes.is_some(),
+        unknown_recipes,
+        currency_gated,
+    }
+}
+
+/// Several items obtained together, sharing crafted ingredients
+#[derive(Debug, Eq, PartialEq)]
+pub struct JointAcquisition {
+    /// Number obtained of each item
+    pub count: u32,
+    /// Cost and source of each item
+    pub items: Vec<(u32, Money, crafting::Source)>,
+    pub cost: Money,
+    /// Money spent, including on leftovers
+    pub outlay: Money,
+    /// Money spent when obtaining each item on its own
+    pub separate_outlay: Money,
+    pub crafting_steps: u32,
+    pub crafted_items: crafting::CraftedItems,
+}
+
+impl JointAcquisition {
+    pub fn savings(&self) -> Money {
+        self.separate_outlay - self.outlay
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -400,8 +2143,24 @@ pub struct Listing {
     pub quantity: u32,
 }
 
+/// Add bought items to a list of listings, merging them with the last listing at the same price
+fn add_listing(listings: &mut Vec<(u32, u32)>, unit_price: u32, quantity: u32) {
+    match listings.last_mut() {
+        Some((last_price, last_quantity)) if *last_price == unit_price => {
+            *last_quantity += quantity;
+        }
+        _ => listings.push((unit_price, quantity)),
+    }
+}
+
 impl ItemListings {
-    fn buy(&mut self, mut count: u32) -> Option<(u32, u32, u32)> {
+    /// Returns the total cost and the lowest and highest prices paid, recording each listing
+    /// bought from in `consumed` if given
+    fn buy(
+        &mut self,
+        mut count: u32,
+        mut consumed: Option<&mut Vec<(u32, u32)>>,
+    ) -> Option<(u32, u32, u32)> {
         let mut cost = 0;
         let mut min_sell = 0;
         let mut max_sell = 0;
@@ -416,6 +2175,9 @@ impl ItemListings {
                 }
                 max_sell = listing.unit_price;
                 cost += listing.unit_price;
+                if let Some(consumed) = consumed.as_deref_mut() {
+                    add_listing(consumed, listing.unit_price, 1);
+                }
                 listing.quantity.is_zero()
             } else {
                 return None;
@@ -429,8 +2191,14 @@ impl ItemListings {
         Some((cost, min_sell, max_sell))
     }
 
-    fn sell(&mut self, mut count: u32) -> Option<(Money, u32)> {
+    /// Returns revenue after fees, revenue before fees and the lowest buy order sold into
+    fn sell(
+        &mut self,
+        mut count: u32,
+        opt: &config::CraftingOptions,
+    ) -> Option<(Money, Money, u32)> {
         let mut revenue = Money::zero();
+        let mut gross_revenue = Money::zero();
         let mut min_buy = 0;
 
         while count > 0 {
@@ -439,8 +2207,8 @@ impl ItemListings {
                 listing.quantity -= 1;
                 count -= 1;
                 min_buy = listing.unit_price;
-                revenue +=
-                    Money::from_copper(listing.unit_price as i32).trading_post_sale_revenue();
+                revenue += opt.sale_revenue(Money::from_copper(listing.unit_price as i32));
+                gross_revenue += Money::from_copper(listing.unit_price as i32);
                 listing.quantity.is_zero()
             } else {
                 return None;
@@ -451,7 +2219,69 @@ impl ItemListings {
             }
         }
 
-        Some((revenue, min_buy))
+        Some((revenue, gross_revenue, min_buy))
+    }
+
+    /// Remove the buy orders at the `count` highest prices, so that they aren't sold into
+    pub fn ignore_top_buys(&mut self, count: u32) {
+        // buys are sorted in ascending price
+        let remaining = self.buys.len().saturating_sub(count as usize);
+        self.buys.truncate(remaining);
+    }
+
+    /// Remove the buy orders priced below the buy order at the given percentile of buy order
+    /// depth, counted from the highest price. Returns whether any were removed.
+    pub fn remove_buys_below_percentile(&mut self, percentile: u32) -> bool {
+        let quantity: u64 = self
+            .buys
+            .iter()
+            .map(|listing| u64::from(listing.quantity))
+            .sum();
+        // rounded up, so that the highest buy order is always kept
+        let depth = Ratio::new(quantity * u64::from(percentile), 100)
+            .ceil()
+            .to_integer();
+
+        // buys are sorted in ascending price
+        let mut reached = 0;
+        let kept = self
+            .buys
+            .iter()
+            .rev()
+            .take_while(|listing| {
+                let keep = reached < depth;
+                reached += u64::from(listing.quantity);
+                keep
+            })
+            .count();
+        let removed = self.buys.len() - kept;
+        self.buys.drain(..removed);
+        removed > 0
+    }
+
+    /// Total quantity of all buy orders
+    pub fn buy_quantity(&self) -> u32 {
+        self.buys.iter().map(|listing| listing.quantity).sum()
+    }
+
+    /// Total quantity of all sell listings
+    pub fn sell_quantity(&self) -> u32 {
+        self.sells.iter().map(|listing| listing.quantity).sum()
+    }
+
+    /// Cost of buying `quantity` items, at the average price of the cheapest listings covering both
+    /// these items and any pending purchases, rather than at the marginal price
+    pub fn average_sell_offer(&self, quantity: u32) -> Option<Money> {
+        debug_assert!(!quantity.is_zero());
+
+        let total_quantity = self.pending_buy_quantity + quantity;
+        let total_cost = ItemListings {
+            pending_buy_quantity: 0,
+            ..self.clone()
+        }
+        .lowest_sell_offer(total_quantity)?;
+
+        Some(Money::from_copper(total_cost as i32) / total_quantity * quantity)
     }
 
     pub fn lowest_sell_offer(&self, mut quantity: u32) -> Option<u32> {
@@ -493,6 +2323,25 @@ impl ItemListings {
             Some(cost)
         }
     }
+
+    /// Number of items, up to `max_quantity`, that can be bought for less than `price` each after
+    /// any pending purchases
+    pub fn quantity_cheaper_than(&self, price: Money, max_quantity: u32) -> u32 {
+        let mut quantity = 0;
+        let mut pending_buy_quantity = self.pending_buy_quantity;
+        for listing in self.sells.iter().rev() {
+            if Money::from_copper(listing.unit_price as i32) >= price {
+                break;
+            }
+            let skipped = pending_buy_quantity.min(listing.quantity);
+            pending_buy_quantity -= skipped;
+            quantity += listing.quantity - skipped;
+            if quantity >= max_quantity {
+                return max_quantity;
+            }
+        }
+        quantity
+    }
 }
 
 impl From<api::ItemListings> for ItemListings {
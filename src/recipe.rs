@@ -1,28 +1,45 @@
 use crate::api;
 use crate::config;
 use crate::gw2efficiency;
+use crate::item::Item;
+use crate::money::Money;
+
+use num_rational::Ratio;
+use num_traits::Zero;
 
 use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum RecipeSource {
     Automatic,
     Discoverable,
     Purchasable,
     Achievement,
+    /// Automatic or discoverable, but no character has the crafting level for it yet
+    LevelLocked,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Recipe {
     pub id: Option<u32>,
     pub output_item_id: u32,
     pub output_item_count: u32,
     pub disciplines: Vec<config::Discipline>,
     pub ingredients: Vec<api::RecipeIngredient>,
+    /// Wallet currencies spent per craft in addition to the item ingredients
+    pub currency_ingredients: Vec<api::RecipeCurrencyIngredient>,
+    /// Average number of items produced, if the number is random and `output_item_count` is
+    /// only its rounded down value
+    pub output_item_average: Option<f64>,
+    /// Number of crafts made each time the recipe is used, which is more than 1 once scaled by
+    /// `with_expected_output`
+    crafts: u32,
     source: RecipeSource,
 }
 
@@ -40,7 +57,10 @@ impl From<api::Recipe> for Recipe {
             output_item_id: recipe.output_item_id,
             output_item_count: recipe.output_item_count,
             disciplines: recipe.disciplines,
-            ingredients: recipe.ingredients,
+            ingredients: merge_duplicate_ingredients(recipe.ingredients),
+            currency_ingredients: vec![],
+            output_item_average: None,
+            crafts: 1,
             source,
         }
     }
@@ -50,13 +70,32 @@ impl TryFrom<gw2efficiency::Recipe> for Recipe {
     type Error = String;
 
     fn try_from(recipe: gw2efficiency::Recipe) -> Result<Self, Self::Error> {
-        let output_item_count = if let Some(count) = recipe.output_item_count {
-            count
-        } else {
-            return Err(format!(
-                "Ignoring '{}'. Failed to parse 'output_item_count' as integer.",
-                recipe.name
-            ));
+        let is_promotion = is_promotion(
+            recipe.output_item_id,
+            &recipe.disciplines,
+            &recipe.ingredients,
+        );
+        let output_item_average = recipe
+            .output_item_count
+            .filter(|count| count.fract() != 0.0);
+        let output_item_count = match recipe.output_item_count {
+            Some(count) if count >= 0.0 && count.fract() == 0.0 => count as u32,
+            // material promotions produce a random number of items, listed as the average, which
+            // is rounded down to be conservative. They use their own output as an ingredient, so
+            // are ignored as recursive when crafting and only evaluated by the promotion scan.
+            Some(count) if count >= 0.0 && is_promotion => count.floor() as u32,
+            // other recipes with a random number of outputs are only used at their average with
+            // --expected-value, see has_random_output
+            Some(count) if count > 0.0 => count.floor() as u32,
+            _ => {
+                // gw2efficiency recipe names are always English, so include the item id for users
+                // of other languages
+                return Err(format!(
+                    "Ignoring custom recipe '{}' for item {}. Failed to parse 'output_item_count' \
+                     as integer.",
+                    recipe.name, recipe.output_item_id
+                ));
+            }
         };
         // Any disciplines _except_ Achievement can be counted as known
         // While some regular discipline precursor recipes must be learned, the
@@ -76,13 +115,63 @@ impl TryFrom<gw2efficiency::Recipe> for Recipe {
             output_item_id: recipe.output_item_id,
             output_item_count,
             disciplines: recipe.disciplines,
-            ingredients: recipe.ingredients,
+            ingredients: merge_duplicate_ingredients(recipe.ingredients),
+            currency_ingredients: recipe.currencies,
+            output_item_average,
+            crafts: 1,
             source,
         })
     }
 }
 
+/// Material promotion recipes upgrade a large number of lower tier materials into higher tier
+/// materials in the Mystic Forge, using one of the higher tier materials as an ingredient
+fn is_promotion(
+    output_item_id: u32,
+    disciplines: &[config::Discipline],
+    ingredients: &[api::RecipeIngredient],
+) -> bool {
+    disciplines.contains(&config::Discipline::MysticForge)
+        && ingredients
+            .iter()
+            .any(|ingredient| ingredient.item_id == output_item_id)
+}
+
+/// Some recipes (e.g. in the Mystic Forge) list the same ingredient more than once. Combine them
+/// so that each ingredient is only priced once, for the total count.
+fn merge_duplicate_ingredients(
+    ingredients: Vec<api::RecipeIngredient>,
+) -> Vec<api::RecipeIngredient> {
+    let mut merged: Vec<api::RecipeIngredient> = Vec::with_capacity(ingredients.len());
+    for ingredient in ingredients {
+        if let Some(existing) = merged
+            .iter_mut()
+            .find(|existing| existing.item_id == ingredient.item_id)
+        {
+            existing.count += ingredient.count;
+        } else {
+            merged.push(ingredient);
+        }
+    }
+    merged
+}
+
 impl Recipe {
+    /// Value of the wallet currencies spent per craft, or `None` if any of them has no configured
+    /// value
+    pub fn wallet_currency_cost(&self) -> Option<Money> {
+        let mut cost = Money::zero();
+        for ingredient in &self.currency_ingredients {
+            cost += Money::from_wallet_currency(ingredient.currency_id, ingredient.count as i32)?;
+        }
+        Some(cost)
+    }
+
+    /// Recipes with no ingredients at all would be free to craft
+    pub fn has_ingredients(&self) -> bool {
+        !self.ingredients.is_empty() || !self.currency_ingredients.is_empty()
+    }
+
     // see https://wiki.guildwars2.com/wiki/Category:Time_gated_recipes
     // for a list of time gated recipes
     // I've left Charged Quartz Crystals off the list, since they can
@@ -105,17 +194,120 @@ impl Recipe {
             || self.output_item_id == 43772 // Charged Quartz Crystal
     }
 
+    /// Whether the recipe produces at least min and at most max items per craft
+    pub fn is_output_count_within(&self, min: Option<u32>, max: Option<u32>) -> bool {
+        self.output_item_count >= min.unwrap_or(0)
+            && self.output_item_count <= max.unwrap_or(u32::MAX)
+    }
+
+    /// Stations at which the recipe can be crafted
+    pub fn stations(&self) -> Vec<config::Station> {
+        let mut stations: Vec<config::Station> = vec![];
+        for discipline in &self.disciplines {
+            let station = discipline.station();
+            if !stations.contains(&station) {
+                stations.push(station);
+            }
+        }
+        stations
+    }
+
+    /// Whether every station at which the recipe can be crafted is one of the given stations
+    pub fn is_only_craftable_at(&self, stations: &[config::Station]) -> bool {
+        let recipe_stations = self.stations();
+        !recipe_stations.is_empty()
+            && recipe_stations
+                .iter()
+                .all(|station| stations.contains(station))
+    }
+
     pub fn is_automatic(&self) -> bool {
         match &self.source {
-            RecipeSource::Purchasable | RecipeSource::Achievement => false,
-            // These aren't included in the API; assume you know them
+            RecipeSource::Purchasable | RecipeSource::Achievement | RecipeSource::LevelLocked => {
+                false
+            }
+            // These aren't included in the API; assume you know them unless
+            // --check-crafting-levels found that no character can craft them
             RecipeSource::Automatic | RecipeSource::Discoverable => true,
-            // TODO: instead, check if account has a char with the required crafting level
-            // Would require a key with the characters scope. Still wouldn't detect
-            // discoverable recipes, but would detect access to them
         }
     }
 
+    /// Mark an automatic or discoverable recipe as unknown because no character has the crafting
+    /// level for it
+    pub fn lock_by_crafting_level(&mut self) {
+        if let RecipeSource::Automatic | RecipeSource::Discoverable = self.source {
+            self.source = RecipeSource::LevelLocked;
+        }
+    }
+
+    /// Whether the recipe is unlocked by completing an achievement. These recipes have no id, so
+    /// are identified by their output item id
+    pub fn is_achievement(&self) -> bool {
+        matches!(self.source, RecipeSource::Achievement)
+    }
+
+    /// Whether the recipe is unlocked by an achievement which isn't in the known achievement
+    /// recipes. If none are known, achievement recipes are all assumed to be known.
+    pub fn is_unknown_achievement(&self, known_achievement_recipes: &Option<HashSet<u32>>) -> bool {
+        self.is_achievement()
+            && matches!(known_achievement_recipes, Some(known) if !known.contains(&self.output_item_id))
+    }
+
+    /// Whether the recipe produces a random number of items, other than a material promotion.
+    /// `output_item_count` is the rounded down average, which may be 0, so these recipes are only
+    /// used with --expected-value after `with_expected_output`
+    pub fn has_random_output(&self) -> bool {
+        self.output_item_average.is_some() && !self.is_promotion()
+    }
+
+    /// Scale a recipe with a random number of outputs to the smallest number of crafts which
+    /// produce a whole number of items on average, e.g. 2 crafts producing 5 items on average for
+    /// a recipe producing 2.5. The average is rounded to two decimal places.
+    pub fn with_expected_output(mut self) -> Self {
+        if let Some(average) = self
+            .output_item_average
+            .filter(|_| self.has_random_output())
+        {
+            let expected = Ratio::new((average * 100.0).round() as u32, 100);
+            let crafts = *expected.denom();
+            self.output_item_count = *expected.numer();
+            self.crafts = crafts;
+            for ingredient in &mut self.ingredients {
+                ingredient.count *= crafts;
+            }
+            for currency in &mut self.currency_ingredients {
+                currency.count *= crafts;
+            }
+        }
+        self
+    }
+
+    /// Number of crafts made each time the recipe is used, see with_expected_output
+    pub fn crafts(&self) -> u32 {
+        self.crafts
+    }
+
+    /// Whether this is a Mystic Forge material promotion, see is_promotion
+    pub fn is_promotion(&self) -> bool {
+        is_promotion(self.output_item_id, &self.disciplines, &self.ingredients)
+    }
+
+    /// Cost of crafting once when every ingredient is bought with currencies, or None if any
+    /// ingredient has no currency value
+    pub fn currency_cost(&self, items_map: &HashMap<u32, Item>) -> Option<Money> {
+        let item_cost: Option<Money> = self
+            .ingredients
+            .iter()
+            .map(|ingredient| {
+                items_map
+                    .get(&ingredient.item_id)
+                    .and_then(|item| item.currency_value())
+                    .map(|value| value * ingredient.count)
+            })
+            .sum();
+        Some(item_cost? + self.wallet_currency_cost()?)
+    }
+
     pub fn sorted_ingredients(&self) -> Vec<&api::RecipeIngredient> {
         let mut ingredients: Vec<&api::RecipeIngredient> = self.ingredients.iter().collect();
         ingredients.sort_unstable_by(|a, b| match b.count.cmp(&a.count) {
@@ -197,6 +389,9 @@ impl Recipe {
                         count: 5,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Automatic,
             },
             // Jade Bot Core Tier 1-10
@@ -219,6 +414,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -244,6 +442,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -269,6 +470,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -294,6 +498,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -319,6 +526,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -344,6 +554,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -369,6 +582,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -394,6 +610,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -419,6 +638,9 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             Recipe {
@@ -444,6 +666,9 @@ impl Recipe {
                         count: 2,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Purchasable,
             },
             // TODO: general vendor interface, for money+item purchases, so I can avoid faking it
@@ -464,12 +689,70 @@ impl Recipe {
                         count: 1,
                     },
                 ],
+                currency_ingredients: vec![],
+                output_item_average: None,
+                crafts: 1,
                 source: RecipeSource::Automatic,
             },
         ]
     }
 }
 
+/// Return the output item ids of recipes which have no ingredients
+pub fn mark_empty_recipes(recipes_map: &HashMap<u32, Recipe>) -> HashSet<u32> {
+    recipes_map
+        .iter()
+        .filter(|(_, recipe)| !recipe.has_ingredients())
+        .map(|(&item_id, _)| item_id)
+        .collect()
+}
+
+/// Item ids used by recipes, as outputs or ingredients, which are missing from the item data
+pub fn unknown_item_ids(
+    recipes_map: &HashMap<u32, Recipe>,
+    items_map: &HashMap<u32, Item>,
+) -> HashSet<u32> {
+    recipes_map
+        .values()
+        .flat_map(|recipe| {
+            std::iter::once(recipe.output_item_id).chain(
+                recipe
+                    .ingredients
+                    .iter()
+                    .map(|ingredient| ingredient.item_id),
+            )
+        })
+        .filter(|item_id| !items_map.contains_key(item_id))
+        .collect()
+}
+
+/// Hash of the output and ingredient item ids of all recipes, which are all that
+/// mark_recursive_recipes depends on. Used to check whether a cached result is still valid
+pub fn recipe_graph_hash(recipes_map: &HashMap<u32, Recipe>) -> u64 {
+    let mut item_ids: Vec<&u32> = recipes_map.keys().collect();
+    item_ids.sort_unstable();
+
+    let mut hasher = DefaultHasher::new();
+    for item_id in item_ids {
+        let recipe = &recipes_map[item_id];
+        item_id.hash(&mut hasher);
+        recipe.output_item_id.hash(&mut hasher);
+        for ingredient in &recipe.ingredients {
+            ingredient.item_id.hash(&mut hasher);
+        }
+        // separates the ingredients of consecutive recipes
+        recipe.ingredients.len().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Result of mark_recursive_recipes, saved between runs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecursiveRecipesCache {
+    pub recipe_graph_hash: u64,
+    pub item_ids: Vec<u32>,
+}
+
 pub fn mark_recursive_recipes(recipes_map: &HashMap<u32, Recipe>) -> HashSet<u32> {
     let mut set = HashSet::new();
     for (recipe_id, recipe) in recipes_map {
@@ -1,4 +1,4 @@
-use crate::api::ItemListings;
+use crate::api::{Character, CoinExchange, ItemListings, WalletCurrency};
 
 use bincode;
 use bincode::{deserialize_from, serialize_into};
@@ -6,19 +6,41 @@ use flate2::read::DeflateDecoder;
 use flate2::write::DeflateEncoder;
 use flate2::Compression;
 use futures::{stream, StreamExt};
+use once_cell::sync::Lazy;
 use serde_json;
 
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::future::Future;
 use std::hash::{Hash, Hasher};
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::config;
 
+/// Client for all requests, with the configured User-Agent and a pool of reused connections
+pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .user_agent(&config::CONFIG.user_agent)
+        // keep idle pooled connections open between queries, up to the number made at once
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .pool_max_idle_per_host(parallel_requests())
+        .tcp_keepalive(POOL_IDLE_TIMEOUT)
+        .build()
+        .expect("Failed to create HTTP client")
+});
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long the API caches responses for, see flush_cache
+const API_CACHE_WINDOW_SECS: u64 = 300;
+
+const API_BASE_URL: &str = "https://api.guildwars2.com/v2";
 const PARALLEL_REQUESTS: usize = 10;
+// coins offered when asking the gem exchange for its rate, as small amounts get a worse rate
+const GEM_EXCHANGE_COINS: u32 = 1000000;
 const MAX_PAGE_SIZE: i32 = 200; // https://wiki.guildwars2.com/wiki/API:2#Paging
 const MAX_ITEM_ID_LENGTH: i32 = 200; // error returned for greater than this amount
 
@@ -49,28 +71,57 @@ where
     T: serde::de::DeserializeOwned,
     Fut: Future<Output = Result<Vec<T>, Box<dyn std::error::Error>>>,
 {
-    if let Ok(file) = File::open(&data_path) {
-        let stream = DeflateDecoder::new(file);
-        deserialize_from(stream).map_err(|e| {
-            format!(
-                "Failed to deserialize existing data at '{}' ({}). \
-                 Try using the --reset-data flag to replace the data files.",
-                data_path.as_ref().display(),
-                e,
-            )
-            .into()
-        })
+    if data_path.as_ref().exists() {
+        read_data_file(data_path)
     } else {
         let items = getter().await?;
-
-        let file = File::create(data_path)?;
-        let stream = DeflateEncoder::new(file, Compression::default());
-        serialize_into(stream, &items)?;
-
+        write_data_file(data_path, &items)?;
         Ok(items)
     }
 }
 
+/// Read a data file previously written by write_data_file
+pub fn read_data_file<T>(data_path: impl AsRef<Path>) -> Result<Vec<T>, Box<dyn std::error::Error>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let file = File::open(&data_path)?;
+    let stream = DeflateDecoder::new(file);
+    deserialize_from(stream).map_err(|e| {
+        format!(
+            "Failed to deserialize existing data at '{}' ({}). \
+             Try using the --reset-data flag to replace the data files.",
+            data_path.as_ref().display(),
+            e,
+        )
+        .into()
+    })
+}
+
+pub fn write_data_file<T>(
+    data_path: impl AsRef<Path>,
+    items: &[T],
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: serde::Serialize,
+{
+    let file = File::create(data_path)?;
+    let stream = DeflateEncoder::new(file, Compression::default());
+    serialize_into(stream, items)?;
+    Ok(())
+}
+
+/// Read data in the same JSON format as returned by the API from a local file
+pub fn read_json_file<T>(path: &Path) -> Result<T, Box<dyn std::error::Error>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let file =
+        File::open(path).map_err(|e| format!("Failed to open '{}' ({})", path.display(), e))?;
+    let de = &mut serde_json::Deserializer::from_reader(io::BufReader::new(file));
+    serde_path_to_error::deserialize(de).map_err(|e| e.into())
+}
+
 pub async fn request_paginated<T>(
     url_path: &str,
     lang: &Option<config::Language>,
@@ -80,31 +131,98 @@ where
     T: serde::Serialize,
     T: serde::de::DeserializeOwned,
 {
-    let mut page_no = 0;
-    let mut page_total = None;
+    let mut items = request_paginated_langs(url_path, std::slice::from_ref(lang), notify).await?;
+    Ok(items.pop().expect("Missing items for language"))
+}
 
-    // update page total with first request
-    let mut items: Vec<T> = request_page(url_path, page_no, &mut page_total, lang, notify).await?;
+/// Request every page of the data in each of the given languages, in the order of `langs`. The
+/// pages of all languages are fetched in the same parallel batches, rather than waiting for one
+/// language to finish before starting the next.
+pub async fn request_paginated_langs<T>(
+    url_path: &str,
+    langs: &[Option<config::Language>],
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<Vec<Vec<T>>, Box<dyn std::error::Error>>
+where
+    T: serde::Serialize,
+    T: serde::de::DeserializeOwned,
+{
+    // update page total of each language with its first request
+    let mut lang_items = vec![];
+    let mut page_totals = vec![];
+    for lang in langs {
+        let mut page_total = None;
+        lang_items.push(request_page::<T>(url_path, 0, &mut page_total, lang, notify).await?);
+        page_totals.push(page_total.expect("Missing page total"));
+    }
 
     // fetch remaining pages in parallel batches
-    page_no += 1;
-
-    // try fetching one extra page in case page total increased while paginating
-    let page_total = page_total.expect("Missing page total") + 1;
-
-    let request_results = stream::iter((page_no..page_total).map(|page_no| async move {
-        request_page::<T>(url_path, page_no, &mut Some(page_total), lang, notify).await
-    }))
-    .buffered(PARALLEL_REQUESTS)
-    .collect::<Vec<Result<Vec<T>, Box<dyn std::error::Error>>>>()
+    let request_results = stream::iter(remaining_pages(&page_totals).into_iter().map(
+        |(lang_index, page_no, page_total)| async move {
+            let lang = &langs[lang_index];
+            let result =
+                request_page::<T>(url_path, page_no, &mut Some(page_total), lang, notify).await;
+            (lang_index, result)
+        },
+    ))
+    .buffered(parallel_requests())
+    .collect::<Vec<(usize, Result<Vec<T>, Box<dyn std::error::Error>>)>>()
     .await;
 
-    for result in request_results.into_iter() {
+    for (lang_index, result) in request_results.into_iter() {
         let mut new_items = result?;
-        items.append(&mut new_items);
+        lang_items[lang_index].append(&mut new_items);
+    }
+
+    Ok(lang_items)
+}
+
+/// Language index, page number and page total of each page after the first, given the page
+/// total of each language
+fn remaining_pages(page_totals: &[usize]) -> Vec<(usize, usize, usize)> {
+    page_totals
+        .iter()
+        .enumerate()
+        .flat_map(|(lang_index, &page_total)| {
+            // try fetching one extra page in case page total increased while paginating
+            let page_total = page_total + 1;
+            (1..page_total).map(move |page_no| (lang_index, page_no, page_total))
+        })
+        .collect()
+}
+
+fn parallel_requests() -> usize {
+    config::CONFIG
+        .parallel_requests
+        .unwrap_or(PARALLEL_REQUESTS)
+}
+
+/// The API to fetch data in the given language from. Chinese data can come from another API with
+/// --zh-api-url
+fn api_base_url(lang: &Option<config::Language>) -> &'static str {
+    match (lang, &config::CONFIG.zh_api_url) {
+        (Some(config::Language::Chinese), Some(zh_api_url)) => zh_api_url,
+        _ => API_BASE_URL,
     }
+}
 
-    Ok(items)
+fn page_url(
+    base_url: &str,
+    url_path: &str,
+    lang: &Option<config::Language>,
+    page_no: usize,
+) -> String {
+    if let Some(code) = config::Language::code(lang) {
+        format!(
+            "{}/{}?lang={}&page={}&page_size={}",
+            base_url, url_path, code, page_no, MAX_PAGE_SIZE
+        )
+    } else {
+        format!(
+            "{}/{}?page={}&page_size={}",
+            base_url, url_path, page_no, MAX_PAGE_SIZE
+        )
+    }
 }
 
 async fn request_page<T>(
@@ -118,22 +236,12 @@ where
     T: serde::Serialize,
     T: serde::de::DeserializeOwned,
 {
-    let url = if let Some(code) = config::Language::code(lang) {
-        format!(
-            "https://api.guildwars2.com/v2/{}?lang={}&page={}&page_size={}",
-            url_path, code, page_no, MAX_PAGE_SIZE
-        )
-    } else {
-        format!(
-            "https://api.guildwars2.com/v2/{}?page={}&page_size={}",
-            url_path, page_no, MAX_PAGE_SIZE
-        )
-    };
+    let url = page_url(api_base_url(lang), url_path, lang, page_no);
 
     if let Some(notify) = notify {
         notify(&url);
     }
-    let response = reqwest::get(&url).await?;
+    let response = CLIENT.get(&url).send().await?;
     if page_total.is_none() {
         let page_total_str = response
             .headers()
@@ -165,25 +273,31 @@ where
     T: serde::Serialize,
     T: serde::de::DeserializeOwned,
 {
-    let mut result = vec![];
-
-    for batch in item_ids.chunks(MAX_ITEM_ID_LENGTH as usize) {
-        let item_ids_str: Vec<String> = batch.iter().map(|id| id.to_string()).collect();
-
-        let url = format!(
-            "https://api.guildwars2.com/v2/{}?ids={}",
-            url_path,
-            item_ids_str.join(",")
-        );
-        if let Some(cache_dir) = cache_dir {
-            result.extend(
-                cached_fetch::<Vec<T>>(&url, cache_dir, notify)
-                    .await?
-                    .into_iter(),
+    // --refresh-prices only applies to trading post data, which changes every few minutes
+    let refresh = config::CONFIG.refresh_prices && url_path.starts_with("commerce/");
+    let request_results = stream::iter(item_ids.chunks(MAX_ITEM_ID_LENGTH as usize).map(
+        |batch| async move {
+            let item_ids_str: Vec<String> = batch.iter().map(|id| id.to_string()).collect();
+
+            let url = format!(
+                "https://api.guildwars2.com/v2/{}?ids={}",
+                url_path,
+                item_ids_str.join(",")
             );
-        } else {
-            result.extend(fetch::<Vec<T>>(&url, None).await?.into_iter());
-        }
+            if let Some(cache_dir) = cache_dir {
+                cached_fetch::<Vec<T>>(&url, cache_dir, refresh, notify).await
+            } else {
+                fetch::<Vec<T>>(&url, None).await
+            }
+        },
+    ))
+    .buffered(parallel_requests())
+    .collect::<Vec<Result<Vec<T>, Box<dyn std::error::Error>>>>()
+    .await;
+
+    let mut result = vec![];
+    for batch_result in request_results.into_iter() {
+        result.extend(batch_result?);
     }
 
     Ok(result)
@@ -199,15 +313,84 @@ pub async fn fetch_account_recipes(
     if let Some(notify) = notify {
         let display = format!("{}{}", base, "<api-key>");
         let private = |_url: &str| notify(&display);
-        Ok(cached_fetch(&url, cache_dir, Some(&private as &dyn Fn(&str))).await?)
+        Ok(cached_fetch(&url, cache_dir, false, Some(&private as &dyn Fn(&str))).await?)
     } else {
-        Ok(cached_fetch(&url, cache_dir, None).await?)
+        Ok(cached_fetch(&url, cache_dir, false, None).await?)
     }
 }
 
+/// Amount of each currency in the account's wallet, by currency id. Requires an API key with the
+/// wallet scope.
+pub async fn fetch_wallet(
+    key: &str,
+    cache_dir: &Path,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<HashMap<u32, u32>, Box<dyn std::error::Error>> {
+    let base = "https://api.guildwars2.com/v2/account/wallet?access_token=";
+    let url = format!("{}{}", base, key);
+    let wallet: Vec<WalletCurrency> = if let Some(notify) = notify {
+        let display = format!("{}{}", base, "<api-key>");
+        let private = |_url: &str| notify(&display);
+        cached_fetch(&url, cache_dir, false, Some(&private as &dyn Fn(&str))).await?
+    } else {
+        cached_fetch(&url, cache_dir, false, None).await?
+    };
+    Ok(wallet
+        .into_iter()
+        .map(|currency| (currency.id, currency.value))
+        .collect())
+}
+
+/// Highest crafting level of any of the account's characters in each discipline. Requires an API
+/// key with the characters scope.
+pub async fn fetch_crafting_levels(
+    key: &str,
+    cache_dir: &Path,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<HashMap<config::Discipline, u16>, Box<dyn std::error::Error>> {
+    let base = "https://api.guildwars2.com/v2/characters?ids=all&access_token=";
+    let url = format!("{}{}", base, key);
+    let characters: Vec<Character> = if let Some(notify) = notify {
+        let display = format!("{}{}", base, "<api-key>");
+        let private = |_url: &str| notify(&display);
+        cached_fetch(&url, cache_dir, false, Some(&private as &dyn Fn(&str))).await?
+    } else {
+        cached_fetch(&url, cache_dir, false, None).await?
+    };
+
+    let mut crafting_levels = HashMap::new();
+    for crafting in characters.iter().flat_map(|character| &character.crafting) {
+        let rating = crafting_levels.entry(crafting.discipline).or_insert(0);
+        *rating = crafting.rating.max(*rating);
+    }
+    Ok(crafting_levels)
+}
+
+/// Coins needed to buy one gem at the current rate of the gem exchange
+pub async fn fetch_gem_rate(
+    cache_dir: &Path,
+    notify: Option<&dyn Fn(&str)>,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let url = format!(
+        "{}/commerce/exchange/coins?quantity={}",
+        API_BASE_URL, GEM_EXCHANGE_COINS
+    );
+    let exchange: CoinExchange = window_cached(
+        &"commerce/exchange/coins",
+        cache_dir,
+        config::CONFIG.refresh_prices,
+        || fetch(&url, notify),
+    )
+    .await?;
+    Ok(exchange.coins_per_gem)
+}
+
+/// Fetch the URL, or read the response cached by an earlier request unless `refresh` is set. The
+/// response is cached either way.
 async fn cached_fetch<T>(
     url: &str,
     cache_dir: &Path,
+    refresh: bool,
     notify: Option<&dyn Fn(&str)>,
 ) -> Result<T, Box<dyn std::error::Error>>
 where
@@ -215,10 +398,12 @@ where
     T: serde::de::DeserializeOwned,
 {
     let cache_path = url_to_cache_path(url, cache_dir);
-    if let Ok(file) = File::open(&cache_path) {
-        let stream = DeflateDecoder::new(file);
-        let v = deserialize_from(stream)?;
-        return Ok(v);
+    if !refresh {
+        if let Ok(file) = File::open(&cache_path) {
+            let stream = DeflateDecoder::new(file);
+            let v = deserialize_from(stream)?;
+            return Ok(v);
+        }
     }
 
     let v = fetch(&url, notify).await?;
@@ -240,7 +425,7 @@ where
         notify(&url.to_string());
     }
 
-    let response = reqwest::get(url).await?;
+    let response = CLIENT.get(url).send().await?;
     let status = response.status();
     if !status.is_success() {
         let err: serde_json::value::Value = response.json().await?;
@@ -248,7 +433,11 @@ where
             .get("text")
             .and_then(|text| text.as_str())
             .unwrap_or_else(|| status.as_str());
-        return Err(text.into());
+        return Err(Box::new(ApiError {
+            url: url.to_string(),
+            status: status.as_u16(),
+            text: text.to_string(),
+        }));
     }
 
     let bytes = response.bytes().await?;
@@ -258,6 +447,105 @@ where
     Ok(v)
 }
 
+/// Error response returned by the API for an unsuccessful request
+#[derive(Debug)]
+pub struct ApiError {
+    pub url: String,
+    pub status: u16,
+    pub text: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Version of the layout of cached results. Increase it whenever a cached type such as
+/// `ProfitableItem` or `Money` changes, since bincode may read the old layout without an error.
+const RESULT_CACHE_VERSION: u32 = 5;
+
+/// Return the result computed from the given inputs on a previous run, or compute and save it.
+/// Results are stored alongside cached API responses and expire with them, so are reused while the
+/// API would return the same data.
+pub fn cached_result<K, T>(
+    inputs: &K,
+    cache_dir: &Path,
+    compute: impl FnOnce() -> T,
+) -> Result<(T, bool), Box<dyn std::error::Error>>
+where
+    K: serde::Serialize,
+    T: serde::Serialize,
+    T: serde::de::DeserializeOwned,
+{
+    let mut hash = DefaultHasher::new();
+    RESULT_CACHE_VERSION.hash(&mut hash);
+    bincode::serialize(inputs)?.hash(&mut hash);
+    let hash = hash.finish();
+
+    let mut cache_path = cache_dir.to_owned();
+    cache_path.push(format!("{}result_{}", config::CACHE_PREFIX, hash));
+    if let Ok(file) = File::open(&cache_path) {
+        let stream = DeflateDecoder::new(file);
+        // results saved by an older version may no longer deserialize, so compute them again
+        if let Ok(v) = deserialize_from(stream) {
+            return Ok((v, true));
+        }
+    }
+
+    let v = compute();
+
+    let file = File::create(cache_path)?;
+    let stream = DeflateEncoder::new(file, Compression::default());
+    serialize_into(stream, &v)?;
+
+    Ok((v, false))
+}
+
+/// Return the data fetched for the given key earlier in the current 5 minute window, or fetch and
+/// save it. The API caches its responses for about as long, so for requests whose URLs change
+/// between runs (e.g. bulk prices, or listings for a different list of item ids) this still reuses
+/// data the API would have returned anyway. Expired files are removed by flush_cache. With
+/// `refresh` set, the data is always fetched and replaces any saved earlier in the window.
+pub async fn window_cached<K, T, Fut>(
+    key: &K,
+    cache_dir: &Path,
+    refresh: bool,
+    fetch: impl FnOnce() -> Fut,
+) -> Result<T, Box<dyn std::error::Error>>
+where
+    K: serde::Serialize,
+    T: serde::Serialize,
+    T: serde::de::DeserializeOwned,
+    Fut: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let window = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / API_CACHE_WINDOW_SECS;
+    let mut hash = DefaultHasher::new();
+    bincode::serialize(&(key, window))?.hash(&mut hash);
+    let hash = hash.finish();
+
+    let mut cache_path = cache_dir.to_owned();
+    cache_path.push(format!("{}window_{}", config::CACHE_PREFIX, hash));
+    if !refresh {
+        if let Ok(file) = File::open(&cache_path) {
+            let stream = DeflateDecoder::new(file);
+            if let Ok(v) = deserialize_from(stream) {
+                return Ok(v);
+            }
+        }
+    }
+
+    let v = fetch().await?;
+
+    let file = File::create(cache_path)?;
+    let stream = DeflateEncoder::new(file, Compression::default());
+    serialize_into(stream, &v)?;
+
+    Ok(v)
+}
+
 fn url_to_cache_path(url: &str, cache_dir: &Path) -> PathBuf {
     let mut hash = DefaultHasher::new();
     url.hash(&mut hash);
@@ -267,3 +555,36 @@ fn url_to_cache_path(url: &str, cache_dir: &Path) -> PathBuf {
     path.push(format!("{}{}", config::CACHE_PREFIX, hash));
     path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chinese_page_urls() {
+        let zh = Some(config::Language::Chinese);
+        assert_eq!(
+            page_url(API_BASE_URL, "items", &zh, 3),
+            "https://api.guildwars2.com/v2/items?lang=zh&page=3&page_size=200"
+        );
+        assert_eq!(
+            page_url("https://zh.example.com/v2", "recipes", &zh, 0),
+            "https://zh.example.com/v2/recipes?lang=zh&page=0&page_size=200"
+        );
+        assert_eq!(
+            page_url(API_BASE_URL, "commerce/prices", &None, 1),
+            "https://api.guildwars2.com/v2/commerce/prices?page=1&page_size=200"
+        );
+        // without --zh-api-url the global API is used
+        assert_eq!(api_base_url(&zh), API_BASE_URL);
+    }
+
+    #[test]
+    fn remaining_pages_of_languages() {
+        assert_eq!(
+            remaining_pages(&[2, 1]),
+            vec![(0, 1, 3), (0, 2, 3), (1, 1, 2)]
+        );
+        assert!(remaining_pages(&[]).is_empty());
+    }
+}
@@ -1,11 +1,17 @@
 use gw2_arbitrage::{
     api::{self, ItemListings, Listing, RecipeIngredient},
-    config::Discipline,
+    config::{CraftingOptions, Discipline, IngredientPricing, SortBy, Station},
     crafting::{self, CraftedItems, PurchasedIngredient},
+    gw2efficiency,
     item::Item,
     money::Money,
-    profit::{calculate_crafting_profit, ProfitableItem},
-    recipe::Recipe,
+    profit::{
+        self, calculate_acquisition_cost, calculate_crafting_profit,
+        calculate_joint_acquisition_cost, AcquiredItem, ChainValue, CraftingLimit, ProfitableItem,
+        SaleVenue,
+    },
+    recipe::{self, Recipe},
+    request,
 };
 
 use std::collections::{HashMap, HashSet};
@@ -84,6 +90,71 @@ impl MockRecipe for Recipe {
     }
 }
 
+trait MockProfitableItem {
+    fn mock(id: u32, count: u32, profit: i32, crafting_cost: i32, crafting_steps: u32) -> Self;
+}
+impl MockProfitableItem for ProfitableItem {
+    fn mock(id: u32, count: u32, profit: i32, crafting_cost: i32, crafting_steps: u32) -> Self {
+        ProfitableItem {
+            id,
+            crafting_cost: Money::from_copper(crafting_cost),
+            count,
+            count_max_roi: count,
+            profit_max_roi: Money::from_copper(profit),
+            profit: Money::from_copper(profit),
+            tax: Money::from_copper(0),
+            max_sell: Money::from_copper(0),
+            min_sell: Money::from_copper(0),
+            buy_volume: 0,
+            breakeven: Money::from_copper(0),
+            first_crafting_cost: Money::from_copper(0),
+            next_crafting_cost: None,
+            estimate_delta: None,
+            limited_by: CraftingLimit::Demand,
+            sales: vec![],
+            recipe_cost: Money::from_copper(0),
+            storage_cost: Money::from_copper(0),
+            crafting_steps,
+            crafted_items: Default::default(),
+        }
+    }
+}
+
+fn mock_recipe_sheet(id: u32, recipe_id: u32) -> Item {
+    serde_json::from_str(
+        format!(
+            "{{
+                \"id\": {},
+                \"name\": \"Recipe Sheet\",
+                \"vendor_value\": 0,
+                \"type\": \"Consumable\",
+                \"rarity\": \"Basic\",
+                \"level\": 0,
+                \"flags\": [],
+                \"restrictions\": [],
+                \"details\": {{ \"Consumable\": {{ \"type\": \"Unlock\", \"recipe_id\": {} }} }}
+            }}",
+            id, recipe_id
+        )
+        .as_str(),
+    )
+    .unwrap()
+}
+
+fn mock_price(id: u32, sell_price: u32) -> api::Price {
+    api::Price {
+        id,
+        buys: api::PriceInfo {
+            unit_price: 0,
+            quantity: 0,
+        },
+        sells: api::PriceInfo {
+            unit_price: sell_price,
+            quantity: 1,
+        },
+    }
+}
+
 // TODO: in main code, calculate revenue based on selling to buy order volume,
 // instead of one at at time. On the upside - this will always be <= true revenue
 fn calc_revenue(buys: Vec<(u32, u32)>) -> Money {
@@ -96,6 +167,14 @@ fn calc_revenue(buys: Vec<(u32, u32)>) -> Money {
         .sum()
 }
 
+fn calc_tax(buys: Vec<(u32, u32)>) -> Money {
+    let gross_revenue = buys
+        .iter()
+        .map(|(count, price)| Money::from_copper(*price as i32) * *count)
+        .sum::<Money>();
+    gross_revenue - calc_revenue(buys)
+}
+
 #[test]
 fn calculate_crafting_profit_agony_infusion_unprofitable_test() {
     let data::TestData {
@@ -199,10 +278,11 @@ fn calculate_crafting_profit_agony_infusion_profitable_test() {
                     crafting::Source::TradingPost
                 ),
                 PurchasedIngredient {
-                    count: 2,
+                    count: 1,
                     min_price: Money::from_copper(120),
-                    max_price: Money::from_copper(178),
-                    total_cost: Money::from_copper(298),
+                    max_price: Money::from_copper(120),
+                    total_cost: Money::from_copper(120),
+                    listings: vec![],
                 }
             ),
             (
@@ -212,6 +292,7 @@ fn calculate_crafting_profit_agony_infusion_profitable_test() {
                     min_price: Money::from_copper(0),
                     max_price: Money::from_copper(0),
                     total_cost: Money::from_copper(0),
+                    listings: vec![],
                 }
             ),
             (
@@ -221,40 +302,64 @@ fn calculate_crafting_profit_agony_infusion_profitable_test() {
                     min_price: Money::from_copper(800000),
                     max_price: Money::from_copper(1100000),
                     total_cost: Money::from_copper(8300000),
+                    listings: vec![],
                 }
             ),
         ]
     );
 
-    // NB: two reagents are purchased from the tp because that amount appears first in the recipe
-    // and the average cost for two items is lower than from the vendor.
-    // ideally only one reagent would be purchased from the tp, but that would introduce complexity.
-    let thermocatalytic_reagent_crafting_cost = (120 + 178) + 4 * 150;
-    let crafting_cost = Money::from_copper(
-        800000 + 2 * 1000000 + 5 * 1100000 + thermocatalytic_reagent_crafting_cost,
-    );
+    // only the reagent listed for less than the vendor price (149.6 each) is bought from the tp,
+    // with the other 5 bought from the vendor
+    let thermocatalytic_reagent_crafting_cost =
+        Money::from_copper(120) + Money::from_copper(5 * 1496) / 10;
+    let crafting_cost = Money::from_copper(800000 + 2 * 1000000 + 5 * 1100000)
+        + thermocatalytic_reagent_crafting_cost;
     let mut crafted = HashMap::new();
     crafted.insert(plus_14_item_id + 1, 4);
     crafted.insert(plus_16_item_id, 2);
     let mut leftovers: HashMap<u32, (u32, Money, crafting::Source)> = HashMap::new();
     leftovers.insert(
         thermocatalytic_reagent_item_id,
-        (6, Money::from_copper(1496) / 10, crafting::Source::Vendor),
+        (5, Money::from_copper(1496) / 10, crafting::Source::Vendor),
     );
+    // 4 of the 10 reagents bought from the vendor are used by later crafts
+    let mut used_leftovers = HashMap::new();
+    used_leftovers.insert(thermocatalytic_reagent_item_id, 4);
+    // 4 x +14, 1 reagent from the tp and 2 from the vendor
+    let first_crafting_cost = Money::from_copper(800000 + 2 * 1000000 + 1100000 + 120)
+        + Money::from_copper(2 * 1496) / 10;
     assert_eq!(
         profitable_item,
         Some(ProfitableItem {
             id: plus_16_item_id,
             crafting_cost,
             count: 2,
+            // the second +16 costs more and sells for less than the first
+            count_max_roi: 1,
+            profit_max_roi: Money::from_copper(7982220).trading_post_sale_revenue()
+                - first_crafting_cost,
             profit: Money::from_copper(7982220 + 7982200).trading_post_sale_revenue()
                 - crafting_cost,
+            tax: calc_tax(vec![(1, 7982220), (1, 7982200)]),
             max_sell: Money::from_copper(7982220),
             min_sell: Money::from_copper(7982200),
-            // (1100000 * 4 + 3 * 150) / (85 / 100)
-            breakeven: Money::from_copper(5177000),
+            buy_volume: 2,
+            // (1100000 * 4 + 3 * 149.6) / (85 / 100)
+            breakeven: Money::from_copper(5176999),
+            first_crafting_cost,
+            next_crafting_cost: None,
+            estimate_delta: None,
+            limited_by: CraftingLimit::Demand,
+            sales: vec![(SaleVenue::BuyOrder, 2)],
+            recipe_cost: Money::from_copper(0),
+            storage_cost: Money::from_copper(0),
             crafting_steps: 6,
-            crafted_items: CraftedItems { crafted, leftovers },
+            crafted_items: CraftedItems {
+                crafted,
+                leftovers,
+                used_leftovers,
+                owned: HashMap::new(),
+            },
         })
     );
 }
@@ -325,14 +430,27 @@ fn calculate_crafting_profit_with_output_item_count_test() {
             id: item_id,
             crafting_cost,
             count: 98,
+            count_max_roi: 98,
+            profit_max_roi: calc_revenue(vec![(47, 198), (50, 199), (1, 200)]) - crafting_cost,
             profit: calc_revenue(vec![(47, 198), (50, 199), (1, 200)]) - crafting_cost,
+            tax: calc_tax(vec![(47, 198), (50, 199), (1, 200)]),
             max_sell: Money::from_copper(200),
             min_sell: Money::from_copper(198),
+            buy_volume: 98,
             breakeven: profitable_item.as_ref().unwrap().breakeven, // inexact, test below
+            first_crafting_cost: crafting_cost / 98,
+            next_crafting_cost: None,
+            estimate_delta: None,
+            limited_by: CraftingLimit::Demand,
+            sales: vec![(SaleVenue::BuyOrder, 98)],
+            recipe_cost: Money::from_copper(0),
+            storage_cost: Money::from_copper(0),
             crafting_steps: 1,
             crafted_items: CraftedItems {
                 crafted,
                 leftovers: Default::default(),
+                used_leftovers: Default::default(),
+                owned: Default::default(),
             },
         })
     );
@@ -364,15 +482,31 @@ fn calculate_crafting_profit_with_output_item_count_test() {
             id: item_id,
             crafting_cost,
             count: 96,
+            // the first batch uses the cheapest ingredients and the highest buy orders, and
+            // every later batch only adds a thinner margin
+            count_max_roi: 3,
+            profit_max_roi: calc_revenue(vec![(1, 200), (2, 199)])
+                - Money::from_copper(90 + 92 + 43),
             profit: calc_revenue(vec![(45, 198), (50, 199), (1, 200)]) - crafting_cost,
+            tax: calc_tax(vec![(45, 198), (50, 199), (1, 200)]),
             max_sell: Money::from_copper(200),
             min_sell: Money::from_copper(198),
+            buy_volume: 98,
             // ((2*94 + 45) / 3) / (85/100)
             breakeven: Money::from_copper(92),
+            first_crafting_cost: Money::from_copper(90 + 92 + 43) / 3,
+            next_crafting_cost: None,
+            estimate_delta: None,
+            limited_by: CraftingLimit::Demand,
+            sales: vec![(SaleVenue::BuyOrder, 96)],
+            recipe_cost: Money::from_copper(0),
+            storage_cost: Money::from_copper(0),
             crafting_steps: 32,
             crafted_items: CraftedItems {
                 crafted,
                 leftovers: Default::default(),
+                used_leftovers: Default::default(),
+                owned: Default::default(),
             },
         })
     );
@@ -618,54 +752,2373 @@ fn calculate_crafting_profit_with_subitem_leftovers() {
     let mut crafted = HashMap::new();
     crafted.insert(1000, 51);
     crafted.insert(2100, 40);
+    // 2100 is crafted in batches of 5, so only the first of each batch isn't a leftover
+    let mut used_leftovers = HashMap::new();
+    used_leftovers.insert(2100, 31);
     assert_eq!(
         profitable_item,
         Some(ProfitableItem {
             id: 1000,
             crafting_cost,
             count: 51,
+            count_max_roi: 1,
+            profit_max_roi: calc_revenue(vec![(1, 200)]) - Money::from_copper(25 + 30 + 15),
             profit: calc_revenue(vec![(50, 155), (1, 200)]) - crafting_cost,
+            tax: calc_tax(vec![(50, 155), (1, 200)]),
             max_sell: Money::from_copper(200),
             min_sell: Money::from_copper(155),
+            // the 100 buy orders at 100 are left unsold
+            buy_volume: 151,
             // (50 * 2 + 30) / (85/100)
             breakeven: Money::from_copper(153),
+            first_crafting_cost: Money::from_copper(25 + 30 + 15),
+            // 50 * 2 + 30 for the leftover, more than the remaining buy orders at 100
+            next_crafting_cost: Some(Money::from_copper(130)),
+            estimate_delta: None,
+            limited_by: CraftingLimit::Demand,
+            sales: vec![(SaleVenue::BuyOrder, 51)],
+            recipe_cost: Money::from_copper(0),
+            storage_cost: Money::from_copper(0),
             crafting_steps: 59,
-            crafted_items: CraftedItems { crafted, leftovers },
+            crafted_items: CraftedItems {
+                crafted,
+                leftovers,
+                used_leftovers,
+                owned: HashMap::new(),
+            },
         })
     );
 }
 
-fn tp_listings_map(
-    from: Vec<(u32, Vec<(u32, u32)>, Vec<(u32, u32)>)>,
-) -> HashMap<u32, ItemListings> {
-    let mut map = HashMap::new();
-    for (id, mut buys, mut sells) in from.into_iter() {
-        buys.sort_by(|(price1, _), (price2, _)| price1.cmp(&price2));
-        sells.sort_by(|(price1, _), (price2, _)| price1.cmp(&price2).reverse());
-        map.insert(
-            id,
-            ItemListings {
-                id,
-                buys: buys
-                    .into_iter()
-                    .map(|(unit_price, quantity)| Listing {
-                        listings: quantity,
-                        unit_price,
-                        quantity,
-                    })
-                    .collect(),
-                sells: sells
-                    .into_iter()
-                    .map(|(unit_price, quantity)| Listing {
-                        listings: quantity,
-                        unit_price,
-                        quantity,
-                    })
-                    .collect(),
+#[test]
+fn calculate_acquisition_cost_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 2, true);
+
+    // no buy orders, so the item can never be profitable, but it is still cheaper to craft
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![], vec![(500, 10)]),
+        (2000, vec![], vec![(100, 3), (150, 10)]),
+    ]);
+
+    assert!(calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    )
+    .is_none());
+
+    let mut purchased_ingredients = HashMap::new();
+    let acquired_item = calculate_acquisition_cost(
+        1000,
+        2,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        Some(&mut purchased_ingredients),
+        &Default::default(),
+    );
+
+    let mut crafted = HashMap::new();
+    crafted.insert(1000, 2);
+    assert_eq!(
+        acquired_item,
+        Some(AcquiredItem {
+            id: 1000,
+            count: 2,
+            cost: Money::from_copper(3 * 100 + 150),
+            source: crafting::Source::Crafting,
+            crafting_steps: 2,
+            crafted_items: CraftedItems {
+                crafted,
+                leftovers: HashMap::new(),
+                used_leftovers: HashMap::new(),
+                owned: HashMap::new(),
             },
+        })
+    );
+    assert_eq!(
+        purchased_ingredients.into_iter().collect::<Vec<_>>(),
+        vec![(
+            (2000, crafting::Source::TradingPost),
+            PurchasedIngredient {
+                count: 4,
+                min_price: Money::from_copper(100),
+                max_price: Money::from_copper(150),
+                total_cost: Money::from_copper(3 * 100 + 150),
+                listings: vec![],
+            }
+        )]
+    );
+}
+
+#[test]
+fn calculate_acquisition_cost_audit_listings_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 2, true);
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![], vec![(500, 10)]),
+        (2000, vec![], vec![(100, 3), (150, 10)]),
+    ]);
+
+    let mut purchased_ingredients = HashMap::new();
+    calculate_acquisition_cost(
+        1000,
+        3,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        Some(&mut purchased_ingredients),
+        &CraftingOptions {
+            audit_listings: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let ingredient = &purchased_ingredients[&(2000, crafting::Source::TradingPost)];
+    assert_eq!(ingredient.count, 6);
+    assert_eq!(ingredient.listings, vec![(100, 3), (150, 3)]);
+    assert_eq!(
+        ingredient.total_cost,
+        Money::from_copper(
+            ingredient
+                .listings
+                .iter()
+                .map(|(unit_price, quantity)| (unit_price * quantity) as i32)
+                .sum()
+        )
+    );
+}
+
+#[test]
+fn calculate_joint_acquisition_cost_test() {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Output Item 1", 0));
+    items_map.insert(1001, Item::mock(1001, "Output Item 2", 0));
+    items_map.insert(2000, Item::mock(2000, "Shared Ingredient", 0));
+    items_map.insert(3000, Item::mock(3000, "Sub-ingredient", 0));
+
+    let mut recipes_map = HashMap::new();
+    for (recipe_id, item_id) in [(300, 1000), (301, 1001)] {
+        recipes_map.insert(
+            item_id,
+            Recipe::mock(
+                recipe_id,
+                item_id,
+                1,
+                [],
+                &[RecipeIngredient {
+                    item_id: 2000,
+                    count: 1,
+                }],
+                true,
+            ),
         );
     }
-    map
+    // makes 2 at a time, so one craft is enough for both output items
+    recipes_map.insert(
+        2000,
+        Recipe::mock(
+            302,
+            2000,
+            2,
+            [],
+            &[RecipeIngredient {
+                item_id: 3000,
+                count: 1,
+            }],
+            true,
+        ),
+    );
+
+    let tp_listings_map = tp_listings_map(vec![(3000, vec![], vec![(100, 10)])]);
+
+    let mut purchased_ingredients = HashMap::new();
+    let joint_acquisition = calculate_joint_acquisition_cost(
+        &[1000, 1001],
+        1,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        Some(&mut purchased_ingredients),
+        &Default::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        joint_acquisition.items,
+        vec![
+            (1000, Money::from_copper(50), crafting::Source::Crafting),
+            (1001, Money::from_copper(50), crafting::Source::Crafting),
+        ]
+    );
+    assert_eq!(joint_acquisition.outlay, Money::from_copper(100));
+    // each item on its own needs a separate craft of the shared ingredient
+    assert_eq!(joint_acquisition.separate_outlay, Money::from_copper(200));
+    assert_eq!(joint_acquisition.savings(), Money::from_copper(100));
+    assert!(joint_acquisition.crafted_items.leftovers.is_empty());
+    assert_eq!(
+        purchased_ingredients
+            .get(&(3000, crafting::Source::TradingPost))
+            .map(|ingredient| ingredient.count),
+        Some(1)
+    );
+}
+
+#[test]
+fn calculate_crafting_profit_with_sale_percentile_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 1, true);
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 2), (250, 4), (200, 4)], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+    ]);
+
+    let count = |sale_percentile| {
+        calculate_crafting_profit(
+            1000,
+            &recipes_map,
+            &items_map,
+            &tp_listings_map,
+            None,
+            &CraftingOptions {
+                sale_percentile,
+                ..Default::default()
+            },
+        )
+        .map(|item| (item.count, item.min_sell, item.limited_by))
+    };
+    assert_eq!(
+        count(None),
+        Some((10, Money::from_copper(200), CraftingLimit::Demand))
+    );
+    assert_eq!(
+        count(Some(100)),
+        Some((10, Money::from_copper(200), CraftingLimit::Demand))
+    );
+    // the 5th of 10 items is bought at 250, so all buy orders at 250 are sold into
+    assert_eq!(
+        count(Some(50)),
+        Some((6, Money::from_copper(250), CraftingLimit::SalePercentile))
+    );
+    assert_eq!(
+        count(Some(25)),
+        Some((6, Money::from_copper(250), CraftingLimit::SalePercentile))
+    );
+    assert_eq!(
+        count(Some(20)),
+        Some((2, Money::from_copper(300), CraftingLimit::SalePercentile))
+    );
+    // the highest buy order is always sold into
+    assert_eq!(
+        count(Some(5)),
+        Some((2, Money::from_copper(300), CraftingLimit::SalePercentile))
+    );
+
+    // the percentile of a deep buy order book doesn't overflow
+    let deep_listings_map = crate::tp_listings_map(vec![
+        (1000, vec![(300, 2), (250, 3), (200, 100_000_000)], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+    ]);
+    let deep_count = |sale_percentile| {
+        calculate_crafting_profit(
+            1000,
+            &recipes_map,
+            &items_map,
+            &deep_listings_map,
+            None,
+            &CraftingOptions {
+                count: Some(10),
+                sale_percentile: Some(sale_percentile),
+                ..Default::default()
+            },
+        )
+        .map(|item| (item.count, item.min_sell, item.limited_by))
+    };
+    assert_eq!(
+        deep_count(50),
+        Some((10, Money::from_copper(200), CraftingLimit::Count))
+    );
+}
+
+#[test]
+fn calculate_crafting_profit_ignore_top_buys_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 1, true);
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(1000, 1), (300, 2), (250, 4), (200, 4)], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+    ]);
+
+    let sales = |ignore_top_buys| {
+        calculate_crafting_profit(
+            1000,
+            &recipes_map,
+            &items_map,
+            &tp_listings_map,
+            None,
+            &CraftingOptions {
+                ignore_top_buys,
+                ..Default::default()
+            },
+        )
+        .map(|item| (item.count, item.max_sell, item.min_sell))
+    };
+    assert_eq!(
+        sales(0),
+        Some((11, Money::from_copper(1000), Money::from_copper(200)))
+    );
+    assert_eq!(
+        sales(1),
+        Some((10, Money::from_copper(300), Money::from_copper(200)))
+    );
+    assert_eq!(
+        sales(2),
+        Some((8, Money::from_copper(250), Money::from_copper(200)))
+    );
+    assert_eq!(sales(4), None);
+}
+
+#[test]
+fn calculate_crafting_profit_breakeven_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 1, true);
+
+    // 200 sells for exactly the 170 the ingredient costs after fees
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(200, 1)], vec![]),
+        (2000, vec![], vec![(170, 5)]),
+    ]);
+
+    let profit = |include_breakeven| {
+        calculate_crafting_profit(
+            1000,
+            &recipes_map,
+            &items_map,
+            &tp_listings_map,
+            None,
+            &CraftingOptions {
+                include_breakeven,
+                ..Default::default()
+            },
+        )
+        .map(|item| (item.count, item.profit))
+    };
+    assert_eq!(profit(false), None);
+    assert_eq!(profit(true), Some((1, Money::from_copper(0))));
+}
+
+#[test]
+fn liquidity_test() {
+    let data::TestData {
+        item_id,
+        items_map,
+        recipes_map,
+        tp_listings_map: infusion_listings_map,
+    } = data::agony_infusions();
+
+    let acquire = |min_sell_volume| {
+        let mut purchased_ingredients = HashMap::new();
+        let acquired_item = calculate_acquisition_cost(
+            item_id,
+            1,
+            &recipes_map,
+            &items_map,
+            &infusion_listings_map,
+            Some(&mut purchased_ingredients),
+            &CraftingOptions {
+                min_sell_volume,
+                ..Default::default()
+            },
+        );
+        (acquired_item.map(|item| item.cost), purchased_ingredients)
+    };
+
+    // the cheapest way to get a +16 is combining two +15s from the trading post
+    let (cost, purchased_ingredients) = acquire(None);
+    assert!(cost.is_some());
+    assert!(purchased_ingredients.contains_key(&(49438, crafting::Source::TradingPost)));
+    assert_eq!(acquire(Some(2)).0, cost);
+    // only 2 +15s are listed, and none of the lower infusions have enough listed either
+    let (thin_cost, purchased_ingredients) = acquire(Some(3));
+    assert_eq!(thin_cost, None);
+    assert!(purchased_ingredients.is_empty());
+
+    let (items_map, recipes_map) = mock_crafting_data(1, 1, true);
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(200, 3)], vec![]),
+        (2000, vec![], vec![(100, 5)]),
+    ]);
+    let profit = |min_sell_volume, min_buy_volume| {
+        calculate_crafting_profit(
+            1000,
+            &recipes_map,
+            &items_map,
+            &tp_listings_map,
+            None,
+            &CraftingOptions {
+                min_sell_volume,
+                min_buy_volume,
+                ..Default::default()
+            },
+        )
+        .map(|item| item.count)
+    };
+    assert_eq!(profit(None, None), Some(3));
+    assert_eq!(profit(None, Some(3)), Some(3));
+    assert_eq!(profit(None, Some(4)), None);
+    assert_eq!(profit(Some(5), None), Some(3));
+    assert_eq!(profit(Some(6), None), None);
+}
+
+#[test]
+fn calculate_crafting_profit_with_overcraft_test() {
+    let (items_map, recipes_map) = mock_crafting_data(5, 1, true);
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(1000, 10)], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+    ]);
+
+    let profitable_item = |allow_overcraft| {
+        calculate_crafting_profit(
+            1000,
+            &recipes_map,
+            &items_map,
+            &tp_listings_map,
+            None,
+            &CraftingOptions {
+                count: Some(3),
+                allow_overcraft,
+                ..Default::default()
+            },
+        )
+    };
+    assert!(profitable_item(false).is_none());
+
+    let profitable_item = profitable_item(true).unwrap();
+    assert_eq!(profitable_item.count, 5);
+    assert_eq!(profitable_item.limited_by, CraftingLimit::Count);
+    assert_eq!(
+        profitable_item.profit,
+        calc_revenue(vec![(3, 1000)]) - Money::from_copper(100)
+    );
+    assert_eq!(
+        profitable_item.crafted_items.leftovers.get(&1000),
+        Some(&(2, Money::from_copper(20), crafting::Source::Crafting))
+    );
+}
+
+/// Ties between sources are resolved in the order trading post, crafting, vendor.
+/// NB: currency conversion factors are unset when testing, so karma purchases are unavailable and
+/// only tokens with a fixed copper value can be used to test currency costs.
+#[test]
+fn calculate_estimated_min_crafting_cost_tie_test() {
+    let lump_of_tin_item_id = 19704; // vendor item, 8 x vendor value
+    let blade_shard_item_id = 50025; // token, always worth 0
+    let apples_item_id = 12788; // karma vendor item
+    let ingredient_item_id = 2000;
+
+    let estimate = |item_id: u32,
+                    vendor_value: u32,
+                    ingredient_item_id: u32,
+                    tp_prices: Vec<(u32, u32)>|
+     -> Option<(Money, crafting::Source)> {
+        let mut items_map = HashMap::new();
+        items_map.insert(item_id, Item::mock(item_id, "Output Item", vendor_value));
+        items_map.insert(
+            ingredient_item_id,
+            Item::mock(ingredient_item_id, "Ingredient", 0),
+        );
+
+        let mut recipes_map = HashMap::new();
+        recipes_map.insert(
+            item_id,
+            Recipe::mock(
+                300,
+                item_id,
+                1,
+                [],
+                &[RecipeIngredient {
+                    item_id: ingredient_item_id,
+                    count: 1,
+                }],
+                true,
+            ),
+        );
+
+        let tp_prices_map = tp_prices
+            .into_iter()
+            .map(|(id, sell_price)| {
+                (
+                    id,
+                    api::Price {
+                        id,
+                        buys: api::PriceInfo {
+                            unit_price: 0,
+                            quantity: 0,
+                        },
+                        sells: api::PriceInfo {
+                            unit_price: sell_price,
+                            quantity: 1,
+                        },
+                    },
+                )
+            })
+            .collect();
+
+        crafting::calculate_estimated_min_crafting_cost(
+            item_id,
+            &recipes_map,
+            &items_map,
+            &tp_prices_map,
+            &Default::default(),
+        )
+        .map(|cost| (cost.cost, cost.source))
+    };
+
+    let copper = Money::from_copper;
+
+    // all equal
+    assert_eq!(
+        estimate(
+            lump_of_tin_item_id,
+            1,
+            ingredient_item_id,
+            vec![(lump_of_tin_item_id, 8), (ingredient_item_id, 8)]
+        ),
+        Some((copper(8), crafting::Source::TradingPost))
+    );
+    // trading post = crafting < vendor
+    assert_eq!(
+        estimate(
+            lump_of_tin_item_id,
+            2,
+            ingredient_item_id,
+            vec![(lump_of_tin_item_id, 8), (ingredient_item_id, 8)]
+        ),
+        Some((copper(8), crafting::Source::TradingPost))
+    );
+    // trading post = vendor < crafting
+    assert_eq!(
+        estimate(
+            lump_of_tin_item_id,
+            1,
+            ingredient_item_id,
+            vec![(lump_of_tin_item_id, 8), (ingredient_item_id, 9)]
+        ),
+        Some((copper(8), crafting::Source::TradingPost))
+    );
+    // crafting = vendor < trading post
+    assert_eq!(
+        estimate(
+            lump_of_tin_item_id,
+            1,
+            ingredient_item_id,
+            vec![(lump_of_tin_item_id, 9), (ingredient_item_id, 8)]
+        ),
+        Some((copper(8), crafting::Source::Crafting))
+    );
+    // crafting = vendor with a currency token ingredient
+    assert_eq!(
+        estimate(lump_of_tin_item_id, 0, blade_shard_item_id, vec![]),
+        Some((copper(0), crafting::Source::Crafting))
+    );
+    // trading post = crafting with a currency token ingredient
+    assert_eq!(
+        estimate(
+            ingredient_item_id,
+            0,
+            blade_shard_item_id,
+            vec![(ingredient_item_id, 0)]
+        ),
+        Some((copper(0), crafting::Source::TradingPost))
+    );
+    // karma vendor items are ignored without a karma conversion factor
+    assert_eq!(
+        estimate(
+            apples_item_id,
+            0,
+            ingredient_item_id,
+            vec![(apples_item_id, 8), (ingredient_item_id, 8)]
+        ),
+        Some((copper(8), crafting::Source::TradingPost))
+    );
+}
+
+#[test]
+fn average_sell_offer_test() {
+    let mut listings = profit::ItemListings::from(
+        tp_listings_map(vec![(1000, vec![], vec![(100, 2), (200, 2)])])
+            .remove(&1000)
+            .unwrap(),
+    );
+
+    assert_eq!(listings.lowest_sell_offer(2), Some(200));
+    assert_eq!(
+        listings.average_sell_offer(2),
+        Some(Money::from_copper(200))
+    );
+
+    // marginal pricing charges the next cheapest listings, while average pricing spreads the cost
+    // of all purchases from the listings
+    listings.pending_buy_quantity = 2;
+    assert_eq!(listings.lowest_sell_offer(2), Some(400));
+    assert_eq!(
+        listings.average_sell_offer(2),
+        Some(Money::from_copper(300))
+    );
+    assert_eq!(listings.lowest_sell_offer(3), None);
+    assert_eq!(listings.average_sell_offer(3), None);
+}
+
+#[test]
+fn quantity_cheaper_than_test() {
+    let mut listings = profit::ItemListings::from(
+        tp_listings_map(vec![(1000, vec![], vec![(100, 2), (150, 1), (200, 2)])])
+            .remove(&1000)
+            .unwrap(),
+    );
+
+    assert_eq!(
+        listings.quantity_cheaper_than(Money::from_copper(100), 10),
+        0
+    );
+    assert_eq!(
+        listings.quantity_cheaper_than(Money::from_copper(150), 10),
+        2
+    );
+    assert_eq!(
+        listings.quantity_cheaper_than(Money::from_copper(200), 10),
+        3
+    );
+    assert_eq!(
+        listings.quantity_cheaper_than(Money::from_copper(200), 1),
+        1
+    );
+
+    listings.pending_buy_quantity = 1;
+    assert_eq!(
+        listings.quantity_cheaper_than(Money::from_copper(200), 10),
+        2
+    );
+}
+
+#[test]
+fn calculate_crafting_profit_with_no_ingredients_test() {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Output Item", 0));
+
+    let mut recipes_map = HashMap::new();
+    recipes_map.insert(1000, Recipe::mock(300, 1000, 1, [], &[], true));
+
+    assert_eq!(
+        recipe::mark_empty_recipes(&recipes_map),
+        vec![1000].into_iter().collect()
+    );
+
+    let tp_listings_map = tp_listings_map(vec![(1000, vec![(200, 10)], vec![(250, 10)])]);
+
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    );
+    assert!(profitable_item.is_none());
+}
+
+#[test]
+fn calculate_crafting_profit_with_duplicate_ingredients_test() {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Output Item", 0));
+    items_map.insert(2000, Item::mock(2000, "Ingredient", 0));
+
+    let mut recipes_map = HashMap::new();
+    let recipe = Recipe::mock(
+        300,
+        1000,
+        1,
+        [Discipline::MysticForge],
+        &[
+            RecipeIngredient {
+                item_id: 2000,
+                count: 1,
+            },
+            RecipeIngredient {
+                item_id: 2000,
+                count: 2,
+            },
+        ],
+        true,
+    );
+    assert_eq!(recipe.ingredients.len(), 1);
+    assert_eq!(recipe.ingredients[0].count, 3);
+    recipes_map.insert(1000, recipe);
+
+    // the 3 cheapest ingredients cost 10 + 20 + 30 together
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(200, 1)], vec![]),
+        (2000, vec![], vec![(10, 1), (20, 1), (30, 1), (40, 10)]),
+    ]);
+
+    let mut purchased_ingredients = HashMap::new();
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        Some(&mut purchased_ingredients),
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(profitable_item.crafting_cost, Money::from_copper(60));
+    assert_eq!(
+        purchased_ingredients
+            .get(&(2000, crafting::Source::TradingPost))
+            .map(|ingredient| ingredient.count),
+        Some(3)
+    );
+}
+
+#[test]
+fn profitable_item_list_estimate_delta_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 1, true);
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(200, 1)], vec![]),
+        (2000, vec![], vec![(60, 1)]),
+    ]);
+
+    let profitable_items = |estimated_cost| {
+        let mut estimated_costs = HashMap::new();
+        estimated_costs.insert(1000, Money::from_copper(estimated_cost));
+        profit::profitable_item_list(
+            &tp_listings_map,
+            &estimated_costs,
+            &vec![1000, 2000],
+            &recipes_map,
+            &items_map,
+            &HashMap::new(),
+        )
+    };
+
+    // the ingredient's price rose after it was estimated
+    let profitable_item = &profitable_items(50)[0];
+    assert_eq!(profitable_item.first_crafting_cost, Money::from_copper(60));
+    assert_eq!(profitable_item.estimate_delta, Some(Money::from_copper(10)));
+    assert!(profitable_item.price_moved());
+
+    let profitable_item = &profitable_items(60)[0];
+    assert_eq!(profitable_item.estimate_delta, Some(Money::from_copper(0)));
+    assert!(!profitable_item.price_moved());
+}
+
+#[test]
+fn include_recipe_cost_test() {
+    let (mut items_map, recipes_map) = mock_crafting_data(1, 1, false);
+    items_map.insert(3000, mock_recipe_sheet(3000, 300));
+    items_map.insert(3001, mock_recipe_sheet(3001, 300));
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 10)], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+    ]);
+
+    let mut recipe_prices = HashMap::new();
+    recipe_prices.insert(3000, mock_price(3000, 600));
+    recipe_prices.insert(3001, mock_price(3001, 500));
+    let recipe_cost = profit::recipe_unlock_cost(&[300], &recipe_prices, &items_map);
+    assert_eq!(recipe_cost, Money::from_copper(500));
+
+    let mut profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    )
+    .unwrap();
+    let profit = calc_revenue(vec![(10, 300)]) - Money::from_copper(10 * 100);
+    assert_eq!(profitable_item.profit, profit);
+
+    // the recipe is paid for once, not once per item
+    profitable_item.include_recipe_cost(recipe_cost);
+    assert_eq!(profitable_item.count, 10);
+    assert_eq!(profitable_item.profit, profit - Money::from_copper(500));
+    assert_eq!(profitable_item.profit_before_recipe_cost(), profit);
+    assert_eq!(
+        profitable_item.profit_per_item(),
+        (profit - Money::from_copper(500)) / 10
+    );
+}
+
+#[test]
+fn non_negative_cost_test() {
+    assert_eq!(
+        crafting::non_negative_cost(1000, Money::from_copper(5)),
+        Some(Money::from_copper(5))
+    );
+    assert_eq!(
+        crafting::non_negative_cost(1000, Money::from_copper(0)),
+        Some(Money::from_copper(0))
+    );
+    // paying 2688 copper for volatile magic without a conversion factor is a loss
+    let converted = Money::new(-2688, 0, 0, 200, 0, 0, 0) / 75;
+    assert_eq!(crafting::non_negative_cost(1000, converted), None);
+
+    // the conversion factor decides whether a cost is negative: without a gem rate, 2 gems are
+    // worth nothing against 150 copper, but at 100 copper per gem they make up for it
+    let coins_per_gem = 100;
+    assert_eq!(
+        crafting::non_negative_cost(1001, Money::new(-150, 0, 0, 0, 0, 0, 2)),
+        None
+    );
+    let converted = Money::from_copper(-150) + Money::from_copper(2 * coins_per_gem);
+    assert_eq!(
+        crafting::non_negative_cost(1002, converted),
+        Some(converted)
+    );
+    let converted = Money::from_copper(-250) + Money::from_copper(2 * coins_per_gem);
+    assert_eq!(crafting::non_negative_cost(1003, converted), None);
+}
+
+#[test]
+fn cached_result_test() {
+    let cache_dir = std::env::temp_dir().join(format!("gw2-arbitrage-test-{}", std::process::id()));
+    std::fs::create_dir_all(&cache_dir).unwrap();
+
+    let inputs = (1000, vec![(2000, 150)]);
+    let result = vec![(1000, Money::new(-2688, 0, 0, 200, 0, 0, 0) / 75)];
+    let (computed, is_cached) =
+        request::cached_result(&inputs, &cache_dir, || result.clone()).unwrap();
+    assert_eq!((computed, is_cached), (result.clone(), false));
+
+    let (reused, is_cached): (Vec<(u32, Money)>, _) =
+        request::cached_result(&inputs, &cache_dir, || unreachable!()).unwrap();
+    assert_eq!((reused, is_cached), (result.clone(), true));
+
+    // a price change computes the result again
+    let changed_inputs = (1000, vec![(2000, 151)]);
+    let (_, is_cached) =
+        request::cached_result(&changed_inputs, &cache_dir, || result.clone()).unwrap();
+    assert!(!is_cached);
+
+    std::fs::remove_dir_all(&cache_dir).unwrap();
+}
+
+#[test]
+fn output_distribution_test() {
+    let fixed = profit::OutputDistribution::fixed(7);
+    assert_eq!(fixed.mean(), 7.0);
+    assert_eq!(fixed.std_dev(), 0.0);
+    assert_eq!(fixed.count_with_probability(1.0), 7);
+
+    let random = profit::OutputDistribution::from_average(6.75);
+    assert!((random.mean() - 6.75).abs() < 1e-9);
+    assert!((random.std_dev() - 0.1875_f64.sqrt()).abs() < 1e-9);
+    assert_eq!(random.count_with_probability(0.5), 7);
+    assert_eq!(random.count_with_probability(0.75), 7);
+    assert_eq!(random.count_with_probability(0.9), 6);
+    assert_eq!(random.count_with_probability(1.0), 6);
+}
+
+#[test]
+fn calculate_crafting_profit_with_missing_listings_test() {
+    let (mut items_map, mut recipes_map) = mock_crafting_data(1, 1, true);
+    items_map.insert(3000, Item::mock(3000, "Sub-ingredient", 0));
+    recipes_map.insert(
+        2000,
+        Recipe::mock(
+            301,
+            2000,
+            1,
+            [],
+            &[RecipeIngredient {
+                item_id: 3000,
+                count: 1,
+            }],
+            true,
+        ),
+    );
+
+    // the intermediate has no listings at all, so can only be crafted
+    let listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 2)], vec![]),
+        (3000, vec![], vec![(100, 100)]),
+    ]);
+    let mut purchased_ingredients = HashMap::new();
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &listings_map,
+        Some(&mut purchased_ingredients),
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(profitable_item.count, 2);
+    assert_eq!(profitable_item.crafted_items.crafted.get(&2000), Some(&2));
+    assert!(!purchased_ingredients.contains_key(&(2000, crafting::Source::TradingPost)));
+
+    // the output item has no listings either, so there is nothing to sell into
+    let unlisted_output_map = tp_listings_map(vec![(3000, vec![], vec![(100, 100)])]);
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &unlisted_output_map,
+        None,
+        &Default::default(),
+    );
+    assert_eq!(profitable_item, None);
+}
+
+#[test]
+fn calculate_crafting_profit_expected_value_test() {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Output Item", 0));
+    items_map.insert(2000, Item::mock(2000, "Ingredient", 0));
+
+    // a 10% chance of 25 items per craft
+    let custom_recipes = gw2efficiency::read_custom_recipes(
+        r#"[
+            {
+                "name": "Output Item",
+                "output_item_id": 1000,
+                "output_item_count": 2.5,
+                "disciplines": ["Mystic Forge"],
+                "ingredients": [{ "item_id": 2000, "count": 1 }]
+            }
+        ]"#
+        .as_bytes(),
+    )
+    .unwrap();
+    let recipe = custom_recipes.into_iter().next().unwrap();
+    assert!(recipe.has_random_output());
+    assert_eq!(recipe.output_item_count, 2);
+    assert_eq!(recipe.output_item_average, Some(2.5));
+
+    // 2 crafts produce 5 items on average
+    let recipe = recipe.with_expected_output();
+    assert_eq!(recipe.output_item_count, 5);
+    assert_eq!(recipe.ingredients[0].count, 2);
+    assert_eq!(recipe.crafts(), 2);
+
+    let mut recipes_map = HashMap::new();
+    recipes_map.insert(1000, recipe);
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(200, 10)], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+    ]);
+
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(profitable_item.count, 10);
+    assert_eq!(profitable_item.crafting_cost, Money::from_copper(4 * 100));
+    assert_eq!(
+        profitable_item.profit,
+        calc_revenue(vec![(10, 200)]) - Money::from_copper(4 * 100)
+    );
+    // each scaled batch is 2 crafts
+    assert_eq!(profitable_item.crafting_steps, 4);
+}
+
+#[test]
+fn calculate_crafting_profit_with_currency_ingredient_test() {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Output Item", 0));
+    items_map.insert(2000, Item::mock(2000, "Ingredient", 0));
+    items_map.insert(3000, Item::mock(3000, "Karma Item", 0));
+
+    let custom_recipes = gw2efficiency::read_custom_recipes(
+        r#"[
+            {
+                "name": "Output Item",
+                "output_item_id": 1000,
+                "output_item_count": 2,
+                "disciplines": ["Mystic Forge"],
+                "ingredients": [{ "item_id": 2000, "count": 1 }],
+                "currencies": [{ "currency_id": 1, "count": 50 }]
+            },
+            {
+                "name": "Karma Item",
+                "output_item_id": 3000,
+                "output_item_count": 1,
+                "disciplines": ["Mystic Forge"],
+                "ingredients": [],
+                "currencies": [{ "currency_id": 2, "count": 1000 }]
+            }
+        ]"#
+        .as_bytes(),
+    )
+    .unwrap();
+    let recipes_map: HashMap<u32, Recipe> = custom_recipes
+        .into_iter()
+        .map(|recipe| (recipe.output_item_id, recipe))
+        .collect();
+    assert_eq!(recipes_map[&1000].currency_ingredients.len(), 1);
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 4)], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+        (3000, vec![(500, 1)], vec![]),
+    ]);
+
+    // the coins are spent once per craft of two items
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(profitable_item.count, 4);
+    assert_eq!(
+        profitable_item.crafting_cost,
+        Money::from_copper(2 * (100 + 50))
+    );
+    let mut tp_prices_map = HashMap::new();
+    tp_prices_map.insert(
+        2000,
+        api::Price {
+            id: 2000,
+            buys: api::PriceInfo {
+                unit_price: 0,
+                quantity: 0,
+            },
+            sells: api::PriceInfo {
+                unit_price: 100,
+                quantity: 100,
+            },
+        },
+    );
+    assert_eq!(
+        crafting::calculate_estimated_min_crafting_cost(
+            1000,
+            &recipes_map,
+            &items_map,
+            &tp_prices_map,
+            &Default::default(),
+        )
+        .map(|estimate| estimate.cost),
+        Some(Money::from_copper(100 + 50) / 2)
+    );
+
+    // without a configured karma value the karma can't be priced, rather than being free
+    assert!(recipes_map[&3000].wallet_currency_cost().is_none());
+    assert!(calculate_crafting_profit(
+        3000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    )
+    .is_none());
+}
+
+#[test]
+fn missed_profits_test() {
+    let (mut items_map, mut recipes_map) = mock_crafting_data(1, 1, true);
+    items_map.insert(3000, Item::mock(3000, "Sub-ingredient", 0));
+    items_map.insert(4000, mock_recipe_sheet(4000, 301));
+    // the intermediate is much cheaper to craft, but the recipe hasn't been learned
+    recipes_map.insert(
+        2000,
+        Recipe::mock(
+            301,
+            2000,
+            1,
+            [],
+            &[RecipeIngredient {
+                item_id: 3000,
+                count: 1,
+            }],
+            false,
+        ),
+    );
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 1)], vec![]),
+        (2000, vec![], vec![(200, 10)]),
+        (3000, vec![], vec![(50, 10)]),
+    ]);
+    let mut tp_prices_map = HashMap::new();
+    tp_prices_map.insert(4000, mock_price(4000, 1500));
+
+    let known_recipes = Some(HashSet::new());
+    let known_recipes_map = profit::known_recipes_map(&recipes_map, &known_recipes);
+    assert!(known_recipes_map.contains_key(&1000));
+    assert!(!known_recipes_map.contains_key(&2000));
+
+    let profitable_item = |recipes_map| {
+        calculate_crafting_profit(
+            1000,
+            recipes_map,
+            &items_map,
+            &tp_listings_map,
+            None,
+            &Default::default(),
+        )
+        .unwrap()
+    };
+    let profitable_items = vec![profitable_item(&recipes_map)];
+    let known_profitable_items = vec![profitable_item(&known_recipes_map)];
+
+    let missed_profits = profit::missed_profits(
+        &profitable_items,
+        &known_profitable_items,
+        &recipes_map,
+        &known_recipes,
+        &tp_prices_map,
+        &items_map,
+    );
+    assert_eq!(missed_profits.len(), 1);
+    let missed = &missed_profits[0];
+    assert_eq!(missed.id, 1000);
+    assert_eq!(
+        missed.profit,
+        calc_revenue(vec![(1, 300)]) - Money::from_copper(50)
+    );
+    assert_eq!(
+        missed.known_profit,
+        Some(calc_revenue(vec![(1, 300)]) - Money::from_copper(200))
+    );
+    assert_eq!(missed.missed_profit(), Money::from_copper(150));
+    assert_eq!(missed.unknown_recipes, vec![301]);
+    assert_eq!(missed.unlock_cost, Money::from_copper(1500));
+
+    // nothing is missed once the recipe is known
+    let known_recipes = Some(vec![301].into_iter().collect());
+    assert!(profit::missed_profits(
+        &profitable_items,
+        &profitable_items,
+        &recipes_map,
+        &known_recipes,
+        &tp_prices_map,
+        &items_map,
+    )
+    .is_empty());
+}
+
+#[test]
+fn find_promotion_profits_test() {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Tier 6 Material", 0));
+    items_map.insert(2000, Item::mock(2000, "Tier 5 Material", 0));
+    items_map.insert(3000, Item::mock(3000, "Philosopher's Stone", 0));
+
+    let promotion_recipes_map = |output_item_count| {
+        let recipe = Recipe::mock(
+            300,
+            1000,
+            output_item_count,
+            [Discipline::MysticForge],
+            &[
+                RecipeIngredient {
+                    item_id: 1000,
+                    count: 1,
+                },
+                RecipeIngredient {
+                    item_id: 2000,
+                    count: 50,
+                },
+                RecipeIngredient {
+                    item_id: 3000,
+                    count: 5,
+                },
+            ],
+            true,
+        );
+        assert!(recipe.is_promotion());
+        let mut map = HashMap::new();
+        map.insert(1000, recipe);
+        map
+    };
+
+    let price = |id, buy, sell| {
+        (
+            id,
+            api::Price {
+                id,
+                buys: api::PriceInfo {
+                    unit_price: buy,
+                    quantity: 1,
+                },
+                sells: api::PriceInfo {
+                    unit_price: sell,
+                    quantity: 1,
+                },
+            },
+        )
+    };
+    let tp_prices_map: HashMap<u32, api::Price> = vec![price(1000, 100, 110), price(2000, 5, 10)]
+        .into_iter()
+        .collect();
+
+    // 110 + 50 * 10 = 610 cost, 85 per item after fees
+    let promotions =
+        profit::find_promotion_profits(&tp_prices_map, &promotion_recipes_map(8), &items_map);
+    assert_eq!(promotions.len(), 1);
+    assert_eq!(promotions[0].cost, Money::from_copper(610));
+    assert_eq!(promotions[0].value, Money::from_copper(680));
+    assert_eq!(promotions[0].profit, Money::from_copper(70));
+    assert_eq!(promotions[0].unpriced_ingredients.len(), 1);
+    assert_eq!(promotions[0].unpriced_ingredients[0].item_id, 3000);
+
+    assert!(
+        profit::find_promotion_profits(&tp_prices_map, &promotion_recipes_map(7), &items_map)
+            .is_empty()
+    );
+
+    // 7 items make a loss, but producing 8 half of the time is profitable on average
+    let mut random_recipes_map = promotion_recipes_map(7);
+    random_recipes_map
+        .get_mut(&1000)
+        .unwrap()
+        .output_item_average = Some(7.5);
+    let promotions =
+        profit::find_promotion_profits(&tp_prices_map, &random_recipes_map, &items_map);
+    assert_eq!(promotions.len(), 1);
+    assert_eq!(promotions[0].profit, Money::from_copper(595 - 610));
+    assert_eq!(promotions[0].expected_profit, Money::from_copper(28)); // 637.5 - 610, rounded
+    assert_eq!(promotions[0].profit_std_dev, Money::from_copper(43)); // 85 * 0.5, rounded
+}
+
+#[test]
+fn find_vendor_sell_items_test() {
+    let test_data = |vendor_value| {
+        let (mut items_map, recipes_map) = mock_crafting_data(1, 3, true);
+        items_map.insert(1000, Item::mock(1000, "Output Item", vendor_value));
+        (items_map, recipes_map)
+    };
+    let mut tp_prices_map = HashMap::new();
+    tp_prices_map.insert(
+        2000,
+        api::Price {
+            id: 2000,
+            buys: api::PriceInfo {
+                unit_price: 40,
+                quantity: 1,
+            },
+            sells: api::PriceInfo {
+                unit_price: 50,
+                quantity: 1,
+            },
+        },
+    );
+
+    let (items_map, recipes_map) = test_data(200);
+    let vendor_sell_items =
+        profit::find_vendor_sell_items(&tp_prices_map, &recipes_map, &items_map);
+    assert_eq!(
+        vendor_sell_items,
+        vec![profit::VendorSellItem {
+            id: 1000,
+            crafting_cost: Money::from_copper(150),
+            vendor_value: Money::from_copper(200),
+            profit: Money::from_copper(50),
+        }]
+    );
+
+    let (items_map, recipes_map) = test_data(150);
+    assert!(profit::find_vendor_sell_items(&tp_prices_map, &recipes_map, &items_map).is_empty());
+}
+
+#[test]
+fn is_scan_candidate_restricted_test() {
+    let account_bound_item: Item = serde_json::from_str(
+        r#"{
+            "id": 1000,
+            "name": "Account Bound Item",
+            "vendor_value": 0,
+            "type": "Armor",
+            "rarity": "Ascended",
+            "level": 80,
+            "flags": ["AccountBound"],
+            "restrictions": []
+        }"#,
+    )
+    .unwrap();
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, account_bound_item);
+    items_map.insert(1001, Item::mock(1001, "Tradable Item", 0));
+
+    let recipe = |item_id| Recipe::mock(300, item_id, 1, [], &[], true);
+
+    assert!(!profit::is_scan_candidate(
+        1000,
+        &recipe(1000),
+        &items_map,
+        profit::Intent::Sell
+    ));
+    for intent in [profit::Intent::Acquire, profit::Intent::VendorSale] {
+        assert!(profit::is_scan_candidate(
+            1000,
+            &recipe(1000),
+            &items_map,
+            intent
+        ));
+    }
+    for intent in [
+        profit::Intent::Sell,
+        profit::Intent::Acquire,
+        profit::Intent::VendorSale,
+    ] {
+        assert!(profit::is_scan_candidate(
+            1001,
+            &recipe(1001),
+            &items_map,
+            intent
+        ));
+    }
+
+    // restricted items can still be acquired by crafting them
+    items_map.insert(2000, Item::mock(2000, "Ingredient", 0));
+    let mut recipes_map = HashMap::new();
+    recipes_map.insert(
+        1000,
+        Recipe::mock(
+            300,
+            1000,
+            1,
+            [],
+            &[RecipeIngredient {
+                item_id: 2000,
+                count: 1,
+            }],
+            true,
+        ),
+    );
+    let tp_listings_map = tp_listings_map(vec![(2000, vec![], vec![(50, 1)])]);
+    let acquired_item = calculate_acquisition_cost(
+        1000,
+        1,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(acquired_item.cost, Money::from_copper(50));
+    assert_eq!(acquired_item.source, crafting::Source::Crafting);
+}
+
+#[test]
+fn recipe_output_count_within_test() {
+    let recipe = Recipe::mock(300, 1000, 5, [], &[], true);
+    assert!(recipe.is_output_count_within(None, None));
+    assert!(recipe.is_output_count_within(Some(5), Some(5)));
+    assert!(recipe.is_output_count_within(Some(2), None));
+    assert!(!recipe.is_output_count_within(Some(6), None));
+    assert!(!recipe.is_output_count_within(None, Some(4)));
+}
+
+#[test]
+fn unknown_item_ids_test() {
+    let (items_map, mut recipes_map) = mock_crafting_data(1, 1, true);
+    recipes_map
+        .get_mut(&1000)
+        .unwrap()
+        .ingredients
+        .push(RecipeIngredient {
+            item_id: 2001,
+            count: 1,
+        });
+    recipes_map.insert(
+        1001,
+        Recipe::mock(
+            301,
+            1001,
+            1,
+            [],
+            &[RecipeIngredient {
+                item_id: 2000,
+                count: 1,
+            }],
+            true,
+        ),
+    );
+
+    assert_eq!(
+        recipe::unknown_item_ids(&recipes_map, &items_map),
+        [1001, 2001].iter().copied().collect::<HashSet<u32>>()
+    );
+}
+
+#[test]
+fn recipe_graph_hash_test() {
+    let recipes_map = |ingredient_id| {
+        let mut recipes_map = HashMap::new();
+        recipes_map.insert(
+            1000,
+            Recipe::mock(
+                300,
+                1000,
+                1,
+                [],
+                &[RecipeIngredient {
+                    item_id: ingredient_id,
+                    count: 1,
+                }],
+                true,
+            ),
+        );
+        recipes_map
+    };
+
+    assert_eq!(
+        recipe::recipe_graph_hash(&recipes_map(2000)),
+        recipe::recipe_graph_hash(&recipes_map(2000))
+    );
+    assert_ne!(
+        recipe::recipe_graph_hash(&recipes_map(2000)),
+        recipe::recipe_graph_hash(&recipes_map(2001))
+    );
+}
+
+#[test]
+fn profit_keeping_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 1, true);
+
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(1000, 2), (500, 3)], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+    ]);
+
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(profitable_item.count, 5);
+
+    // the two kept items would have sold into the 1000 copper buy orders, so keeping them saves
+    // the fees on those sales
+    let kept_profit = profitable_item
+        .profit_keeping(2, tp_listings_map.get(&1000), &Default::default())
+        .unwrap();
+    assert_eq!(kept_profit.kept, 2);
+    assert_eq!(kept_profit.value, Money::from_copper(2000));
+    assert_eq!(
+        kept_profit.profit,
+        Money::from_copper(2000) + calc_revenue(vec![(3, 500)]) - Money::from_copper(500)
+    );
+
+    // no more can be kept than were crafted
+    let kept_profit = profitable_item
+        .profit_keeping(10, tp_listings_map.get(&1000), &Default::default())
+        .unwrap();
+    assert_eq!(kept_profit.kept, 5);
+    assert_eq!(
+        kept_profit.profit,
+        Money::from_copper(3500) - Money::from_copper(500)
+    );
+
+    // with --value the item is crafted without buy orders, and kept items have the same value
+    let value_opt = CraftingOptions {
+        value: Some(1000),
+        count: Some(3),
+        ..Default::default()
+    };
+    let no_buys_listings_map = crate::tp_listings_map(vec![
+        (1000, vec![], vec![]),
+        (2000, vec![], vec![(100, 100)]),
+    ]);
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &no_buys_listings_map,
+        None,
+        &value_opt,
+    )
+    .unwrap();
+    assert_eq!(profitable_item.count, 3);
+    let kept_profit = profitable_item
+        .profit_keeping(2, no_buys_listings_map.get(&1000), &value_opt)
+        .unwrap();
+    assert_eq!(kept_profit.kept, 2);
+    assert_eq!(kept_profit.value, Money::from_copper(2000));
+    assert_eq!(kept_profit.profit, profitable_item.profit);
+    assert_eq!(
+        profitable_item.profit_keeping(2, None, &value_opt),
+        Some(kept_profit)
+    );
+    // without --value, items without buy orders to sell into can't be valued
+    assert_eq!(
+        profitable_item.profit_keeping(2, no_buys_listings_map.get(&1000), &Default::default()),
+        None
+    );
+}
+
+#[test]
+fn feasibility_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 2, false);
+
+    // enough ingredients for 3 crafts, and none of the output for sale
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![], vec![]),
+        (2000, vec![], vec![(100, 4), (150, 3)]),
+    ]);
+
+    let feasibility = |count, known_recipes: &Option<HashSet<u32>>| {
+        profit::feasibility(
+            1000,
+            count,
+            &recipes_map,
+            &items_map,
+            &tp_listings_map,
+            known_recipes,
+            &Default::default(),
+        )
+    };
+
+    let known_recipes = Some(vec![300].into_iter().collect());
+    let report = feasibility(2, &known_recipes);
+    assert!(report.feasible);
+    assert_eq!(report.max_count, 2);
+    assert_eq!(report.cost, Some(Money::from_copper(4 * 100)));
+    assert_eq!(report.source, Some(crafting::Source::Crafting));
+
+    let report = feasibility(5, &known_recipes);
+    assert!(!report.feasible);
+    assert_eq!(report.max_count, 3);
+    assert_eq!(report.cost, Some(Money::from_copper(4 * 100 + 2 * 150)));
+    assert!(report.unknown_recipes.is_empty());
+    assert!(report.currency_gated.is_empty());
+
+    let report = feasibility(1, &Some(HashSet::new()));
+    assert!(!report.feasible);
+    assert!(report.recipes_checked);
+    assert_eq!(report.unknown_recipes, vec![300]);
+
+    // without known recipes they can't be checked, so aren't held against the item
+    let report = feasibility(1, &None);
+    assert!(report.feasible);
+    assert!(!report.recipes_checked);
+}
+
+#[test]
+fn unknown_achievement_recipes_test() {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Output Item", 0));
+    items_map.insert(2000, Item::mock(2000, "Ingredient", 0));
+    let custom_recipes = gw2efficiency::read_custom_recipes(
+        r#"[
+            {
+                "name": "Output Item",
+                "output_item_id": 1000,
+                "output_item_count": 1,
+                "disciplines": ["Achievement"],
+                "ingredients": [{ "item_id": 2000, "count": 1 }]
+            }
+        ]"#
+        .as_bytes(),
+    )
+    .unwrap();
+    let mut recipes_map = HashMap::new();
+    recipes_map.insert(1000, custom_recipes.into_iter().next().unwrap());
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 1)], vec![]),
+        (2000, vec![], vec![(100, 1)]),
+    ]);
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &Default::default(),
+    )
+    .unwrap();
+    let crafted_items = &profitable_item.crafted_items;
+
+    // achievement recipes have no id, so are never among the unknown recipe ids
+    let known_recipes = Some(HashSet::new());
+    assert!(crafted_items
+        .unknown_recipes(&recipes_map, &known_recipes)
+        .is_empty());
+    // without a list of known achievement recipes, they are assumed to be known
+    assert!(crafted_items
+        .unknown_achievement_recipes(&recipes_map, &None)
+        .is_empty());
+    assert_eq!(
+        crafted_items.unknown_achievement_recipes(&recipes_map, &Some(HashSet::new())),
+        vec![1000]
+    );
+    assert!(crafted_items
+        .unknown_achievement_recipes(&recipes_map, &Some(vec![1000].into_iter().collect()))
+        .is_empty());
+}
+
+#[test]
+fn ingredient_pricing_test() {
+    let (mut items_map, mut recipes_map) = mock_crafting_data(1, 1, true);
+    items_map.insert(3000, Item::mock(3000, "Intermediate Item", 0));
+    // the ingredient is bought twice per craft, directly and for the intermediate item
+    recipes_map
+        .get_mut(&1000)
+        .unwrap()
+        .ingredients
+        .push(RecipeIngredient {
+            item_id: 3000,
+            count: 1,
+        });
+    recipes_map.insert(
+        3000,
+        Recipe::mock(
+            301,
+            3000,
+            1,
+            [],
+            &[RecipeIngredient {
+                item_id: 2000,
+                count: 1,
+            }],
+            true,
+        ),
+    );
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(600, 10)], vec![]),
+        (2000, vec![], vec![(100, 1), (150, 1), (200, 30)]),
+    ]);
+    let profit = |ingredient_pricing| {
+        let mut purchased_ingredients = HashMap::new();
+        let profitable_item = calculate_crafting_profit(
+            1000,
+            &recipes_map,
+            &items_map,
+            &tp_listings_map,
+            Some(&mut purchased_ingredients),
+            &CraftingOptions {
+                ingredient_pricing,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let purchased = purchased_ingredients
+            .remove(&(2000, crafting::Source::TradingPost))
+            .unwrap();
+        (profitable_item, purchased)
+    };
+
+    let (marginal_item, purchased) = profit(IngredientPricing::Marginal);
+    assert_eq!(marginal_item.count, 10);
+    assert_eq!(
+        marginal_item.crafting_cost,
+        Money::from_copper(100 + 150 + 9 * 2 * 200)
+    );
+    assert_eq!(purchased.count, 20);
+    assert_eq!(purchased.total_cost, marginal_item.crafting_cost);
+
+    // the second purchase in a craft is priced at the average of both, and the purchases are
+    // recorded at the price used to choose to buy them
+    let (average_item, purchased) = profit(IngredientPricing::Average);
+    assert_eq!(average_item.count, 10);
+    assert_eq!(
+        average_item.crafting_cost,
+        Money::from_copper(100 + 125 + 9 * 2 * 200)
+    );
+    assert_eq!(purchased.count, 20);
+    assert_eq!(purchased.total_cost, average_item.crafting_cost);
+}
+
+#[test]
+fn calculate_crafting_profit_with_fees_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 2, true);
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 3)], vec![]),
+        (2000, vec![], vec![(100, 10)]),
+    ]);
+    let profit = |opt: &CraftingOptions| {
+        calculate_crafting_profit(1000, &recipes_map, &items_map, &tp_listings_map, None, opt)
+    };
+
+    let profitable_item = profit(&Default::default()).unwrap();
+    assert_eq!(profitable_item.count, 3);
+    assert_eq!(profitable_item.profit, Money::from_copper(3 * (255 - 200)));
+    assert_eq!(profitable_item.tax, Money::from_copper(3 * 45));
+
+    let no_fees = CraftingOptions {
+        listing_fee_percent: Some(0),
+        exchange_fee_percent: Some(0),
+        ..Default::default()
+    };
+    let profitable_item = profit(&no_fees).unwrap();
+    assert_eq!(profitable_item.count, 3);
+    assert_eq!(profitable_item.profit, Money::from_copper(3 * (300 - 200)));
+    assert_eq!(profitable_item.tax, Money::from_copper(0));
+    assert_eq!(profitable_item.breakeven, Money::from_copper(200));
+    // no fees are saved by keeping items
+    let kept_profit = profitable_item
+        .profit_keeping(2, tp_listings_map.get(&1000), &no_fees)
+        .unwrap();
+    assert_eq!(kept_profit.value, Money::from_copper(2 * 300));
+    assert_eq!(kept_profit.profit, profitable_item.profit);
+
+    // 20% of 300 each leaves less than the cost of crafting
+    let high_fees = CraftingOptions {
+        listing_fee_percent: Some(20),
+        exchange_fee_percent: Some(20),
+        ..Default::default()
+    };
+    assert!(profit(&high_fees).is_none());
+}
+
+#[test]
+fn sort_profitable_items_test() {
+    let mut profitable_items = vec![
+        ProfitableItem::mock(1, 10, 1000, 10000, 100),
+        ProfitableItem::mock(2, 2, 600, 1000, 20),
+        ProfitableItem::mock(3, 4, 800, 8000, 4),
+        ProfitableItem::mock(4, 3, 600, 6000, 20),
+    ];
+    let sorted_ids = |profitable_items: &[ProfitableItem]| {
+        profitable_items
+            .iter()
+            .map(|item| item.id)
+            .collect::<Vec<_>>()
+    };
+
+    profit::sort_profitable_items(&mut profitable_items, SortBy::Profit);
+    assert_eq!(sorted_ids(&profitable_items), vec![2, 4, 3, 1]);
+
+    profit::sort_profitable_items(&mut profitable_items, SortBy::ProfitPerItem);
+    assert_eq!(sorted_ids(&profitable_items), vec![1, 4, 3, 2]);
+
+    // items 2 and 4 both make 30 per step, and have the same total profit
+    profit::sort_profitable_items(&mut profitable_items, SortBy::ProfitPerStep);
+    assert_eq!(sorted_ids(&profitable_items), vec![1, 2, 4, 3]);
+
+    // items 1, 3 and 4 all make 10% on cost
+    profit::sort_profitable_items(&mut profitable_items, SortBy::ProfitOnCost);
+    assert_eq!(sorted_ids(&profitable_items), vec![4, 3, 1, 2]);
+}
+
+#[test]
+fn storage_cost_test() {
+    let purchased_ingredient = |count| PurchasedIngredient {
+        count,
+        max_price: Money::from_copper(0),
+        min_price: Money::from_copper(0),
+        total_cost: Money::from_copper(0),
+        listings: vec![],
+    };
+    let mut purchased_ingredients = HashMap::new();
+    purchased_ingredients.insert(
+        (2000, crafting::Source::TradingPost),
+        purchased_ingredient(10),
+    );
+    purchased_ingredients.insert(
+        (3000, crafting::Source::TradingPost),
+        purchased_ingredient(251),
+    );
+    assert_eq!(
+        profit::inventory_slots(&purchased_ingredients, &HashMap::new()),
+        1 + 2
+    );
+
+    // a compact craft overtakes one which needs more slots
+    let mut profitable_items = vec![
+        ProfitableItem::mock(1, 10, 800, 8000, 10),
+        ProfitableItem::mock(2, 10, 900, 8000, 10),
+    ];
+    profitable_items[1].include_storage_cost(Money::from_copper(200));
+    assert_eq!(profitable_items[1].profit, Money::from_copper(700));
+    assert!(!profitable_items[1].meets_min_roi(10));
+    profit::sort_profitable_items(&mut profitable_items, SortBy::Profit);
+    assert_eq!(profitable_items[1].id, 1);
+
+    // including the cost again replaces it
+    profitable_items[0].include_storage_cost(Money::from_copper(100));
+    assert_eq!(profitable_items[0].profit, Money::from_copper(800));
+    assert_eq!(profitable_items[0].storage_cost, Money::from_copper(100));
+}
+
+#[test]
+fn meets_min_roi_test() {
+    let profitable_item =
+        |profit, crafting_cost| ProfitableItem::mock(1000, 1, profit, crafting_cost, 1);
+
+    // high margin, low absolute profit
+    let cheap_item = profitable_item(50, 100);
+    // low margin, high absolute profit
+    let expensive_item = profitable_item(50000, 1000000);
+    assert!(cheap_item.meets_min_roi(20));
+    assert!(!expensive_item.meets_min_roi(20));
+    assert!(expensive_item.meets_min_roi(5));
+    // exactly on the minimum
+    assert!(cheap_item.meets_min_roi(50));
+    assert!(!cheap_item.meets_min_roi(51));
+    // costs in other currencies without a configured rate are free
+    let karma_item = ProfitableItem {
+        crafting_cost: Money::new(100, 1000, 0, 0, 0, 0, 0),
+        ..profitable_item(50, 0)
+    };
+    assert!(karma_item.meets_min_roi(50));
+    // amounts that would overflow when multiplied by the percentage
+    let gold_item = profitable_item(25_000_000, 25_000_000);
+    assert!(gold_item.meets_min_roi(100));
+    assert!(!gold_item.meets_min_roi(101));
+    let large_item = profitable_item(500_000_000, 1_000_000_000);
+    assert!(large_item.meets_min_roi(50));
+    assert!(!large_item.meets_min_roi(51));
+}
+
+#[test]
+fn best_sale_venue_test() {
+    let opt = CraftingOptions::default();
+    let crafting_cost = Money::from_copper(100);
+    let tp_prices = |buy_price, sell_price| api::Price {
+        id: 1000,
+        buys: api::PriceInfo {
+            unit_price: buy_price,
+            quantity: 1,
+        },
+        sells: api::PriceInfo {
+            unit_price: sell_price,
+            quantity: 1,
+        },
+    };
+    let best_venue = |vendor_value, tp_prices: Option<&api::Price>| {
+        let item = Item::mock(1000, "Output Item", vendor_value);
+        profit::best_sale_venue(Some(&item), tp_prices, crafting_cost, &opt)
+            .map(|venue_profit| (venue_profit.venue, venue_profit.profit))
+    };
+
+    // buy orders close to the lowest listing
+    assert_eq!(
+        best_venue(0, Some(&tp_prices(200, 201))),
+        Some((SaleVenue::BuyOrder, Money::from_copper(170 - 100)))
+    );
+    // a wide spread makes listing more profitable, even after 5% of the price is lost to holding
+    // the listing until it sells
+    assert_eq!(
+        best_venue(0, Some(&tp_prices(200, 301))),
+        Some((SaleVenue::Listing, Money::from_copper(255 - 15 - 100)))
+    );
+    // merchants pay more than the trading post after fees
+    assert_eq!(
+        best_venue(200, Some(&tp_prices(200, 201))),
+        Some((SaleVenue::Vendor, Money::from_copper(200 - 100)))
+    );
+    // account bound items can only be sold to merchants
+    assert_eq!(
+        best_venue(50, None),
+        Some((SaleVenue::Vendor, Money::from_copper(50 - 100)))
+    );
+    assert_eq!(best_venue(0, None), None);
+    // equal profits prefer the venue which sells immediately
+    assert_eq!(
+        best_venue(170, Some(&tp_prices(200, 201))),
+        Some((SaleVenue::BuyOrder, Money::from_copper(170 - 100)))
+    );
+}
+
+#[test]
+fn calculate_crafting_profit_sale_venues_test() {
+    let (mut items_map, recipes_map) = mock_crafting_data(1, 2, true);
+    items_map.insert(1000, Item::mock(1000, "Output Item", 230));
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(250, 1), (300, 2)], vec![(400, 2)]),
+        (2000, vec![], vec![(100, 10)]),
+    ]);
+    let profit = |sale_venues: Vec<SaleVenue>, listing_hold_cost_percent| {
+        let opt = CraftingOptions {
+            sale_venues,
+            listing_hold_cost_percent,
+            ..Default::default()
+        };
+        calculate_crafting_profit(1000, &recipes_map, &items_map, &tp_listings_map, None, &opt)
+            .unwrap()
+    };
+
+    let sale_revenue = |price| CraftingOptions::default().sale_revenue(Money::from_copper(price));
+    let listing_revenue =
+        |price| CraftingOptions::default().listing_revenue(Money::from_copper(price));
+
+    // only buy orders by default, down to the last one at 250
+    let profitable_item = profit(vec![], None);
+    assert_eq!(profitable_item.sales, vec![(SaleVenue::BuyOrder, 3)]);
+    assert_eq!(
+        profitable_item.profit,
+        sale_revenue(300) * 2 + sale_revenue(250) - Money::from_copper(3 * 200)
+    );
+
+    // the merchant pays more than the buy order at 250 after fees, and isn't limited by demand
+    let profitable_item = profit(vec![SaleVenue::BuyOrder, SaleVenue::Vendor], None);
+    assert_eq!(
+        profitable_item.sales,
+        vec![(SaleVenue::BuyOrder, 2), (SaleVenue::Vendor, 3)]
+    );
+    assert_eq!(profitable_item.limited_by, CraftingLimit::Supply);
+    assert_eq!(
+        profitable_item.profit,
+        Money::from_copper(2 * (255 - 200) + 3 * (230 - 200))
+    );
+
+    // undercutting the lowest listing at 399 beats every other venue, but only as many can be
+    // listed as are already listed at that price
+    let all_venues = vec![SaleVenue::BuyOrder, SaleVenue::Listing, SaleVenue::Vendor];
+    let profitable_item = profit(all_venues.clone(), None);
+    assert_eq!(
+        profitable_item.sales,
+        vec![
+            (SaleVenue::Listing, 2),
+            (SaleVenue::BuyOrder, 2),
+            (SaleVenue::Vendor, 1)
+        ]
+    );
+    assert_eq!(
+        profitable_item.tax,
+        (Money::from_copper(399) - listing_revenue(399)) * 2
+            + (Money::from_copper(300) - sale_revenue(300)) * 2
+    );
+
+    // losing 30% of the price while the listing waits to sell makes the other venues pay more
+    let profitable_item = profit(all_venues, Some(30));
+    assert_eq!(
+        profitable_item.sales,
+        vec![(SaleVenue::BuyOrder, 2), (SaleVenue::Vendor, 3)]
+    );
+}
+
+#[test]
+fn inventory_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 2, true);
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 3)], vec![]),
+        (2000, vec![], vec![(100, 10)]),
+    ]);
+    let profit = |inventory: Vec<(u32, u32)>| {
+        let mut purchased_ingredients = HashMap::new();
+        let profitable_item = calculate_crafting_profit(
+            1000,
+            &recipes_map,
+            &items_map,
+            &tp_listings_map,
+            Some(&mut purchased_ingredients),
+            &CraftingOptions {
+                inventory: inventory.into_iter().collect(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let bought = purchased_ingredients
+            .get(&(2000, crafting::Source::TradingPost))
+            .map(|ingredient| ingredient.count);
+        (profitable_item, bought)
+    };
+
+    let (profitable_item, bought) = profit(vec![]);
+    assert_eq!(profitable_item.count, 3);
+    assert_eq!(bought, Some(6));
+
+    // owning 2 ingredients covers the first craft
+    let (owned_item, bought) = profit(vec![(2000, 2)]);
+    assert_eq!(owned_item.count, 3);
+    assert_eq!(bought, Some(4));
+    assert_eq!(owned_item.crafting_cost, Money::from_copper(4 * 100));
+    assert_eq!(
+        owned_item.profit,
+        profitable_item.profit + Money::from_copper(2 * 100)
+    );
+    assert_eq!(owned_item.crafted_items.used_leftovers.get(&2000), Some(&2));
+    assert!(owned_item.crafted_items.leftovers.is_empty());
+    assert!(owned_item
+        .crafted_items
+        .remaining_inventory(&vec![(2000, 2)].into_iter().collect())
+        .is_empty());
+
+    // owned ingredients which aren't needed aren't leftovers of the craft, and owned output items
+    // aren't sold as if they were crafted
+    let (owned_item, bought) = profit(vec![(2000, 10), (1000, 5)]);
+    assert_eq!(owned_item.count, 3);
+    assert_eq!(bought, None);
+    assert_eq!(owned_item.crafting_cost, Money::from_copper(0));
+    assert!(owned_item.crafted_items.leftovers.is_empty());
+    // the next item priced only has what is left
+    let inventory = vec![(2000, 10), (1000, 5)].into_iter().collect();
+    assert_eq!(
+        owned_item.crafted_items.remaining_inventory(&inventory),
+        vec![(2000, 4), (1000, 5)].into_iter().collect()
+    );
+}
+
+#[test]
+fn chain_values_test() {
+    let (mut items_map, mut recipes_map) = mock_crafting_data(1, 2, true);
+    items_map.insert(3000, Item::mock(3000, "Sub-ingredient", 0));
+    recipes_map.insert(
+        2000,
+        Recipe::mock(
+            301,
+            2000,
+            1,
+            [],
+            &[RecipeIngredient {
+                item_id: 3000,
+                count: 1,
+            }],
+            true,
+        ),
+    );
+    let buy_price = |id, unit_price, quantity| api::Price {
+        buys: api::PriceInfo {
+            unit_price,
+            quantity,
+        },
+        ..mock_price(id, 0)
+    };
+    let mut tp_prices_map = HashMap::new();
+    tp_prices_map.insert(1000, buy_price(1000, 1000, 5));
+    tp_prices_map.insert(2000, buy_price(2000, 100, 50));
+    tp_prices_map.insert(3000, mock_price(3000, 50));
+
+    // 2 intermediates break even at 1000 - 15% for the output, and the 5 buy orders for the
+    // output can only use 10 of them
+    let chain_values = profit::chain_values(&tp_prices_map, &recipes_map, &items_map);
+    assert_eq!(
+        chain_values.get(&2000),
+        Some(&ChainValue {
+            value: Money::from_copper(850 / 2),
+            demand: 10,
+        })
+    );
+
+    // without buy orders for the output there is no demand from the recipe
+    tp_prices_map.insert(1000, buy_price(1000, 1000, 0));
+    let chain_values = profit::chain_values(&tp_prices_map, &recipes_map, &items_map);
+    assert_eq!(chain_values.get(&2000), None);
+}
+
+#[test]
+fn find_undercut_items_test() {
+    let (items_map, recipes_map) = mock_crafting_data(1, 3, true);
+    let mut tp_prices_map = HashMap::new();
+    tp_prices_map.insert(1000, mock_price(1000, 300));
+    tp_prices_map.insert(2000, mock_price(2000, 50));
+
+    // undercutting the lowest listing by 1 copper still leaves a profit after fees
+    let sell_price = Money::from_copper(299);
+    let undercut_items = profit::find_undercut_items(&tp_prices_map, &recipes_map, &items_map);
+    assert_eq!(
+        undercut_items,
+        vec![profit::UndercutItem {
+            id: 1000,
+            crafting_cost: Money::from_copper(150),
+            sell_price,
+            profit: CraftingOptions::default().sale_revenue(sell_price) - Money::from_copper(150),
+        }]
+    );
+
+    // the listing is too cheap to undercut once fees are paid
+    tp_prices_map.insert(1000, mock_price(1000, 170));
+    assert!(profit::find_undercut_items(&tp_prices_map, &recipes_map, &items_map).is_empty());
+
+    // without sell listings there is nothing to undercut
+    tp_prices_map.insert(
+        1000,
+        api::Price {
+            sells: api::PriceInfo {
+                unit_price: 0,
+                quantity: 0,
+            },
+            ..mock_price(1000, 0)
+        },
+    );
+    assert!(profit::find_undercut_items(&tp_prices_map, &recipes_map, &items_map).is_empty());
+}
+
+#[test]
+fn find_currency_conversions_test() {
+    let mut items_map = HashMap::new();
+    // 50025 == Blade Shard, an account bound token with no value
+    items_map.insert(50025, Item::mock(50025, "Blade Shard", 0));
+    // 19792 == Spool of Jute Thread, bought from a master craftsman for 8 times its vendor value
+    items_map.insert(19792, Item::mock(19792, "Spool of Jute Thread", 1));
+    items_map.insert(2000, Item::mock(2000, "Trading Post Item", 0));
+
+    let recipe = |ingredients: &[RecipeIngredient]| {
+        Recipe::mock(300, 50025, 1, [Discipline::MysticForge], ingredients, false)
+    };
+    let vendor_ingredient = RecipeIngredient {
+        item_id: 19792,
+        count: 3,
+    };
+    let tp_ingredient = RecipeIngredient {
+        item_id: 2000,
+        count: 1,
+    };
+
+    assert_eq!(
+        recipe(&[vendor_ingredient]).currency_cost(&items_map),
+        Some(Money::from_copper(24))
+    );
+    // ingredients bought on the trading post aren't a currency conversion
+    assert_eq!(
+        recipe(&[vendor_ingredient, tp_ingredient]).currency_cost(&items_map),
+        None
+    );
+
+    // spending coins on a token worth nothing is not profitable
+    let mut recipes_map = HashMap::new();
+    recipes_map.insert(50025, recipe(&[vendor_ingredient]));
+    assert!(profit::find_currency_conversions(&recipes_map, &items_map).is_empty());
+    recipes_map.insert(50025, recipe(&[tp_ingredient]));
+    assert!(profit::find_currency_conversions(&recipes_map, &items_map).is_empty());
+}
+
+#[test]
+fn recipe_stations_test() {
+    assert_eq!("guildhall".parse(), Ok(Station::GuildHall));
+    assert_eq!("mysticforge".parse(), Ok(Station::MysticForge));
+
+    let recipe = Recipe::mock(
+        300,
+        1000,
+        1,
+        [
+            Discipline::Scribe,
+            Discipline::Armorsmith,
+            Discipline::Tailor,
+        ],
+        &[],
+        true,
+    );
+    assert_eq!(
+        recipe.stations(),
+        vec![Station::GuildHall, Station::Crafting]
+    );
+    assert!(recipe.is_only_craftable_at(&[Station::GuildHall, Station::Crafting]));
+    // the recipe can still be crafted at a crafting station without a guild hall
+    assert!(!recipe.is_only_craftable_at(&[Station::GuildHall]));
+
+    let recipe = Recipe::mock(301, 1000, 1, [Discipline::Scribe], &[], true);
+    assert!(recipe.is_only_craftable_at(&[Station::GuildHall, Station::MysticForge]));
+
+    // recipes without disciplines aren't excluded by any station
+    let recipe = Recipe::mock(302, 1000, 1, [], &[], true);
+    assert!(!recipe.is_only_craftable_at(&[Station::GuildHall]));
+}
+
+#[tokio::test]
+async fn get_custom_recipes_test() {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/recipes.json", listener.local_addr().unwrap());
+    // answers two requests, returning whether each one was conditional on the stored etag
+    let server = std::thread::spawn(move || {
+        let body = r#"[{
+            "name": "Output Item",
+            "output_item_id": 1000,
+            "output_item_count": 1,
+            "disciplines": ["Mystic Forge"],
+            "ingredients": [{ "item_id": 2000, "count": 1 }]
+        }]"#;
+        let mut conditional = vec![];
+        for stream in listener.incoming().take(2) {
+            let mut stream = stream.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8(request).unwrap().to_lowercase();
+            let is_conditional = request.contains("if-none-match: \"v1\"\r\n");
+            let response = if is_conditional {
+                "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+            } else {
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+            conditional.push(is_conditional);
+        }
+        conditional
+    });
+
+    let data_dir = std::env::temp_dir().join(format!(
+        "gw2-arbitrage-custom-recipes-test-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let data_path = data_dir.join("custom.bin");
+
+    // the first download stores the recipes with their etag, and the second one is skipped by
+    // the server because the recipes haven't changed since
+    for _ in 0..2 {
+        let recipes = gw2efficiency::get_custom_recipes_from(&url, &data_path, None)
+            .await
+            .unwrap();
+        assert_eq!(recipes.len(), 1);
+        assert_eq!(recipes[0].output_item_id, 1000);
+    }
+    assert_eq!(server.join().unwrap(), vec![false, true]);
+
+    // without validators there is no way to check for changes, so the stored recipes are used
+    // without a request
+    std::fs::write(data_path.with_extension("json"), "{}").unwrap();
+    let notify = |url: &str| panic!("Unexpected request to {}", url);
+    let recipes = gw2efficiency::get_custom_recipes_from(&url, &data_path, Some(&notify))
+        .await
+        .unwrap();
+    assert_eq!(recipes.len(), 1);
+
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[test]
+fn inventory_crafted_leftovers_test() {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Output Item", 0));
+    items_map.insert(2000, Item::mock(2000, "Ingredient", 0));
+    items_map.insert(2500, Item::mock(2500, "Intermediate", 0));
+    let mut recipes_map = HashMap::new();
+    recipes_map.insert(
+        1000,
+        Recipe::mock(
+            300,
+            1000,
+            1,
+            [],
+            &[RecipeIngredient {
+                item_id: 2500,
+                count: 1,
+            }],
+            true,
+        ),
+    );
+    recipes_map.insert(
+        2500,
+        Recipe::mock(
+            301,
+            2500,
+            5,
+            [],
+            &[RecipeIngredient {
+                item_id: 2000,
+                count: 1,
+            }],
+            true,
+        ),
+    );
+    let tp_listings_map = tp_listings_map(vec![
+        (1000, vec![(300, 2)], vec![]),
+        (2000, vec![], vec![(100, 10)]),
+    ]);
+    let inventory: HashMap<u32, u32> = vec![(2500, 1), (2000, 1)].into_iter().collect();
+    let profitable_item = calculate_crafting_profit(
+        1000,
+        &recipes_map,
+        &items_map,
+        &tp_listings_map,
+        None,
+        &CraftingOptions {
+            inventory: inventory.clone(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    // the owned intermediate covers the first craft, and the second crafts more of it from the
+    // owned ingredient, so the intermediates left over cost nothing but weren't owned
+    assert_eq!(profitable_item.count, 2);
+    assert_eq!(profitable_item.crafting_cost, Money::from_copper(0));
+    assert_eq!(
+        profitable_item.crafted_items.leftovers.get(&2500),
+        Some(&(4, Money::from_copper(0), crafting::Source::Crafting))
+    );
+    assert!(profitable_item
+        .crafted_items
+        .remaining_inventory(&inventory)
+        .is_empty());
+}
+
+fn tp_listings_map(
+    from: Vec<(u32, Vec<(u32, u32)>, Vec<(u32, u32)>)>,
+) -> HashMap<u32, ItemListings> {
+    let mut map = HashMap::new();
+    for (id, mut buys, mut sells) in from.into_iter() {
+        buys.sort_by(|(price1, _), (price2, _)| price1.cmp(&price2));
+        sells.sort_by(|(price1, _), (price2, _)| price1.cmp(&price2).reverse());
+        map.insert(
+            id,
+            ItemListings {
+                id,
+                buys: buys
+                    .into_iter()
+                    .map(|(unit_price, quantity)| Listing {
+                        listings: quantity,
+                        unit_price,
+                        quantity,
+                    })
+                    .collect(),
+                sells: sells
+                    .into_iter()
+                    .map(|(unit_price, quantity)| Listing {
+                        listings: quantity,
+                        unit_price,
+                        quantity,
+                    })
+                    .collect(),
+            },
+        );
+    }
+    map
+}
+
+/// An output item 1000 crafted by recipe 300 from `ingredient_count` of item 2000
+fn mock_crafting_data(
+    output_item_count: u32,
+    ingredient_count: u32,
+    automatic: bool,
+) -> (HashMap<u32, Item>, HashMap<u32, Recipe>) {
+    let mut items_map = HashMap::new();
+    items_map.insert(1000, Item::mock(1000, "Output Item", 0));
+    items_map.insert(2000, Item::mock(2000, "Ingredient", 0));
+    let mut recipes_map = HashMap::new();
+    recipes_map.insert(
+        1000,
+        Recipe::mock(
+            300,
+            1000,
+            output_item_count,
+            [],
+            &[RecipeIngredient {
+                item_id: 2000,
+                count: ingredient_count,
+            }],
+            automatic,
+        ),
+    );
+    (items_map, recipes_map)
 }
 
 mod data {